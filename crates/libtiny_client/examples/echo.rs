@@ -1,6 +1,6 @@
 //! An echo bot that just repeats stuff sent to it (either in a channel or as PRIVMSG).
 
-use libtiny_client::{Client, Event, ServerInfo};
+use libtiny_client::{AutoJoin, Client, Event, ServerInfo};
 use libtiny_common::ChanNameRef;
 use libtiny_wire::{Cmd, Msg, MsgTarget, Pfx};
 
@@ -31,7 +31,10 @@ fn main() {
 
     let chans = args_vec[1..]
         .iter()
-        .map(|c| ChanNameRef::new(c).to_owned())
+        .map(|c| AutoJoin {
+            chan: ChanNameRef::new(c).to_owned(),
+            key: None,
+        })
         .collect::<Vec<_>>();
 
     let server_info = ServerInfo {
@@ -41,9 +44,16 @@ fn main() {
         pass: None,
         realname: "tiny echo bot".to_owned(),
         nicks: vec![nick],
-        auto_join: chans.to_owned(),
+        auto_join: chans,
         nickserv_ident: None,
+        tls_client_cert: None,
         sasl_auth: None,
+        encoding: None,
+        send_encoding: None,
+        caps: libtiny_client::default_caps(),
+        send_burst: libtiny_client::DEFAULT_SEND_BURST,
+        send_interval_ms: libtiny_client::DEFAULT_SEND_INTERVAL_MS,
+        proxy: None,
     };
 
     println!("{:?}", server_info);
@@ -68,11 +78,21 @@ async fn echo_bot_task(server_info: ServerInfo) {
     let mut rcv_ev = ReceiverStream::new(rcv_ev);
     while let Some(ev) = rcv_ev.next().await {
         println!("Client event: {:?}", ev);
-        if let Event::Msg(Msg {
-            pfx: Some(Pfx::User { nick, .. }),
-            cmd: Cmd::PRIVMSG { target, msg, .. },
-        }) = ev
+        if let Event::Msg {
+            msg:
+                Msg {
+                    pfx: Some(Pfx::User { nick, .. }),
+                    cmd: Cmd::PRIVMSG { targets, msg, .. },
+                    ..
+                },
+            ..
+        } = ev
         {
+            // Only reply to the first target of multi-target messages
+            let target = match targets.into_iter().next() {
+                Some(target) => target,
+                None => continue,
+            };
             let echo_msg = match target {
                 MsgTarget::User(_) => {
                     // Message is a PRIVMSG to us, just echo the whole message to the sender
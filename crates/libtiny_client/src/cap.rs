@@ -0,0 +1,326 @@
+//! IRCv3 capability negotiation. See https://ircv3.net/specs/extensions/capability-negotiation
+//!
+//! On connect we send `CAP LS 302` (when there are capabilities to request) before registering,
+//! request the wanted capabilities that the server supports, and end the negotiation with
+//! `CAP END` when all requests are answered. With version 302 servers notify us about
+//! capabilities that become available or unavailable with `CAP NEW` and `CAP DEL`; we request
+//! new capabilities that we want, and remove deleted ones from the enabled set.
+
+use libtiny_wire as wire;
+
+use std::collections::HashMap;
+use tokio::sync::mpsc::Sender;
+
+/// Max. length of the capability list in a `CAP REQ`. Requests longer than this are split into
+/// multiple messages, as the server has to ACK or NAK a request as a whole.
+const MAX_REQ_LEN: usize = 400;
+
+#[derive(Debug)]
+pub(crate) struct Caps {
+    /// Capabilities to request when available, in the order they're requested.
+    wanted: Vec<String>,
+
+    /// Capabilities advertised by the server with `CAP LS` and `CAP NEW`, with their values.
+    /// Capabilities without values map to an empty string.
+    available: HashMap<String, String>,
+
+    /// Capabilities acknowledged by the server, in the order they're acknowledged.
+    enabled: Vec<String>,
+
+    /// Number of `CAP REQ`s that are not answered yet.
+    pending_reqs: usize,
+
+    /// Are we still registering? `CAP END` is sent, once, when the negotiation is over and we're
+    /// still registering.
+    registering: bool,
+
+    /// Did we get the full `CAP LS` reply? Multi-line replies are accumulated until the last line.
+    ls_done: bool,
+
+    /// Is SASL authentication in progress? Registration is ended after authentication.
+    authenticating: bool,
+}
+
+impl Caps {
+    pub(crate) fn new(wanted: Vec<String>) -> Caps {
+        Caps {
+            wanted,
+            available: HashMap::new(),
+            enabled: vec![],
+            pending_reqs: 0,
+            registering: false,
+            ls_done: false,
+            authenticating: false,
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.available.clear();
+        self.enabled.clear();
+        self.pending_reqs = 0;
+        self.registering = false;
+        self.ls_done = false;
+        self.authenticating = false;
+    }
+
+    /// Start the negotiation by sending `CAP LS 302`. Should be called before registration.
+    pub(crate) fn start(&mut self, snd_irc_msg: &mut Sender<String>) {
+        if !self.wanted.is_empty() {
+            self.registering = true;
+            snd_irc_msg.try_send(wire::cap_ls()).unwrap();
+        }
+    }
+
+    pub(crate) fn is_wanted(&self, cap: &str) -> bool {
+        self.wanted.iter().any(|wanted| wanted == cap)
+    }
+
+    pub(crate) fn is_available(&self, cap: &str) -> bool {
+        self.available.contains_key(cap)
+    }
+
+    pub(crate) fn is_enabled(&self, cap: &str) -> bool {
+        self.enabled.iter().any(|enabled| enabled == cap)
+    }
+
+    pub(crate) fn enabled(&self) -> &[String] {
+        &self.enabled
+    }
+
+    /// Value of an available capability, e.g. "PLAIN,EXTERNAL" for "sasl=PLAIN,EXTERNAL".
+    /// Empty when the capability doesn't have a value.
+    pub(crate) fn value(&self, cap: &str) -> Option<&str> {
+        self.available.get(cap).map(String::as_str)
+    }
+
+    /// Delay `CAP END` until `sasl_done` is called.
+    pub(crate) fn start_sasl(&mut self) {
+        self.authenticating = true;
+    }
+
+    /// SASL authentication succeeded or failed, end the registration if negotiation is over.
+    pub(crate) fn sasl_done(&mut self, snd_irc_msg: &mut Sender<String>) {
+        self.authenticating = false;
+        self.maybe_end(snd_irc_msg);
+    }
+
+    /// Registration is done (we got RPL_WELCOME). Servers without capability negotiation support
+    /// register without a `CAP END`.
+    pub(crate) fn registered(&mut self) {
+        self.registering = false;
+    }
+
+    /// Handle a `CAP` message. Returns the capabilities that are enabled with this message.
+    /// `maybe_end` should be called after this, after starting SASL authentication if needed.
+    pub(crate) fn handle_cap(
+        &mut self,
+        subcommand: &str,
+        params: &[String],
+        more: bool,
+        snd_irc_msg: &mut Sender<String>,
+    ) -> Vec<String> {
+        match subcommand {
+            "LS" => {
+                // After the last line of a reply, the next LS reply starts a new list
+                if self.ls_done {
+                    self.available.clear();
+                    self.ls_done = false;
+                }
+                self.add_available(params);
+                if !more {
+                    self.ls_done = true;
+                    self.request_wanted(snd_irc_msg);
+                }
+                vec![]
+            }
+            "NEW" => {
+                self.add_available(params);
+                self.request_wanted(snd_irc_msg);
+                vec![]
+            }
+            "DEL" => {
+                for cap in params {
+                    self.available.remove(cap.as_str());
+                    self.enabled.retain(|enabled| enabled != cap);
+                }
+                vec![]
+            }
+            "ACK" => {
+                self.pending_reqs = self.pending_reqs.saturating_sub(1);
+                let mut new_caps = vec![];
+                for cap in params {
+                    if cap.is_empty() {
+                        continue;
+                    }
+                    if let Some(cap) = cap.strip_prefix('-') {
+                        self.enabled.retain(|enabled| enabled != cap);
+                    } else if !self.is_enabled(cap) {
+                        self.enabled.push(cap.clone());
+                        new_caps.push(cap.clone());
+                    }
+                }
+                new_caps
+            }
+            "NAK" => {
+                self.pending_reqs = self.pending_reqs.saturating_sub(1);
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// End the registration if we're done with the negotiation.
+    pub(crate) fn maybe_end(&mut self, snd_irc_msg: &mut Sender<String>) {
+        if self.registering && self.ls_done && self.pending_reqs == 0 && !self.authenticating {
+            self.registering = false;
+            snd_irc_msg.try_send(wire::cap_end()).unwrap();
+        }
+    }
+
+    fn add_available(&mut self, params: &[String]) {
+        for cap in params {
+            if cap.is_empty() {
+                continue;
+            }
+            let (name, value) = match cap.find('=') {
+                None => (cap.as_str(), ""),
+                Some(idx) => (&cap[..idx], &cap[idx + 1..]),
+            };
+            self.available.insert(name.to_owned(), value.to_owned());
+        }
+    }
+
+    /// Request wanted capabilities that are available but not enabled yet.
+    fn request_wanted(&mut self, snd_irc_msg: &mut Sender<String>) {
+        let mut req: Vec<&str> = vec![];
+        let mut req_len = 0;
+        for cap in &self.wanted {
+            if !self.available.contains_key(cap) || self.enabled.contains(cap) {
+                continue;
+            }
+            if !req.is_empty() && req_len + cap.len() + 1 > MAX_REQ_LEN {
+                snd_irc_msg.try_send(wire::cap_req(&req)).unwrap();
+                self.pending_reqs += 1;
+                req.clear();
+                req_len = 0;
+            }
+            req_len += cap.len() + 1;
+            req.push(cap);
+        }
+        if !req.is_empty() {
+            snd_irc_msg.try_send(wire::cap_req(&req)).unwrap();
+            self.pending_reqs += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+    use tokio::sync::mpsc::{self, Receiver};
+
+    fn caps(wanted: &[&str]) -> Caps {
+        Caps::new(wanted.iter().map(|cap| (*cap).to_owned()).collect())
+    }
+
+    fn params(caps: &str) -> Vec<String> {
+        caps.split(' ').map(str::to_owned).collect()
+    }
+
+    fn sent(rcv: &mut Receiver<String>) -> Vec<String> {
+        let mut msgs = vec![];
+        while let Some(Some(msg)) = rcv.recv().now_or_never() {
+            msgs.push(msg);
+        }
+        msgs
+    }
+
+    #[test]
+    fn negotiation() {
+        let (mut snd, mut rcv) = mpsc::channel(100);
+        let mut caps = caps(&["cap-notify", "server-time", "sasl", "batch"]);
+
+        caps.start(&mut snd);
+        assert_eq!(sent(&mut rcv), vec!["CAP LS 302\r\n"]);
+
+        // Multi-line LS reply, requests are sent after the last line
+        caps.handle_cap(
+            "LS",
+            &params("cap-notify sasl=PLAIN,EXTERNAL"),
+            true,
+            &mut snd,
+        );
+        caps.maybe_end(&mut snd);
+        assert_eq!(sent(&mut rcv), Vec::<String>::new());
+        caps.handle_cap("LS", &params("server-time away-notify"), false, &mut snd);
+        caps.maybe_end(&mut snd);
+        assert_eq!(
+            sent(&mut rcv),
+            vec!["CAP REQ :cap-notify server-time sasl\r\n"]
+        );
+        assert!(caps.is_available("away-notify"));
+        assert!(!caps.is_available("batch"));
+        assert_eq!(caps.value("sasl"), Some("PLAIN,EXTERNAL"));
+        assert_eq!(caps.value("server-time"), Some(""));
+
+        // SASL delays CAP END
+        let new_caps = caps.handle_cap(
+            "ACK",
+            &params("cap-notify server-time sasl"),
+            false,
+            &mut snd,
+        );
+        assert_eq!(new_caps, params("cap-notify server-time sasl"));
+        caps.start_sasl();
+        caps.maybe_end(&mut snd);
+        assert_eq!(sent(&mut rcv), Vec::<String>::new());
+        caps.sasl_done(&mut snd);
+        assert_eq!(sent(&mut rcv), vec!["CAP END\r\n"]);
+        assert_eq!(caps.enabled(), &params("cap-notify server-time sasl")[..]);
+
+        // New caps are requested after registration, without a CAP END
+        caps.handle_cap("NEW", &params("batch"), false, &mut snd);
+        assert_eq!(sent(&mut rcv), vec!["CAP REQ :batch\r\n"]);
+        // Trailing space
+        caps.handle_cap("ACK", &params("batch "), false, &mut snd);
+        caps.maybe_end(&mut snd);
+        assert_eq!(sent(&mut rcv), Vec::<String>::new());
+        assert!(caps.is_enabled("batch"));
+        assert!(!caps.is_enabled(""));
+
+        caps.handle_cap("DEL", &params("batch server-time"), false, &mut snd);
+        assert!(!caps.is_enabled("batch"));
+        assert!(!caps.is_available("server-time"));
+        assert_eq!(caps.enabled(), &params("cap-notify sasl")[..]);
+    }
+
+    #[test]
+    fn nak_ends_negotiation() {
+        let (mut snd, mut rcv) = mpsc::channel(100);
+        let mut caps = caps(&["cap-notify"]);
+
+        caps.start(&mut snd);
+        caps.handle_cap("LS", &params("cap-notify"), false, &mut snd);
+        caps.maybe_end(&mut snd);
+        caps.handle_cap("NAK", &params("cap-notify"), false, &mut snd);
+        caps.maybe_end(&mut snd);
+        assert_eq!(
+            sent(&mut rcv),
+            vec!["CAP LS 302\r\n", "CAP REQ :cap-notify\r\n", "CAP END\r\n"]
+        );
+        assert_eq!(caps.enabled(), &[] as &[String]);
+
+        // Nothing to request
+        caps.reset();
+        caps.start(&mut snd);
+        caps.handle_cap("LS", &params("sasl"), false, &mut snd);
+        caps.maybe_end(&mut snd);
+        assert_eq!(sent(&mut rcv), vec!["CAP LS 302\r\n", "CAP END\r\n"]);
+
+        // No negotiation when we don't want any caps
+        let mut caps = Caps::new(vec![]);
+        caps.start(&mut snd);
+        assert_eq!(sent(&mut rcv), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,316 @@
+//! Framing of IRC messages, for use with `tokio_util::codec::{FramedRead, FramedWrite, Framed}`.
+
+use crate::wire;
+
+use bytes::BytesMut;
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Max. size of the message tags part, including the leading '@' and the trailing space. See
+/// https://ircv3.net/specs/extensions/message-tags#size-limit
+pub const MAX_TAGS_LEN: usize = 8191;
+
+/// Max. size of the rest of the message, including the "\r\n" suffix, unless the server
+/// advertises a longer `LINELEN`. See `IrcCodec::set_linelen`.
+pub const MAX_BODY_LEN: usize = 512;
+
+/// Decodes lines into `wire::Msg`s and encodes `String`s, which should already have the "\r\n"
+/// suffix. Lines that are longer than the limits are skipped and yield an error, without
+/// buffering the whole line.
+#[derive(Debug, Clone)]
+pub struct IrcCodec {
+    /// Encoding used to decode lines that are not valid UTF-8.
+    encoding: Option<&'static wire::Encoding>,
+    /// Encoding of outgoing messages. UTF-8 when `None`.
+    send_encoding: Option<&'static wire::Encoding>,
+    /// Set when we're skipping a line that's too long. Bytes are dropped until the next '\n'.
+    discarding: bool,
+    /// Number of bytes in the buffer that we already searched for a '\n'.
+    searched: usize,
+    /// Max. size of a message without the tags, including the "\r\n" suffix.
+    max_body_len: usize,
+}
+
+impl Default for IrcCodec {
+    fn default() -> Self {
+        IrcCodec::new(None, None)
+    }
+}
+
+impl IrcCodec {
+    pub fn new(
+        encoding: Option<&'static wire::Encoding>,
+        send_encoding: Option<&'static wire::Encoding>,
+    ) -> IrcCodec {
+        IrcCodec {
+            encoding,
+            send_encoding,
+            discarding: false,
+            searched: 0,
+            max_body_len: MAX_BODY_LEN,
+        }
+    }
+
+    /// Accept messages up to the `LINELEN` advertised by the server (`ISupport::linelen`).
+    /// `MAX_BODY_LEN` is used for smaller values.
+    pub fn set_linelen(&mut self, linelen: usize) {
+        self.max_body_len = linelen.max(MAX_BODY_LEN);
+    }
+
+    fn max_line_len(&self) -> usize {
+        MAX_TAGS_LEN + self.max_body_len
+    }
+}
+
+impl Decoder for IrcCodec {
+    /// Messages that can't be parsed or are too long are returned as `Err`, the stream is still
+    /// usable after those.
+    type Item = Result<wire::Msg, String>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        let nl_idx = match buf[self.searched..].iter().position(|b| *b == b'\n') {
+            Some(offset) => self.searched + offset,
+            None => {
+                if self.discarding || buf.len() > self.max_line_len() {
+                    self.discarding = true;
+                    buf.clear();
+                    self.searched = 0;
+                } else {
+                    self.searched = buf.len();
+                }
+                return Ok(None);
+            }
+        };
+
+        let line = buf.split_to(nl_idx + 1);
+        self.searched = 0;
+
+        if self.discarding {
+            self.discarding = false;
+            return Ok(Some(Err("Line too long".to_owned())));
+        }
+
+        let line = line.strip_suffix(b"\n").unwrap();
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if let Err(err) = check_line_len(line, self.max_body_len) {
+            return Ok(Some(Err(err)));
+        }
+
+        Ok(Some(wire::parse_irc_line(line, self.encoding)))
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        let ret = self.decode(buf)?;
+        if ret.is_none() {
+            // Connection closed in the middle of a line, drop the incomplete line
+            buf.clear();
+            self.discarding = false;
+            self.searched = 0;
+        }
+        Ok(ret)
+    }
+}
+
+impl Encoder<String> for IrcCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: String, buf: &mut BytesMut) -> io::Result<()> {
+        match self.send_encoding {
+            None => buf.extend_from_slice(msg.as_bytes()),
+            Some(encoding) => buf.extend_from_slice(&encode(encoding, &msg)),
+        }
+        Ok(())
+    }
+}
+
+/// Encode a message with the given encoding. Characters that can't be represented in the encoding
+/// are replaced with '?'. (`Encoding::encode` replaces them with HTML numeric character
+/// references, which IRC clients don't decode.)
+pub(crate) fn encode(encoding: &'static wire::Encoding, msg: &str) -> Vec<u8> {
+    let mut encoder = encoding.new_encoder();
+    let mut bytes = Vec::with_capacity(msg.len());
+    let mut msg = msg;
+    loop {
+        let (result, read) =
+            encoder.encode_from_utf8_to_vec_without_replacement(msg, &mut bytes, true);
+        msg = &msg[read..];
+        match result {
+            wire::EncoderResult::InputEmpty => return bytes,
+            wire::EncoderResult::OutputFull => bytes.reserve(msg.len().max(16)),
+            wire::EncoderResult::Unmappable(_) => {
+                bytes.reserve(1);
+                bytes.push(b'?');
+            }
+        }
+    }
+}
+
+/// Check tags and body lengths of a line without the "\r\n" suffix.
+fn check_line_len(line: &[u8], max_body_len: usize) -> Result<(), String> {
+    let mut body = line;
+    if line.starts_with(b"@") {
+        // Tags are followed by a space
+        let tags_len = line
+            .iter()
+            .position(|b| *b == b' ')
+            .map(|idx| idx + 1)
+            .unwrap_or_else(|| line.len());
+        if tags_len > MAX_TAGS_LEN {
+            return Err(format!("Message tags too long ({} bytes)", tags_len));
+        }
+        body = &line[tags_len..];
+    }
+    if body.len() + 2 > max_body_len {
+        return Err(format!("Message too long ({} bytes)", body.len() + 2));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::SinkExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio_stream::StreamExt;
+    use tokio_util::codec::Framed;
+
+    fn decode_all(codec: &mut IrcCodec, buf: &mut BytesMut) -> Vec<Result<wire::Msg, String>> {
+        let mut msgs = vec![];
+        while let Some(msg) = codec.decode(buf).unwrap() {
+            msgs.push(msg);
+        }
+        msgs
+    }
+
+    #[test]
+    fn decode_partial_lines() {
+        let mut codec = IrcCodec::default();
+        let mut buf = BytesMut::new();
+
+        buf.extend_from_slice(b"PING :a\r\nPING");
+        let msgs = decode_all(&mut codec, &mut buf);
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(
+            msgs[0].as_ref().unwrap().cmd,
+            wire::Cmd::PING {
+                server: "a".to_owned()
+            }
+        );
+
+        // Lines without '\r' are also accepted
+        buf.extend_from_slice(b" :b\n");
+        let msgs = decode_all(&mut codec, &mut buf);
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(
+            msgs[0].as_ref().unwrap().cmd,
+            wire::Cmd::PING {
+                server: "b".to_owned()
+            }
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_overlong_lines() {
+        let mut codec = IrcCodec::default();
+        let mut buf = BytesMut::new();
+
+        // A line without "\r\n" that doesn't fit: the buffer shouldn't grow unbounded
+        for _ in 0..10 {
+            buf.extend_from_slice(&[b'a'; 1000]);
+            assert_eq!(decode_all(&mut codec, &mut buf), vec![]);
+            assert!(buf.len() <= codec.max_line_len());
+        }
+        buf.extend_from_slice(b"aaa\r\nPING :a\r\n");
+        let msgs = decode_all(&mut codec, &mut buf);
+        assert_eq!(msgs.len(), 2);
+        assert!(msgs[0].is_err());
+        assert!(msgs[1].is_ok());
+
+        // Body longer than 512 bytes
+        let mut line = b"PRIVMSG #chan :".to_vec();
+        line.extend_from_slice(&[b'a'; MAX_BODY_LEN]);
+        line.extend_from_slice(b"\r\n");
+        buf.extend_from_slice(&line);
+        assert!(decode_all(&mut codec, &mut buf)[0].is_err());
+
+        // Long tags are OK as long as they're within the tags limit
+        let mut line = b"@a=".to_vec();
+        line.extend_from_slice(&[b'a'; 4000]);
+        line.extend_from_slice(b" PING :a\r\n");
+        buf.extend_from_slice(&line);
+        assert!(decode_all(&mut codec, &mut buf)[0].is_ok());
+
+        let mut line = b"@a=".to_vec();
+        line.extend_from_slice(&[b'a'; MAX_TAGS_LEN]);
+        line.extend_from_slice(b" PING :a\r\n");
+        buf.extend_from_slice(&line);
+        assert!(decode_all(&mut codec, &mut buf)[0].is_err());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_linelen() {
+        let mut codec = IrcCodec::default();
+        let mut buf = BytesMut::new();
+        let mut line = b"PRIVMSG #chan :".to_vec();
+        line.extend_from_slice(&[b'a'; 900]);
+        line.extend_from_slice(b"\r\n");
+
+        buf.extend_from_slice(&line);
+        assert!(decode_all(&mut codec, &mut buf)[0].is_err());
+
+        // After LINELEN=1024
+        codec.set_linelen(1024);
+        buf.extend_from_slice(&line);
+        assert!(decode_all(&mut codec, &mut buf)[0].is_ok());
+
+        let mut line = b"PRIVMSG #chan :".to_vec();
+        line.extend_from_slice(&[b'a'; 1024]);
+        line.extend_from_slice(b"\r\n");
+        buf.extend_from_slice(&line);
+        assert!(decode_all(&mut codec, &mut buf)[0].is_err());
+
+        // Smaller values are ignored
+        codec.set_linelen(100);
+        buf.extend_from_slice(b"PRIVMSG #chan :hi\r\n");
+        assert!(decode_all(&mut codec, &mut buf)[0].is_ok());
+    }
+
+    #[test]
+    fn encode_with_encoding() {
+        let mut codec = IrcCodec::new(None, Some(wire::Encoding::for_label(b"latin1").unwrap()));
+        let mut buf = BytesMut::new();
+        codec
+            .encode("PRIVMSG #chan :é\r\n".to_owned(), &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..], b"PRIVMSG #chan :\xe9\r\n");
+
+        // Characters that are not in the encoding
+        buf.clear();
+        codec
+            .encode("PRIVMSG #chan :→ é\r\n".to_owned(), &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..], b"PRIVMSG #chan :? \xe9\r\n");
+    }
+
+    #[tokio::test]
+    async fn duplex() {
+        let (client, mut server) = tokio::io::duplex(64);
+        let mut framed = Framed::new(client, IrcCodec::default());
+
+        framed.send("NICK tiny\r\n".to_owned()).await.unwrap();
+        let mut read_buf = [0; 11];
+        tokio::io::AsyncReadExt::read_exact(&mut server, &mut read_buf)
+            .await
+            .unwrap();
+        assert_eq!(&read_buf, b"NICK tiny\r\n");
+
+        server.write_all(b"PING :a\r\nPING :b\r\n").await.unwrap();
+        drop(server);
+        assert!(framed.next().await.unwrap().unwrap().is_ok());
+        assert!(framed.next().await.unwrap().unwrap().is_ok());
+        assert!(framed.next().await.is_none());
+    }
+}
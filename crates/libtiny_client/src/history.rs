@@ -0,0 +1,386 @@
+//! Fetching messages that we missed while disconnected, with IRCv3 `batch` and
+//! `draft/chathistory` (see https://ircv3.net/specs/extensions/chathistory), or with ZNC's
+//! `*playback` module (`znc.in/playback`) as a fallback.
+//!
+//! We remember the server time of the last message seen in each channel and private conversation.
+//! When we join a channel (including rejoining after a reconnect) we request the messages sent
+//! after that time, and after registration we do the same for private conversations. Servers send
+//! the messages in `chathistory` (ZNC: `znc.in/playback`) batches, which are collected and
+//! delivered as one `Event::History` per batch.
+
+use crate::cap::Caps;
+use crate::utils;
+use crate::Event;
+use libtiny_common::{CaseMapping, ChanName};
+use libtiny_wire as wire;
+use libtiny_wire::{Cmd, Msg};
+
+use std::collections::HashMap;
+use tokio::sync::mpsc::Sender;
+
+/// Types of the batches that we collect into `Event::History`s. The first parameter of these
+/// batches is the target (channel or nick) of the messages.
+const HISTORY_BATCH_TYPES: &[&str] = &["chathistory", "znc.in/playback"];
+
+/// Max. number of messages to request with `CHATHISTORY`, when the server allows more (see
+/// `ISupport::chathistory`).
+const CHATHISTORY_LIMIT: usize = 100;
+
+#[derive(Debug)]
+pub(crate) struct History {
+    /// History batches that we're receiving, indexed by batch references.
+    batches: HashMap<String, Batch>,
+
+    /// Last messages seen in channels and private conversations, indexed by targets normalized
+    /// with the server's case mapping. Not cleared on reset, as it's used to fetch the messages
+    /// that we missed while disconnected.
+    last_seen: HashMap<String, LastSeen>,
+}
+
+#[derive(Debug)]
+struct Batch {
+    target: String,
+    msgs: Vec<(Msg, time::Tm)>,
+}
+
+#[derive(Debug)]
+struct LastSeen {
+    /// Channel, or nick of the other side of a private conversation
+    target: String,
+    /// Time of the last message, in the format of `time` tags (e.g. "2011-10-19T16:40:51.620Z")
+    time: String,
+    /// `time` in milliseconds since the epoch, for comparing times with different precisions
+    millis: i64,
+}
+
+impl History {
+    pub(crate) fn new() -> History {
+        History {
+            batches: HashMap::new(),
+            last_seen: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.batches.clear();
+    }
+
+    /// Record a message in a channel or private conversation. `time` is the message's `time` tag.
+    /// Messages without a `time` tag are not recorded, as the local clock can't be compared with
+    /// the server's. Invalid `time` tags are ignored.
+    pub(crate) fn seen(&mut self, target: &str, time: &str, mapping: CaseMapping) {
+        let millis = match utils::parse_server_time_millis(time) {
+            None => return,
+            Some(millis) => millis,
+        };
+        let last_seen = self
+            .last_seen
+            .entry(mapping.normalize(target))
+            .or_insert_with(|| LastSeen {
+                target: target.to_owned(),
+                time: String::new(),
+                millis: i64::MIN,
+            });
+        // Messages in history batches can be older than the ones we've already seen
+        if millis > last_seen.millis {
+            last_seen.time = time.to_owned();
+            last_seen.millis = millis;
+        }
+    }
+
+    /// Request the messages sent to `target` since the last message we've seen there. With
+    /// `draft/chathistory` nothing is requested for targets that we haven't seen a message in.
+    pub(crate) fn fetch(
+        &self,
+        target: &str,
+        caps: &Caps,
+        isupport: &wire::ISupport,
+        mapping: CaseMapping,
+        snd_irc_msg: &mut Sender<String>,
+    ) {
+        let last_seen = self
+            .last_seen
+            .get(&mapping.normalize(target))
+            .map(|last_seen| last_seen.time.as_str());
+        if caps.is_enabled("draft/chathistory") {
+            if let Some(time) = last_seen {
+                let limit = match isupport.chathistory {
+                    None | Some(0) => CHATHISTORY_LIMIT,
+                    Some(max) => max.min(CHATHISTORY_LIMIT),
+                };
+                snd_irc_msg
+                    .try_send(wire::chathistory_after(target, time, limit))
+                    .unwrap();
+            }
+        } else if caps.is_enabled("znc.in/playback") {
+            // Without a time ZNC plays the whole buffer, as it does for clients that don't
+            // support `znc.in/playback`
+            let from = last_seen
+                .and_then(znc_playback_time)
+                .unwrap_or_else(|| "0".to_owned());
+            snd_irc_msg
+                .try_send(wire::privmsg(
+                    "*playback",
+                    &format!("PLAY {} {}", target, from),
+                ))
+                .unwrap();
+        }
+    }
+
+    /// Request the messages in the private conversations that we've seen a message in.
+    pub(crate) fn fetch_private(
+        &self,
+        caps: &Caps,
+        isupport: &wire::ISupport,
+        mapping: CaseMapping,
+        snd_irc_msg: &mut Sender<String>,
+    ) {
+        for last_seen in self.last_seen.values() {
+            if !isupport.is_chan(&last_seen.target) {
+                self.fetch(&last_seen.target, caps, isupport, mapping, snd_irc_msg);
+            }
+        }
+    }
+
+    /// Handle the start and end of history batches, and collect the messages in them. Returns
+    /// the message back when it's not a part of a history batch.
+    pub(crate) fn handle_msg(
+        &mut self,
+        msg: Msg,
+        timestamp: time::Tm,
+        isupport: &wire::ISupport,
+        mapping: CaseMapping,
+        snd_ev: &mut Sender<Event>,
+    ) -> Option<(Msg, time::Tm)> {
+        match &msg.cmd {
+            Cmd::BATCH {
+                reference,
+                start: true,
+                params,
+            } if params.len() > 1 && HISTORY_BATCH_TYPES.contains(&params[0].as_str()) => {
+                self.batches.insert(
+                    reference.clone(),
+                    Batch {
+                        target: params[1].clone(),
+                        msgs: vec![],
+                    },
+                );
+                None
+            }
+            Cmd::BATCH {
+                reference,
+                start: false,
+                ..
+            } => match self.batches.remove(reference) {
+                None => Some((msg, timestamp)),
+                Some(Batch { target, msgs }) => {
+                    if !msgs.is_empty() {
+                        let target = if isupport.is_chan(&target) {
+                            wire::MsgTarget::Chan(ChanName::new(target))
+                        } else {
+                            wire::MsgTarget::User(target)
+                        };
+                        snd_ev.try_send(Event::History { target, msgs }).unwrap();
+                    }
+                    None
+                }
+            },
+            _ => {
+                let batch = match msg.tags.get("batch") {
+                    None => return Some((msg, timestamp)),
+                    Some(reference) => match self.batches.get_mut(reference) {
+                        None => return Some((msg, timestamp)),
+                        Some(batch) => batch,
+                    },
+                };
+                let target = batch.target.clone();
+                batch.msgs.push((msg, timestamp));
+                let time = batch.msgs.last().unwrap().0.tags.get("time").cloned();
+                if let Some(time) = time {
+                    self.seen(&target, &time, mapping);
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Convert a `time` tag to the format `PLAY` of ZNC's `*playback` expects: seconds since the
+/// epoch, with milliseconds.
+fn znc_playback_time(time: &str) -> Option<String> {
+    let millis = utils::parse_server_time_millis(time)?;
+    Some(format!("{}.{:03}", millis / 1000, millis % 1000))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+    use tokio::sync::mpsc::{self, Receiver};
+
+    fn sent<T>(rcv: &mut Receiver<T>) -> Vec<T> {
+        let mut msgs = vec![];
+        while let Some(Some(msg)) = rcv.recv().now_or_never() {
+            msgs.push(msg);
+        }
+        msgs
+    }
+
+    fn caps(enabled: &str) -> Caps {
+        let (mut snd, _rcv) = mpsc::channel(100);
+        let mut caps = Caps::new(vec![enabled.to_owned()]);
+        caps.handle_cap("ACK", &[enabled.to_owned()], false, &mut snd);
+        caps
+    }
+
+    fn parse(line: &str) -> Msg {
+        wire::parse_irc_line(line.as_bytes(), None).unwrap()
+    }
+
+    #[test]
+    fn fetch() {
+        let (mut snd, mut rcv) = mpsc::channel(100);
+        let mapping = CaseMapping::Rfc1459;
+        let isupport = wire::ISupport::default();
+        let mut history = History::new();
+        history.seen("#chan", "2011-10-19T16:40:51.620Z", mapping);
+        history.seen("#CHAN", "2011-10-19T16:40:50.000Z", mapping);
+        history.seen("nick", "2011-10-19T16:40:52.000Z", mapping);
+
+        let chathistory = caps("draft/chathistory");
+        history.fetch("#Chan", &chathistory, &isupport, mapping, &mut snd);
+        history.fetch("#other", &chathistory, &isupport, mapping, &mut snd);
+        history.fetch_private(&chathistory, &isupport, mapping, &mut snd);
+        assert_eq!(
+            sent(&mut rcv),
+            vec![
+                "CHATHISTORY AFTER #Chan timestamp=2011-10-19T16:40:51.620Z 100\r\n",
+                "CHATHISTORY AFTER nick timestamp=2011-10-19T16:40:52.000Z 100\r\n",
+            ]
+        );
+
+        let playback = caps("znc.in/playback");
+        history.fetch("#chan", &playback, &isupport, mapping, &mut snd);
+        history.fetch("#other", &playback, &isupport, mapping, &mut snd);
+        assert_eq!(
+            sent(&mut rcv),
+            vec![
+                "PRIVMSG *playback :PLAY #chan 1319042451.620\r\n",
+                "PRIVMSG *playback :PLAY #other 0\r\n",
+            ]
+        );
+
+        // Neither is enabled
+        history.fetch("#chan", &caps("batch"), &isupport, mapping, &mut snd);
+        assert_eq!(sent(&mut rcv), Vec::<String>::new());
+    }
+
+    #[test]
+    fn seen_mixed_precision() {
+        let (mut snd, mut rcv) = mpsc::channel(100);
+        let mapping = CaseMapping::Rfc1459;
+        let isupport = wire::ISupport::default();
+        let chathistory = caps("draft/chathistory");
+        let mut history = History::new();
+
+        // "...:51Z" is after "...:51.620Z" as a string, but earlier
+        history.seen("#chan", "2011-10-19T16:40:51.620Z", mapping);
+        history.seen("#chan", "2011-10-19T16:40:51Z", mapping);
+        history.seen("#chan", "invalid", mapping);
+        history.fetch("#chan", &chathistory, &isupport, mapping, &mut snd);
+        assert_eq!(
+            sent(&mut rcv),
+            vec!["CHATHISTORY AFTER #chan timestamp=2011-10-19T16:40:51.620Z 100\r\n"]
+        );
+
+        history.seen("#chan", "2011-10-19T16:40:52Z", mapping);
+        history.fetch("#chan", &chathistory, &isupport, mapping, &mut snd);
+        assert_eq!(
+            sent(&mut rcv),
+            vec!["CHATHISTORY AFTER #chan timestamp=2011-10-19T16:40:52Z 100\r\n"]
+        );
+        history.fetch(
+            "#chan",
+            &caps("znc.in/playback"),
+            &isupport,
+            mapping,
+            &mut snd,
+        );
+        assert_eq!(
+            sent(&mut rcv),
+            vec!["PRIVMSG *playback :PLAY #chan 1319042452.000\r\n"]
+        );
+    }
+
+    #[test]
+    fn collect_batches() {
+        let (mut snd, mut rcv) = mpsc::channel(100);
+        let mapping = CaseMapping::Rfc1459;
+        let isupport = wire::ISupport::default();
+        let mut history = History::new();
+        let now = time::now();
+
+        let lines = [
+            ":irc.server BATCH +a chathistory #chan",
+            ":irc.server BATCH +b netsplit irc.hub other.host",
+            "@batch=a;time=2011-10-19T16:40:51.620Z :x!u@h PRIVMSG #chan :hi",
+            "@batch=b :y!u@h QUIT :irc.hub other.host",
+            "@time=2011-10-19T16:41:00.000Z :x!u@h PRIVMSG #chan :live",
+            "@batch=a;time=2011-10-19T16:40:52.000Z :x!u@h PRIVMSG #chan :there",
+            ":irc.server BATCH -a",
+            ":irc.server BATCH -b",
+            ":irc.server BATCH +c chathistory nick",
+            ":irc.server BATCH -c",
+        ];
+        let mut passed = vec![];
+        for line in &lines {
+            if let Some((msg, _)) =
+                history.handle_msg(parse(line), now, &isupport, mapping, &mut snd)
+            {
+                passed.push(msg);
+            }
+        }
+
+        // Messages that are not in history batches are passed through
+        assert_eq!(
+            passed,
+            vec![
+                parse(lines[1]),
+                parse(lines[3]),
+                parse(lines[4]),
+                parse(lines[7])
+            ]
+        );
+
+        // Empty batches are not reported
+        let evs = sent(&mut rcv);
+        assert_eq!(evs.len(), 1);
+        match &evs[0] {
+            Event::History { target, msgs } => {
+                assert_eq!(
+                    target,
+                    &wire::MsgTarget::Chan(ChanName::new("#chan".to_owned()))
+                );
+                assert_eq!(
+                    msgs.iter().map(|(msg, _)| msg).collect::<Vec<_>>(),
+                    vec![&parse(lines[2]), &parse(lines[5])]
+                );
+            }
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+
+        // Last seen time is updated with the messages in the batch
+        let (mut snd_msg, mut rcv_msg) = mpsc::channel(100);
+        history.fetch(
+            "#chan",
+            &caps("draft/chathistory"),
+            &isupport,
+            mapping,
+            &mut snd_msg,
+        );
+        assert_eq!(
+            sent(&mut rcv_msg),
+            vec!["CHATHISTORY AFTER #chan timestamp=2011-10-19T16:40:52.000Z 100\r\n"]
+        );
+    }
+}
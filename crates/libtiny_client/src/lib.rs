@@ -1,27 +1,41 @@
 #![allow(clippy::unneeded_field_pattern)]
 #![allow(clippy::cognitive_complexity)]
 
+mod cap;
+mod codec;
+mod history;
 mod pinger;
+mod proxy;
+mod request;
+mod sasl;
+mod send_queue;
 mod state;
 mod stream;
 mod utils;
 
-use libtiny_common::{ChanName, ChanNameRef};
+use libtiny_common::{CaseMapping, ChanName, ChanNameRef};
 pub use libtiny_wire as wire;
 
+pub use codec::IrcCodec;
+
 use pinger::Pinger;
+use send_queue::SendQueue;
 use state::State;
 use stream::{Stream, StreamError};
 
+use std::cell::RefCell;
 use std::net::{SocketAddr, ToSocketAddrs};
-use std::time::Duration;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use futures_util::future::FutureExt;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use futures_util::SinkExt;
+use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 use tokio::{pin, select};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, FramedWrite};
 
 #[macro_use]
 extern crate log;
@@ -33,6 +47,41 @@ extern crate log;
 /// `Client` tries to reconnect on error after this many seconds.
 pub const RECONNECT_SECS: u64 = 30;
 
+/// Requests like `Client::whois` fail with `RequestError::Timeout` when the reply doesn't arrive
+/// in this many seconds.
+pub const REQUEST_TIMEOUT_SECS: u64 = 30;
+
+/// Default value of `ServerInfo::send_burst`.
+pub const DEFAULT_SEND_BURST: usize = 5;
+
+/// Default value of `ServerInfo::send_interval_ms`.
+pub const DEFAULT_SEND_INTERVAL_MS: u64 = 2000;
+
+/// IRCv3 capabilities that the client supports. Used as the default value of
+/// `ServerInfo::caps`.
+pub const DEFAULT_CAPS: &[&str] = &[
+    "account-notify",
+    "away-notify",
+    "batch",
+    "cap-notify",
+    "chghost",
+    "draft/chathistory",
+    "echo-message",
+    "extended-join",
+    "labeled-response",
+    "multi-prefix",
+    "server-time",
+    "setname",
+    "userhost-in-names",
+    "znc.in/playback",
+    "znc.in/server-time-iso",
+];
+
+/// `DEFAULT_CAPS` as a `ServerInfo::caps` value.
+pub fn default_caps() -> Vec<String> {
+    DEFAULT_CAPS.iter().map(|cap| (*cap).to_owned()).collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct ServerInfo {
     /// Server address
@@ -44,6 +93,10 @@ pub struct ServerInfo {
     /// Use TLS?
     pub tls: bool,
 
+    /// TLS client certificate, used to identify with the certificate fingerprint (CertFP) or to
+    /// authenticate with SASL EXTERNAL. Only used when `tls` is set.
+    pub tls_client_cert: Option<TlsClientCert>,
+
     /// Server password.
     pub pass: Option<String>,
 
@@ -53,7 +106,7 @@ pub struct ServerInfo {
     pub nicks: Vec<String>,
 
     /// Channels to automatically join
-    pub auto_join: Vec<ChanName>,
+    pub auto_join: Vec<AutoJoin>,
 
     /// Nickserv password. Sent to NickServ on connecting to the server and nick change, before
     /// join commands.
@@ -61,15 +114,276 @@ pub struct ServerInfo {
 
     /// SASL authentication credentials,
     pub sasl_auth: Option<SASLAuth>,
+
+    /// Encoding to decode incoming messages that are not valid UTF-8 with. When `None` invalid
+    /// UTF-8 sequences are replaced with U+FFFD REPLACEMENT CHARACTER.
+    pub encoding: Option<&'static wire::Encoding>,
+
+    /// Encoding of outgoing messages. UTF-8 when `None`. Characters that can't be represented in
+    /// the encoding are sent as '?'.
+    pub send_encoding: Option<&'static wire::Encoding>,
+
+    /// IRCv3 capabilities to request when the server supports them. Capability negotiation is
+    /// skipped when this is empty and `sasl_auth` is not set. "sasl" is requested when
+    /// `sasl_auth` is set, so it doesn't need to be in the list. See also `default_caps`.
+    pub caps: Vec<String>,
+
+    /// Outgoing flood control: max. number of messages to send at once. Messages over this are
+    /// queued and sent one in every `send_interval_ms` milliseconds. PING, PONG and QUIT are not
+    /// delayed. 0 disables flood control. See also `DEFAULT_SEND_BURST`.
+    pub send_burst: usize,
+
+    /// See `send_burst` and `DEFAULT_SEND_INTERVAL_MS`.
+    pub send_interval_ms: u64,
+
+    /// Proxy to connect to the server through. With `tls` the TLS connection is established over
+    /// the proxied connection.
+    pub proxy: Option<Proxy>,
+}
+
+/// A channel to automatically join, with an optional channel key
+#[derive(Debug, Clone)]
+pub struct AutoJoin {
+    pub chan: ChanName,
+    pub key: Option<String>,
+}
+
+impl AutoJoin {
+    /// Parse a channel and an optional key separated by whitespace, e.g. `"#chan key"`.
+    pub fn parse(s: &str) -> Option<AutoJoin> {
+        let mut words = s.split_whitespace();
+        let chan = ChanName::new(words.next()?.to_owned());
+        let key = words.next().map(str::to_owned);
+        Some(AutoJoin { chan, key })
+    }
+}
+
+/// SASL authentication mechanism and credentials
+#[derive(Debug, Clone)]
+pub enum SASLAuth {
+    /// Username and password, sent in plain text.
+    Plain { username: String, password: String },
+    /// Authenticate with the TLS client certificate. See `ServerInfo::tls_client_cert`.
+    External,
+    /// SCRAM-SHA-256, falls back to SCRAM-SHA-1 when the server doesn't support SHA-256. The
+    /// password is not sent to the server, and the server is verified to know the password.
+    ScramSha256 { username: String, password: String },
+    /// SCRAM-SHA-1
+    ScramSha1 { username: String, password: String },
+}
+
+/// A TLS client certificate and its private key, PEM encoded. The key should be in PKCS#8 format
+/// ("BEGIN PRIVATE KEY"); with rustls PKCS#1 RSA keys ("BEGIN RSA PRIVATE KEY") are also supported.
+/// `cert` and `key` can be the same file when the file has both the certificate and the key.
+#[derive(Debug, Clone)]
+pub struct TlsClientCert {
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+/// A proxy to connect to the server through. `addr` and `port` are the address and port of the
+/// proxy.
+#[derive(Debug, Clone)]
+pub enum Proxy {
+    /// SOCKS5 proxy (RFC 1928). With `remote_dns` the server's host name is sent to the proxy to
+    /// be resolved (e.g. with Tor), otherwise we resolve it and send the address.
+    Socks5 {
+        addr: String,
+        port: u16,
+        auth: Option<ProxyAuth>,
+        remote_dns: bool,
+    },
+    /// HTTP proxy that supports the `CONNECT` method. The server's host name is resolved by the
+    /// proxy.
+    HttpConnect {
+        addr: String,
+        port: u16,
+        auth: Option<ProxyAuth>,
+    },
 }
 
-/// SASL authentication credentials
+/// Username and password of a proxy. Sent in plain text, with the SOCKS5 username/password
+/// authentication (RFC 1929) or HTTP basic authentication.
 #[derive(Debug, Clone)]
-pub struct SASLAuth {
+pub struct ProxyAuth {
     pub username: String,
     pub password: String,
 }
 
+/// What we know about a user in one of our channels, or us. See `Client::get_user`.
+///
+/// Most of the fields are updated with the IRCv3 capabilities `away-notify`, `account-notify`,
+/// `extended-join`, `chghost`, `setname`, `multi-prefix` and `userhost-in-names`, and WHO and
+/// WHOIS replies. Fields are `None` until we learn about them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserInfo {
+    pub nick: String,
+    pub user: Option<String>,
+    pub host: Option<String>,
+    pub realname: Option<String>,
+    /// Services account of the user. `None` when the user is not logged in, or we don't know.
+    pub account: Option<String>,
+    /// Away message when the user is away. Empty when the user is away but we don't know the
+    /// message.
+    pub away: Option<String>,
+    /// Channels of the user that we're also in, with the user's prefix modes in the channel (e.g.
+    /// "ov" for an op with voice) in order of decreasing rank.
+    pub chans: Vec<(ChanName, String)>,
+}
+
+/// What we know about one of our channels. See `Client::get_channel`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelInfo {
+    pub name: ChanName,
+    /// Channel modes, other than list and prefix modes, with their arguments. E.g.
+    /// `[('n', None), ('l', Some("50"))]`. Updated with RPL_CHANNELMODEIS (requested when we join
+    /// the channel) and MODE messages.
+    pub modes: Vec<(char, Option<String>)>,
+    /// `None` when the channel doesn't have a topic, or we don't know the topic.
+    pub topic: Option<Topic>,
+    /// Creation time of the channel, as a Unix timestamp (RPL_CREATIONTIME)
+    pub created: Option<u64>,
+    /// Users in the channel, sorted by nick
+    pub members: Vec<ChannelMember>,
+    /// Ban list (mode `b`). `None` until requested with `Client::request_mode_list`, then updated
+    /// with MODE messages.
+    pub bans: Option<Vec<ModeListEntry>>,
+    /// Ban exception list (mode `e`). See `bans`.
+    pub excepts: Option<Vec<ModeListEntry>>,
+    /// Invite exception list (mode `I`). See `bans`.
+    pub invites: Option<Vec<ModeListEntry>>,
+}
+
+/// A user in a channel. See `ChannelInfo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelMember {
+    pub nick: String,
+    /// Prefix modes of the user (e.g. "ov"), in order of decreasing rank. With `multi-prefix` we
+    /// know all of the modes, otherwise only the highest one until a MODE.
+    pub modes: String,
+    /// Membership prefixes for `modes`, e.g. "@+". The first one is the one to show next to the
+    /// nick.
+    pub prefixes: String,
+}
+
+/// Topic of a channel. `setter` and `time` are from RPL_TOPICWHOTIME, or the TOPIC message that
+/// set the topic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topic {
+    pub text: String,
+    /// Nick or `nick!user@host` mask of the user who set the topic
+    pub setter: Option<String>,
+    /// Unix timestamp
+    pub time: Option<u64>,
+}
+
+/// An entry in a ban, ban exception or invite exception list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModeListEntry {
+    pub mask: String,
+    /// Nick or `nick!user@host` mask of the user who added the entry
+    pub setter: Option<String>,
+    /// Unix timestamp
+    pub time: Option<u64>,
+}
+
+/// Lists of masks in a channel, see `Client::request_mode_list`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModeList {
+    /// Ban list (mode `b`)
+    Bans,
+    /// Ban exceptions (mode `e`, or `EXCEPTS` in RPL_ISUPPORT)
+    Excepts,
+    /// Invite exceptions (mode `I`, or `INVEX` in RPL_ISUPPORT)
+    Invites,
+}
+
+/// Reply to `Client::whois`. Fields are `None` (or empty) when the server doesn't send the
+/// information.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WhoisInfo {
+    pub nick: String,
+    pub user: Option<String>,
+    pub host: Option<String>,
+    pub realname: Option<String>,
+    /// Server that the user is connected to
+    pub server: Option<String>,
+    /// Description of `server`
+    pub server_info: Option<String>,
+    /// Services account of the user
+    pub account: Option<String>,
+    /// Away message, when the user is away
+    pub away: Option<String>,
+    /// Channels of the user, with membership prefixes (e.g. "@#chan")
+    pub chans: Vec<String>,
+    /// Idle time, in seconds
+    pub idle: Option<u64>,
+    /// Sign-on time, as a Unix timestamp
+    pub signon: Option<u64>,
+    /// Is the user an IRC operator?
+    pub operator: bool,
+    /// Is the user connected with TLS? (RPL_WHOISSECURE)
+    pub secure: bool,
+    /// Other information in the reply that is not in the fields above, e.g. "is connecting from
+    /// *@127.0.0.1 127.0.0.1", one line per reply message.
+    pub other: Vec<String>,
+}
+
+impl WhoisInfo {
+    pub(crate) fn new(nick: &str) -> WhoisInfo {
+        WhoisInfo {
+            nick: nick.to_owned(),
+            ..WhoisInfo::default()
+        }
+    }
+}
+
+/// A user in the reply to `Client::who`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoReply {
+    /// A channel of the user. `None` when the user is not in a channel that we can see.
+    pub chan: Option<ChanName>,
+    pub nick: String,
+    pub user: String,
+    pub host: String,
+    pub server: String,
+    /// `H` (here) or `G` (gone), followed by optional `*` (IRC operator) and membership prefixes
+    pub flags: String,
+    pub away: bool,
+    pub realname: String,
+}
+
+/// A channel in the reply to `Client::list`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListEntry {
+    pub chan: ChanName,
+    /// Number of visible users in the channel
+    pub users: usize,
+    pub topic: String,
+}
+
+/// Why a request like `Client::whois` failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestError {
+    /// The server replied with an error numeric, e.g. ERR_NOSUCHNICK (401). `msg` is the
+    /// human-readable message in the reply.
+    Reply { num: u16, msg: String },
+    /// No reply in `REQUEST_TIMEOUT_SECS` seconds after the request was sent.
+    Timeout,
+    /// Disconnected before the reply.
+    Disconnected,
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::Reply { msg, .. } => f.write_str(msg),
+            RequestError::Timeout => f.write_str("Timed out waiting for the reply"),
+            RequestError::Disconnected => f.write_str("Disconnected before the reply"),
+        }
+    }
+}
+
 /// IRC client events. Returned by `Client` to the users via a channel.
 ///
 /// Note that Client only returns when it can't resolve the domain name. In all other cases (no
@@ -97,12 +411,26 @@ pub enum Event {
     CantResolveAddr,
     /// Nick changed.
     NickChange { new_nick: String },
-    /// A message from the server
-    Msg(wire::Msg),
+    /// A message from the server. `timestamp` is the time in the message's `time` tag when the
+    /// server sends one (see the `server-time` capability), the time the message was received
+    /// otherwise.
+    Msg { msg: wire::Msg, timestamp: time::Tm },
     /// A wire-protocol error
     WireError(String),
     /// Channel join error message
     ChannelJoinError { chan: ChanName, msg: String },
+    /// A message sent with `Client::privmsg` was not echoed back by the server, even though
+    /// `echo-message` is enabled. Either the server rejected the message, or it wasn't echoed in
+    /// 30 seconds after it was sent. `target` is the message target without STATUSMSG prefixes.
+    MsgNotEchoed { target: String, msg: String },
+    /// Messages that we missed in a channel or private conversation while disconnected (or not in
+    /// the channel), fetched with `draft/chathistory` or ZNC playback. Oldest first, with the
+    /// same timestamps as `Event::Msg`. `target` is the channel, or the other side of the private
+    /// conversation. These messages are not sent as `Event::Msg`s.
+    History {
+        target: wire::MsgTarget,
+        msgs: Vec<(wire::Msg, time::Tm)>,
+    },
 }
 
 impl From<StreamError> for Event {
@@ -128,6 +456,9 @@ pub struct Client {
     /// Reference to the state, to be able to provide methods like `get_nick` and
     /// `is_nick_accepted`.
     state: State,
+
+    /// Messages waiting to be sent, shared with the writer task of the current connection.
+    send_queue: Rc<RefCell<SendQueue>>,
 }
 
 impl Client {
@@ -161,6 +492,63 @@ impl Client {
         self.state.is_nick_accepted()
     }
 
+    /// Get server capabilities advertised with RPL_ISUPPORT (005). Until the server sends 005 this
+    /// returns the defaults.
+    // FIXME: This clones the whole thing
+    pub fn get_isupport(&self) -> wire::ISupport {
+        self.state.get_isupport()
+    }
+
+    /// Is the name a channel name, according to the server's `CHANTYPES`? See
+    /// `wire::ISupport::is_chan`.
+    pub fn is_chan(&self, name: &str) -> bool {
+        self.state.is_chan(name)
+    }
+
+    /// Is the mode a membership prefix mode, according to the server's `PREFIX`? See
+    /// `wire::ISupport::is_prefix_mode`.
+    pub fn is_prefix_mode(&self, mode: char) -> bool {
+        self.state.is_prefix_mode(mode)
+    }
+
+    /// Split membership prefixes of a nick in a RPL_NAMREPLY, according to the server's `PREFIX`.
+    /// See `wire::ISupport::split_nick_prefix`.
+    pub fn split_nick_prefix<'a>(&self, nick: &'a str) -> (&'a str, &'a str) {
+        self.state.split_nick_prefix(nick)
+    }
+
+    /// Get the case mapping of the server. Use this to compare nicks and match hostmasks, e.g. with
+    /// `wire::Hostmask::matches`.
+    pub fn get_case_mapping(&self) -> CaseMapping {
+        self.state.get_case_mapping()
+    }
+
+    /// Is the capability in the list of capabilities to request? See `ServerInfo::caps`.
+    pub fn is_cap_wanted(&self, cap: &str) -> bool {
+        self.state.is_cap_wanted(cap)
+    }
+
+    /// Did the server advertise the capability in `CAP LS` or `CAP NEW`?
+    pub fn is_cap_available(&self, cap: &str) -> bool {
+        self.state.is_cap_available(cap)
+    }
+
+    /// Get the value of an available capability, e.g. "PLAIN,EXTERNAL" for
+    /// "sasl=PLAIN,EXTERNAL". Empty string when the capability doesn't have a value.
+    pub fn get_cap_value(&self, cap: &str) -> Option<String> {
+        self.state.get_cap_value(cap)
+    }
+
+    /// Is the capability enabled on this connection?
+    pub fn is_cap_enabled(&self, cap: &str) -> bool {
+        self.state.is_cap_enabled(cap)
+    }
+
+    /// Get capabilities enabled on this connection, in the order they're enabled.
+    pub fn get_enabled_caps(&self) -> Vec<String> {
+        self.state.get_enabled_caps()
+    }
+
     /// Send a message directly to the server. "\r\n" suffix is added by this method.
     pub fn raw_msg(&mut self, msg: &str) {
         self.msg_chan
@@ -169,7 +557,8 @@ impl Client {
     }
 
     /// Split a privmsg to multiple messages so that each message is, when the hostname and nick
-    /// prefix added by the server, fits in one IRC message.
+    /// prefix added by the server, fits in one IRC message. Lengths are calculated with the send
+    /// encoding.
     ///
     /// `extra_len`: Size (in bytes) for a prefix/suffix etc. that'll be added to each line.
     pub fn split_privmsg<'a>(
@@ -177,33 +566,23 @@ impl Client {
         extra_len: usize,
         msg: &'a str,
     ) -> impl Iterator<Item = &'a str> {
-        // Max msg len calculation adapted from hexchat
-        // (src/common/outbound.c:split_up_text)
-        let mut max = 512; // RFC 2812
-        max -= 3; // :, !, @
-        max -= 13; // " PRIVMSG ", " ", :, \r, \n
-        max -= self.get_nick().len();
-        max -= extra_len;
-        match self.state.get_usermask() {
-            None => {
-                max -= 9; // max username
-                max -= 64; // max possible hostname (63) + '@'
-                           // NOTE(osa): I think hexchat has an error here, it
-                           // uses 65
-            }
-            Some(ref usermask) => {
-                max -= usermask.len();
-            }
-        }
-
-        assert!(max > 0);
-
-        utils::split_iterator(msg, max)
+        let max = utils::max_privmsg_len(
+            &self.state.get_isupport(),
+            &self.get_nick(),
+            self.state.get_usermask().as_deref(),
+            extra_len,
+        );
+        utils::split_iterator(msg, max, self.state.get_send_encoding())
     }
 
     /// Send a privmsg. Note that this method does not split long messages into smaller messages;
     /// use `split_privmsg` for that.
+    ///
+    /// When `echo-message` is enabled the server sends the message back to us (as an
+    /// `Event::Msg`) once it's accepted, so it shouldn't be shown until then. Messages that are
+    /// not echoed are reported with `Event::MsgNotEchoed`.
     pub fn privmsg(&mut self, target: &str, msg: &str, is_action: bool) {
+        self.state.add_pending_echo(target, msg, is_action);
         let wire_fn = if is_action {
             wire::action
         } else {
@@ -214,6 +593,14 @@ impl Client {
             .unwrap();
     }
 
+    /// Send a CTCP query. `args` is added after the CTCP command when not empty. Replies are
+    /// NOTICEs with the same CTCP command.
+    pub fn ctcp(&mut self, target: &str, ctcp: &wire::CTCP, args: &str) {
+        self.msg_chan
+            .try_send(Cmd::Msg(wire::ctcp_query(target, ctcp, args)))
+            .unwrap();
+    }
+
     /// Join the given list of channels.
     pub fn join<'a, I>(&mut self, chans: I)
     where
@@ -255,6 +642,123 @@ impl Client {
     pub fn get_chan_nicks(&self, chan: &ChanNameRef) -> Vec<String> {
         self.state.get_chan_nicks(chan)
     }
+
+    /// Get what we know about a user in one of our channels, or us. Returns `None` for users
+    /// that are not in any of our channels.
+    pub fn get_user(&self, nick: &str) -> Option<UserInfo> {
+        self.state.get_user(nick)
+    }
+
+    /// Number of messages waiting in the send queue. See `ServerInfo::send_burst`.
+    pub fn send_queue_len(&self) -> usize {
+        self.send_queue.borrow().len()
+    }
+
+    /// Drop PRIVMSGs and NOTICEs to `target` (a channel or a nick) waiting in the send queue, e.g.
+    /// when the user closes the tab. Returns the number of dropped messages.
+    pub fn cancel_queued(&mut self, target: &str) -> usize {
+        let mapping = self.state.get_case_mapping();
+        let dropped = self.send_queue.borrow_mut().cancel(target, mapping);
+        self.state.cancel_pending_echoes(&dropped);
+        dropped.len()
+    }
+
+    /// Get what we know about one of our channels: modes, topic, members with their prefix modes,
+    /// and the mode lists that were requested. Returns `None` if we're not in the channel.
+    pub fn get_channel(&self, chan: &ChanNameRef) -> Option<ChannelInfo> {
+        self.state.get_channel(chan)
+    }
+
+    /// Request a ban, ban exception or invite exception list of a channel. The list is available
+    /// in `ChannelInfo` after the reply, and kept up to date with MODE messages. The reply is also
+    /// sent as `Event::Msg`s.
+    pub fn request_mode_list(&mut self, chan: &ChanNameRef, list: ModeList) {
+        let mode = self.state.get_list_mode(list);
+        self.msg_chan
+            .try_send(Cmd::Msg(wire::mode(
+                chan.display(),
+                &[wire::ModeChange {
+                    mode,
+                    set: true,
+                    arg: None,
+                }],
+            )))
+            .unwrap()
+    }
+
+    /// Send a WHOIS query and wait for the reply. Fails with `RequestError::Reply` when the server
+    /// replies with an error (e.g. ERR_NOSUCHNICK), with `RequestError::Timeout` when the reply
+    /// doesn't arrive in `REQUEST_TIMEOUT_SECS` seconds, and with `RequestError::Disconnected`
+    /// when the connection is lost before the reply.
+    ///
+    /// Replies are matched with the request using `labeled-response` when it's enabled, and by the
+    /// reply numerics otherwise. Replies to requests are not sent as `Event::Msg`s.
+    pub async fn whois(&mut self, nick: &str) -> Result<WhoisInfo, RequestError> {
+        let response = request::Response::Whois(Box::new(WhoisInfo::new(nick)));
+        match self.request(response, nick, wire::whois(nick)).await? {
+            request::Response::Whois(info) => Ok(*info),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Send a WHO query for a channel or a nick mask and wait for the reply. See `whois` for the
+    /// errors.
+    pub async fn who(&mut self, mask: &str) -> Result<Vec<WhoReply>, RequestError> {
+        let response = request::Response::Who(vec![]);
+        match self.request(response, mask, wire::who(mask)).await? {
+            request::Response::Who(replies) => Ok(replies),
+            _ => unreachable!(),
+        }
+    }
+
+    /// List channels and wait for the reply. `filter` is a comma-separated list of channels, or a
+    /// server-specific filter (see `ELIST` in RPL_ISUPPORT). See `whois` for the errors.
+    pub async fn list(&mut self, filter: Option<&str>) -> Result<Vec<ListEntry>, RequestError> {
+        let response = request::Response::List(vec![]);
+        match self.request(response, "", wire::list(filter)).await? {
+            request::Response::List(entries) => Ok(entries),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Get nicks in a channel from the server, with their prefix modes (e.g. "ov") in order of
+    /// decreasing rank. Unlike `get_chan_nicks`, this works for channels that we're not in. See
+    /// `whois` for the errors.
+    pub async fn names(
+        &mut self,
+        chan: &ChanNameRef,
+    ) -> Result<Vec<(String, String)>, RequestError> {
+        let response = request::Response::Names(vec![]);
+        match self
+            .request(response, chan.display(), wire::names(chan))
+            .await?
+        {
+            request::Response::Names(names) => Ok(names),
+            _ => unreachable!(),
+        }
+    }
+
+    async fn request(
+        &mut self,
+        response: request::Response,
+        target: &str,
+        msg: String,
+    ) -> Result<request::Response, RequestError> {
+        let (id, msg, rcv_sent, rcv_response) = self.state.add_request(response, target, msg);
+        self.msg_chan.try_send(Cmd::Msg(msg)).unwrap();
+        // The request can wait in the send queue, the timeout starts when it's sent. The sender is
+        // dropped when the request is dropped on reconnect, `rcv_response` fails in that case.
+        let _ = rcv_sent.await;
+        match tokio::time::timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS), rcv_response).await {
+            Ok(Ok(result)) => result,
+            // Pending requests are dropped when we reconnect
+            Ok(Err(_)) => Err(RequestError::Disconnected),
+            Err(_) => {
+                self.state.cancel_request(id);
+                Err(RequestError::Timeout)
+            }
+        }
+    }
 }
 
 //
@@ -293,7 +797,18 @@ fn connect(server_info: ServerInfo) -> (Client, mpsc::Receiver<Event>) {
     let irc_state = State::new(server_info.clone());
     let irc_state_clone = irc_state.clone();
 
-    let task = main_loop(server_info, irc_state_clone, snd_ev, rcv_cmd);
+    let send_queue = Rc::new(RefCell::new(SendQueue::new(
+        server_info.send_burst,
+        Duration::from_millis(server_info.send_interval_ms),
+    )));
+
+    let task = main_loop(
+        server_info,
+        irc_state_clone,
+        send_queue.clone(),
+        snd_ev,
+        rcv_cmd,
+    );
     tokio::task::spawn_local(task);
 
     (
@@ -301,6 +816,7 @@ fn connect(server_info: ServerInfo) -> (Client, mpsc::Receiver<Event>) {
             msg_chan: snd_cmd,
             serv_name,
             state: irc_state,
+            send_queue,
         },
         rcv_ev,
     )
@@ -309,6 +825,7 @@ fn connect(server_info: ServerInfo) -> (Client, mpsc::Receiver<Event>) {
 async fn main_loop(
     server_info: ServerInfo,
     irc_state: State,
+    send_queue: Rc<RefCell<SendQueue>>,
     mut snd_ev: mpsc::Sender<Event>,
     rcv_cmd: mpsc::Receiver<Cmd>,
 ) {
@@ -322,6 +839,9 @@ async fn main_loop(
 
     // Main loop just tries to (re)connect
     'connect: loop {
+        // Requests sent on the previous connection won't be answered
+        irc_state.reset_requests();
+
         if wait {
             match wait_(&mut rcv_cmd).await {
                 TaskResult::Done(()) => {}
@@ -350,9 +870,22 @@ async fn main_loop(
 
         debug!("Resolving address");
 
-        let serv_name_clone = serv_name.clone();
+        // With a proxy we connect to the proxy, which connects to the server
+        let (connect_name, connect_port) = match &server_info.proxy {
+            None => (serv_name.clone(), port),
+            Some(Proxy::Socks5 {
+                addr,
+                port: proxy_port,
+                ..
+            })
+            | Some(Proxy::HttpConnect {
+                addr,
+                port: proxy_port,
+                ..
+            }) => (addr.clone(), *proxy_port),
+        };
 
-        let addr_iter = match resolve_addr(serv_name_clone, port, &mut rcv_cmd).await {
+        let addr_iter = match resolve_addr(connect_name, connect_port, &mut rcv_cmd).await {
             TaskResult::Done(Ok(addr_iter)) => {
                 debug!("resolve_addr: done");
                 addr_iter
@@ -384,6 +917,41 @@ async fn main_loop(
 
         debug!("Address resolved: {:?}", addrs);
 
+        // Host to ask the proxy to connect to. Without remote DNS the server's address is
+        // resolved here.
+        let proxy_host = match &server_info.proxy {
+            Some(Proxy::Socks5 {
+                remote_dns: false, ..
+            }) => match resolve_addr(serv_name.clone(), port, &mut rcv_cmd).await {
+                TaskResult::Done(Ok(mut addr_iter)) => match addr_iter.next() {
+                    Some(addr) => addr.ip().to_string(),
+                    None => {
+                        snd_ev.send(Event::CantResolveAddr).await.unwrap();
+                        return;
+                    }
+                },
+                TaskResult::Done(Err(err)) => {
+                    debug!("resolve_addr: {:?}", err);
+                    snd_ev.send(Event::IoErr(err)).await.unwrap();
+                    wait = true;
+                    continue;
+                }
+                TaskResult::Reconnect(mb_port) => {
+                    port = mb_port.unwrap_or(port);
+                    wait = false;
+                    continue;
+                }
+                TaskResult::Return => {
+                    return;
+                }
+            },
+            _ => serv_name.clone(),
+        };
+        let proxy = server_info
+            .proxy
+            .as_ref()
+            .map(|proxy| (proxy, proxy_host.as_str()));
+
         //
         // Establish TCP connection to the server
         //
@@ -391,7 +959,10 @@ async fn main_loop(
         let stream = match try_connect(
             addrs,
             &serv_name,
+            port,
+            proxy,
             server_info.tls,
+            server_info.tls_client_cert.as_ref(),
             &mut rcv_cmd,
             &mut snd_ev,
         )
@@ -413,7 +984,10 @@ async fn main_loop(
             }
         };
 
-        let (mut read_half, mut write_half) = tokio::io::split(stream);
+        let (read_half, write_half) = tokio::io::split(stream);
+        let codec = IrcCodec::new(server_info.encoding, server_info.send_encoding);
+        let mut read_half = FramedRead::new(read_half, codec.clone());
+        let mut write_half = FramedWrite::new(write_half, codec);
 
         debug!("Done");
 
@@ -421,27 +995,55 @@ async fn main_loop(
         // Do the business
         //
 
-        // Reset the connection state
+        // Reset the connection state. Messages queued for the previous connection are dropped.
         irc_state.reset();
+        let epoch = send_queue.borrow_mut().reset(Instant::now());
         // Introduce self
-        if server_info.sasl_auth.is_some() {
-            // Will introduce self after getting a response to this LS command.
-            // This is to avoid getting stuck during nick registration. See the
-            // discussion in #91.
-            snd_msg.try_send(wire::cap_ls()).unwrap();
-        } else {
-            irc_state.introduce(&mut snd_msg);
-        }
-
-        // Spawn a task for outgoing messages.
-        let snd_ev_clone = snd_ev.clone();
+        irc_state.introduce(&mut snd_msg);
+
+        // Spawn a task for outgoing messages. Messages are sent through the send queue, for flood
+        // control. Messages that are not echoed in time after they're sent are reported here too,
+        // without waiting for a message from the server.
+        let mut snd_ev_clone = snd_ev.clone();
+        let send_queue_clone = send_queue.clone();
+        let irc_state_clone = irc_state.clone();
         tokio::task::spawn_local(async move {
-            let mut rcv_msg = ReceiverStream::new(rcv_msg);
-            while let Some(msg) = rcv_msg.next().await {
-                if let Err(io_err) = write_half.write_all(msg.as_str().as_bytes()).await {
-                    debug!("IO error when writing: {:?}", io_err);
-                    snd_ev_clone.send(Event::IoErr(io_err)).await.unwrap();
-                    return;
+            let mut rcv_msg = ReceiverStream::new(rcv_msg).fuse();
+            loop {
+                let now = Instant::now();
+                let delay = send_queue_clone.borrow().next_send_delay(now);
+                let echo_timeout = irc_state_clone.next_echo_timeout(now);
+                select! {
+                    msg = rcv_msg.next() => {
+                        match msg {
+                            // Connection is closed or reset
+                            None => return,
+                            Some(msg) => send_queue_clone.borrow_mut().push(msg),
+                        }
+                    }
+                    () = tokio::time::sleep(delay.unwrap_or_default()), if delay.is_some() => {}
+                    () = tokio::time::sleep(echo_timeout.unwrap_or_default()), if echo_timeout.is_some() => {
+                        irc_state_clone.expire_pending_echoes(&mut snd_ev_clone);
+                    }
+                }
+                loop {
+                    let msg = {
+                        let mut send_queue = send_queue_clone.borrow_mut();
+                        if send_queue.epoch() != epoch {
+                            // Queue is reset for a new connection
+                            return;
+                        }
+                        match send_queue.pop(Instant::now()) {
+                            None => break,
+                            Some(msg) => msg,
+                        }
+                    };
+                    irc_state_clone.msg_sent(&msg);
+                    if let Err(io_err) = write_half.send(msg).await {
+                        debug!("IO error when writing: {:?}", io_err);
+                        snd_ev_clone.send(Event::IoErr(io_err)).await.unwrap();
+                        return;
+                    }
                 }
             }
         });
@@ -450,11 +1052,7 @@ async fn main_loop(
         let (mut pinger, rcv_ping_evs) = Pinger::new();
         let mut rcv_ping_evs = ReceiverStream::new(rcv_ping_evs).fuse();
 
-        let mut parse_buf: Vec<u8> = Vec::with_capacity(1024);
-
         loop {
-            let mut read_buf: [u8; 1024] = [0; 1024];
-
             select! {
                 cmd = rcv_cmd.next() => {
                     match cmd {
@@ -484,35 +1082,48 @@ async fn main_loop(
                 // It's fine to fuse() the read_half here because we restart main loop with a new
                 // stream when this stream ends (either with an error, or when it's closed on the
                 // remote end), so we never poll it again after it terminates.
-                bytes = read_half.read(&mut read_buf).fuse() => {
-                    match bytes {
-                        Err(io_err) => {
+                msg = read_half.next().fuse() => {
+                    match msg {
+                        Some(Err(io_err)) => {
                             debug!("main loop: error when reading from socket: {:?}", io_err);
                             snd_ev.send(Event::IoErr(io_err)).await.unwrap();
                             snd_ev.send(Event::Disconnected).await.unwrap();
                             wait = true;
                             continue 'connect;
                         }
-                        Ok(0) => {
-                            debug!("main loop: read 0 bytes");
+                        None => {
+                            debug!("main loop: connection closed");
                             snd_ev.send(Event::ConnectionClosed).await.unwrap();
                             snd_ev.send(Event::Disconnected).await.unwrap();
                             wait = true;
                             continue 'connect;
                         }
-                        Ok(bytes) => {
-                            parse_buf.extend_from_slice(&read_buf[0..bytes]);
-                            while let Some(msg) = wire::parse_irc_msg(&mut parse_buf) {
-                                match msg {
-                                    Err(err) => {
-                                        snd_ev.send(Event::WireError(err)).await.unwrap();
-                                    }
-                                    Ok(mut msg) => {
-                                        debug!("parsed msg: {:?}", msg);
-                                        pinger.reset();
-                                        irc_state.update(&mut msg, &mut snd_ev, &mut snd_msg);
-                                        snd_ev.send(Event::Msg(msg)).await.unwrap();
-                                    }
+                        Some(Ok(Err(err))) => {
+                            snd_ev.send(Event::WireError(err)).await.unwrap();
+                        }
+                        Some(Ok(Ok(msg))) => {
+                            debug!("parsed msg: {:?}", msg);
+                            pinger.reset();
+                            let timestamp = msg
+                                .tags
+                                .get("time")
+                                .and_then(|time| utils::parse_server_time(time))
+                                .unwrap_or_else(time::now);
+                            if let Some((mut msg, timestamp)) =
+                                irc_state.handle_history(msg, timestamp, &mut snd_ev)
+                            {
+                                irc_state.update(&mut msg, &mut snd_ev, &mut snd_msg);
+                                if let wire::Cmd::Reply {
+                                    num: wire::numeric::RPL_ISUPPORT,
+                                    ..
+                                } = msg.cmd
+                                {
+                                    // The server may advertise a longer LINELEN
+                                    read_half.decoder_mut().set_linelen(irc_state.get_linelen());
+                                }
+                                // Replies to requests are returned to the callers
+                                if !irc_state.handle_reply(&msg) {
+                                    snd_ev.send(Event::Msg { msg, timestamp }).await.unwrap();
                                 }
                             }
                         }
@@ -540,6 +1151,25 @@ async fn main_loop(
     }
 }
 
+async fn connect_stream(
+    addr: SocketAddr,
+    serv_name: &str,
+    port: u16,
+    proxy: Option<(&Proxy, &str)>,
+    use_tls: bool,
+    client_cert: Option<&TlsClientCert>,
+) -> Result<Stream, StreamError> {
+    let mut tcp_stream = TcpStream::connect(addr).await?;
+    if let Some((proxy, host)) = proxy {
+        proxy::handshake(&mut tcp_stream, proxy, host, port).await?;
+    }
+    if use_tls {
+        Stream::new_tls(tcp_stream, serv_name, client_cert).await
+    } else {
+        Ok(Stream::new_tcp(tcp_stream))
+    }
+}
+
 enum TaskResult<A> {
     Done(A),
     Return,
@@ -620,21 +1250,24 @@ async fn resolve_addr<S: StreamExt<Item = Cmd> + Unpin>(
     }
 }
 
+/// Connect to the server, or to the proxy when `proxy` is given. `addrs` are the addresses of the
+/// server or the proxy, tried in order. `proxy` has the host that the proxy should connect to.
+#[allow(clippy::too_many_arguments)]
 async fn try_connect<S: StreamExt<Item = Cmd> + Unpin>(
     addrs: Vec<SocketAddr>,
     serv_name: &str,
+    port: u16,
+    proxy: Option<(&Proxy, &str)>,
     use_tls: bool,
+    client_cert: Option<&TlsClientCert>,
     rcv_cmd: &mut S,
     snd_ev: &mut mpsc::Sender<Event>,
 ) -> TaskResult<Option<Stream>> {
     let connect_task = async move {
         for addr in addrs {
             snd_ev.send(Event::Connecting(addr)).await.unwrap();
-            let mb_stream = if use_tls {
-                Stream::new_tls(addr, &serv_name).await
-            } else {
-                Stream::new_tcp(addr).await
-            };
+            let mb_stream =
+                connect_stream(addr, serv_name, port, proxy, use_tls, client_cert).await;
             match mb_stream {
                 Err(err) => {
                     snd_ev.send(Event::from(err)).await.unwrap();
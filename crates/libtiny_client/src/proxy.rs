@@ -0,0 +1,391 @@
+//! Connecting to the server through a SOCKS5 (RFC 1928, with RFC 1929 username/password
+//! authentication) or an HTTP `CONNECT` proxy.
+//!
+//! `handshake` is run on the TCP connection to the proxy, before TLS. After the handshake the
+//! connection is a tunnel to the server.
+
+use crate::{Proxy, ProxyAuth};
+
+use std::io::{Error, Result};
+use std::net::IpAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Max. size of the HTTP response to a `CONNECT` request. The response is just a status line and
+/// a few headers, bigger responses are not from a proxy.
+const MAX_HTTP_RESPONSE_LEN: usize = 8192;
+
+/// Ask the proxy to connect to `host` (a host name, or an IP address) and `port`.
+pub(crate) async fn handshake<S>(stream: &mut S, proxy: &Proxy, host: &str, port: u16) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match proxy {
+        Proxy::Socks5 { auth, .. } => socks5_handshake(stream, auth.as_ref(), host, port).await,
+        Proxy::HttpConnect { auth, .. } => http_handshake(stream, auth.as_ref(), host, port).await,
+    }
+}
+
+fn proxy_err(msg: String) -> Error {
+    Error::other(msg)
+}
+
+async fn socks5_handshake<S>(
+    stream: &mut S,
+    auth: Option<&ProxyAuth>,
+    host: &str,
+    port: u16,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Greeting: version, supported authentication methods (0: none, 2: username/password)
+    match auth {
+        None => stream.write_all(&[5, 1, 0]).await?,
+        Some(_) => stream.write_all(&[5, 2, 0, 2]).await?,
+    }
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 5 {
+        return Err(proxy_err("Not a SOCKS5 proxy".to_owned()));
+    }
+    match (reply[1], auth) {
+        (0, _) => {}
+        (2, Some(auth)) => {
+            let username = auth.username.as_bytes();
+            let password = auth.password.as_bytes();
+            if username.len() > 255 || password.len() > 255 {
+                return Err(proxy_err(
+                    "SOCKS5 username and password can be at most 255 bytes".to_owned(),
+                ));
+            }
+            let mut msg = vec![1, username.len() as u8];
+            msg.extend_from_slice(username);
+            msg.push(password.len() as u8);
+            msg.extend_from_slice(password);
+            stream.write_all(&msg).await?;
+            // Reply: version of the username/password authentication (1), status
+            stream.read_exact(&mut reply).await?;
+            if reply[0] != 1 {
+                return Err(proxy_err(format!(
+                    "Invalid SOCKS5 authentication reply version: {}",
+                    reply[0]
+                )));
+            }
+            if reply[1] != 0 {
+                return Err(proxy_err("SOCKS5 proxy authentication failed".to_owned()));
+            }
+        }
+        _ => {
+            return Err(proxy_err(
+                "SOCKS5 proxy doesn't support the authentication method".to_owned(),
+            ));
+        }
+    }
+
+    // Request: version, command (1: CONNECT), reserved, address, port
+    let mut msg = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            msg.push(1);
+            msg.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            msg.push(4);
+            msg.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(proxy_err(format!("Host name too long: {}", host)));
+            }
+            msg.push(3);
+            msg.push(host.len() as u8);
+            msg.extend_from_slice(host.as_bytes());
+        }
+    }
+    msg.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&msg).await?;
+
+    // Reply: version, status, reserved, bound address, bound port
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 5 {
+        return Err(proxy_err(format!(
+            "Invalid SOCKS5 reply version: {}",
+            reply[0]
+        )));
+    }
+    if reply[1] != 0 {
+        return Err(proxy_err(format!(
+            "SOCKS5 proxy can't connect to {}:{}: {}",
+            host,
+            port,
+            socks5_error(reply[1])
+        )));
+    }
+    let addr_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        atyp => {
+            return Err(proxy_err(format!(
+                "Unknown address type in SOCKS5 reply: {}",
+                atyp
+            )));
+        }
+    };
+    // Bound address and port are not used
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+fn socks5_error(status: u8) -> &'static str {
+    match status {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+async fn http_handshake<S>(
+    stream: &mut S,
+    auth: Option<&ProxyAuth>,
+    host: &str,
+    port: u16,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let authority = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => format!("[{}]:{}", host, port),
+        _ => format!("{}:{}", host, port),
+    };
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    if let Some(auth) = auth {
+        let credentials = base64::encode(format!("{}:{}", auth.username, auth.password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read the response one byte at a time, to not read any of the IRC messages after it
+    let mut response = vec![];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() == MAX_HTTP_RESPONSE_LEN {
+            return Err(proxy_err("HTTP proxy response too long".to_owned()));
+        }
+        response.push(stream.read_u8().await?);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or("");
+    let mut words = status_line.split(' ');
+    match (words.next(), words.next()) {
+        (Some(version), Some("200")) if version.starts_with("HTTP/") => Ok(()),
+        _ => Err(proxy_err(format!(
+            "HTTP proxy can't connect to {}: {}",
+            authority, status_line
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn socks5(auth: Option<ProxyAuth>) -> Proxy {
+        Proxy::Socks5 {
+            addr: "127.0.0.1".to_owned(),
+            port: 1080,
+            auth,
+            remote_dns: true,
+        }
+    }
+
+    fn auth() -> ProxyAuth {
+        ProxyAuth {
+            username: "user".to_owned(),
+            password: "pass".to_owned(),
+        }
+    }
+
+    /// A SOCKS5 proxy stand-in that accepts one connection, checks the handshake, and sends "hi"
+    /// through the tunnel.
+    async fn socks5_proxy(listener: TcpListener, expected_request: Vec<u8>) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut greeting = [0u8; 2];
+        stream.read_exact(&mut greeting).await.unwrap();
+        let mut methods = vec![0u8; greeting[1] as usize];
+        stream.read_exact(&mut methods).await.unwrap();
+        if methods.contains(&2) {
+            stream.write_all(&[5, 2]).await.unwrap();
+            let mut auth = [0u8; 11];
+            stream.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            stream.write_all(&[1, 0]).await.unwrap();
+        } else {
+            stream.write_all(&[5, 0]).await.unwrap();
+        }
+        let mut request = vec![0u8; expected_request.len()];
+        stream.read_exact(&mut request).await.unwrap();
+        assert_eq!(request, expected_request);
+        stream
+            .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80])
+            .await
+            .unwrap();
+        stream.write_all(b"hi").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn socks5_remote_dns() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut expected_request = vec![5, 1, 0, 3, 11];
+        expected_request.extend_from_slice(b"irc.example");
+        expected_request.extend_from_slice(&6697u16.to_be_bytes());
+        let proxy = tokio::spawn(socks5_proxy(listener, expected_request));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        handshake(&mut stream, &socks5(Some(auth())), "irc.example", 6697)
+            .await
+            .unwrap();
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn socks5_ip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let expected_request = vec![5, 1, 0, 1, 10, 0, 0, 1, 0x1a, 0x0b];
+        let proxy = tokio::spawn(socks5_proxy(listener, expected_request));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        handshake(&mut stream, &socks5(None), "10.0.0.1", 6667)
+            .await
+            .unwrap();
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn socks5_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[5, 0]).await.unwrap();
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).await.unwrap();
+            // Connection refused
+            stream
+                .write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let err = handshake(&mut stream, &socks5(None), "10.0.0.1", 6667)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "SOCKS5 proxy can't connect to 10.0.0.1:6667: connection refused"
+        );
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn socks5_invalid_reply() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[5, 0]).await.unwrap();
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).await.unwrap();
+            // SOCKS4 reply
+            stream.write_all(&[0, 90, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let err = handshake(&mut stream, &socks5(None), "10.0.0.1", 6667)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Invalid SOCKS5 reply version: 0");
+        proxy.await.unwrap();
+    }
+
+    /// An HTTP proxy stand-in that accepts one connection, checks the request, and replies with
+    /// `response` followed by "hi".
+    async fn http_proxy(listener: TcpListener, expected_request: &'static str, response: &str) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = vec![0u8; expected_request.len()];
+        stream.read_exact(&mut request).await.unwrap();
+        assert_eq!(String::from_utf8(request).unwrap(), expected_request);
+        stream.write_all(response.as_bytes()).await.unwrap();
+        stream.write_all(b"hi").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn http_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = tokio::spawn(http_proxy(
+            listener,
+            "CONNECT irc.example:6697 HTTP/1.1\r\n\
+             Host: irc.example:6697\r\n\
+             Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n",
+            "HTTP/1.1 200 Connection established\r\nVia: proxy\r\n\r\n",
+        ));
+
+        let http = Proxy::HttpConnect {
+            addr: "127.0.0.1".to_owned(),
+            port: 8080,
+            auth: Some(auth()),
+        };
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        handshake(&mut stream, &http, "irc.example", 6697)
+            .await
+            .unwrap();
+        // Data after the response is not consumed by the handshake
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+        proxy.await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = tokio::spawn(http_proxy(
+            listener,
+            "CONNECT [::1]:6667 HTTP/1.1\r\nHost: [::1]:6667\r\n\r\n",
+            "HTTP/1.1 403 Forbidden\r\n\r\n",
+        ));
+        let http = Proxy::HttpConnect {
+            addr: "127.0.0.1".to_owned(),
+            port: 8080,
+            auth: None,
+        };
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let err = handshake(&mut stream, &http, "::1", 6667)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "HTTP proxy can't connect to [::1]:6667: HTTP/1.1 403 Forbidden"
+        );
+        proxy.await.unwrap();
+    }
+}
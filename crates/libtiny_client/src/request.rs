@@ -0,0 +1,782 @@
+//! Requests with replies: `WHOIS`, `WHO`, `LIST` and `NAMES`. See `Client::whois` and friends.
+//!
+//! With `labeled-response` (see https://ircv3.net/specs/extensions/labeled-response) requests are
+//! sent with a `label` tag, and the server sends the reply (a single message, or a
+//! `labeled-response` batch) with the same label. Otherwise replies are matched with the oldest
+//! pending request of the same kind by the reply numerics and their nick, mask or channel
+//! parameters, and requests are done with the end-of-reply numerics (e.g. RPL_ENDOFWHOIS).
+//!
+//! Replies to requests are not sent to the user as `Event::Msg`s.
+
+use crate::{ListEntry, RequestError, WhoReply, WhoisInfo};
+use libtiny_common::{CaseMapping, ChanName};
+use libtiny_wire as wire;
+use libtiny_wire::{numeric, Cmd, Msg, Numeric};
+
+use std::collections::HashMap;
+use tokio::sync::oneshot;
+
+/// Numerics sent in WHOIS replies, with the nick as the first parameter. Includes the common
+/// non-standard ones, e.g. RPL_WHOISHOST (378).
+const WHOIS_NUMERICS: &[u16] = &[
+    numeric::RPL_USINGSSL,
+    numeric::RPL_WHOISCERTFP,
+    numeric::RPL_AWAY,
+    numeric::RPL_WHOISREGNICK,
+    numeric::RPL_WHOISHELPOP,
+    numeric::RPL_WHOISUSER,
+    numeric::RPL_WHOISSERVER,
+    numeric::RPL_WHOISOPERATOR,
+    numeric::RPL_WHOISIDLE,
+    numeric::RPL_ENDOFWHOIS,
+    numeric::RPL_WHOISCHANNELS,
+    numeric::RPL_WHOISSPECIAL,
+    numeric::RPL_WHOISACCOUNT,
+    numeric::RPL_WHOISBOT,
+    numeric::RPL_WHOISACTUALLY,
+    numeric::RPL_WHOISHOST,
+    numeric::RPL_WHOISMODES,
+    numeric::RPL_WHOISSECURE,
+];
+
+/// Errors about a command, with the command as the first parameter. These fail the requests with
+/// the command.
+const CMD_ERRORS: &[u16] = &[
+    numeric::RPL_TRYAGAIN,
+    numeric::ERR_TOOMANYMATCHES,
+    numeric::ERR_UNKNOWNCOMMAND,
+    numeric::ERR_NEEDMOREPARAMS,
+];
+
+/// Reply of a request, filled in as the reply messages arrive.
+#[derive(Debug)]
+pub(crate) enum Response {
+    Whois(Box<WhoisInfo>),
+    Who(Vec<WhoReply>),
+    List(Vec<ListEntry>),
+    /// Nicks with their prefix modes
+    Names(Vec<(String, String)>),
+}
+
+pub(crate) type ResponseReceiver = oneshot::Receiver<Result<Response, RequestError>>;
+
+#[derive(Debug)]
+pub(crate) struct Requests {
+    next_id: u64,
+
+    /// Requests waiting for replies, oldest first.
+    pending: Vec<Request>,
+
+    /// `labeled-response` batches that we're receiving, mapped to the labels of the requests.
+    batches: HashMap<String, String>,
+}
+
+#[derive(Debug)]
+struct Request {
+    id: u64,
+    /// Label of the request when `labeled-response` is enabled.
+    label: Option<String>,
+    /// Command of the request, e.g. "WHOIS". Errors about the command (e.g. ERR_NEEDMOREPARAMS)
+    /// fail the request.
+    cmd: &'static str,
+    /// Nick, mask or channel of the request, normalized with the server's case mapping. Empty for
+    /// LIST.
+    target: String,
+    response: Response,
+    /// `None` when the request failed with an error that is followed by the rest of the reply,
+    /// see `Request::continues_after_error`. The request is removed at the end of the reply.
+    snd_response: Option<oneshot::Sender<Result<Response, RequestError>>>,
+    /// The request message and the sender to notify when it's sent, until it's sent. Messages can
+    /// wait in the send queue, see `ServerInfo::send_burst`.
+    unsent: Option<(String, oneshot::Sender<()>)>,
+}
+
+/// Status of a request after handling a reply message.
+enum Status {
+    Pending,
+    Done,
+    Failed(RequestError),
+}
+
+impl Requests {
+    pub(crate) fn new() -> Requests {
+        Requests {
+            next_id: 0,
+            pending: vec![],
+            batches: HashMap::new(),
+        }
+    }
+
+    /// Drop the pending requests, which fails them with `RequestError::Disconnected`.
+    pub(crate) fn reset(&mut self) {
+        self.pending.clear();
+        self.batches.clear();
+    }
+
+    /// Add a request. `msg` is the request message, as generated by the `libtiny_wire`
+    /// functions. Returns the id of the request (see `cancel`), the message to send, with the
+    /// label when `labeled` is set, a receiver notified when the message is sent (see `sent`), and
+    /// the receiver of the response.
+    pub(crate) fn add(
+        &mut self,
+        response: Response,
+        target: &str,
+        msg: String,
+        labeled: bool,
+        mapping: CaseMapping,
+    ) -> (u64, String, oneshot::Receiver<()>, ResponseReceiver) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let (label, msg) = if labeled {
+            let label = id.to_string();
+            let msg = wire::tagged(&[("label", &label)], msg);
+            (Some(label), msg)
+        } else {
+            (None, msg)
+        };
+
+        let cmd = match response {
+            Response::Whois(_) => "WHOIS",
+            Response::Who(_) => "WHO",
+            Response::List(_) => "LIST",
+            Response::Names(_) => "NAMES",
+        };
+
+        let (snd_sent, rcv_sent) = oneshot::channel();
+        let (snd_response, rcv_response) = oneshot::channel();
+        self.pending.push(Request {
+            id,
+            label,
+            cmd,
+            target: mapping.normalize(target),
+            response,
+            snd_response: Some(snd_response),
+            unsent: Some((msg.clone(), snd_sent)),
+        });
+        (id, msg, rcv_sent, rcv_response)
+    }
+
+    /// A message is written to the connection. Notifies the oldest unsent request with the
+    /// message.
+    pub(crate) fn sent(&mut self, msg: &str) {
+        let req = self.pending.iter_mut().find(|req| match &req.unsent {
+            Some((req_msg, _)) => req_msg == msg,
+            None => false,
+        });
+        if let Some((_, snd_sent)) = req.and_then(|req| req.unsent.take()) {
+            // The receiver is dropped when the caller stops waiting for the response
+            let _ = snd_sent.send(());
+        }
+    }
+
+    /// Remove a request, e.g. after a timeout. Replies to the request that arrive later are sent
+    /// to the user as `Event::Msg`s, unless they're labeled.
+    pub(crate) fn cancel(&mut self, id: u64) {
+        self.pending.retain(|req| req.id != id);
+    }
+
+    /// Handle a message that may be a reply to a request. Returns whether the message is a part
+    /// of a reply.
+    pub(crate) fn handle_msg(
+        &mut self,
+        msg: &Msg,
+        isupport: &wire::ISupport,
+        mapping: CaseMapping,
+    ) -> bool {
+        self.remove_dropped();
+
+        // A labeled reply: a single message (`ACK` when there's no reply), or the start of a
+        // `labeled-response` batch
+        if let Some(label) = msg.tags.get("label") {
+            let req_idx = match self.find_label(label) {
+                None => return false,
+                Some(req_idx) => req_idx,
+            };
+            match &msg.cmd {
+                Cmd::BATCH {
+                    reference,
+                    start: true,
+                    params,
+                } if params.first().map(String::as_str) == Some("labeled-response") => {
+                    self.batches.insert(reference.clone(), label.clone());
+                }
+                _ => {
+                    let status = self.pending[req_idx].handle_reply(&msg.cmd, isupport);
+                    match status {
+                        Status::Failed(err) => self.finish(req_idx, Some(err)),
+                        Status::Pending | Status::Done => self.finish(req_idx, None),
+                    }
+                }
+            }
+            return true;
+        }
+
+        // End of a `labeled-response` batch
+        if let Cmd::BATCH {
+            reference,
+            start: false,
+            ..
+        } = &msg.cmd
+        {
+            if let Some(label) = self.batches.remove(reference) {
+                if let Some(req_idx) = self.find_label(&label) {
+                    self.finish(req_idx, None);
+                }
+                return true;
+            }
+        }
+
+        // A message in a `labeled-response` batch
+        if let Some(label) = msg
+            .tags
+            .get("batch")
+            .and_then(|batch| self.batches.get(batch))
+        {
+            if let Some(req_idx) = self.find_label(label) {
+                if let Status::Failed(err) = self.pending[req_idx].handle_reply(&msg.cmd, isupport)
+                {
+                    self.finish(req_idx, Some(err));
+                }
+            }
+            return true;
+        }
+
+        // Unlabeled replies, matched by the numerics
+        let (num, params) = match &msg.cmd {
+            Cmd::Reply { num, params } => (*num, params),
+            _ => return false,
+        };
+        let req_idx = match self
+            .pending
+            .iter()
+            .position(|req| req.label.is_none() && req.matches(num, params, mapping))
+        {
+            None => return false,
+            Some(req_idx) => req_idx,
+        };
+        match self.pending[req_idx].handle_reply(&msg.cmd, isupport) {
+            Status::Pending => {}
+            Status::Done => self.finish(req_idx, None),
+            Status::Failed(err) if self.pending[req_idx].continues_after_error(num) => {
+                let req = &mut self.pending[req_idx];
+                if let Some(snd_response) = req.snd_response.take() {
+                    let _ = snd_response.send(Err(err));
+                }
+            }
+            Status::Failed(err) => self.finish(req_idx, Some(err)),
+        }
+        true
+    }
+
+    /// Remove the requests that the callers are not waiting for anymore, so that replies to them
+    /// are not mistaken for replies to other requests.
+    fn remove_dropped(&mut self) {
+        self.pending.retain(|req| match &req.snd_response {
+            Some(snd_response) => !snd_response.is_closed(),
+            None => true,
+        });
+    }
+
+    fn find_label(&self, label: &str) -> Option<usize> {
+        self.pending
+            .iter()
+            .position(|req| req.label.as_deref() == Some(label))
+    }
+
+    /// Remove a request and send the response, or the error.
+    fn finish(&mut self, req_idx: usize, err: Option<RequestError>) {
+        let req = self.pending.remove(req_idx);
+        let result = match err {
+            None => Ok(req.response),
+            Some(err) => Err(err),
+        };
+        // Receiver may be dropped, e.g. when the caller is not waiting for the response anymore
+        if let Some(snd_response) = req.snd_response {
+            let _ = snd_response.send(result);
+        }
+    }
+}
+
+impl Request {
+    /// Is the unlabeled error followed by the rest of the reply? Servers send RPL_ENDOFWHOIS after
+    /// ERR_NOSUCHNICK.
+    fn continues_after_error(&self, num: u16) -> bool {
+        matches!(self.response, Response::Whois(_)) && num == numeric::ERR_NOSUCHNICK
+    }
+
+    /// Is the unlabeled numeric a reply to this request?
+    fn matches(&self, num: u16, params: &[String], mapping: CaseMapping) -> bool {
+        let arg_matches = |idx: usize| {
+            params
+                .get(idx)
+                .map(|arg| mapping.normalize(arg) == self.target)
+                .unwrap_or(false)
+        };
+
+        if self.snd_response.is_none() {
+            // Failed, only the end of the reply is left
+            return num == numeric::RPL_ENDOFWHOIS && arg_matches(1);
+        }
+
+        if is_error(num) {
+            // Errors about the target, with the target as the first parameter
+            let target_errors: &[u16] = match self.response {
+                Response::Whois(_) => &[numeric::ERR_NOSUCHNICK],
+                Response::Who(_) | Response::List(_) | Response::Names(_) => &[],
+            };
+            return (target_errors.contains(&num) && arg_matches(1))
+                || (CMD_ERRORS.contains(&num)
+                    && params
+                        .get(1)
+                        .map(|arg| arg.eq_ignore_ascii_case(self.cmd))
+                        .unwrap_or(false));
+        }
+
+        match self.response {
+            Response::Whois(_) => WHOIS_NUMERICS.contains(&num) && arg_matches(1),
+            Response::Who(_) => match num {
+                // Channel, or nick of the user. Replies to masks with wildcards can't be matched
+                // with the mask so they're matched with the oldest request.
+                numeric::RPL_WHOREPLY => {
+                    arg_matches(1) || arg_matches(5) || self.target.contains(['*', '?'])
+                }
+                numeric::RPL_ENDOFWHO => arg_matches(1),
+                _ => false,
+            },
+            Response::List(_) => matches!(
+                num,
+                numeric::RPL_LISTSTART | numeric::RPL_LIST | numeric::RPL_LISTEND
+            ),
+            Response::Names(_) => match num {
+                numeric::RPL_NAMREPLY => arg_matches(2),
+                numeric::RPL_ENDOFNAMES => arg_matches(1),
+                _ => false,
+            },
+        }
+    }
+
+    /// Add a reply message to the response.
+    fn handle_reply(&mut self, cmd: &Cmd, isupport: &wire::ISupport) -> Status {
+        let (num, params) = match cmd {
+            Cmd::Reply { num, params } => (*num, params),
+            _ => return Status::Pending,
+        };
+
+        if is_error(num) {
+            return Status::Failed(RequestError::Reply {
+                num,
+                msg: params.last().cloned().unwrap_or_default(),
+            });
+        }
+
+        match &mut self.response {
+            Response::Whois(info) => match Numeric::parse(num, params) {
+                Numeric::RplWhoisUser {
+                    user,
+                    host,
+                    realname,
+                    ..
+                } => {
+                    info.user = Some(user.to_owned());
+                    info.host = Some(host.to_owned());
+                    info.realname = Some(realname.to_owned());
+                }
+                Numeric::RplWhoisServer {
+                    server,
+                    info: server_info,
+                    ..
+                } => {
+                    info.server = Some(server.to_owned());
+                    info.server_info = Some(server_info.to_owned());
+                }
+                Numeric::RplWhoisOperator { .. } => {
+                    info.operator = true;
+                }
+                Numeric::RplWhoisIdle { idle, signon, .. } => {
+                    info.idle = Some(idle);
+                    info.signon = signon;
+                }
+                Numeric::RplWhoisChannels { chans, .. } => {
+                    info.chans
+                        .extend(chans.split_whitespace().map(str::to_owned));
+                }
+                Numeric::RplWhoisAccount { account, .. } => {
+                    info.account = Some(account.to_owned());
+                }
+                Numeric::RplAway { msg, .. } => {
+                    info.away = Some(msg.to_owned());
+                }
+                Numeric::RplEndOfWhois { .. } => {
+                    return Status::Done;
+                }
+                _ if num == numeric::RPL_WHOISSECURE => {
+                    info.secure = true;
+                }
+                _ => {
+                    // Other information about the user, e.g. "is connecting from *@host"
+                    if params.len() > 2 {
+                        info.other.push(params[2..].join(" "));
+                    }
+                }
+            },
+
+            Response::Who(replies) => match Numeric::parse(num, params) {
+                Numeric::RplWhoReply {
+                    chan,
+                    user,
+                    host,
+                    server,
+                    nick,
+                    flags,
+                    realname,
+                    ..
+                } => {
+                    replies.push(WhoReply {
+                        chan: if chan == "*" {
+                            None
+                        } else {
+                            Some(ChanName::new(chan.to_owned()))
+                        },
+                        nick: nick.to_owned(),
+                        user: user.to_owned(),
+                        host: host.to_owned(),
+                        server: server.to_owned(),
+                        away: flags.starts_with('G'),
+                        flags: flags.to_owned(),
+                        realname: realname.to_owned(),
+                    });
+                }
+                Numeric::RplEndOfWho { .. } => {
+                    return Status::Done;
+                }
+                _ => {}
+            },
+
+            Response::List(entries) => match Numeric::parse(num, params) {
+                Numeric::RplList {
+                    chan,
+                    visible,
+                    topic,
+                } => {
+                    entries.push(ListEntry {
+                        chan: chan.to_owned(),
+                        users: visible,
+                        topic: topic.to_owned(),
+                    });
+                }
+                Numeric::RplListEnd => {
+                    return Status::Done;
+                }
+                _ => {}
+            },
+
+            Response::Names(names) => match Numeric::parse(num, params) {
+                Numeric::RplNamReply { nicks, .. } => {
+                    for name in nicks.split_whitespace() {
+                        let (prefixes, name) = isupport.split_nick_prefix(name);
+                        let modes = isupport
+                            .prefix
+                            .iter()
+                            .filter(|(_, prefix)| prefixes.contains(*prefix))
+                            .map(|(mode, _)| *mode)
+                            .collect();
+                        names.push((wire::Hostmask::parse(name).nick, modes));
+                    }
+                }
+                Numeric::RplEndOfNames { .. } => {
+                    return Status::Done;
+                }
+                _ => {}
+            },
+        }
+
+        Status::Pending
+    }
+}
+
+fn is_error(num: u16) -> bool {
+    numeric::is_error(num) || num == numeric::RPL_TRYAGAIN
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+
+    fn feed(requests: &mut Requests, lines: &[&str]) -> Vec<bool> {
+        let isupport = wire::ISupport::default();
+        lines
+            .iter()
+            .map(|line| {
+                let msg = wire::parse_irc_line(line.as_bytes(), None).unwrap();
+                requests.handle_msg(&msg, &isupport, CaseMapping::Rfc1459)
+            })
+            .collect()
+    }
+
+    fn response(rcv: &mut ResponseReceiver) -> Option<Result<Response, RequestError>> {
+        rcv.now_or_never().map(Result::unwrap)
+    }
+
+    #[test]
+    fn unlabeled() {
+        let mut requests = Requests::new();
+        let mapping = CaseMapping::Rfc1459;
+
+        let (_, msg, _, mut rcv_whois) = requests.add(
+            Response::Whois(Box::new(WhoisInfo::new("Nick"))),
+            "Nick",
+            wire::whois("Nick"),
+            false,
+            mapping,
+        );
+        assert_eq!(msg, "WHOIS Nick\r\n");
+        let (_, _, _, mut rcv_names) = requests.add(
+            Response::Names(vec![]),
+            "#chan",
+            wire::names(ChanName::new("#chan".to_owned()).as_ref()),
+            false,
+            mapping,
+        );
+        let (_, _, _, mut rcv_unknown) = requests.add(
+            Response::Whois(Box::new(WhoisInfo::new("unknown"))),
+            "unknown",
+            wire::whois("unknown"),
+            false,
+            mapping,
+        );
+
+        let consumed = feed(
+            &mut requests,
+            &[
+                ":x 311 me nick ~u host * :Real Name",
+                ":x 353 me = #chan :@+a b!u@h",
+                ":x 319 me nick :@#chan #other",
+                ":x 378 me nick :is connecting from *@1.2.3.4",
+                ":x 311 me other ~u host * :Other",
+                ":x 401 me unknown :No such nick/channel",
+                ":x 318 me nick :End of /WHOIS list.",
+                ":x 318 me unknown :End of /WHOIS list.",
+                ":x 318 me unknown :End of /WHOIS list.",
+            ],
+        );
+        assert_eq!(
+            consumed,
+            vec![true, true, true, true, false, true, true, true, false]
+        );
+
+        let info = match response(&mut rcv_whois) {
+            Some(Ok(Response::Whois(info))) => info,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(info.user.as_deref(), Some("~u"));
+        assert_eq!(info.realname.as_deref(), Some("Real Name"));
+        assert_eq!(info.chans, vec!["@#chan", "#other"]);
+        assert_eq!(info.other, vec!["is connecting from *@1.2.3.4"]);
+
+        assert!(matches!(
+            response(&mut rcv_unknown),
+            Some(Err(RequestError::Reply { num: 401, .. }))
+        ));
+
+        assert!(response(&mut rcv_names).is_none());
+        feed(&mut requests, &[":x 366 me #CHAN :End of /NAMES list."]);
+        match response(&mut rcv_names) {
+            Some(Ok(Response::Names(names))) => assert_eq!(
+                names,
+                vec![
+                    ("a".to_owned(), "ov".to_owned()),
+                    ("b".to_owned(), String::new())
+                ]
+            ),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn unlabeled_who() {
+        let mut requests = Requests::new();
+        let mapping = CaseMapping::Rfc1459;
+
+        let (_, _, _, mut rcv_chan) = requests.add(
+            Response::Who(vec![]),
+            "#chan",
+            wire::who("#chan"),
+            false,
+            mapping,
+        );
+        let (_, _, _, mut rcv_nick) = requests.add(
+            Response::Who(vec![]),
+            "nick",
+            wire::who("nick"),
+            false,
+            mapping,
+        );
+        let (_, _, _, mut rcv_list) =
+            requests.add(Response::List(vec![]), "", wire::list(None), false, mapping);
+
+        let consumed = feed(
+            &mut requests,
+            &[
+                ":x 352 me #other ~u host x a H :0 A",
+                ":x 352 me #Chan ~u host x a H :0 A",
+                ":x 352 me * ~u host x Nick H :0 Nick",
+                ":x 354 me 152 #chan a",
+                ":x 315 me #chan :End of /WHO list.",
+                // Errors that can't be replies to the requests
+                ":x 401 me nick :No such nick/channel",
+                ":x 404 me #chan :Cannot send to channel",
+                ":x 263 me LIST :Server load is temporarily too heavy",
+                ":x 315 me nick :End of /WHO list.",
+            ],
+        );
+        assert_eq!(
+            consumed,
+            vec![false, true, true, false, true, false, false, true, true]
+        );
+
+        match response(&mut rcv_chan) {
+            Some(Ok(Response::Who(replies))) => {
+                assert_eq!(replies.len(), 1);
+                assert_eq!(replies[0].nick, "a");
+            }
+            other => panic!("{:?}", other),
+        }
+        match response(&mut rcv_nick) {
+            Some(Ok(Response::Who(replies))) => {
+                assert_eq!(replies.len(), 1);
+                assert_eq!(replies[0].chan, None);
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(
+            response(&mut rcv_list),
+            Some(Err(RequestError::Reply { num: 263, .. }))
+        ));
+    }
+
+    #[test]
+    fn labeled() {
+        let mut requests = Requests::new();
+        let mapping = CaseMapping::Rfc1459;
+
+        let (_, msg, _, mut rcv_list) =
+            requests.add(Response::List(vec![]), "", wire::list(None), true, mapping);
+        assert_eq!(msg, "@label=0 LIST\r\n");
+        let (_, msg, _, mut rcv_who) = requests.add(
+            Response::Who(vec![]),
+            "#chan",
+            wire::who("#chan"),
+            true,
+            mapping,
+        );
+        assert_eq!(msg, "@label=1 WHO #chan\r\n");
+        let (id, _, _, _rcv_cancelled) = requests.add(
+            Response::Who(vec![]),
+            "#chan",
+            wire::who("#chan"),
+            true,
+            mapping,
+        );
+        requests.cancel(id);
+
+        let consumed = feed(
+            &mut requests,
+            &[
+                "@label=0 :x BATCH +b labeled-response",
+                "@batch=b :x 321 me Channel :Users Name",
+                "@batch=b :x 322 me #chan 3 :topic",
+                // Unlabeled replies are not matched with labeled requests
+                ":x 352 me #chan ~u host x nick H :0 Real Name",
+                "@label=1 :x 352 me #chan ~u host x nick G@ :0 Real Name",
+                ":x BATCH -b",
+                "@label=2 :x 315 me #chan :End of /WHO list.",
+            ],
+        );
+        assert_eq!(consumed, vec![true, true, true, false, true, true, false]);
+
+        match response(&mut rcv_list) {
+            Some(Ok(Response::List(entries))) => assert_eq!(
+                entries,
+                vec![ListEntry {
+                    chan: ChanName::new("#chan".to_owned()),
+                    users: 3,
+                    topic: "topic".to_owned(),
+                }]
+            ),
+            other => panic!("{:?}", other),
+        }
+
+        match response(&mut rcv_who) {
+            Some(Ok(Response::Who(replies))) => {
+                assert_eq!(replies.len(), 1);
+                assert_eq!(replies[0].nick, "nick");
+                assert!(replies[0].away);
+            }
+            other => panic!("{:?}", other),
+        }
+
+        // Pending requests fail on reset
+        let (_, _, _, rcv) =
+            requests.add(Response::List(vec![]), "", wire::list(None), true, mapping);
+        requests.reset();
+        assert!(rcv.now_or_never().unwrap().is_err());
+    }
+
+    #[test]
+    fn sent() {
+        let mut requests = Requests::new();
+        let mapping = CaseMapping::Rfc1459;
+
+        let (_, _, mut rcv_sent_1, _rcv_1) =
+            requests.add(Response::List(vec![]), "", wire::list(None), false, mapping);
+        let (_, _, mut rcv_sent_2, _rcv_2) =
+            requests.add(Response::List(vec![]), "", wire::list(None), false, mapping);
+
+        // Requests with the same message are sent in order
+        requests.sent("WHOIS nick\r\n");
+        assert!((&mut rcv_sent_1).now_or_never().is_none());
+        requests.sent("LIST\r\n");
+        assert!(rcv_sent_1.now_or_never().unwrap().is_ok());
+        assert!((&mut rcv_sent_2).now_or_never().is_none());
+        requests.sent("LIST\r\n");
+        assert!(rcv_sent_2.now_or_never().unwrap().is_ok());
+    }
+
+    #[test]
+    fn dropped() {
+        let mut requests = Requests::new();
+        let mapping = CaseMapping::Rfc1459;
+
+        let (_, _, _, rcv_dropped) = requests.add(
+            Response::Who(vec![]),
+            "#chan",
+            wire::who("#chan"),
+            false,
+            mapping,
+        );
+        let (_, _, _, mut rcv_who) = requests.add(
+            Response::Who(vec![]),
+            "#chan",
+            wire::who("#chan"),
+            false,
+            mapping,
+        );
+        drop(rcv_dropped);
+
+        let consumed = feed(
+            &mut requests,
+            &[
+                ":x 352 me #chan ~u host x a H :0 A",
+                ":x 315 me #chan :End of /WHO list.",
+                ":x 315 me #chan :End of /WHO list.",
+            ],
+        );
+        assert_eq!(consumed, vec![true, true, false]);
+        match response(&mut rcv_who) {
+            Some(Ok(Response::Who(replies))) => assert_eq!(replies.len(), 1),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,595 @@
+//! SASL authentication. See https://ircv3.net/specs/extensions/sasl-3.1
+//!
+//! Payloads are base64 encoded and sent in `AUTHENTICATE` messages of at most 400 bytes. A
+//! payload that is a multiple of 400 bytes is terminated with `AUTHENTICATE +`, which is also
+//! used for empty payloads. The server splits its challenges the same way.
+
+use crate::SASLAuth;
+use libtiny_wire as wire;
+
+use hmac::{Hmac, Mac, NewMac};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+
+/// Max. length of the base64 payload in an `AUTHENTICATE` message.
+const MAX_CHUNK_LEN: usize = 400;
+
+/// Max. SCRAM iteration count we accept. Salting the password is done in a blocking task, but we
+/// still don't let the server make us spin for too long.
+const MAX_SCRAM_ITERATIONS: u32 = 1_000_000;
+
+/// An authentication in progress.
+#[derive(Debug)]
+pub(crate) struct Sasl {
+    state: MechState,
+    /// Base64 chunks of the server challenge received so far.
+    challenge: String,
+}
+
+#[derive(Debug)]
+enum MechState {
+    Plain {
+        username: String,
+        password: String,
+    },
+    External,
+    /// Shared with the blocking task that salts the password
+    Scram(Arc<Mutex<Scram>>),
+}
+
+/// Response to an `AUTHENTICATE` message from the server.
+pub(crate) enum SaslResponse {
+    /// Messages to send
+    Msgs(Vec<String>),
+    /// Run the function in a blocking task (e.g. with `tokio::task::spawn_blocking`) and send
+    /// the messages it returns. Used for the SCRAM client-final-message, as salting the password
+    /// can take a long time.
+    Blocking(Box<dyn FnOnce() -> Vec<String> + Send>),
+}
+
+impl Sasl {
+    /// Start an authentication. `mechs` is the list of mechanisms that the server supports, if
+    /// known (from the `sasl` capability value or RPL_SASLMECHS). With `SASLAuth::ScramSha256`,
+    /// SCRAM-SHA-1 is used when the server supports SCRAM-SHA-1 but not SCRAM-SHA-256.
+    pub(crate) fn new(auth: &SASLAuth, mechs: Option<&str>) -> Sasl {
+        let state = match auth {
+            SASLAuth::Plain { username, password } => MechState::Plain {
+                username: username.clone(),
+                password: password.clone(),
+            },
+            SASLAuth::External => MechState::External,
+            SASLAuth::ScramSha256 { username, password } => {
+                let supports = |mech: &str| {
+                    mechs.map(|mechs| mechs.split(',').any(|m| m.eq_ignore_ascii_case(mech)))
+                };
+                let hash = if supports("SCRAM-SHA-256") == Some(false)
+                    && supports("SCRAM-SHA-1") == Some(true)
+                {
+                    ScramHash::Sha1
+                } else {
+                    ScramHash::Sha256
+                };
+                MechState::Scram(Arc::new(Mutex::new(Scram::new(hash, username, password))))
+            }
+            SASLAuth::ScramSha1 { username, password } => MechState::Scram(Arc::new(Mutex::new(
+                Scram::new(ScramHash::Sha1, username, password),
+            ))),
+        };
+        Sasl {
+            state,
+            challenge: String::new(),
+        }
+    }
+
+    /// The `AUTHENTICATE <mechanism>` message that starts the authentication.
+    pub(crate) fn start_msg(&self) -> String {
+        wire::authenticate(self.mechanism())
+    }
+
+    pub(crate) fn mechanism(&self) -> &'static str {
+        match &self.state {
+            MechState::Plain { .. } => "PLAIN",
+            MechState::External => "EXTERNAL",
+            MechState::Scram(scram) => scram.lock().unwrap().hash.mechanism(),
+        }
+    }
+
+    /// Handle an `AUTHENTICATE` message from the server. When the exchange fails the response
+    /// aborts the authentication, and the server replies with ERR_SASLABORTED.
+    pub(crate) fn handle_authenticate(&mut self, param: &str) -> SaslResponse {
+        if param != "+" {
+            self.challenge.push_str(param);
+        }
+        if param.len() == MAX_CHUNK_LEN {
+            // More chunks to follow
+            return SaslResponse::Msgs(vec![]);
+        }
+
+        let challenge = match base64::decode(std::mem::take(&mut self.challenge)) {
+            Ok(challenge) => challenge,
+            Err(err) => {
+                warn!("Can't decode SASL challenge: {}", err);
+                return SaslResponse::Msgs(vec![wire::authenticate("*")]);
+            }
+        };
+
+        let response = match &mut self.state {
+            MechState::Plain { username, password } => {
+                Ok(format!("{}\x00{}\x00{}", username, username, password).into_bytes())
+            }
+            // Empty response: authorization identity is derived from the certificate
+            MechState::External => Ok(vec![]),
+            MechState::Scram(scram) => {
+                if scram.lock().unwrap().salts_password() {
+                    let scram = scram.clone();
+                    return SaslResponse::Blocking(Box::new(move || {
+                        let mut scram = scram.lock().unwrap();
+                        let mechanism = scram.hash.mechanism();
+                        response_msgs(mechanism, scram.step(&challenge))
+                    }));
+                }
+                scram.lock().unwrap().step(&challenge)
+            }
+        };
+
+        SaslResponse::Msgs(response_msgs(self.mechanism(), response))
+    }
+
+    /// The server rejected the mechanism and listed the mechanisms it supports (RPL_SASLMECHS).
+    /// Returns the authentication to try instead, if there's one.
+    pub(crate) fn fallback(&self, mechs: &str) -> Option<Sasl> {
+        match &self.state {
+            MechState::Scram(scram) => {
+                let scram = scram.lock().unwrap();
+                if scram.hash == ScramHash::Sha256
+                    && mechs
+                        .split(',')
+                        .any(|mech| mech.eq_ignore_ascii_case("SCRAM-SHA-1"))
+                {
+                    Some(Sasl {
+                        state: MechState::Scram(Arc::new(Mutex::new(Scram::new(
+                            ScramHash::Sha1,
+                            &scram.username,
+                            &scram.password,
+                        )))),
+                        challenge: String::new(),
+                    })
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Messages to send for a response, or to abort the authentication when the exchange failed.
+fn response_msgs(mechanism: &str, response: Result<Vec<u8>, String>) -> Vec<String> {
+    match response {
+        Ok(response) => authenticate_msgs(&response),
+        Err(err) => {
+            warn!("SASL {} authentication failed: {}", mechanism, err);
+            vec![wire::authenticate("*")]
+        }
+    }
+}
+
+/// Split a payload into `AUTHENTICATE` messages.
+fn authenticate_msgs(payload: &[u8]) -> Vec<String> {
+    let encoded = base64::encode(payload);
+    let mut msgs: Vec<String> = encoded
+        .as_bytes()
+        .chunks(MAX_CHUNK_LEN)
+        // base64 is ASCII so chunks are valid UTF-8
+        .map(|chunk| wire::authenticate(std::str::from_utf8(chunk).unwrap()))
+        .collect();
+    if encoded.len().is_multiple_of(MAX_CHUNK_LEN) {
+        msgs.push(wire::authenticate("+"));
+    }
+    msgs
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// SCRAM, see RFC 5802 and RFC 7677
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScramHash {
+    Sha1,
+    Sha256,
+}
+
+impl ScramHash {
+    fn mechanism(self) -> &'static str {
+        match self {
+            ScramHash::Sha1 => "SCRAM-SHA-1",
+            ScramHash::Sha256 => "SCRAM-SHA-256",
+        }
+    }
+
+    fn hash(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            ScramHash::Sha1 => Sha1::digest(data).to_vec(),
+            ScramHash::Sha256 => Sha256::digest(data).to_vec(),
+        }
+    }
+
+    fn hmac(self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            ScramHash::Sha1 => {
+                let mut mac = Hmac::<Sha1>::new_from_slice(key).unwrap();
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            ScramHash::Sha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    /// PBKDF2 with HMAC as the pseudorandom function and output length of the hash function.
+    fn salted_password(self, password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+        let mut block = salt.to_vec();
+        block.extend_from_slice(&1u32.to_be_bytes());
+        let mut u = self.hmac(password, &block);
+        let mut result = u.clone();
+        for _ in 1..iterations {
+            u = self.hmac(password, &u);
+            xor(&mut result, &u);
+        }
+        result
+    }
+}
+
+#[derive(Debug)]
+struct Scram {
+    hash: ScramHash,
+    username: String,
+    password: String,
+    client_nonce: String,
+    step: ScramStep,
+}
+
+#[derive(Debug)]
+enum ScramStep {
+    /// Send client-first-message
+    ClientFirst,
+    /// Got server-first-message, send client-final-message
+    ClientFinal {
+        client_first_bare: String,
+    },
+    /// Got server-final-message, verify the server signature
+    Verify {
+        server_signature: Vec<u8>,
+    },
+    Done,
+}
+
+impl Scram {
+    fn new(hash: ScramHash, username: &str, password: &str) -> Scram {
+        let client_nonce = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(24)
+            .map(char::from)
+            .collect();
+        Scram::with_nonce(hash, username, password, client_nonce)
+    }
+
+    fn with_nonce(hash: ScramHash, username: &str, password: &str, client_nonce: String) -> Scram {
+        Scram {
+            hash,
+            username: username.to_owned(),
+            password: password.to_owned(),
+            client_nonce,
+            step: ScramStep::ClientFirst,
+        }
+    }
+
+    /// Whether the next step salts the password, which is slow with high iteration counts.
+    fn salts_password(&self) -> bool {
+        matches!(self.step, ScramStep::ClientFinal { .. })
+    }
+
+    /// Process a server message and return the response.
+    fn step(&mut self, server_msg: &[u8]) -> Result<Vec<u8>, String> {
+        match std::mem::replace(&mut self.step, ScramStep::Done) {
+            ScramStep::ClientFirst => {
+                // The server starts with an empty challenge. Usernames are not SASLprep'd, we
+                // only escape ',' and '='.
+                let client_first_bare = format!(
+                    "n={},r={}",
+                    self.username.replace('=', "=3D").replace(',', "=2C"),
+                    self.client_nonce
+                );
+                let msg = format!("n,,{}", client_first_bare);
+                self.step = ScramStep::ClientFinal { client_first_bare };
+                Ok(msg.into_bytes())
+            }
+
+            ScramStep::ClientFinal { client_first_bare } => {
+                let server_first = std::str::from_utf8(server_msg)
+                    .map_err(|_| "server-first-message is not valid UTF-8".to_owned())?;
+                let attrs = parse_attrs(server_first)?;
+                let nonce = find_attr(&attrs, 'r')?;
+                let salt = base64::decode(find_attr(&attrs, 's')?)
+                    .map_err(|err| format!("Can't decode salt: {}", err))?;
+                let iterations: u32 = find_attr(&attrs, 'i')?
+                    .parse()
+                    .map_err(|_| "Invalid iteration count".to_owned())?;
+                if !nonce.starts_with(&self.client_nonce) || nonce.len() == self.client_nonce.len()
+                {
+                    return Err("Invalid server nonce".to_owned());
+                }
+                if iterations == 0 || iterations > MAX_SCRAM_ITERATIONS {
+                    return Err(format!("Invalid iteration count: {}", iterations));
+                }
+
+                // "biws" is base64 of "n,,", the GS2 header without channel binding
+                let client_final_without_proof = format!("c=biws,r={}", nonce);
+                let auth_msg = format!(
+                    "{},{},{}",
+                    client_first_bare, server_first, client_final_without_proof
+                );
+
+                let hash = self.hash;
+                let salted_password =
+                    hash.salted_password(self.password.as_bytes(), &salt, iterations);
+                let client_key = hash.hmac(&salted_password, b"Client Key");
+                let stored_key = hash.hash(&client_key);
+                let client_signature = hash.hmac(&stored_key, auth_msg.as_bytes());
+                let mut client_proof = client_key;
+                xor(&mut client_proof, &client_signature);
+
+                let server_key = hash.hmac(&salted_password, b"Server Key");
+                let server_signature = hash.hmac(&server_key, auth_msg.as_bytes());
+                self.step = ScramStep::Verify { server_signature };
+
+                Ok(format!(
+                    "{},p={}",
+                    client_final_without_proof,
+                    base64::encode(&client_proof)
+                )
+                .into_bytes())
+            }
+
+            ScramStep::Verify { server_signature } => {
+                let server_final = std::str::from_utf8(server_msg)
+                    .map_err(|_| "server-final-message is not valid UTF-8".to_owned())?;
+                let attrs = parse_attrs(server_final)?;
+                if let Ok(err) = find_attr(&attrs, 'e') {
+                    return Err(format!("Server error: {}", err));
+                }
+                let verifier = base64::decode(find_attr(&attrs, 'v')?)
+                    .map_err(|err| format!("Can't decode server signature: {}", err))?;
+                if verifier != server_signature {
+                    return Err("Invalid server signature".to_owned());
+                }
+                // Empty response to finish the exchange
+                Ok(vec![])
+            }
+
+            ScramStep::Done => Err("Unexpected server message".to_owned()),
+        }
+    }
+}
+
+/// Parse `a=value,b=value` attributes.
+fn parse_attrs(msg: &str) -> Result<Vec<(char, &str)>, String> {
+    msg.split(',')
+        .map(|attr| {
+            let mut chars = attr.chars();
+            match (chars.next(), chars.next()) {
+                (Some(name), Some('=')) => Ok((name, chars.as_str())),
+                _ => Err(format!("Invalid SCRAM attribute: {:?}", attr)),
+            }
+        })
+        .collect()
+}
+
+fn find_attr<'a>(attrs: &[(char, &'a str)], name: char) -> Result<&'a str, String> {
+    attrs
+        .iter()
+        .find(|(attr_name, _)| *attr_name == name)
+        .map(|(_, value)| *value)
+        .ok_or_else(|| format!("Missing SCRAM attribute '{}'", name))
+}
+
+fn xor(a: &mut [u8], b: &[u8]) {
+    for (a, b) in a.iter_mut().zip(b) {
+        *a ^= b;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scram_exchange(
+        mut scram: Scram,
+        server_first: &str,
+        client_final: &str,
+        server_final: &str,
+    ) -> Result<Vec<u8>, String> {
+        assert_eq!(
+            scram.step(b"").unwrap(),
+            format!("n,,n=user,r={}", scram.client_nonce).into_bytes()
+        );
+        assert_eq!(
+            String::from_utf8(scram.step(server_first.as_bytes()).unwrap()).unwrap(),
+            client_final
+        );
+        scram.step(server_final.as_bytes())
+    }
+
+    #[test]
+    fn scram_sha_1() {
+        // Test vectors from RFC 5802
+        let scram = || {
+            Scram::with_nonce(
+                ScramHash::Sha1,
+                "user",
+                "pencil",
+                "fyko+d2lbbFgONRv9qkxdawL".to_owned(),
+            )
+        };
+        let server_first = "r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096";
+        let client_final = "c=biws,r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,\
+                            p=v0X8v3Bz2T0CJGbJQyF0X+HI4Ts=";
+        assert_eq!(
+            scram_exchange(
+                scram(),
+                server_first,
+                client_final,
+                "v=rmF9pqV8S7suAoZWja4dJRkFsKQ="
+            ),
+            Ok(vec![])
+        );
+        assert!(scram_exchange(
+            scram(),
+            server_first,
+            client_final,
+            "v=AAF9pqV8S7suAoZWja4dJRkFsKQ="
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn scram_sha_256() {
+        // Test vectors from RFC 7677
+        let scram = || {
+            Scram::with_nonce(
+                ScramHash::Sha256,
+                "user",
+                "pencil",
+                "rOprNGfwEbeRWgbNEkqO".to_owned(),
+            )
+        };
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                            s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        let client_final = "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                            p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+        assert_eq!(
+            scram_exchange(
+                scram(),
+                server_first,
+                client_final,
+                "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="
+            ),
+            Ok(vec![])
+        );
+        assert!(scram_exchange(scram(), server_first, client_final, "e=invalid-proof").is_err());
+
+        // Server nonce should extend the client nonce
+        let mut scram = scram();
+        scram.step(b"").unwrap();
+        assert!(scram
+            .step(b"r=abc,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096")
+            .is_err());
+    }
+
+    #[test]
+    fn chunking() {
+        assert_eq!(authenticate_msgs(b""), vec!["AUTHENTICATE +\r\n"]);
+        assert_eq!(authenticate_msgs(b"abc"), vec!["AUTHENTICATE YWJj\r\n"]);
+
+        // 300 bytes = 400 bytes in base64, needs a terminating "+"
+        let msgs = authenticate_msgs(&[0; 300]);
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0].len(), "AUTHENTICATE \r\n".len() + 400);
+        assert_eq!(msgs[1], "AUTHENTICATE +\r\n");
+
+        let msgs = authenticate_msgs(&[0; 301]);
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[1], "AUTHENTICATE AA==\r\n");
+
+        // Chunked challenges are joined
+        let mut sasl = Sasl::new(&SASLAuth::External, None);
+        let challenge = base64::encode([0; 300]);
+        assert_eq!(
+            immediate_msgs(sasl.handle_authenticate(&challenge)),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            immediate_msgs(sasl.handle_authenticate("+")),
+            vec!["AUTHENTICATE +\r\n"]
+        );
+    }
+
+    /// Messages of a response that doesn't need a blocking task
+    fn immediate_msgs(response: SaslResponse) -> Vec<String> {
+        match response {
+            SaslResponse::Msgs(msgs) => msgs,
+            SaslResponse::Blocking(_) => panic!("Unexpected blocking response"),
+        }
+    }
+
+    #[test]
+    fn scram_blocking_step() {
+        // Test vectors from RFC 7677
+        let mut sasl = Sasl {
+            state: MechState::Scram(Arc::new(Mutex::new(Scram::with_nonce(
+                ScramHash::Sha256,
+                "user",
+                "pencil",
+                "rOprNGfwEbeRWgbNEkqO".to_owned(),
+            )))),
+            challenge: String::new(),
+        };
+        assert_eq!(
+            immediate_msgs(sasl.handle_authenticate("+")),
+            vec![wire::authenticate(&base64::encode(
+                "n,,n=user,r=rOprNGfwEbeRWgbNEkqO"
+            ))]
+        );
+
+        // Password is salted in a blocking task
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                            s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        let client_final = match sasl.handle_authenticate(&base64::encode(server_first)) {
+            SaslResponse::Blocking(salt) => salt(),
+            SaslResponse::Msgs(_) => panic!("Expected a blocking response"),
+        };
+        assert_eq!(
+            client_final,
+            vec![wire::authenticate(&base64::encode(
+                "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                 p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+            ))]
+        );
+
+        let server_final = "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=";
+        assert_eq!(
+            immediate_msgs(sasl.handle_authenticate(&base64::encode(server_final))),
+            vec!["AUTHENTICATE +\r\n"]
+        );
+    }
+
+    #[test]
+    fn mechanism_selection() {
+        let auth = SASLAuth::ScramSha256 {
+            username: "user".to_owned(),
+            password: "pass".to_owned(),
+        };
+        assert_eq!(Sasl::new(&auth, None).mechanism(), "SCRAM-SHA-256");
+        assert_eq!(
+            Sasl::new(&auth, Some("PLAIN,SCRAM-SHA-1,SCRAM-SHA-256")).mechanism(),
+            "SCRAM-SHA-256"
+        );
+        assert_eq!(
+            Sasl::new(&auth, Some("PLAIN,SCRAM-SHA-1")).mechanism(),
+            "SCRAM-SHA-1"
+        );
+
+        let sasl = Sasl::new(&auth, None);
+        assert_eq!(
+            sasl.fallback("PLAIN,SCRAM-SHA-1")
+                .map(|sasl| sasl.mechanism()),
+            Some("SCRAM-SHA-1")
+        );
+        assert!(sasl.fallback("PLAIN").is_none());
+    }
+}
@@ -0,0 +1,256 @@
+//! Outgoing flood control. Servers disconnect clients that send too many messages in a short
+//! time ("Excess Flood"), so messages are sent through a token bucket: `burst` messages can be
+//! sent at once, after that one message every `interval`.
+//!
+//! The queue is shared by the `Client` (to report the queue depth and cancel queued messages) and
+//! the writer task of the current connection.
+
+use libtiny_common::CaseMapping;
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Commands that are sent before other queued messages, without waiting for a token. Delaying
+/// these can get us disconnected with a ping timeout, or make quitting slow.
+const PRIORITY_CMDS: &[&str] = &["PING", "PONG", "QUIT"];
+
+#[derive(Debug)]
+pub(crate) struct SendQueue {
+    /// Max. number of tokens. 0 disables flood control.
+    burst: usize,
+
+    /// A token is added in every `interval`.
+    interval: Duration,
+
+    /// Available tokens. Sending a message takes one.
+    tokens: usize,
+
+    /// When the last token was added.
+    last_refill: Instant,
+
+    /// Messages waiting to be sent. Priority messages are at the front.
+    msgs: VecDeque<QueuedMsg>,
+
+    /// Incremented on reset. Writer tasks of old connections stop when this changes.
+    epoch: u64,
+}
+
+#[derive(Debug)]
+struct QueuedMsg {
+    msg: String,
+    priority: bool,
+}
+
+impl SendQueue {
+    pub(crate) fn new(burst: usize, interval: Duration) -> SendQueue {
+        SendQueue {
+            burst,
+            interval,
+            tokens: burst,
+            last_refill: Instant::now(),
+            msgs: VecDeque::new(),
+            epoch: 0,
+        }
+    }
+
+    /// Drop the queued messages and fill the bucket, for a new connection. Returns the new epoch.
+    pub(crate) fn reset(&mut self, now: Instant) -> u64 {
+        self.msgs.clear();
+        self.tokens = self.burst;
+        self.last_refill = now;
+        self.epoch += 1;
+        self.epoch
+    }
+
+    pub(crate) fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Number of messages waiting to be sent.
+    pub(crate) fn len(&self) -> usize {
+        self.msgs.len()
+    }
+
+    /// Add a message (including the trailing "\r\n") to the queue.
+    pub(crate) fn push(&mut self, msg: String) {
+        let priority = PRIORITY_CMDS.contains(&msg_cmd(&msg));
+        if priority {
+            let idx = self
+                .msgs
+                .iter()
+                .position(|queued| !queued.priority)
+                .unwrap_or(self.msgs.len());
+            self.msgs.insert(idx, QueuedMsg { msg, priority });
+        } else {
+            self.msgs.push_back(QueuedMsg { msg, priority });
+        }
+    }
+
+    /// Get the next message to send if it can be sent now.
+    pub(crate) fn pop(&mut self, now: Instant) -> Option<String> {
+        self.refill(now);
+        let priority = self.msgs.front()?.priority;
+        if self.burst != 0 {
+            if self.tokens == 0 && !priority {
+                return None;
+            }
+            self.tokens = self.tokens.saturating_sub(1);
+        }
+        self.msgs.pop_front().map(|queued| queued.msg)
+    }
+
+    /// How long to wait before the next message can be sent. `None` when the queue is empty.
+    pub(crate) fn next_send_delay(&self, now: Instant) -> Option<Duration> {
+        let front = self.msgs.front()?;
+        if self.burst == 0 || self.tokens != 0 || front.priority {
+            return Some(Duration::from_secs(0));
+        }
+        let next_refill = self.last_refill + self.interval;
+        Some(next_refill.saturating_duration_since(now))
+    }
+
+    /// Drop queued PRIVMSGs and NOTICEs to `target`. Returns the dropped messages.
+    pub(crate) fn cancel(&mut self, target: &str, mapping: CaseMapping) -> Vec<String> {
+        let target = mapping.normalize(target);
+        let (dropped, kept): (VecDeque<QueuedMsg>, VecDeque<QueuedMsg>) =
+            self.msgs.drain(..).partition(|queued| {
+                let mut words = skip_tags(&queued.msg).split(' ');
+                match (words.next(), words.next()) {
+                    (Some("PRIVMSG"), Some(msg_target)) | (Some("NOTICE"), Some(msg_target)) => {
+                        mapping.normalize(msg_target) == target
+                    }
+                    _ => false,
+                }
+            });
+        self.msgs = kept;
+        dropped.into_iter().map(|queued| queued.msg).collect()
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if self.tokens >= self.burst {
+            self.last_refill = now;
+            return;
+        }
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        let new_tokens = (elapsed.as_millis() / self.interval.as_millis().max(1)) as usize;
+        if new_tokens == 0 {
+            return;
+        }
+        self.tokens = (self.tokens + new_tokens).min(self.burst);
+        if self.tokens == self.burst {
+            self.last_refill = now;
+        } else {
+            self.last_refill += self.interval * new_tokens as u32;
+        }
+    }
+}
+
+/// Drop the IRCv3 message tags of a message, if it has any.
+fn skip_tags(msg: &str) -> &str {
+    if msg.starts_with('@') {
+        msg.split_once(' ').map(|(_, rest)| rest).unwrap_or("")
+    } else {
+        msg
+    }
+}
+
+/// Command of a message that we send, e.g. "PRIVMSG".
+fn msg_cmd(msg: &str) -> &str {
+    skip_tags(msg).split([' ', '\r']).next().unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pop_all(queue: &mut SendQueue, now: Instant) -> Vec<String> {
+        let mut msgs = vec![];
+        while let Some(msg) = queue.pop(now) {
+            msgs.push(msg);
+        }
+        msgs
+    }
+
+    #[test]
+    fn token_bucket() {
+        let interval = Duration::from_secs(2);
+        let mut queue = SendQueue::new(2, interval);
+        let now = Instant::now();
+        queue.reset(now);
+
+        for i in 0..4 {
+            queue.push(format!("PRIVMSG #chan :{}\r\n", i));
+        }
+        assert_eq!(queue.next_send_delay(now), Some(Duration::from_secs(0)));
+        assert_eq!(
+            pop_all(&mut queue, now),
+            vec!["PRIVMSG #chan :0\r\n", "PRIVMSG #chan :1\r\n"]
+        );
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.next_send_delay(now), Some(interval));
+
+        // Priority messages skip the queue and don't wait for a token
+        queue.push("PONG :x.y.z\r\n".to_owned());
+        queue.push("QUIT :bye\r\n".to_owned());
+        assert_eq!(queue.next_send_delay(now), Some(Duration::from_secs(0)));
+        assert_eq!(
+            pop_all(&mut queue, now),
+            vec!["PONG :x.y.z\r\n", "QUIT :bye\r\n"]
+        );
+
+        // One token in every interval
+        let later = now + Duration::from_secs(3);
+        assert_eq!(queue.next_send_delay(later), Some(Duration::from_secs(0)));
+        assert_eq!(pop_all(&mut queue, later), vec!["PRIVMSG #chan :2\r\n"]);
+        assert_eq!(queue.next_send_delay(later), Some(Duration::from_secs(1)));
+        let later = now + Duration::from_secs(4);
+        assert_eq!(pop_all(&mut queue, later), vec!["PRIVMSG #chan :3\r\n"]);
+        assert_eq!(queue.next_send_delay(later), None);
+
+        // The bucket doesn't fill over the burst
+        let later = now + Duration::from_secs(100);
+        for i in 0..3 {
+            queue.push(format!("PRIVMSG #chan :{}\r\n", i));
+        }
+        assert_eq!(pop_all(&mut queue, later).len(), 2);
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn no_flood_control() {
+        let mut queue = SendQueue::new(0, Duration::from_secs(2));
+        let now = Instant::now();
+        for i in 0..10 {
+            queue.push(format!("PRIVMSG #chan :{}\r\n", i));
+        }
+        assert_eq!(pop_all(&mut queue, now).len(), 10);
+    }
+
+    #[test]
+    fn cancel() {
+        let mut queue = SendQueue::new(1, Duration::from_secs(2));
+        let now = Instant::now();
+        queue.push("PRIVMSG #a :1\r\n".to_owned());
+        queue.push("PRIVMSG #Chan :2\r\n".to_owned());
+        queue.push("NOTICE #chan :3\r\n".to_owned());
+        queue.push("@label=1 PRIVMSG #chan :4\r\n".to_owned());
+        queue.push("PART #chan\r\n".to_owned());
+        queue.push("PRIVMSG nick :5\r\n".to_owned());
+
+        assert_eq!(
+            queue.cancel("#CHAN", CaseMapping::Rfc1459),
+            vec![
+                "PRIVMSG #Chan :2\r\n",
+                "NOTICE #chan :3\r\n",
+                "@label=1 PRIVMSG #chan :4\r\n"
+            ]
+        );
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.pop(now), Some("PRIVMSG #a :1\r\n".to_owned()));
+        assert_eq!(
+            queue.cancel("nick", CaseMapping::Rfc1459),
+            vec!["PRIVMSG nick :5\r\n"]
+        );
+        assert_eq!(queue.len(), 1);
+    }
+}
@@ -1,16 +1,24 @@
 #![allow(clippy::zero_prefixed_literal)]
 
+use crate::cap::Caps;
+use crate::history::History;
+use crate::request::{Requests, Response, ResponseReceiver};
+use crate::sasl::{Sasl, SaslResponse};
 use crate::utils;
-use crate::{Cmd, Event, ServerInfo};
-use libtiny_common::{ChanName, ChanNameRef};
+use crate::{
+    ChannelInfo, ChannelMember, Cmd, Event, ModeList, ModeListEntry, ServerInfo, Topic, UserInfo,
+};
+use libtiny_common::{CaseMapping, ChanName, ChanNameRef, Nick, NickRef};
 use libtiny_wire as wire;
-use libtiny_wire::{Msg, Pfx};
+use libtiny_wire::{numeric, Msg, Numeric, Pfx};
 
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{HashMap, VecDeque};
 use std::rc::Rc;
+use std::time::Instant;
 
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::oneshot;
 use tokio::time::{timeout, Duration};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::StreamExt;
@@ -44,13 +52,62 @@ impl State {
         self.inner.borrow_mut().update(msg, snd_ev, snd_irc_msg);
     }
 
+    /// Collect messages in history batches. Returns the message back when it's not a part of a
+    /// history batch, in which case it should be passed to `update`.
+    pub(crate) fn handle_history(
+        &self,
+        msg: Msg,
+        timestamp: time::Tm,
+        snd_ev: &mut Sender<Event>,
+    ) -> Option<(Msg, time::Tm)> {
+        let inner = &mut *self.inner.borrow_mut();
+        let mapping = inner.case_mapping();
+        inner
+            .history
+            .handle_msg(msg, timestamp, &inner.isupport, mapping, snd_ev)
+    }
+
+    /// Handle a message that may be a reply to a request, or to the MODE query that we send when
+    /// we join a channel. Returns whether the message is a part of a reply, in which case it
+    /// shouldn't be sent to the user.
+    pub(crate) fn handle_reply(&self, msg: &Msg) -> bool {
+        let inner = &mut *self.inner.borrow_mut();
+        if inner.handle_mode_query_reply(msg) {
+            return true;
+        }
+        let mapping = inner.case_mapping();
+        inner.requests.handle_msg(msg, &inner.isupport, mapping)
+    }
+
+    /// Add a request, see `Requests::add`. The request is labeled when `labeled-response` is
+    /// enabled.
+    pub(crate) fn add_request(
+        &self,
+        response: Response,
+        target: &str,
+        msg: String,
+    ) -> (u64, String, oneshot::Receiver<()>, ResponseReceiver) {
+        let inner = &mut *self.inner.borrow_mut();
+        let labeled = inner.caps.is_enabled("labeled-response") && inner.caps.is_enabled("batch");
+        let mapping = inner.case_mapping();
+        inner.requests.add(response, target, msg, labeled, mapping)
+    }
+
+    pub(crate) fn cancel_request(&self, id: u64) {
+        self.inner.borrow_mut().requests.cancel(id)
+    }
+
+    pub(crate) fn reset_requests(&self) {
+        self.inner.borrow_mut().requests.reset()
+    }
+
     pub(crate) fn introduce(&self, snd_irc_msg: &mut Sender<String>) {
         self.inner.borrow_mut().introduce(snd_irc_msg)
     }
 
     // FIXME: This allocates a new String
     pub(crate) fn get_nick(&self) -> String {
-        self.inner.borrow().current_nick.clone()
+        self.inner.borrow().current_nick.display().to_owned()
     }
 
     // FIXME: Maybe use RwLock instead of Mutex
@@ -62,6 +119,54 @@ impl State {
         self.inner.borrow().usermask.clone()
     }
 
+    pub(crate) fn get_isupport(&self) -> wire::ISupport {
+        self.inner.borrow().isupport.clone()
+    }
+
+    pub(crate) fn is_chan(&self, name: &str) -> bool {
+        self.inner.borrow().isupport.is_chan(name)
+    }
+
+    pub(crate) fn is_prefix_mode(&self, mode: char) -> bool {
+        self.inner.borrow().isupport.is_prefix_mode(mode)
+    }
+
+    pub(crate) fn split_nick_prefix<'a>(&self, nick: &'a str) -> (&'a str, &'a str) {
+        self.inner.borrow().isupport.split_nick_prefix(nick)
+    }
+
+    pub(crate) fn get_linelen(&self) -> usize {
+        self.inner.borrow().isupport.linelen
+    }
+
+    pub(crate) fn get_case_mapping(&self) -> CaseMapping {
+        self.inner.borrow().case_mapping()
+    }
+
+    pub(crate) fn get_send_encoding(&self) -> Option<&'static wire::Encoding> {
+        self.inner.borrow().server_info.send_encoding
+    }
+
+    pub(crate) fn is_cap_wanted(&self, cap: &str) -> bool {
+        self.inner.borrow().caps.is_wanted(cap)
+    }
+
+    pub(crate) fn is_cap_available(&self, cap: &str) -> bool {
+        self.inner.borrow().caps.is_available(cap)
+    }
+
+    pub(crate) fn is_cap_enabled(&self, cap: &str) -> bool {
+        self.inner.borrow().caps.is_enabled(cap)
+    }
+
+    pub(crate) fn get_cap_value(&self, cap: &str) -> Option<String> {
+        self.inner.borrow().caps.value(cap).map(str::to_owned)
+    }
+
+    pub(crate) fn get_enabled_caps(&self) -> Vec<String> {
+        self.inner.borrow().caps.enabled().to_vec()
+    }
+
     pub(crate) fn set_away(&self, msg: Option<&str>) {
         self.inner.borrow_mut().away_status = msg.map(str::to_owned);
     }
@@ -70,6 +175,18 @@ impl State {
         self.inner.borrow().get_chan_nicks(chan)
     }
 
+    pub(crate) fn get_user(&self, nick: &str) -> Option<UserInfo> {
+        self.inner.borrow().get_user(nick)
+    }
+
+    pub(crate) fn get_channel(&self, chan: &ChanNameRef) -> Option<ChannelInfo> {
+        self.inner.borrow().get_channel(chan)
+    }
+
+    pub(crate) fn get_list_mode(&self, list: ModeList) -> char {
+        self.inner.borrow().get_list_mode(list)
+    }
+
     pub(crate) fn leave_channel(&self, msg_chan: &mut Sender<Cmd>, chan: &ChanNameRef) {
         self.inner.borrow_mut().leave_channel(msg_chan, chan)
     }
@@ -77,6 +194,36 @@ impl State {
     pub(crate) fn kill_join_tasks(&self) {
         self.inner.borrow_mut().kill_join_tasks();
     }
+
+    pub(crate) fn add_pending_echo(&self, target: &str, msg: &str, is_action: bool) {
+        self.inner
+            .borrow_mut()
+            .add_pending_echo(target, msg, is_action)
+    }
+
+    /// A message is written to the connection by the writer task. Starts the timeouts of the
+    /// pending echo and request with the message.
+    pub(crate) fn msg_sent(&self, msg: &str) {
+        let inner = &mut *self.inner.borrow_mut();
+        inner.msg_sent(msg, Instant::now());
+        inner.requests.sent(msg);
+    }
+
+    /// Messages are dropped from the send queue, see `Client::cancel_queued`. Their pending
+    /// echoes are removed without reporting them.
+    pub(crate) fn cancel_pending_echoes(&self, msgs: &[String]) {
+        self.inner.borrow_mut().cancel_pending_echoes(msgs)
+    }
+
+    pub(crate) fn next_echo_timeout(&self, now: Instant) -> Option<Duration> {
+        self.inner.borrow().next_echo_timeout(now)
+    }
+
+    pub(crate) fn expire_pending_echoes(&self, snd_ev: &mut Sender<Event>) {
+        self.inner
+            .borrow_mut()
+            .expire_pending_echoes(Instant::now(), snd_ev)
+    }
 }
 
 struct StateInner {
@@ -92,7 +239,7 @@ struct StateInner {
 
     /// A cache of current nick, to avoid allocating new nicks when inventing new nicks with
     /// underscores.
-    current_nick: String,
+    current_nick: Nick,
 
     /// Currently joined channels. Every channel we join will be added here to be able to re-join
     /// automatically on reconnect and channels we leave will be removed.
@@ -119,6 +266,37 @@ struct StateInner {
     /// Do we have a nick yet? Try another nick on ERR_NICKNAMEINUSE (433) until we've got a nick.
     nick_accepted: bool,
 
+    /// Server capabilities advertised with RPL_ISUPPORT (005). Defaults until 005.
+    isupport: wire::ISupport,
+
+    /// Times of the automatic CTCP replies sent in the last `CTCP_REPLY_PERIOD`, oldest first.
+    ctcp_replies: VecDeque<Instant>,
+
+    /// IRCv3 capability negotiation state
+    caps: Caps,
+
+    /// SASL authentication in progress
+    sasl: Option<Sasl>,
+
+    /// SASL mechanisms supported by the server, from RPL_SASLMECHS (908). Used to fall back to
+    /// another mechanism when the server rejects ours.
+    sasl_mechs: Option<String>,
+
+    /// Messages that we sent when `echo-message` is enabled and the server hasn't echoed yet,
+    /// oldest first. Not cleared on reset: messages sent right before a disconnect, or dropped
+    /// from the send queue on reset, are reported as not echoed after `ECHO_TIMEOUT`.
+    pending_echoes: VecDeque<PendingEcho>,
+
+    /// Users in our channels, and us, indexed by nicks normalized with the server's case mapping.
+    /// Users are removed when they're not in any of our channels anymore.
+    users: HashMap<String, User>,
+
+    /// Fetching missed messages with `draft/chathistory` or ZNC playback
+    history: History,
+
+    /// Requests waiting for replies, see `Client::whois`
+    requests: Requests,
+
     /// Server information
     server_info: ServerInfo,
 }
@@ -127,12 +305,100 @@ struct StateInner {
 struct Chan {
     /// Name of the channel
     name: ChanName,
-    /// Set of nicknames in channel
-    nicks: HashSet<String>,
+    /// Users in channel, indexed by nicks normalized with the server's case mapping
+    nicks: HashMap<String, Member>,
     /// Channel joined state
     join_state: JoinState,
     /// Join attempts
     join_attempts: u8,
+    /// Channel key, used when (re)joining the channel
+    key: Option<String>,
+    /// Channel modes other than list and prefix modes, with their arguments
+    modes: Vec<(char, Option<String>)>,
+    topic: Option<Topic>,
+    /// Creation time, as a Unix timestamp
+    created: Option<u64>,
+    /// Mode lists (bans etc.) that were requested, indexed by the list modes
+    mode_lists: HashMap<char, ModeListState>,
+    /// Replies to the MODE query that we send when we join, which are not sent to the user
+    mode_query: ModeQuery,
+}
+
+/// State of the MODE query sent when we join a channel. See `StateInner::handle_mode_query_reply`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ModeQuery {
+    /// No replies expected
+    Done,
+    /// Waiting for RPL_CHANNELMODEIS
+    Modes,
+    /// Got RPL_CHANNELMODEIS, RPL_CREATIONTIME may follow
+    CreationTime,
+}
+
+/// A requested mode list of a channel
+#[derive(Debug, Default)]
+struct ModeListState {
+    entries: Vec<ModeListEntry>,
+    /// Did we get the end of the list? The next list reply starts a new list.
+    complete: bool,
+}
+
+/// A user in a channel
+#[derive(Debug)]
+struct Member {
+    nick: Nick,
+    /// Channel prefix modes of the user (e.g. "ov"), in order of decreasing rank. With
+    /// `multi-prefix` we know all of the modes, otherwise only the highest one until a MODE.
+    modes: String,
+}
+
+/// What we know about a user. See `UserInfo` for the fields.
+#[derive(Debug)]
+struct User {
+    nick: Nick,
+    user: Option<String>,
+    host: Option<String>,
+    realname: Option<String>,
+    account: Option<String>,
+    away: Option<String>,
+}
+
+impl User {
+    fn new(nick: &str) -> User {
+        User {
+            nick: Nick::new(nick.to_owned()),
+            user: None,
+            host: None,
+            realname: None,
+            account: None,
+            away: None,
+        }
+    }
+
+    /// Update user and host from a message prefix
+    fn set_userhost(&mut self, pfx: &Pfx) {
+        if let Some(hostmask) = pfx.hostmask() {
+            if let Some(user) = hostmask.user.filter(|user| !user.is_empty()) {
+                self.user = Some(user);
+            }
+            if let Some(host) = hostmask.host {
+                self.host = Some(host);
+            }
+        }
+    }
+}
+
+/// A PRIVMSG waiting to be echoed by the server.
+#[derive(Debug)]
+struct PendingEcho {
+    /// Target of the message, without STATUSMSG prefixes
+    target: String,
+    msg: String,
+    is_action: bool,
+    /// The message as sent to the server, to find it when it's sent
+    line: String,
+    /// When the message was written to the connection. `None` while it's in the send queue.
+    sent: Option<Instant>,
 }
 
 /// State transitions:
@@ -157,27 +423,141 @@ enum JoinState {
 
 const MAX_JOIN_RETRIES: u8 = 3;
 
+/// Max. number of automatic CTCP replies in `CTCP_REPLY_PERIOD`. Queries over the limit are not
+/// answered, to avoid getting disconnected for flooding when someone sends a lot of queries.
+const MAX_CTCP_REPLIES: usize = 3;
+const CTCP_REPLY_PERIOD: Duration = Duration::from_secs(10);
+
+/// CTCP commands that we answer. Sent in CLIENTINFO replies.
+const CLIENTINFO: &str = "ACTION CLIENTINFO PING TIME";
+
+/// Messages that are not echoed back by the server in this duration are reported with
+/// `Event::MsgNotEchoed`.
+const ECHO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Current time as a Unix timestamp, for topics and mode list entries set while we're in the
+/// channel.
+fn now_unix() -> u64 {
+    time::get_time().sec as u64
+}
+
 impl Chan {
     fn new(name: ChanName) -> Chan {
         Chan {
             name,
-            nicks: HashSet::new(),
+            nicks: HashMap::new(),
             join_state: JoinState::NotJoined,
             join_attempts: MAX_JOIN_RETRIES,
+            key: None,
+            modes: vec![],
+            topic: None,
+            created: None,
+            mode_lists: HashMap::new(),
+            mode_query: ModeQuery::Done,
         }
     }
 
-    fn with_nicks(name: ChanName, nicks: HashSet<String>) -> Chan {
-        Chan {
-            name,
-            nicks,
-            join_state: JoinState::NotJoined,
-            join_attempts: MAX_JOIN_RETRIES,
+    /// Add a nick to the channel with the given prefix modes. Modes of the nick are overridden if
+    /// it's already in the channel.
+    fn add_nick(&mut self, nick: &str, modes: String, mapping: CaseMapping) {
+        self.nicks.insert(
+            mapping.normalize(nick),
+            Member {
+                nick: Nick::new(nick.to_owned()),
+                modes,
+            },
+        );
+    }
+
+    /// Returns the nick's membership if the nick was in the channel.
+    fn remove_nick(&mut self, nick: &str, mapping: CaseMapping) -> Option<Member> {
+        self.nicks.remove(&mapping.normalize(nick))
+    }
+
+    /// Set or unset a prefix mode (e.g. 'o') of a nick. `prefix` is the PREFIX of the server.
+    fn set_prefix_mode(
+        &mut self,
+        nick: &str,
+        mode: char,
+        set: bool,
+        prefix: &[(char, char)],
+        mapping: CaseMapping,
+    ) {
+        if let Some(member) = self.nicks.get_mut(&mapping.normalize(nick)) {
+            let modes = prefix
+                .iter()
+                .map(|(mode, _)| *mode)
+                .filter(|mode_| {
+                    if *mode_ == mode {
+                        set
+                    } else {
+                        member.modes.contains(*mode_)
+                    }
+                })
+                .collect();
+            member.modes = modes;
+        }
+    }
+
+    /// Normalize nicks again after a case mapping change.
+    fn set_case_mapping(&mut self, mapping: CaseMapping) {
+        self.nicks = self
+            .nicks
+            .drain()
+            .map(|(_, member)| (member.nick.normalized_with(mapping), member))
+            .collect();
+    }
+
+    /// Set or unset a channel mode other than list and prefix modes.
+    fn set_mode(&mut self, mode: char, set: bool, arg: Option<String>) {
+        match self.modes.iter_mut().find(|(mode_, _)| *mode_ == mode) {
+            Some(entry) if set => entry.1 = arg,
+            Some(_) => self.modes.retain(|(mode_, _)| *mode_ != mode),
+            None if set => self.modes.push((mode, arg)),
+            None => {}
+        }
+    }
+
+    /// Add an entry to a mode list, from a list reply. Starts a new list if the list was
+    /// complete.
+    fn add_mode_list_entry(&mut self, mode: char, entry: ModeListEntry) {
+        let list = self.mode_lists.entry(mode).or_default();
+        if list.complete {
+            list.entries.clear();
+            list.complete = false;
+        }
+        list.entries.push(entry);
+    }
+
+    /// End of a mode list reply. Lists without entries are also created here.
+    fn end_mode_list(&mut self, mode: char) {
+        let list = self.mode_lists.entry(mode).or_default();
+        if list.complete {
+            list.entries.clear();
+        }
+        list.complete = true;
+    }
+
+    /// Update a mode list with a MODE message. Lists that we don't know are not updated.
+    fn update_mode_list(&mut self, mode: char, set: bool, mask: &str, setter: &str) {
+        if let Some(list) = self.mode_lists.get_mut(&mode) {
+            list.entries.retain(|entry| entry.mask != mask);
+            if set {
+                list.entries.push(ModeListEntry {
+                    mask: mask.to_owned(),
+                    setter: Some(setter.to_owned()),
+                    time: Some(now_unix()),
+                });
+            }
         }
     }
 
     fn reset(&mut self) {
         self.nicks.clear();
+        self.modes.clear();
+        self.topic = None;
+        self.created = None;
+        self.mode_lists.clear();
         self.join_state = JoinState::NotJoined;
         self.join_attempts = MAX_JOIN_RETRIES;
     }
@@ -201,12 +581,26 @@ impl Chan {
 
 impl StateInner {
     fn new(server_info: ServerInfo) -> StateInner {
-        let current_nick = server_info.nicks[0].to_owned();
+        let current_nick = Nick::new(server_info.nicks[0].to_owned());
         let chans = server_info
             .auto_join
             .iter()
-            .map(|s| Chan::new(s.to_owned()))
+            .map(|auto_join| {
+                let mut chan = Chan::new(auto_join.chan.clone());
+                chan.key = auto_join.key.clone();
+                chan
+            })
+            .collect();
+        // Only request SASL when we have the credentials
+        let mut wanted_caps: Vec<String> = server_info
+            .caps
+            .iter()
+            .filter(|cap| cap.as_str() != "sasl")
+            .cloned()
             .collect();
+        if server_info.sasl_auth.is_some() {
+            wanted_caps.push("sasl".to_owned());
+        }
         StateInner {
             nicks: server_info.nicks.clone(),
             nickserv_ident: server_info.nickserv_ident.clone(),
@@ -217,6 +611,15 @@ impl StateInner {
             servername: None,
             usermask: None,
             nick_accepted: false,
+            isupport: wire::ISupport::default(),
+            ctcp_replies: VecDeque::new(),
+            caps: Caps::new(wanted_caps),
+            sasl: None,
+            sasl_mechs: None,
+            pending_echoes: VecDeque::new(),
+            users: HashMap::new(),
+            history: History::new(),
+            requests: Requests::new(),
             server_info,
         }
     }
@@ -225,13 +628,25 @@ impl StateInner {
         self.nick_accepted = false;
         self.nicks = self.server_info.nicks.clone();
         self.current_nick_idx = 0;
-        self.current_nick = self.nicks[0].clone();
+        self.current_nick = Nick::new(self.nicks[0].clone());
         // Only reset the values here; the key set will be used to join channels
         for chan in &mut self.chans {
             chan.reset();
         }
         self.servername = None;
         self.usermask = None;
+        self.isupport = wire::ISupport::default();
+        self.caps.reset();
+        self.sasl = None;
+        self.sasl_mechs = None;
+        self.users.clear();
+        self.history.reset();
+        self.requests.reset();
+        // Queued messages are dropped, start their timeouts
+        let now = Instant::now();
+        for echo in &mut self.pending_echoes {
+            echo.sent.get_or_insert(now);
+        }
     }
 
     fn send_ping(&mut self, snd_irc_msg: &mut Sender<String>) {
@@ -244,8 +659,11 @@ impl StateInner {
         if let Some(ref pass) = self.server_info.pass {
             snd_irc_msg.try_send(wire::pass(pass)).unwrap();
         }
+        // Servers that support capability negotiation suspend the registration until `CAP END`.
+        // Others ignore the `CAP` command and register right away.
+        self.caps.start(snd_irc_msg);
         snd_irc_msg
-            .try_send(wire::nick(&self.current_nick))
+            .try_send(wire::nick(self.current_nick.display()))
             .unwrap();
         snd_irc_msg
             .try_send(wire::user(&self.nicks[0], &self.server_info.realname))
@@ -261,11 +679,452 @@ impl StateInner {
             for _ in 0..n_underscores {
                 new_nick.push('_');
             }
-            self.current_nick = new_nick;
+            self.current_nick = Nick::new(new_nick);
+        } else {
+            self.current_nick = Nick::new(self.nicks[self.current_nick_idx].clone());
+        }
+        self.current_nick.display()
+    }
+
+    fn case_mapping(&self) -> CaseMapping {
+        self.isupport.casemapping
+    }
+
+    /// Is the nick our current nick, according to the server's case mapping?
+    fn is_current_nick(&self, nick: &str) -> bool {
+        self.current_nick
+            .eq_with(NickRef::new(nick), self.case_mapping())
+    }
+
+    fn add_pending_echo(&mut self, target: &str, msg: &str, is_action: bool) {
+        if !self.caps.is_enabled("echo-message") {
+            return;
+        }
+        let line = if is_action {
+            wire::action(target, msg)
+        } else {
+            wire::privmsg(target, msg)
+        };
+        let target = match self.isupport.split_statusmsg(target) {
+            Some((_, chan)) => chan,
+            None => target,
+        };
+        self.pending_echoes.push_back(PendingEcho {
+            target: target.to_owned(),
+            msg: msg.to_owned(),
+            is_action,
+            line,
+            sent: None,
+        });
+    }
+
+    fn msg_sent(&mut self, msg: &str, now: Instant) {
+        if let Some(echo) = self
+            .pending_echoes
+            .iter_mut()
+            .find(|echo| echo.sent.is_none() && echo.line == msg)
+        {
+            echo.sent = Some(now);
+        }
+    }
+
+    fn cancel_pending_echoes(&mut self, msgs: &[String]) {
+        for msg in msgs {
+            if let Some(idx) = self
+                .pending_echoes
+                .iter()
+                .position(|echo| echo.sent.is_none() && &echo.line == msg)
+            {
+                self.pending_echoes.remove(idx);
+            }
+        }
+    }
+
+    /// Is the pending message sent to the target, according to the server's case mapping?
+    fn is_echo_target(&self, echo: &PendingEcho, target: &str) -> bool {
+        let mapping = self.case_mapping();
+        mapping.normalize(&echo.target) == mapping.normalize(target)
+    }
+
+    /// Handle a message from us echoed by the server. The echo is matched with the oldest pending
+    /// message with the same target and text. Messages from us that don't match a pending message
+    /// (e.g. sent by another client attached to the same bouncer) are ignored, pending messages
+    /// that are not echoed are reported by `expire_pending_echoes`.
+    fn handle_echo(&mut self, target: &str, msg: &str, is_action: bool) {
+        let idx = self.pending_echoes.iter().position(|echo| {
+            echo.is_action == is_action && echo.msg == msg && self.is_echo_target(echo, target)
+        });
+        if let Some(idx) = idx {
+            self.pending_echoes.remove(idx);
+        }
+    }
+
+    /// The server rejected a message to the target, report the oldest pending message to the
+    /// target as not echoed.
+    fn fail_pending_echo(&mut self, target: &str, snd_ev: &mut Sender<Event>) {
+        let idx = self
+            .pending_echoes
+            .iter()
+            .position(|echo| self.is_echo_target(echo, target));
+        if let Some(idx) = idx {
+            let echo = self.pending_echoes.remove(idx).unwrap();
+            snd_ev
+                .try_send(Event::MsgNotEchoed {
+                    target: echo.target,
+                    msg: echo.msg,
+                })
+                .unwrap();
+        }
+    }
+
+    /// How long until the oldest pending message times out. `None` when there are no pending
+    /// messages that are sent. Messages are sent in order, so messages in the send queue come
+    /// after the sent ones.
+    fn next_echo_timeout(&self, now: Instant) -> Option<Duration> {
+        let sent = self.pending_echoes.front()?.sent?;
+        Some((sent + ECHO_TIMEOUT).saturating_duration_since(now))
+    }
+
+    fn expire_pending_echoes(&mut self, now: Instant, snd_ev: &mut Sender<Event>) {
+        while let Some(echo) = self.pending_echoes.front() {
+            match echo.sent {
+                Some(sent) if now.saturating_duration_since(sent) >= ECHO_TIMEOUT => {}
+                _ => break,
+            }
+            let echo = self.pending_echoes.pop_front().unwrap();
+            snd_ev
+                .try_send(Event::MsgNotEchoed {
+                    target: echo.target,
+                    msg: echo.msg,
+                })
+                .unwrap();
+        }
+    }
+
+    /// Is the message a reply to the MODE query that we send when we join a channel? The query is
+    /// only to update the channel state, so the replies are not shown to the user. Replies to
+    /// MODE queries of the user are sent as usual.
+    fn handle_mode_query_reply(&mut self, msg: &Msg) -> bool {
+        let (num, chan) = match &msg.cmd {
+            wire::Cmd::Reply { num, params } if params.len() > 1 => (*num, &params[1]),
+            _ => return false,
+        };
+        let chan_idx = match self.find_chan_idx(ChanNameRef::new(chan)) {
+            None => return false,
+            Some(chan_idx) => chan_idx,
+        };
+        let chan = &mut self.chans[chan_idx];
+        match (num, chan.mode_query) {
+            // RPL_CHANNELMODEIS
+            (324, ModeQuery::Modes) => {
+                chan.mode_query = ModeQuery::CreationTime;
+                true
+            }
+            // RPL_CREATIONTIME
+            (329, ModeQuery::CreationTime) => {
+                chan.mode_query = ModeQuery::Done;
+                true
+            }
+            // The server doesn't send RPL_CREATIONTIME, this is a reply to the user
+            (324, ModeQuery::CreationTime) => {
+                chan.mode_query = ModeQuery::Done;
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Find a channel, using the server's case mapping for the channel name.
+    fn find_chan_idx(&self, chan: &ChanNameRef) -> Option<usize> {
+        let mapping = self.case_mapping();
+        utils::find_idx(&self.chans, |c| c.name.eq_with(chan, mapping))
+    }
+
+    /// Handle `nick` joining `chan`. If this is us create the channel state, otherwise add the
+    /// nick to the channel.
+    fn handle_join(&mut self, nick: &str, chan: &ChanNameRef) {
+        if self.is_current_nick(nick) {
+            // We joined a channel, initialize channel state
+            match self.find_chan_idx(chan) {
+                None => {
+                    let mut chan = Chan::new(chan.to_owned());
+                    // Since nick was found in the prefix, we are in the channel
+                    chan.join_state = JoinState::Joined;
+                    self.chans.push(chan);
+                }
+                Some(chan_idx) => {
+                    // This happens because we initialize channel states for channels that we will
+                    // join on connection when the client is first created
+                    let chan = &mut self.chans[chan_idx];
+                    chan.join_state = JoinState::Joined;
+                    chan.nicks.clear();
+                }
+            }
+        } else {
+            match self.find_chan_idx(chan) {
+                Some(chan_idx) => {
+                    let mapping = self.case_mapping();
+                    self.chans[chan_idx].add_nick(
+                        self.isupport.strip_nick_prefix(nick),
+                        String::new(),
+                        mapping,
+                    );
+                }
+                None => {
+                    debug!("Can't find channel state for JOIN: {}", chan.display());
+                }
+            }
+        }
+    }
+
+    /// Handle `nick` leaving `chan`. If this is us remove the channel state, otherwise remove the
+    /// nick from the channel.
+    fn handle_part(&mut self, nick: &str, chan: &ChanNameRef) {
+        match self.find_chan_idx(chan) {
+            None => {
+                debug!("Can't find channel state for PART: {}", chan.display());
+            }
+            Some(chan_idx) => {
+                if self.is_current_nick(nick) {
+                    self.chans.remove(chan_idx);
+                } else {
+                    let mapping = self.case_mapping();
+                    self.chans[chan_idx]
+                        .remove_nick(self.isupport.strip_nick_prefix(nick), mapping);
+                }
+                self.remove_unknown_users();
+            }
+        }
+    }
+
+    /// Get the record of a user, creating a new one if we don't know the user yet.
+    fn user_mut(&mut self, nick: &str) -> &mut User {
+        let key = self.case_mapping().normalize(nick);
+        self.users.entry(key).or_insert_with(|| User::new(nick))
+    }
+
+    /// Get the record of a user if we know the user.
+    fn known_user_mut(&mut self, nick: &str) -> Option<&mut User> {
+        let key = self.case_mapping().normalize(nick);
+        self.users.get_mut(&key)
+    }
+
+    /// Remove users that are not in any of our channels.
+    fn remove_unknown_users(&mut self) {
+        let chans = &self.chans;
+        let current_nick = self.current_nick.normalized_with(self.case_mapping());
+        self.users.retain(|key, _| {
+            *key == current_nick || chans.iter().any(|chan| chan.nicks.contains_key(key))
+        });
+    }
+
+    fn get_user(&self, nick: &str) -> Option<UserInfo> {
+        let key = self.case_mapping().normalize(nick);
+        let user = self.users.get(&key)?;
+        let chans = self
+            .chans
+            .iter()
+            .filter_map(|chan| {
+                chan.nicks
+                    .get(&key)
+                    .map(|member| (chan.name.clone(), member.modes.clone()))
+            })
+            .collect();
+        Some(UserInfo {
+            nick: user.nick.display().to_owned(),
+            user: user.user.clone(),
+            host: user.host.clone(),
+            realname: user.realname.clone(),
+            account: user.account.clone(),
+            away: user.away.clone(),
+            chans,
+        })
+    }
+
+    /// Returns whether we can send an automatic CTCP reply now. Updates the rate limiter state.
+    fn ctcp_reply_allowed(&mut self, now: Instant) -> bool {
+        while let Some(time) = self.ctcp_replies.front() {
+            if now.duration_since(*time) >= CTCP_REPLY_PERIOD {
+                self.ctcp_replies.pop_front();
+            } else {
+                break;
+            }
+        }
+        if self.ctcp_replies.len() < MAX_CTCP_REPLIES {
+            self.ctcp_replies.push_back(now);
+            true
         } else {
-            self.current_nick = self.nicks[self.current_nick_idx].clone();
+            false
+        }
+    }
+
+    /// Update channel and user state with a numeric reply.
+    fn handle_numeric(&mut self, numeric: Numeric) {
+        match numeric {
+            Numeric::RplAway { nick, msg } => {
+                if let Some(user) = self.known_user_mut(nick) {
+                    user.away = Some(msg.to_owned());
+                }
+            }
+
+            Numeric::RplUnaway { .. } => {
+                let nick = self.current_nick.display().to_owned();
+                self.user_mut(&nick).away = None;
+            }
+
+            Numeric::RplNowAway { .. } => {
+                let nick = self.current_nick.display().to_owned();
+                let away = self.away_status.clone().unwrap_or_default();
+                self.user_mut(&nick).away = Some(away);
+            }
+
+            Numeric::RplWhoisUser {
+                nick,
+                user: username,
+                host,
+                realname,
+            } => {
+                if let Some(user) = self.known_user_mut(nick) {
+                    user.user = Some(username.to_owned());
+                    user.host = Some(host.to_owned());
+                    user.realname = Some(realname.to_owned());
+                }
+            }
+
+            Numeric::RplWhoisAccount { nick, account } => {
+                if let Some(user) = self.known_user_mut(nick) {
+                    user.account = Some(account.to_owned());
+                }
+            }
+
+            Numeric::RplWhoReply {
+                nick,
+                user: username,
+                host,
+                flags,
+                realname,
+                ..
+            } => {
+                if let Some(user) = self.known_user_mut(nick) {
+                    user.user = Some(username.to_owned());
+                    user.host = Some(host.to_owned());
+                    // 'G' for gone, 'H' for here
+                    if flags.starts_with('G') {
+                        if user.away.is_none() {
+                            user.away = Some(String::new());
+                        }
+                    } else {
+                        user.away = None;
+                    }
+                    user.realname = Some(realname.to_owned());
+                }
+            }
+
+            Numeric::RplChannelModeIs { chan, modes } => {
+                if let Some(chan_idx) = self.find_chan_idx(chan) {
+                    let isupport = &self.isupport;
+                    let changes = wire::parse_mode_changes(
+                        &modes[0],
+                        modes[1..].iter().map(String::as_str),
+                        |mode, set| isupport.chan_mode_takes_arg(mode, set),
+                    );
+                    let chan = &mut self.chans[chan_idx];
+                    chan.modes = changes
+                        .into_iter()
+                        .filter(|change| {
+                            change.set
+                                && !isupport.chanmodes.a.contains(change.mode)
+                                && !isupport.prefix.iter().any(|(mode, _)| *mode == change.mode)
+                        })
+                        .map(|change| (change.mode, change.arg))
+                        .collect();
+                }
+            }
+
+            Numeric::RplCreationTime { chan, time } => {
+                if let Some(chan_idx) = self.find_chan_idx(chan) {
+                    self.chans[chan_idx].created = Some(time);
+                }
+            }
+
+            Numeric::RplNoTopic { chan } => {
+                if let Some(chan_idx) = self.find_chan_idx(chan) {
+                    self.chans[chan_idx].topic = None;
+                }
+            }
+
+            Numeric::RplTopic { chan, topic } => {
+                if let Some(chan_idx) = self.find_chan_idx(chan) {
+                    self.chans[chan_idx].topic = Some(Topic {
+                        text: topic.to_owned(),
+                        setter: None,
+                        time: None,
+                    });
+                }
+            }
+
+            Numeric::RplTopicWhoTime { chan, setter, time } => {
+                if let Some(chan_idx) = self.find_chan_idx(chan) {
+                    if let Some(topic) = &mut self.chans[chan_idx].topic {
+                        topic.setter = Some(setter.to_owned());
+                        topic.time = Some(time);
+                    }
+                }
+            }
+
+            Numeric::RplBanList {
+                chan,
+                mask,
+                setter,
+                time,
+            } => self.add_mode_list_entry(ModeList::Bans, chan, mask, setter, time),
+            Numeric::RplExceptList {
+                chan,
+                mask,
+                setter,
+                time,
+            } => self.add_mode_list_entry(ModeList::Excepts, chan, mask, setter, time),
+            Numeric::RplInviteList {
+                chan,
+                mask,
+                setter,
+                time,
+            } => self.add_mode_list_entry(ModeList::Invites, chan, mask, setter, time),
+
+            Numeric::RplEndOfBanList { chan } => self.end_mode_list(ModeList::Bans, chan),
+            Numeric::RplEndOfExceptList { chan } => self.end_mode_list(ModeList::Excepts, chan),
+            Numeric::RplEndOfInviteList { chan } => self.end_mode_list(ModeList::Invites, chan),
+
+            _ => {}
+        }
+    }
+
+    fn add_mode_list_entry(
+        &mut self,
+        list: ModeList,
+        chan: &ChanNameRef,
+        mask: &str,
+        setter: Option<&str>,
+        time: Option<u64>,
+    ) {
+        let mode = self.get_list_mode(list);
+        if let Some(chan_idx) = self.find_chan_idx(chan) {
+            self.chans[chan_idx].add_mode_list_entry(
+                mode,
+                ModeListEntry {
+                    mask: mask.to_owned(),
+                    setter: setter.map(str::to_owned),
+                    time,
+                },
+            );
+        }
+    }
+
+    fn end_mode_list(&mut self, list: ModeList, chan: &ChanNameRef) {
+        let mode = self.get_list_mode(list);
+        if let Some(chan_idx) = self.find_chan_idx(chan) {
+            self.chans[chan_idx].end_mode_list(mode);
         }
-        &self.current_nick
     }
 
     fn update(
@@ -274,23 +1133,171 @@ impl StateInner {
         snd_ev: &mut Sender<Event>,
         snd_irc_msg: &mut Sender<String>,
     ) {
+        self.expire_pending_echoes(Instant::now(), snd_ev);
+
         let Msg {
+            ref tags,
             ref pfx,
             ref mut cmd,
         } = msg;
 
+        if let Some(numeric) = cmd.numeric() {
+            self.handle_numeric(numeric);
+        }
+
         use wire::Cmd::*;
         match cmd {
+            // PRIVMSG and NOTICE: Wire parser only knows about '#' channels, use CHANTYPES and
+            // STATUSMSG to find channel targets. STATUSMSG prefixes (e.g. '@' in "@#chan") are
+            // dropped.
+            //
+            // CTCP queries: Reply to PING, TIME and CLIENTINFO, rate limited.
+            PRIVMSG {
+                targets,
+                msg,
+                is_notice,
+                ctcp,
+            } => {
+                for target in targets.iter_mut() {
+                    if let wire::MsgTarget::User(name) = target {
+                        if let Some((_, chan)) = self.isupport.split_statusmsg(name) {
+                            *target = wire::MsgTarget::Chan(ChanName::new(chan.to_owned()));
+                        }
+                    }
+                }
+
+                // Our own message echoed back by the server (see `echo-message`)
+                match (pfx, &targets[..]) {
+                    (Some(Pfx::User { nick, .. }), [target])
+                    | (Some(Pfx::Ambiguous(nick)), [target])
+                        if !*is_notice
+                            && matches!(ctcp, None | Some(wire::CTCP::Action))
+                            && self.is_current_nick(nick) =>
+                    {
+                        let target = match target {
+                            wire::MsgTarget::Chan(chan) => chan.display(),
+                            wire::MsgTarget::User(nick) => nick,
+                        };
+                        let is_action = ctcp.is_some();
+                        self.handle_echo(target, msg, is_action);
+                    }
+                    _ => {}
+                }
+
+                // Remember the last message in the channel or private conversation, to fetch
+                // the messages after it on reconnect
+                if let (Some(Pfx::User { nick, .. }) | Some(Pfx::Ambiguous(nick)), Some(time)) =
+                    (pfx, tags.get("time"))
+                {
+                    let mapping = self.case_mapping();
+                    for target in targets.iter() {
+                        let target = match target {
+                            wire::MsgTarget::Chan(chan) => chan.display(),
+                            wire::MsgTarget::User(target) if self.is_current_nick(nick) => target,
+                            wire::MsgTarget::User(_) => nick,
+                        };
+                        self.history.seen(target, time, mapping);
+                    }
+                }
+
+                match (pfx, ctcp) {
+                    (Some(Pfx::User { nick, .. }), Some(ctcp))
+                    | (Some(Pfx::Ambiguous(nick)), Some(ctcp))
+                        if !*is_notice && !self.is_current_nick(nick) =>
+                    {
+                        let reply = match ctcp {
+                            wire::CTCP::Ping => Some(msg.clone()),
+                            wire::CTCP::Time => Some(time::now().rfc822z().to_string()),
+                            wire::CTCP::ClientInfo => Some(CLIENTINFO.to_owned()),
+                            _ => None,
+                        };
+                        if let Some(reply) = reply {
+                            if self.ctcp_reply_allowed(Instant::now()) {
+                                snd_irc_msg
+                                    .try_send(wire::ctcp_reply(nick, ctcp, &reply))
+                                    .unwrap();
+                            } else {
+                                debug!("Not replying to CTCP {} from {}", ctcp.name(), nick);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            // MODE: Wire parser uses default channel modes, parse the mode string again using
+            // CHANMODES and PREFIX. Update channel modes, mode lists, and prefix modes of users.
+            MODE {
+                target,
+                changes,
+                modes,
+                args,
+            } => {
+                if let wire::MsgTarget::User(name) = target {
+                    if self.isupport.is_chan(name) {
+                        *target = wire::MsgTarget::Chan(ChanName::new(name.clone()));
+                    }
+                }
+                if let wire::MsgTarget::Chan(chan) = target {
+                    let isupport = &self.isupport;
+                    *changes = wire::parse_mode_changes(
+                        modes,
+                        args.iter().map(String::as_str),
+                        |mode, set| isupport.chan_mode_takes_arg(mode, set),
+                    );
+
+                    if let Some(chan_idx) = self.find_chan_idx(chan) {
+                        let mapping = self.case_mapping();
+                        let setter = match pfx {
+                            Some(Pfx::User { nick, .. })
+                            | Some(Pfx::Ambiguous(nick))
+                            | Some(Pfx::Server(nick)) => nick.as_str(),
+                            None => "",
+                        };
+                        let prefix = &self.isupport.prefix;
+                        let list_modes = &self.isupport.chanmodes.a;
+                        let chan = &mut self.chans[chan_idx];
+                        for change in changes.iter() {
+                            if prefix.iter().any(|(mode, _)| *mode == change.mode) {
+                                if let Some(ref nick) = change.arg {
+                                    chan.set_prefix_mode(
+                                        nick,
+                                        change.mode,
+                                        change.set,
+                                        prefix,
+                                        mapping,
+                                    );
+                                }
+                            } else if list_modes.contains(change.mode) {
+                                if let Some(ref mask) = change.arg {
+                                    chan.update_mode_list(change.mode, change.set, mask, setter);
+                                }
+                            } else {
+                                chan.set_mode(change.mode, change.set, change.arg.clone());
+                            }
+                        }
+                    }
+                }
+            }
+
             // PING: Send PONG
             PING { server } => {
                 snd_irc_msg.try_send(wire::pong(server)).unwrap();
             }
 
-            // JOIN: If this is us then update usermask if possible, create the channel state. If
+            // JOIN: If this is us then update usermask if possible, create the channel state,
+            // request the channel modes, and fetch the messages that we missed in the channel. If
             // someone else add the nick to channel.
-            JOIN { chan } => {
+            //
+            // Update the user's record, with the account and realname with `extended-join`.
+            JOIN {
+                chans,
+                account,
+                realname,
+                ..
+            } => {
                 match pfx {
-                    Some(Pfx::User { nick, user }) if nick == &self.current_nick => {
+                    Some(Pfx::User { nick, user }) if self.is_current_nick(nick) => {
                         // Set usermask
                         let usermask = format!("{}!{}", nick, user);
                         self.usermask = Some(usermask);
@@ -299,36 +1306,34 @@ impl StateInner {
                 }
 
                 match pfx {
-                    Some(Pfx::User { nick, .. }) | Some(Pfx::Ambiguous(nick)) => {
-                        if nick == &self.current_nick {
-                            // We joined a channel, initialize channel state
-                            match utils::find_idx(&self.chans, |c| &c.name == chan) {
-                                None => {
-                                    let mut chan = Chan::new(chan.to_owned());
-                                    // Since nick was found in the prefix, we are in the channel
-                                    chan.join_state = JoinState::Joined;
-                                    self.chans.push(chan);
-                                }
-                                Some(chan_idx) => {
-                                    // This happens because we initialize channel states for channels
-                                    // that we will join on connection when the client is first created
-                                    let chan = &mut self.chans[chan_idx];
-                                    chan.join_state = JoinState::Joined;
-                                    chan.nicks.clear();
-                                }
-                            }
-                        } else {
-                            match utils::find_idx(&self.chans, |c| &c.name == chan) {
-                                Some(chan_idx) => {
-                                    self.chans[chan_idx]
-                                        .nicks
-                                        .insert(wire::drop_nick_prefix(nick).to_owned());
-                                }
-                                None => {
-                                    debug!("Can't find channel state for JOIN: {:?}", cmd);
+                    Some(pfx @ Pfx::User { nick, .. }) | Some(pfx @ Pfx::Ambiguous(nick)) => {
+                        for chan in chans.iter() {
+                            self.handle_join(nick, chan);
+                        }
+                        if self.is_current_nick(nick) {
+                            let mapping = self.case_mapping();
+                            for chan in chans.iter() {
+                                snd_irc_msg
+                                    .try_send(wire::mode(chan.display(), &[]))
+                                    .unwrap();
+                                if let Some(chan_idx) = self.find_chan_idx(chan) {
+                                    self.chans[chan_idx].mode_query = ModeQuery::Modes;
                                 }
+                                self.history.fetch(
+                                    chan.display(),
+                                    &self.caps,
+                                    &self.isupport,
+                                    mapping,
+                                    snd_irc_msg,
+                                );
                             }
                         }
+                        let user = self.user_mut(nick);
+                        user.set_userhost(pfx);
+                        if realname.is_some() {
+                            user.account = account.clone();
+                            user.realname = realname.clone();
+                        }
                     }
                     Some(Pfx::Server(_)) | None => {}
                 }
@@ -336,33 +1341,32 @@ impl StateInner {
 
             // PART: If this is us remove the channel state. Otherwise remove the nick from the
             // channel.
-            PART { chan, .. } => match pfx {
+            PART { chans, .. } => match pfx {
                 Some(Pfx::User { nick, .. }) | Some(Pfx::Ambiguous(nick)) => {
-                    if nick == &self.current_nick {
-                        match utils::find_idx(&self.chans, |c| &c.name == chan) {
-                            None => {
-                                debug!("Can't find channel state: {}", chan.display());
-                            }
-                            Some(chan_idx) => {
-                                self.chans.remove(chan_idx);
-                            }
-                        }
-                    } else {
-                        match utils::find_idx(&self.chans, |c| &c.name == chan) {
-                            Some(chan_idx) => {
-                                self.chans[chan_idx]
-                                    .nicks
-                                    .remove(wire::drop_nick_prefix(nick));
-                            }
-                            None => {
-                                debug!("Can't find channel state for PART: {:?}", cmd);
-                            }
-                        }
+                    for chan in chans.iter() {
+                        self.handle_part(nick, chan);
                     }
                 }
                 Some(Pfx::Server(_)) | None => {}
             },
 
+            // KICK: If we were kicked remove the channel state. Otherwise remove the nick from the
+            // channel.
+            KICK { chan, nick, .. } => match self.find_chan_idx(chan) {
+                None => {
+                    debug!("Can't find channel state for KICK: {:?}", cmd);
+                }
+                Some(chan_idx) => {
+                    if self.is_current_nick(nick) {
+                        self.chans.remove(chan_idx);
+                    } else {
+                        let mapping = self.case_mapping();
+                        self.chans[chan_idx].remove_nick(nick, mapping);
+                    }
+                    self.remove_unknown_users();
+                }
+            },
+
             // QUIT: Update the `chans` field for the channels that the user was in
             QUIT { ref mut chans, .. } => {
                 let nick = match pfx {
@@ -371,25 +1375,102 @@ impl StateInner {
                         return;
                     }
                 };
+                let mapping = self.case_mapping();
                 for chan in self.chans.iter_mut() {
-                    if chan.nicks.contains(nick) {
+                    if chan.remove_nick(nick, mapping).is_some() {
                         chans.push(chan.name.to_owned());
-                        chan.nicks.remove(nick);
+                    }
+                }
+                self.users.remove(&mapping.normalize(nick));
+            }
+
+            // AWAY (away-notify): Update the user's away status
+            AWAY { msg: away } => {
+                if let Some(Pfx::User { nick, .. }) | Some(Pfx::Ambiguous(nick)) = pfx {
+                    if let Some(user) = self.known_user_mut(nick) {
+                        user.away = away.clone();
+                    }
+                }
+            }
+
+            // ACCOUNT (account-notify): Update the user's account
+            ACCOUNT { account } => {
+                if let Some(Pfx::User { nick, .. }) | Some(Pfx::Ambiguous(nick)) = pfx {
+                    if let Some(user) = self.known_user_mut(nick) {
+                        user.account = account.clone();
+                    }
+                }
+            }
+
+            // CHGHOST: Update the user's user@host
+            CHGHOST {
+                user: new_user,
+                host,
+            } => {
+                if let Some(Pfx::User { nick, .. }) | Some(Pfx::Ambiguous(nick)) = pfx {
+                    if let Some(user) = self.known_user_mut(nick) {
+                        user.user = Some(new_user.clone());
+                        user.host = Some(host.clone());
+                    }
+                }
+            }
+
+            // SETNAME: Update the user's realname
+            SETNAME { realname } => {
+                if let Some(Pfx::User { nick, .. }) | Some(Pfx::Ambiguous(nick)) = pfx {
+                    if let Some(user) = self.known_user_mut(nick) {
+                        user.realname = Some(realname.clone());
                     }
                 }
             }
 
+            // TOPIC: Update the channel's topic
+            TOPIC { chan, topic } => {
+                if let Some(chan_idx) = self.find_chan_idx(chan) {
+                    let setter = match pfx {
+                        Some(Pfx::User { nick, .. })
+                        | Some(Pfx::Ambiguous(nick))
+                        | Some(Pfx::Server(nick)) => Some(nick.clone()),
+                        None => None,
+                    };
+                    self.chans[chan_idx].topic = if topic.is_empty() {
+                        None
+                    } else {
+                        Some(Topic {
+                            text: topic.clone(),
+                            setter,
+                            time: Some(now_unix()),
+                        })
+                    };
+                }
+            }
+
             // 396: Try to set usermask.
             Reply { num: 396, params } => {
                 // :hobana.freenode.net 396 osa1 haskell/developer/osa1
                 // :is now your hidden host (set by services.)
                 if params.len() == 3 {
-                    let usermask =
-                        format!("{}!~{}@{}", self.current_nick, self.nicks[0], params[1]);
+                    let usermask = format!(
+                        "{}!~{}@{}",
+                        self.current_nick.display(),
+                        self.nicks[0],
+                        params[1]
+                    );
                     self.usermask = Some(usermask);
                 }
             }
 
+            // ERR_NOSUCHNICK, ERR_NOSUCHCHANNEL, ERR_CANNOTSENDTOCHAN: The message to the target
+            // won't be echoed.
+            // ex. Reply { num: 404, params: ["<your_nick>", "<channel name>", "<Server reply message>"] }
+            Reply { num: 401, params }
+            | Reply { num: 403, params }
+            | Reply { num: 404, params }
+                if params.len() > 1 =>
+            {
+                self.fail_pending_echo(&params[1], snd_ev);
+            }
+
             // Reply 477 when user needs to be identified with NickServ to join a channel
             // ex. Reply { num: 477, params: ["<your_nick>", "<channel name>", "<Server reply message>"] }
             Reply { num: 477, params } => {
@@ -397,15 +1478,19 @@ impl StateInner {
                 if let (Some(channel), Some(msg_477)) = (params.get(1), params.get(2)) {
                     let channel = ChanNameRef::new(channel);
                     snd_ev
-                        .try_send(Event::Msg(wire::Msg {
-                            pfx: pfx.clone(),
-                            cmd: wire::Cmd::PRIVMSG {
-                                ctcp: None,
-                                is_notice: true,
-                                msg: msg_477.clone(),
-                                target: wire::MsgTarget::Chan(channel.to_owned()),
+                        .try_send(Event::Msg {
+                            msg: wire::Msg {
+                                tags: Default::default(),
+                                pfx: pfx.clone(),
+                                cmd: wire::Cmd::PRIVMSG {
+                                    ctcp: None,
+                                    is_notice: true,
+                                    msg: msg_477.clone(),
+                                    targets: vec![wire::MsgTarget::Chan(channel.to_owned())],
+                                },
                             },
-                        }))
+                            timestamp: time::now(),
+                        })
                         .unwrap();
                     // Get channel name from params
                     if self.nickserv_ident.is_some() {
@@ -415,7 +1500,7 @@ impl StateInner {
                             msg,
                         };
                         // Find channel in self.chans
-                        if let Some(idx) = utils::find_idx(&self.chans, |c| c.name == *channel) {
+                        if let Some(idx) = self.find_chan_idx(channel) {
                             let chan = &mut self.chans[idx];
                             // Retry joining channel if retries are available
                             if let Some(retries) = chan.retry_join() {
@@ -439,6 +1524,7 @@ impl StateInner {
                                 }
                                 tokio::task::spawn_local(retry_channel_join(
                                     channel.to_owned(),
+                                    chan.key.clone(),
                                     snd_irc_msg,
                                     rcv_abort,
                                 ));
@@ -491,10 +1577,11 @@ impl StateInner {
                 snd_ev.try_send(Event::Connected).unwrap();
                 snd_ev
                     .try_send(Event::NickChange {
-                        new_nick: self.current_nick.clone(),
+                        new_nick: self.current_nick.display().to_owned(),
                     })
                     .unwrap();
                 self.nick_accepted = true;
+                self.caps.registered();
                 if let Some(ref pwd) = self.nickserv_ident {
                     snd_irc_msg
                         .try_send(wire::privmsg("NickServ", &format!("identify {}", pwd)))
@@ -519,6 +1606,23 @@ impl StateInner {
                 }
             }
 
+            // RPL_ISUPPORT: Update server capabilities
+            Reply { num: 005, params } => {
+                let old_mapping = self.case_mapping();
+                self.isupport.update(params);
+                let new_mapping = self.case_mapping();
+                if old_mapping != new_mapping {
+                    for chan in &mut self.chans {
+                        chan.set_case_mapping(new_mapping);
+                    }
+                    self.users = self
+                        .users
+                        .drain()
+                        .map(|(_, user)| (user.nick.normalized_with(new_mapping), user))
+                        .collect();
+                }
+            }
+
             // ERR_NICKNAMEINUSE: Try another nick if we don't have a nick yet.
             Reply { num: 433, .. } => {
                 if !self.nick_accepted {
@@ -540,14 +1644,17 @@ impl StateInner {
             } => {
                 match pfx {
                     Some(Pfx::User { nick: old_nick, .. }) | Some(Pfx::Ambiguous(old_nick)) => {
-                        if old_nick == &self.current_nick {
+                        if self.is_current_nick(old_nick) {
                             snd_ev
                                 .try_send(Event::NickChange {
                                     new_nick: new_nick.to_owned(),
                                 })
                                 .unwrap();
 
-                            match utils::find_idx(&self.nicks, |nick| nick == new_nick) {
+                            let mapping = self.case_mapping();
+                            match utils::find_idx(&self.nicks, |nick| {
+                                mapping.equals(nick, new_nick)
+                            }) {
                                 None => {
                                     self.nicks.push(new_nick.to_owned());
                                     self.current_nick_idx = self.nicks.len() - 1;
@@ -557,7 +1664,7 @@ impl StateInner {
                                 }
                             }
 
-                            self.current_nick = new_nick.to_owned();
+                            self.current_nick = Nick::new(new_nick.to_owned());
 
                             if let Some(ref pwd) = self.nickserv_ident {
                                 snd_irc_msg
@@ -570,95 +1677,157 @@ impl StateInner {
                         }
 
                         // Rename the nick in channel states, also populate the chan list
+                        let mapping = self.case_mapping();
                         for chan in &mut self.chans {
-                            if chan.nicks.remove(old_nick) {
-                                chan.nicks.insert(new_nick.to_owned());
+                            if let Some(member) = chan.remove_nick(old_nick, mapping) {
+                                chan.add_nick(new_nick, member.modes, mapping);
                                 chans.push(chan.name.to_owned());
                             }
                         }
+
+                        // Rename the user's record
+                        if let Some(mut user) = self.users.remove(&mapping.normalize(old_nick)) {
+                            user.nick = Nick::new(new_nick.to_owned());
+                            self.users.insert(mapping.normalize(new_nick), user);
+                        }
                     }
                     Some(Pfx::Server(_)) | None => {}
                 }
             }
 
-            // RPL_ENDOFMOTD: Join channels, set away status
+            // RPL_ENDOFMOTD: Join channels, set away status, fetch the messages that we missed in
+            // private conversations
             Reply { num: 376, .. } => {
                 if !self.chans.is_empty() {
-                    let chans = self.chans.iter().map(|c| c.name.as_ref());
-                    snd_irc_msg.try_send(wire::join(chans)).unwrap();
+                    let chans = self
+                        .chans
+                        .iter()
+                        .map(|c| (c.name.as_ref(), c.key.as_deref()));
+                    snd_irc_msg.try_send(wire::join_with_keys(chans)).unwrap();
                 }
                 if self.away_status.is_some() {
                     snd_irc_msg
                         .try_send(wire::away(self.away_status.as_deref()))
                         .unwrap();
                 }
+                let mapping = self.case_mapping();
+                self.history
+                    .fetch_private(&self.caps, &self.isupport, mapping, snd_irc_msg);
             }
 
-            // RPL_NAMREPLY: Set users in a channel
+            // RPL_NAMREPLY: Set users in a channel. With `multi-prefix` nicks can have more than
+            // one prefix, with `userhost-in-names` nicks are `nick!user@host`.
             Reply { num: 353, params } => {
                 let chan = ChanNameRef::new(&params[2]);
-                match utils::find_idx(&self.chans, |c| &c.name == chan) {
-                    None => self.chans.push(Chan::with_nicks(
-                        chan.to_owned(),
-                        params[3]
-                            .split_whitespace()
-                            .map(|s| wire::drop_nick_prefix(s).to_owned())
-                            .collect(),
-                    )),
-                    Some(idx) => {
-                        let nick_set = &mut self.chans[idx].nicks;
-                        for nick in params[3].split_whitespace() {
-                            nick_set.insert(wire::drop_nick_prefix(nick).to_owned());
-                        }
+                let chan_idx = match self.find_chan_idx(chan) {
+                    None => {
+                        self.chans.push(Chan::new(chan.to_owned()));
+                        self.chans.len() - 1
+                    }
+                    Some(idx) => idx,
+                };
+                let mapping = self.case_mapping();
+                for name in params[3].split_whitespace() {
+                    let (prefixes, name) = self.isupport.split_nick_prefix(name);
+                    let modes = self
+                        .isupport
+                        .prefix
+                        .iter()
+                        .filter(|(_, prefix)| prefixes.contains(*prefix))
+                        .map(|(mode, _)| *mode)
+                        .collect();
+                    let hostmask = wire::Hostmask::parse(name);
+                    self.chans[chan_idx].add_nick(&hostmask.nick, modes, mapping);
+                    let user = self.user_mut(&hostmask.nick);
+                    if hostmask.user.is_some() {
+                        user.user = hostmask.user;
+                    }
+                    if hostmask.host.is_some() {
+                        user.host = hostmask.host;
                     }
                 }
             }
 
-            // SASL authentication
             CAP {
                 client: _,
                 subcommand,
                 params,
+                more,
             } => {
-                match subcommand.as_ref() {
-                    "ACK" => {
-                        if params.iter().any(|cap| cap.as_str() == "sasl") {
-                            snd_irc_msg.try_send(wire::authenticate("PLAIN")).unwrap();
-                        }
-                    }
-                    "NAK" => {
-                        snd_irc_msg.try_send(wire::cap_end()).unwrap();
+                let new_caps = self.caps.handle_cap(subcommand, params, *more, snd_irc_msg);
+                if new_caps.iter().any(|cap| cap == "sasl") {
+                    if let Some(ref auth) = self.server_info.sasl_auth {
+                        // Will end the registration after authentication
+                        self.caps.start_sasl();
+                        let sasl = Sasl::new(auth, self.caps.value("sasl"));
+                        snd_irc_msg.try_send(sasl.start_msg()).unwrap();
+                        self.sasl = Some(sasl);
                     }
-                    "LS" => {
-                        self.introduce(snd_irc_msg);
-                        if params.iter().any(|cap| cap == "sasl") {
-                            snd_irc_msg.try_send(wire::cap_req(&["sasl"])).unwrap();
-                            // Will wait for CAP ... ACK from server before authentication.
+                }
+                self.caps.maybe_end(snd_irc_msg);
+            }
+
+            AUTHENTICATE { ref param } => {
+                if let Some(ref mut sasl) = self.sasl {
+                    match sasl.handle_authenticate(param) {
+                        SaslResponse::Msgs(msgs) => {
+                            for msg in msgs {
+                                snd_irc_msg.try_send(msg).unwrap();
+                            }
+                        }
+                        SaslResponse::Blocking(f) => {
+                            let snd_irc_msg = snd_irc_msg.clone();
+                            tokio::task::spawn_blocking(move || {
+                                for msg in f() {
+                                    // Connection may be closed in the meantime
+                                    let _ = snd_irc_msg.try_send(msg);
+                                }
+                            });
                         }
                     }
-                    _ => {}
                 }
             }
 
-            AUTHENTICATE { ref param } => {
-                if param.as_str() == "+" {
-                    // Empty AUTHENTICATE response; server accepted the specified SASL mechanism
-                    // (PLAIN)
-                    if let Some(ref auth) = self.server_info.sasl_auth {
-                        let msg = format!(
-                            "{}\x00{}\x00{}",
-                            auth.username, auth.username, auth.password
-                        );
-                        snd_irc_msg
-                            .try_send(wire::authenticate(&base64::encode(&msg)))
-                            .unwrap();
+            Reply {
+                num: numeric::RPL_SASLMECHS,
+                params,
+            } => {
+                // <nick> <mechanisms> :are available SASL mechanisms
+                self.sasl_mechs = params.get(1).cloned();
+            }
+
+            Reply {
+                num: numeric::ERR_SASLFAIL,
+                ..
+            } => {
+                // Try another mechanism if the server doesn't support ours
+                let fallback = match (&self.sasl, &self.sasl_mechs) {
+                    (Some(sasl), Some(mechs)) => sasl.fallback(mechs),
+                    _ => None,
+                };
+                self.sasl_mechs = None;
+                match fallback {
+                    Some(sasl) => {
+                        snd_irc_msg.try_send(sasl.start_msg()).unwrap();
+                        self.sasl = Some(sasl);
+                    }
+                    None => {
+                        self.sasl = None;
+                        self.caps.sasl_done(snd_irc_msg);
                     }
                 }
             }
 
-            Reply { num: 903, .. } | Reply { num: 904, .. } => {
-                // 903: RPL_SASLSUCCESS, 904: ERR_SASLFAIL
-                snd_irc_msg.try_send(wire::cap_end()).unwrap();
+            Reply {
+                num:
+                    numeric::RPL_SASLSUCCESS
+                    | numeric::ERR_SASLTOOLONG
+                    | numeric::ERR_SASLABORTED
+                    | numeric::ERR_SASLALREADY,
+                ..
+            } => {
+                self.sasl = None;
+                self.caps.sasl_done(snd_irc_msg);
             }
 
             // Ignore the rest
@@ -667,7 +1836,7 @@ impl StateInner {
     }
 
     fn get_chan_nicks(&self, chan: &ChanNameRef) -> Vec<String> {
-        match utils::find_idx(&self.chans, |c| c.name == *chan) {
+        match self.find_chan_idx(chan) {
             None => {
                 error!("Could not find channel index in get_chan_nicks.");
                 vec![]
@@ -676,19 +1845,74 @@ impl StateInner {
                 let mut nicks = self.chans[chan_idx]
                     .nicks
                     .iter()
-                    .cloned()
-                    .collect::<Vec<String>>();
-                nicks.sort_unstable_by(|a, b| {
-                    a.to_lowercase().partial_cmp(&b.to_lowercase()).unwrap()
-                });
+                    .collect::<Vec<(&String, &Member)>>();
+                nicks.sort_unstable_by_key(|(key, _)| *key);
                 nicks
+                    .into_iter()
+                    .map(|(_, member)| member.nick.display().to_owned())
+                    .collect()
             }
         }
     }
 
+    fn get_channel(&self, chan: &ChanNameRef) -> Option<ChannelInfo> {
+        let chan = &self.chans[self.find_chan_idx(chan)?];
+        let mut members = chan
+            .nicks
+            .iter()
+            .map(|(key, member)| {
+                let prefixes = self
+                    .isupport
+                    .prefix
+                    .iter()
+                    .filter(|(mode, _)| member.modes.contains(*mode))
+                    .map(|(_, prefix)| *prefix)
+                    .collect();
+                (
+                    key,
+                    ChannelMember {
+                        nick: member.nick.display().to_owned(),
+                        modes: member.modes.clone(),
+                        prefixes,
+                    },
+                )
+            })
+            .collect::<Vec<(&String, ChannelMember)>>();
+        members.sort_unstable_by_key(|(key, _)| *key);
+        let mode_list = |list| {
+            chan.mode_lists
+                .get(&self.get_list_mode(list))
+                .map(|list| list.entries.clone())
+        };
+        Some(ChannelInfo {
+            name: chan.name.clone(),
+            modes: chan.modes.clone(),
+            topic: chan.topic.clone(),
+            created: chan.created,
+            members: members.into_iter().map(|(_, member)| member).collect(),
+            bans: mode_list(ModeList::Bans),
+            excepts: mode_list(ModeList::Excepts),
+            invites: mode_list(ModeList::Invites),
+        })
+    }
+
+    /// Mode of a mode list. Exception modes can be changed with `EXCEPTS` and `INVEX` in
+    /// RPL_ISUPPORT.
+    fn get_list_mode(&self, list: ModeList) -> char {
+        let (token, default) = match list {
+            ModeList::Bans => return 'b',
+            ModeList::Excepts => ("EXCEPTS", 'e'),
+            ModeList::Invites => ("INVEX", 'I'),
+        };
+        match self.isupport.other.get(token) {
+            Some(Some(mode)) => mode.chars().next().unwrap_or(default),
+            _ => default,
+        }
+    }
+
     /// If channel is in Joining state cancel Joining task, otherwise sent part message
     fn leave_channel(&mut self, msg_chan: &mut Sender<Cmd>, chan: &ChanNameRef) {
-        if let Some(idx) = utils::find_idx(&self.chans, |c| c.name == *chan) {
+        if let Some(idx) = self.find_chan_idx(chan) {
             match &mut self.chans[idx].join_state {
                 JoinState::NotJoined => {}
                 JoinState::Joining { stop_task, .. } => {
@@ -712,6 +1936,7 @@ impl StateInner {
 
 async fn retry_channel_join(
     channel: ChanName,
+    key: Option<String>,
     snd_irc_msg: Sender<String>,
     rcv_abort: Receiver<()>,
 ) {
@@ -723,7 +1948,10 @@ async fn retry_channel_join(
         Err(_) => {
             // Send join message
             snd_irc_msg
-                .try_send(wire::join(std::iter::once(channel.as_ref())))
+                .try_send(wire::join_with_keys(std::iter::once((
+                    channel.as_ref(),
+                    key.as_deref(),
+                ))))
                 .unwrap();
         }
         Ok(_) => {
@@ -769,6 +1997,514 @@ fn parse_server_pfx(pfx: Option<&Pfx>) -> Option<String> {
 mod tests {
     use super::*;
 
+    fn test_server_info() -> ServerInfo {
+        ServerInfo {
+            addr: "x.y.z".to_owned(),
+            port: 6667,
+            tls: false,
+            pass: None,
+            realname: "tiny".to_owned(),
+            nicks: vec!["tiny".to_owned()],
+            auto_join: vec![],
+            nickserv_ident: None,
+            tls_client_cert: None,
+            sasl_auth: None,
+            encoding: None,
+            send_encoding: None,
+            caps: vec![],
+            send_burst: crate::DEFAULT_SEND_BURST,
+            send_interval_ms: crate::DEFAULT_SEND_INTERVAL_MS,
+            proxy: None,
+        }
+    }
+
+    /// Messages sent and events generated by the state in `feed`.
+    #[derive(Debug, Default)]
+    struct Fed {
+        sent: Vec<String>,
+        events: Vec<Event>,
+    }
+
+    /// Parse the given lines and update the state with them.
+    fn feed(state: &mut StateInner, lines: &[&str]) -> Fed {
+        let (mut snd_ev, mut rcv_ev) = tokio::sync::mpsc::channel(100);
+        let (mut snd_irc_msg, mut rcv_irc_msg) = tokio::sync::mpsc::channel(100);
+        for line in lines {
+            let mut buf = format!("{}\r\n", line).into_bytes();
+            let mut msg = wire::parse_irc_msg(&mut buf).unwrap().unwrap();
+            state.update(&mut msg, &mut snd_ev, &mut snd_irc_msg);
+        }
+        Fed {
+            sent: drain(&mut rcv_irc_msg),
+            events: drain(&mut rcv_ev),
+        }
+    }
+
+    /// Receive the values that are ready in a channel.
+    fn drain<T>(rcv: &mut tokio::sync::mpsc::Receiver<T>) -> Vec<T> {
+        let mut values = vec![];
+        while let Some(Some(value)) = futures_util::FutureExt::now_or_never(rcv.recv()) {
+            values.push(value);
+        }
+        values
+    }
+
+    #[test]
+    fn case_mapping_nick_tracking() {
+        let mut state = StateInner::new(test_server_info());
+        feed(
+            &mut state,
+            &[
+                ":x.y.z 005 tiny CASEMAPPING=rfc1459 :are supported by this server",
+                ":TINY!u@h JOIN #Chan",
+                ":x.y.z 353 tiny = #chan :@Nick[a] +other",
+                ":nick{a}!u@h NICK new",
+                ":OTHER!u@h PART #CHAN",
+            ],
+        );
+        assert_eq!(state.get_chan_nicks(ChanNameRef::new("#chan")), vec!["new"]);
+
+        // With ascii mapping brackets are different characters
+        let mut state = StateInner::new(test_server_info());
+        feed(
+            &mut state,
+            &[
+                ":x.y.z 005 tiny CASEMAPPING=ascii :are supported by this server",
+                ":tiny!u@h JOIN #chan",
+                ":x.y.z 353 tiny = #chan :Nick[a] nick{a}",
+                ":NICK[A]!u@h QUIT :bye",
+            ],
+        );
+        assert_eq!(
+            state.get_chan_nicks(ChanNameRef::new("#chan")),
+            vec!["nick{a}"]
+        );
+    }
+
+    #[test]
+    fn user_tracking() {
+        let mut state = StateInner::new(test_server_info());
+        feed(
+            &mut state,
+            &[
+                ":x.y.z 005 tiny PREFIX=(qov)~@+ :are supported by this server",
+                ":tiny!u@h JOIN #chan * :tiny",
+                ":x.y.z 353 tiny = #chan :tiny!u@h @+op!o@op.host other!x@y",
+                ":joiner!j@j.host JOIN #chan acc :Real Name",
+                ":op!o@op.host MODE #chan +q-o+v op op other",
+                ":other!x@y AWAY :gone",
+                ":op!o@op.host ACCOUNT op_acc",
+                ":joiner!j@j.host ACCOUNT *",
+                ":joiner!j@j.host CHGHOST new new.host",
+                ":joiner!new@new.host SETNAME :New Name",
+            ],
+        );
+
+        let op = state.get_user("OP").unwrap();
+        assert_eq!(op.nick, "op");
+        assert_eq!(op.user.as_deref(), Some("o"));
+        assert_eq!(op.host.as_deref(), Some("op.host"));
+        assert_eq!(op.account.as_deref(), Some("op_acc"));
+        assert_eq!(op.away, None);
+        assert_eq!(
+            op.chans,
+            vec![(ChanName::new("#chan".to_owned()), "qv".to_owned())]
+        );
+
+        let other = state.get_user("other").unwrap();
+        assert_eq!(other.away.as_deref(), Some("gone"));
+        assert_eq!(other.chans[0].1, "v");
+
+        let joiner = state.get_user("joiner").unwrap();
+        assert_eq!(joiner.user.as_deref(), Some("new"));
+        assert_eq!(joiner.host.as_deref(), Some("new.host"));
+        assert_eq!(joiner.realname.as_deref(), Some("New Name"));
+        assert_eq!(joiner.account, None);
+
+        feed(
+            &mut state,
+            &[
+                ":other!x@y AWAY",
+                ":op!o@op.host NICK op2",
+                ":joiner!new@new.host PART #chan",
+                ":tiny!u@h JOIN #other",
+                ":x.y.z 353 tiny = #other :tiny op2",
+            ],
+        );
+        assert_eq!(state.get_user("other").unwrap().away, None);
+        assert_eq!(state.get_user("op"), None);
+        assert_eq!(state.get_user("joiner"), None);
+        let op = state.get_user("op2").unwrap();
+        assert_eq!(op.account.as_deref(), Some("op_acc"));
+        assert_eq!(op.chans.len(), 2);
+
+        // Users are forgotten when we leave their channels
+        feed(&mut state, &[":tiny!u@h PART #chan"]);
+        assert_eq!(state.get_user("other"), None);
+        assert!(state.get_user("op2").is_some());
+        feed(&mut state, &[":op2!o@op.host QUIT :bye"]);
+        assert_eq!(state.get_user("op2"), None);
+        assert_eq!(state.get_user("tiny").unwrap().chans.len(), 1);
+    }
+
+    #[test]
+    fn mode_query_replies() {
+        let mut state = StateInner::new(test_server_info());
+        let fed = feed(&mut state, &[":tiny!u@h JOIN #a,#b"]);
+        assert!(fed.sent.contains(&"MODE #a\r\n".to_owned()));
+
+        let mut handle = |line: &str| {
+            let msg = wire::parse_irc_line(line.as_bytes(), None).unwrap();
+            state.handle_mode_query_reply(&msg)
+        };
+
+        // Replies to the query sent on join are not shown, replies to the user's queries are
+        assert!(handle(":x.y.z 324 tiny #a +nt"));
+        assert!(handle(":x.y.z 329 tiny #A 1500000000"));
+        assert!(!handle(":x.y.z 324 tiny #a +nt"));
+        assert!(!handle(":x.y.z 329 tiny #a 1500000000"));
+
+        // Without RPL_CREATIONTIME
+        assert!(handle(":x.y.z 324 tiny #b +nt"));
+        assert!(!handle(":x.y.z 324 tiny #b +nt"));
+        assert!(!handle(":x.y.z 324 tiny #other +nt"));
+    }
+
+    #[test]
+    fn channel_tracking() {
+        let mut state = StateInner::new(test_server_info());
+        feed(
+            &mut state,
+            &[
+                ":x.y.z 005 tiny PREFIX=(ov)@+ CHANMODES=beI,k,jl,imnpst EXCEPTS :are supported",
+                ":tiny!u@h JOIN #chan",
+                ":x.y.z 332 tiny #chan :old topic",
+                ":x.y.z 333 tiny #chan op!o@h 1600000000",
+                ":x.y.z 353 tiny = #chan :@op +voiced tiny",
+                ":x.y.z 324 tiny #chan +ntl 50",
+                ":x.y.z 329 tiny #chan 1500000000",
+                ":x.y.z 367 tiny #chan *!*@spam op 1600000000",
+                ":x.y.z 368 tiny #chan :End of channel ban list",
+                ":x.y.z 349 tiny #chan :End of channel exception list",
+                ":op!o@h MODE #chan -l+kb-b+v key *!*@new *!*@spam tiny",
+                ":op!o@h MODE #chan +e *!*@friend",
+                ":op!o@h TOPIC #chan :new topic",
+            ],
+        );
+        let info = state.get_channel(ChanNameRef::new("#CHAN")).unwrap();
+        assert_eq!(
+            info.modes,
+            vec![('n', None), ('t', None), ('k', Some("key".to_owned()))]
+        );
+        let topic = info.topic.unwrap();
+        assert_eq!(topic.text, "new topic");
+        assert_eq!(topic.setter.as_deref(), Some("op"));
+        assert_eq!(info.created, Some(1500000000));
+        assert_eq!(
+            info.members
+                .iter()
+                .map(|member| (member.nick.as_str(), member.prefixes.as_str()))
+                .collect::<Vec<_>>(),
+            vec![("op", "@"), ("tiny", "+"), ("voiced", "+")]
+        );
+        let bans = info.bans.unwrap();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].mask, "*!*@new");
+        assert_eq!(bans[0].setter.as_deref(), Some("op"));
+        assert_eq!(info.excepts.unwrap()[0].mask, "*!*@friend");
+        // Not requested
+        assert_eq!(info.invites, None);
+
+        // A new list reply replaces the list, topic is updated with RPL_TOPICWHOTIME
+        feed(
+            &mut state,
+            &[
+                ":x.y.z 367 tiny #chan *!*@other",
+                ":x.y.z 368 tiny #chan :End of channel ban list",
+                ":x.y.z 332 tiny #chan :newer topic",
+                ":x.y.z 333 tiny #chan op2 1600000001",
+                // `j` is not a default mode with an argument
+                ":op!o@h MODE #chan -t+jo 3:5 voiced",
+            ],
+        );
+        let info = state.get_channel(ChanNameRef::new("#chan")).unwrap();
+        assert_eq!(
+            info.bans,
+            Some(vec![ModeListEntry {
+                mask: "*!*@other".to_owned(),
+                setter: None,
+                time: None,
+            }])
+        );
+        assert_eq!(
+            info.topic,
+            Some(Topic {
+                text: "newer topic".to_owned(),
+                setter: Some("op2".to_owned()),
+                time: Some(1600000001),
+            })
+        );
+
+        // RPL_TOPIC without our nick, as in RFC 2812
+        feed(&mut state, &[":x.y.z 332 #chan :rfc topic"]);
+        let info = state.get_channel(ChanNameRef::new("#chan")).unwrap();
+        assert_eq!(info.topic.unwrap().text, "rfc topic");
+        assert_eq!(
+            info.modes,
+            vec![
+                ('n', None),
+                ('k', Some("key".to_owned())),
+                ('j', Some("3:5".to_owned()))
+            ]
+        );
+        let voiced = info
+            .members
+            .iter()
+            .find(|member| member.nick == "voiced")
+            .unwrap();
+        assert_eq!(voiced.prefixes, "@+");
+
+        assert_eq!(state.get_channel(ChanNameRef::new("#other")), None);
+    }
+
+    #[test]
+    fn multi_chan_join_part() {
+        let mut server_info = test_server_info();
+        server_info.auto_join = vec![
+            crate::AutoJoin::parse("#a").unwrap(),
+            crate::AutoJoin::parse("#b key").unwrap(),
+        ];
+        let mut state = StateInner::new(server_info);
+
+        // Channels with keys are joined first
+        let (mut snd_ev, _rcv_ev) = tokio::sync::mpsc::channel(100);
+        let (mut snd_irc_msg, mut rcv_irc_msg) = tokio::sync::mpsc::channel(100);
+        let mut buf = b":x.y.z 376 tiny :End of /MOTD command.\r\n".to_vec();
+        let mut msg = wire::parse_irc_msg(&mut buf).unwrap().unwrap();
+        state.update(&mut msg, &mut snd_ev, &mut snd_irc_msg);
+        let join = futures_util::FutureExt::now_or_never(rcv_irc_msg.recv());
+        assert_eq!(join, Some(Some("JOIN #b,#a key\r\n".to_owned())));
+
+        feed(
+            &mut state,
+            &[
+                ":tiny!u@h JOIN #a,#b,#c",
+                ":other!u@h JOIN #a,#c",
+                ":other!u@h PART #a,#b,#c",
+                ":tiny!u@h PART #b,#c :bye",
+            ],
+        );
+        let chans: Vec<&str> = state.chans.iter().map(|c| c.name.display()).collect();
+        assert_eq!(chans, vec!["#a"]);
+        assert!(state.get_chan_nicks(ChanNameRef::new("#a")).is_empty());
+    }
+
+    #[test]
+    fn sasl_auth() {
+        for (auth, mechanism, response) in &[
+            (
+                crate::SASLAuth::Plain {
+                    username: "user".to_owned(),
+                    password: "pass".to_owned(),
+                },
+                "PLAIN",
+                "dXNlcgB1c2VyAHBhc3M=",
+            ),
+            (crate::SASLAuth::External, "EXTERNAL", "+"),
+        ] {
+            let mut server_info = test_server_info();
+            server_info.sasl_auth = Some(auth.clone());
+            let mut state = StateInner::new(server_info);
+            let (mut snd_irc_msg, mut rcv_irc_msg) = tokio::sync::mpsc::channel(100);
+            state.introduce(&mut snd_irc_msg);
+            assert_eq!(
+                drain(&mut rcv_irc_msg),
+                vec!["CAP LS 302\r\n", "NICK tiny\r\n", "USER tiny 8 * :tiny\r\n"]
+            );
+
+            let fed = feed(&mut state, &[":x.y.z CAP * LS :sasl=PLAIN,EXTERNAL"]);
+            assert_eq!(fed.sent, vec!["CAP REQ :sasl\r\n"]);
+            let fed = feed(&mut state, &[":x.y.z CAP * ACK :sasl"]);
+            assert_eq!(fed.sent, vec![format!("AUTHENTICATE {}\r\n", mechanism)]);
+            let fed = feed(&mut state, &["AUTHENTICATE +"]);
+            assert_eq!(fed.sent, vec![format!("AUTHENTICATE {}\r\n", response)]);
+            let fed = feed(
+                &mut state,
+                &[":x.y.z 903 * :SASL authentication successful"],
+            );
+            assert_eq!(fed.sent, vec!["CAP END\r\n"]);
+        }
+    }
+
+    #[test]
+    fn sasl_mechanism_fallback() {
+        let mut server_info = test_server_info();
+        server_info.sasl_auth = Some(crate::SASLAuth::ScramSha256 {
+            username: "user".to_owned(),
+            password: "pass".to_owned(),
+        });
+        let mut state = StateInner::new(server_info);
+        let (mut snd_irc_msg, _rcv_irc_msg) = tokio::sync::mpsc::channel(100);
+        state.introduce(&mut snd_irc_msg);
+
+        // Server doesn't advertise the mechanisms in the cap value
+        let fed = feed(
+            &mut state,
+            &[":x.y.z CAP * LS :sasl", ":x.y.z CAP * ACK :sasl"],
+        );
+        assert_eq!(
+            fed.sent,
+            vec!["CAP REQ :sasl\r\n", "AUTHENTICATE SCRAM-SHA-256\r\n"]
+        );
+        let fed = feed(
+            &mut state,
+            &[
+                ":x.y.z 908 tiny PLAIN,SCRAM-SHA-1 :are available SASL mechanisms",
+                ":x.y.z 904 tiny :SASL authentication failed",
+            ],
+        );
+        assert_eq!(fed.sent, vec!["AUTHENTICATE SCRAM-SHA-1\r\n"]);
+
+        // Client-first-message
+        let fed = feed(&mut state, &["AUTHENTICATE +"]);
+        let client_first = fed.sent[0]
+            .trim_end()
+            .strip_prefix("AUTHENTICATE ")
+            .unwrap();
+        assert!(base64::decode(client_first)
+            .unwrap()
+            .starts_with(b"n,,n=user,r="));
+
+        // Second failure ends the registration
+        let fed = feed(&mut state, &[":x.y.z 904 tiny :SASL authentication failed"]);
+        assert_eq!(fed.sent, vec!["CAP END\r\n"]);
+    }
+
+    /// `MsgNotEchoed` events as (target, msg) pairs.
+    fn not_echoed(events: Vec<Event>) -> Vec<(String, String)> {
+        events
+            .into_iter()
+            .filter_map(|ev| match ev {
+                Event::MsgNotEchoed { target, msg } => Some((target, msg)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn echo_message() {
+        let mut server_info = test_server_info();
+        server_info.caps = vec!["echo-message".to_owned()];
+        let mut state = StateInner::new(server_info);
+
+        // Not tracked when the cap is not enabled
+        state.add_pending_echo("#chan", "a", false);
+        assert!(state.pending_echoes.is_empty());
+
+        feed(
+            &mut state,
+            &[
+                ":x.y.z CAP * LS :echo-message",
+                ":x.y.z CAP * ACK :echo-message",
+                ":x.y.z 005 tiny STATUSMSG=@+ :are supported",
+            ],
+        );
+        state.add_pending_echo("#chan", "a", false);
+        state.add_pending_echo("@#chan", "b", true);
+        state.add_pending_echo("#chan", "c", false);
+        state.add_pending_echo("nick", "d", false);
+        state.add_pending_echo("#other", "e", false);
+
+        // Targets are compared with the case mapping
+        let fed = feed(&mut state, &[":TINY!u@h PRIVMSG @#CHAN :\x01ACTION b\x01"]);
+        assert_eq!(not_echoed(fed.events), vec![]);
+        assert_eq!(state.pending_echoes.len(), 4);
+
+        // Messages from others, NOTICEs, and our messages sent by another client attached to the
+        // same bouncer don't match
+        let fed = feed(
+            &mut state,
+            &[
+                ":a!u@h PRIVMSG #chan :a",
+                ":tiny!u@h NOTICE #chan :a",
+                ":tiny!u@h PRIVMSG #chan :from another client",
+            ],
+        );
+        assert_eq!(not_echoed(fed.events), vec![]);
+        assert_eq!(state.pending_echoes.len(), 4);
+
+        // Echoes are matched by target and text, in any order
+        let fed = feed(
+            &mut state,
+            &[":tiny!u@h PRIVMSG #chan :c", ":tiny!u@h PRIVMSG #chan :a"],
+        );
+        assert_eq!(not_echoed(fed.events), vec![]);
+        assert_eq!(state.pending_echoes.len(), 2);
+
+        // Rejected messages
+        let fed = feed(
+            &mut state,
+            &[
+                ":x.y.z 404 tiny #other :Cannot send to channel",
+                ":x.y.z 401 tiny nick :No such nick/channel",
+            ],
+        );
+        assert_eq!(
+            not_echoed(fed.events),
+            vec![
+                ("#other".to_owned(), "e".to_owned()),
+                ("nick".to_owned(), "d".to_owned())
+            ]
+        );
+
+        assert!(state.pending_echoes.is_empty());
+
+        // Timeout starts when the message is sent
+        state.add_pending_echo("#chan", "f", false);
+        state.add_pending_echo("#chan", "g", true);
+        let now = Instant::now();
+        assert_eq!(state.next_echo_timeout(now), None);
+        let (mut snd_ev, mut rcv_ev) = tokio::sync::mpsc::channel(100);
+        state.expire_pending_echoes(now + ECHO_TIMEOUT, &mut snd_ev);
+        assert_eq!(not_echoed(drain(&mut rcv_ev)), vec![]);
+
+        state.msg_sent("PRIVMSG #chan :f\r\n", now);
+        assert_eq!(state.next_echo_timeout(now), Some(ECHO_TIMEOUT));
+        state.expire_pending_echoes(now + ECHO_TIMEOUT, &mut snd_ev);
+        assert_eq!(
+            not_echoed(drain(&mut rcv_ev)),
+            vec![("#chan".to_owned(), "f".to_owned())]
+        );
+        assert_eq!(state.next_echo_timeout(now), None);
+
+        // Messages dropped from the send queue are not reported
+        state.cancel_pending_echoes(&["PRIVMSG #chan :\x01ACTION g\x01\r\n".to_owned()]);
+        assert!(state.pending_echoes.is_empty());
+    }
+
+    #[test]
+    fn ctcp_replies() {
+        let mut state = StateInner::new(test_server_info());
+
+        let fed = feed(&mut state, &[":a!u@h PRIVMSG tiny :\x01PING 123\x01"]);
+        assert_eq!(fed.sent, vec!["NOTICE a :\x01PING 123\x01\r\n"]);
+        let fed = feed(&mut state, &[":a!u@h PRIVMSG #chan :\x01CLIENTINFO\x01"]);
+        assert_eq!(
+            fed.sent,
+            vec!["NOTICE a :\x01CLIENTINFO ACTION CLIENTINFO PING TIME\x01\r\n"]
+        );
+
+        // Replies are not answered
+        let fed = feed(&mut state, &[":a!u@h NOTICE tiny :\x01PING 123\x01"]);
+        assert!(fed.sent.is_empty());
+
+        // Rate limited
+        let fed = feed(&mut state, &[":a!u@h PRIVMSG tiny :\x01TIME\x01"]);
+        assert!(fed.sent[0].starts_with("NOTICE a :\x01TIME "));
+        let fed = feed(&mut state, &[":a!u@h PRIVMSG tiny :\x01PING 456\x01"]);
+        assert!(fed.sent.is_empty());
+    }
+
     #[test]
     fn test_parse_servername_1() {
         // IRC standard
@@ -815,4 +2551,46 @@ mod tests {
             Some("irc.gitter.im".to_owned())
         );
     }
+
+    #[test]
+    fn fetch_history_on_reconnect() {
+        let mut server_info = test_server_info();
+        server_info.caps = vec!["draft/chathistory".to_owned()];
+        let mut state = StateInner::new(server_info);
+        feed(
+            &mut state,
+            &[
+                ":x.y.z CAP * LS :draft/chathistory",
+                ":x.y.z CAP * ACK :draft/chathistory",
+                ":tiny!u@h JOIN #chan",
+                "@time=2011-10-19T16:40:51.620Z :a!u@h PRIVMSG #chan :hi",
+                "@time=2011-10-19T16:40:52.000Z :a!u@h PRIVMSG tiny :hi",
+                "@time=2011-10-19T16:40:53.000Z :tiny!u@h PRIVMSG A :hello",
+                // Messages without a server time are not recorded
+                ":b!u@h PRIVMSG tiny :hi",
+            ],
+        );
+
+        state.reset();
+        let fed = feed(
+            &mut state,
+            &[
+                ":x.y.z CAP * LS :draft/chathistory",
+                ":x.y.z CAP * ACK :draft/chathistory",
+                ":x.y.z 005 tiny CHATHISTORY=50 :are supported by this server",
+                ":x.y.z 376 tiny :End of /MOTD command.",
+                ":tiny!u@h JOIN #chan",
+            ],
+        );
+        assert_eq!(
+            fed.sent,
+            vec![
+                "CAP REQ :draft/chathistory\r\n",
+                "JOIN #chan\r\n",
+                "CHATHISTORY AFTER a timestamp=2011-10-19T16:40:53.000Z 50\r\n",
+                "MODE #chan\r\n",
+                "CHATHISTORY AFTER #chan timestamp=2011-10-19T16:40:51.620Z 50\r\n",
+            ]
+        );
+    }
 }
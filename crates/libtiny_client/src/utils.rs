@@ -1,12 +1,72 @@
+use crate::{codec, wire};
+
+use time::Tm;
+
 pub(crate) struct SplitIterator<'a> {
     s: Option<&'a str>,
     max: usize,
+    encoding: Option<&'static wire::Encoding>,
 }
 
-/// Iterate over subslices that are at most `max` long (in bytes). Splits are
-/// made on whitespace characters when possible.
-pub(crate) fn split_iterator(s: &str, max: usize) -> SplitIterator {
-    SplitIterator { s: Some(s), max }
+/// Min. length of the chunks of `Client::split_privmsg`, used when the message prefix and command
+/// leave less room than this, e.g. with a very long nick.
+const MIN_PRIVMSG_LEN: usize = 64;
+
+/// Max. length of the text of a PRIVMSG, so that the message fits in one line when the server
+/// adds our nick and user@host prefix. `usermask` is our `user@host` when known.
+///
+/// `extra_len`: Size (in bytes) for a prefix/suffix etc. that'll be added to each line.
+pub(crate) fn max_privmsg_len(
+    isupport: &wire::ISupport,
+    nick: &str,
+    usermask: Option<&str>,
+    extra_len: usize,
+) -> usize {
+    // Max msg len calculation adapted from hexchat
+    // (src/common/outbound.c:split_up_text)
+    let mut max: usize = isupport.linelen; // 512 unless the server advertises LINELEN
+    max = max.saturating_sub(3); // :, !, @
+    max = max.saturating_sub(13); // " PRIVMSG ", " ", :, \r, \n
+    max = max.saturating_sub(nick.len());
+    max = max.saturating_sub(extra_len);
+    match usermask {
+        None => {
+            // max username, USERLEN or 9
+            max = max.saturating_sub(isupport.userlen.unwrap_or(9));
+            // max possible hostname, HOSTLEN or 63, + '@'
+            // NOTE(osa): I think hexchat has an error here, it uses 65
+            max = max.saturating_sub(isupport.hostlen.unwrap_or(63) + 1);
+        }
+        Some(usermask) => {
+            max = max.saturating_sub(usermask.len());
+        }
+    }
+    max.max(MIN_PRIVMSG_LEN)
+}
+
+/// Iterate over subslices that are at most `max` long (in bytes, when encoded with `encoding`,
+/// UTF-8 when `None`). Splits are made on whitespace characters when possible.
+pub(crate) fn split_iterator<'a>(
+    s: &'a str,
+    max: usize,
+    encoding: Option<&'static wire::Encoding>,
+) -> SplitIterator<'a> {
+    SplitIterator {
+        s: Some(s),
+        max,
+        encoding,
+    }
+}
+
+impl<'a> SplitIterator<'a> {
+    /// Length of the character when sent
+    fn char_len(&self, c: char) -> usize {
+        match self.encoding {
+            None => c.len_utf8(),
+            Some(encoding) if c.is_ascii() && encoding.is_ascii_compatible() => 1,
+            Some(encoding) => codec::encode(encoding, c.encode_utf8(&mut [0; 4])).len(),
+        }
+    }
 }
 
 impl<'a> Iterator for SplitIterator<'a> {
@@ -17,49 +77,46 @@ impl<'a> Iterator for SplitIterator<'a> {
             return None;
         }
 
-        match self.s {
-            None => None,
-            Some(s) => {
-                if s.len() <= self.max {
-                    let ret = Some(s);
-                    self.s = None;
-                    ret
+        let s = self.s?;
+
+        // Length of `s[0..split]` when sent
+        let mut len = 0;
+        // End of the longest prefix that fits
+        let mut split = 0;
+        // Split at the last whitespace character that fits, including the whitespace if possible
+        let mut ws_split = 0;
+
+        for (idx, c) in s.char_indices() {
+            let c_len = self.char_len(c);
+            if c.is_whitespace() {
+                ws_split = if len + c_len <= self.max {
+                    idx + c.len_utf8()
                 } else {
-                    let mut split = 0;
-
-                    // try to split at a whitespace character
-                    for (ws_idx, ws_char) in s.rmatch_indices(char::is_whitespace) {
-                        if ws_idx <= self.max {
-                            // should we include ws char?
-                            if ws_idx + ws_char.len() <= self.max {
-                                split = ws_idx + ws_char.len();
-                            } else {
-                                split = ws_idx;
-                            }
-                            break;
-                        }
-                    }
-
-                    if split == 0 {
-                        // couldn't split at a whitespace, just split at any char
-                        for i in 0..4 {
-                            if s.is_char_boundary(self.max - i) {
-                                split = self.max - i;
-                                break;
-                            }
-                        }
-                    }
-
-                    if split == 0 {
-                        panic!("Can't split long msg: {:?}", s);
-                    } else {
-                        let ret = Some(&s[0..split]);
-                        self.s = Some(&s[split..]);
-                        ret
-                    }
-                }
+                    idx
+                };
+            }
+            if len + c_len > self.max {
+                break;
             }
+            len += c_len;
+            split = idx + c.len_utf8();
+        }
+
+        if split == s.len() {
+            self.s = None;
+            return Some(s);
+        }
+
+        if ws_split != 0 {
+            split = ws_split;
+        }
+
+        if split == 0 {
+            panic!("Can't split long msg: {:?}", s);
         }
+
+        self.s = Some(&s[split..]);
+        Some(&s[0..split])
     }
 }
 
@@ -72,19 +129,108 @@ pub(crate) fn find_idx<A, F: Fn(&A) -> bool>(slice: &[A], f: F) -> Option<usize>
     None
 }
 
+/// Parse a `time` tag value in the format "2011-10-19T16:40:51.620Z", as sent by servers with the
+/// `server-time` capability. Returns the time in the local time zone. Fractions of seconds are
+/// ignored.
+pub(crate) fn parse_server_time(s: &str) -> Option<Tm> {
+    let s = s.strip_suffix('Z')?;
+    let secs_end = s.find('.').unwrap_or(s.len());
+    let utc = time::strptime(&s[..secs_end], "%Y-%m-%dT%H:%M:%S").ok()?;
+    Some(time::at(utc.to_timespec()))
+}
+
+/// Parse a `time` tag value (see `parse_server_time`) to milliseconds since the epoch. Digits
+/// after milliseconds are ignored.
+pub(crate) fn parse_server_time_millis(s: &str) -> Option<i64> {
+    let secs = parse_server_time(s)?.to_timespec().sec;
+    let millis = match s.strip_suffix('Z')?.split_once('.') {
+        None => 0,
+        Some((_, frac)) => {
+            if frac.is_empty() || !frac.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            // Pad or truncate to 3 digits
+            format!("{:0<3.3}", frac).parse::<i64>().ok()?
+        }
+    };
+    Some(secs * 1000 + millis)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_server_time() {
+        let tm = parse_server_time("2011-10-19T16:40:51.620Z").unwrap();
+        assert_eq!(tm.to_timespec().sec, 1_319_042_451);
+        let tm = parse_server_time("2011-10-19T16:40:51Z").unwrap();
+        assert_eq!(tm.to_timespec().sec, 1_319_042_451);
+        assert!(parse_server_time("2011-10-19T16:40:51").is_none());
+        assert!(parse_server_time("1319042451").is_none());
+    }
+
+    #[test]
+    fn test_parse_server_time_millis() {
+        let secs = 1_319_042_451_000;
+        assert_eq!(
+            parse_server_time_millis("2011-10-19T16:40:51.620Z"),
+            Some(secs + 620)
+        );
+        assert_eq!(parse_server_time_millis("2011-10-19T16:40:51Z"), Some(secs));
+        assert_eq!(
+            parse_server_time_millis("2011-10-19T16:40:51.6Z"),
+            Some(secs + 600)
+        );
+        assert_eq!(
+            parse_server_time_millis("2011-10-19T16:40:51.123456Z"),
+            Some(secs + 123)
+        );
+        assert_eq!(parse_server_time_millis("2011-10-19T16:40:51.Z"), None);
+        assert_eq!(parse_server_time_millis("2011-10-19T16:40:51"), None);
+    }
+
+    #[test]
+    fn test_max_privmsg_len() {
+        let mut isupport = wire::ISupport::default();
+        assert_eq!(
+            max_privmsg_len(&isupport, "tiny", None, 0),
+            512 - 16 - 4 - 9 - 64
+        );
+        assert_eq!(
+            max_privmsg_len(&isupport, "tiny", Some("u@host"), 2),
+            512 - 16 - 4 - 2 - 6
+        );
+
+        // Out-of-range values are ignored
+        let params: Vec<String> = ["tiny", "LINELEN=100", "HOSTLEN=600", "USERLEN=600", "are"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        isupport.update(&params);
+        assert_eq!(
+            max_privmsg_len(&isupport, "tiny", None, 0),
+            512 - 16 - 4 - 9 - 64
+        );
+
+        // Long nicks and prefixes leave a minimum length
+        let nick = "n".repeat(600);
+        assert_eq!(max_privmsg_len(&isupport, &nick, None, 0), MIN_PRIVMSG_LEN);
+        assert_eq!(
+            max_privmsg_len(&isupport, "tiny", Some("u@host"), 1000),
+            MIN_PRIVMSG_LEN
+        );
+    }
+
     #[test]
     fn test_split_iterator_1() {
-        let iter = split_iterator("yada yada yada", 5);
+        let iter = split_iterator("yada yada yada", 5, None);
         assert_eq!(iter.collect::<Vec<&str>>(), vec!["yada ", "yada ", "yada"]);
     }
 
     #[test]
     fn test_split_iterator_2() {
-        let iter = split_iterator("yada yada yada", 4);
+        let iter = split_iterator("yada yada yada", 4, None);
         assert_eq!(
             iter.collect::<Vec<&str>>(),
             // weird but OK
@@ -94,7 +240,7 @@ mod tests {
 
     #[test]
     fn test_split_iterator_3() {
-        let iter = split_iterator("yada yada yada", 3);
+        let iter = split_iterator("yada yada yada", 3, None);
         assert_eq!(
             iter.collect::<Vec<&str>>(),
             vec!["yad", "a ", "yad", "a ", "yad", "a"]
@@ -103,7 +249,7 @@ mod tests {
 
     #[test]
     fn test_split_iterator_4() {
-        let iter = split_iterator("longwordislong", 3);
+        let iter = split_iterator("longwordislong", 3, None);
         assert_eq!(
             iter.collect::<Vec<&str>>(),
             vec!["lon", "gwo", "rdi", "slo", "ng"]
@@ -112,14 +258,29 @@ mod tests {
 
     #[test]
     fn test_split_iterator_5() {
-        let iter = split_iterator("", 3);
+        let iter = split_iterator("", 3, None);
         assert_eq!(iter.collect::<Vec<&str>>(), vec![""]);
     }
 
     #[test]
     fn test_split_iterator_6() {
-        let iter = split_iterator("", 0);
+        let iter = split_iterator("", 0, None);
         let ret: Vec<&str> = vec![];
         assert_eq!(iter.collect::<Vec<&str>>(), ret);
     }
+
+    #[test]
+    fn test_split_iterator_encoding() {
+        // 2 bytes per char in UTF-8, 1 in latin1
+        let latin1 = wire::Encoding::for_label(b"latin1").unwrap();
+        let iter = split_iterator("ééé ééé", 4, Some(latin1));
+        assert_eq!(iter.collect::<Vec<&str>>(), vec!["ééé ", "ééé"]);
+        let iter = split_iterator("ééé ééé", 4, None);
+        assert_eq!(iter.collect::<Vec<&str>>(), vec!["éé", "é ", "éé", "é"]);
+
+        // 3 bytes per char in UTF-8, 2 in Shift_JIS
+        let sjis = wire::Encoding::for_label(b"shift_jis").unwrap();
+        let iter = split_iterator("日本語", 4, Some(sjis));
+        assert_eq!(iter.collect::<Vec<&str>>(), vec!["日本", "語"]);
+    }
 }
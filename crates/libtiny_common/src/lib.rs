@@ -1,46 +1,102 @@
 //! This crate implements common types used by other libtiny crates.
 
 use std::borrow::Borrow;
-use std::hash::{Hash, Hasher};
+use std::hash::Hash;
 use std::ops::Deref;
 
-/// Channel names according to RFC 2812, section 1.3. Channel names are case insensitive, so this
-/// type defines `Eq`, and `Hash` traits that work in a case-insensitive way. `ChanName::display`
-/// method shows the channel name with the original casing.
-#[derive(Debug, Clone)]
-pub struct ChanName(String);
+/// Case mappings used to compare nicks and channel names. Servers advertise the mapping they use
+/// with `CASEMAPPING` in RPL_ISUPPORT (005).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CaseMapping {
+    /// Only ASCII letters are case insensitive.
+    Ascii,
 
-/// Slice version of `ChanName`
-#[derive(Debug)]
-pub struct ChanNameRef(str);
+    /// Same as `Ascii`, plus '[', ']', '\\', '~' are lowercase versions of '{', '}', '|', '^',
+    /// respectively. See RFC 2812 section 2.2.
+    #[default]
+    Rfc1459,
 
-impl Deref for ChanName {
-    type Target = ChanNameRef;
+    /// Same as `Rfc1459`, but '~' and '^' are different characters.
+    StrictRfc1459,
 
-    fn deref(&self) -> &Self::Target {
-        self.as_ref()
-    }
+    /// Unicode case folding, as in RFC 7613. We don't implement the full PRECIS profile, we only
+    /// map characters to their lowercase versions.
+    Rfc7613,
 }
 
-// https://github.com/rust-lang/rust/blob/10b3595ba6a4c658c9dea105488fc562c815e434/library/std/src/path.rs#L1735
-impl AsRef<ChanNameRef> for ChanName {
-    fn as_ref(&self) -> &ChanNameRef {
-        ChanNameRef::new(self.0.as_ref())
+impl CaseMapping {
+    /// Parse a `CASEMAPPING` value. Returns `None` for unknown mappings.
+    pub fn from_name(name: &str) -> Option<CaseMapping> {
+        match name {
+            "ascii" => Some(CaseMapping::Ascii),
+            "rfc1459" => Some(CaseMapping::Rfc1459),
+            "strict-rfc1459" => Some(CaseMapping::StrictRfc1459),
+            "rfc7613" => Some(CaseMapping::Rfc7613),
+            _ => None,
+        }
+    }
+
+    /// Name of the mapping, as advertised in `CASEMAPPING`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CaseMapping::Ascii => "ascii",
+            CaseMapping::Rfc1459 => "rfc1459",
+            CaseMapping::StrictRfc1459 => "strict-rfc1459",
+            CaseMapping::Rfc7613 => "rfc7613",
+        }
+    }
+
+    fn fold<'a>(&self, s: &'a str) -> impl Iterator<Item = char> + 'a {
+        let mapping = *self;
+        s.chars().flat_map(move |c| match mapping {
+            CaseMapping::Ascii => FoldedChar::One(std::iter::once(c.to_ascii_lowercase())),
+            CaseMapping::Rfc1459 => FoldedChar::One(std::iter::once(rfc1459_to_lower(c))),
+            CaseMapping::StrictRfc1459 if c == '~' => FoldedChar::One(std::iter::once(c)),
+            CaseMapping::StrictRfc1459 => FoldedChar::One(std::iter::once(rfc1459_to_lower(c))),
+            CaseMapping::Rfc7613 => FoldedChar::Unicode(c.to_lowercase()),
+        })
+    }
+
+    /// Normalize a nick or channel name, so that two names are equal under this mapping when their
+    /// normalized versions are equal.
+    pub fn normalize(&self, s: &str) -> String {
+        self.fold(s).collect()
+    }
+
+    /// Compare two nicks or channel names under this mapping.
+    pub fn equals(&self, s1: &str, s2: &str) -> bool {
+        // All characters that are mapped in the non-unicode mappings are ASCII, which have the
+        // same encoding length, so we can compare byte lengths.
+        if *self != CaseMapping::Rfc7613 && s1.len() != s2.len() {
+            return false;
+        }
+        self.fold(s1).eq(self.fold(s2))
     }
 }
 
-impl<'a> Borrow<ChanNameRef> for ChanName {
-    fn borrow(&self) -> &ChanNameRef {
-        self.as_ref()
+/// Result of case folding a single character.
+enum FoldedChar {
+    One(std::iter::Once<char>),
+    Unicode(std::char::ToLowercase),
+}
+
+impl Iterator for FoldedChar {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        match self {
+            FoldedChar::One(iter) => iter.next(),
+            FoldedChar::Unicode(iter) => iter.next(),
+        }
     }
 }
 
-// Used to normalize channel names. Rules are:
+// Used to normalize nicks and channel names in rfc1459 case mapping. Rules are:
 //
 // - ASCII characters are mapped to their lowercase versions
 // - '[', ']', '\\', '~' are mapped to '{', '}', '|', '^', respectively. See RFC 2812 section 2.2.
 // - Non-ASCII characters are left unchanged.
-fn to_lower(c: char) -> char {
+fn rfc1459_to_lower(c: char) -> char {
     match c {
         '[' => '{',
         ']' => '}',
@@ -50,97 +106,95 @@ fn to_lower(c: char) -> char {
     }
 }
 
-impl ChanName {
-    pub fn new(name: String) -> Self {
-        ChanName(name)
-    }
+/// Implements a case insensitive string type and its slice version. Case insensitivity depends on
+/// the server's case mapping, so `Eq` and `Hash` compare the names exactly. Use `eq_with` and
+/// `normalized_with` to compare names under a case mapping.
+macro_rules! case_insensitive_str {
+    ($owned:ident, $borrowed:ident) => {
+        impl Deref for $owned {
+            type Target = $borrowed;
+
+            fn deref(&self) -> &Self::Target {
+                self.as_ref()
+            }
+        }
 
-    pub fn display(&self) -> &str {
-        &self.0
-    }
-}
+        // https://github.com/rust-lang/rust/blob/10b3595ba6a4c658c9dea105488fc562c815e434/library/std/src/path.rs#L1735
+        impl AsRef<$borrowed> for $owned {
+            fn as_ref(&self) -> &$borrowed {
+                $borrowed::new(self.0.as_ref())
+            }
+        }
 
-impl ChanNameRef {
-    pub fn new(name: &str) -> &Self {
-        unsafe { &*(name as *const str as *const ChanNameRef) }
-    }
+        impl<'a> Borrow<$borrowed> for $owned {
+            fn borrow(&self) -> &$borrowed {
+                self.as_ref()
+            }
+        }
 
-    pub fn display(&self) -> &str {
-        &self.0
-    }
+        impl $owned {
+            pub fn new(name: String) -> Self {
+                $owned(name)
+            }
 
-    pub fn normalized(&self) -> String {
-        self.0.chars().map(to_lower).collect()
-    }
-}
+            pub fn display(&self) -> &str {
+                &self.0
+            }
+        }
 
-impl ToOwned for ChanNameRef {
-    type Owned = ChanName;
+        impl $borrowed {
+            pub fn new(name: &str) -> &Self {
+                unsafe { &*(name as *const str as *const $borrowed) }
+            }
 
-    fn to_owned(&self) -> Self::Owned {
-        ChanName(self.0.to_owned())
-    }
-}
+            pub fn display(&self) -> &str {
+                &self.0
+            }
 
-impl PartialEq for ChanName {
-    fn eq(&self, other: &Self) -> bool {
-        let self_borrowed: &ChanNameRef = self.borrow();
-        let other_borrowed: &ChanNameRef = other.borrow();
-        self_borrowed.eq(other_borrowed)
-    }
-}
+            /// Normalize with the given case mapping.
+            pub fn normalized_with(&self, mapping: CaseMapping) -> String {
+                mapping.normalize(&self.0)
+            }
 
-impl Eq for ChanName {}
+            /// Compare with the given case mapping.
+            pub fn eq_with(&self, other: &$borrowed, mapping: CaseMapping) -> bool {
+                mapping.equals(&self.0, &other.0)
+            }
+        }
 
-impl PartialEq<ChanNameRef> for ChanName {
-    fn eq(&self, other: &ChanNameRef) -> bool {
-        let self_borrowed: &ChanNameRef = self.borrow();
-        self_borrowed.eq(other)
-    }
-}
+        impl ToOwned for $borrowed {
+            type Owned = $owned;
 
-impl Hash for ChanName {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        let self_borrowed: &ChanNameRef = self.borrow();
-        self_borrowed.hash(state)
-    }
+            fn to_owned(&self) -> Self::Owned {
+                $owned(self.0.to_owned())
+            }
+        }
+    };
 }
 
-impl PartialEq for ChanNameRef {
-    fn eq(&self, other: &Self) -> bool {
-        // https://github.com/rust-lang/rust/blob/b4acb110333392ecdaf890fce080e4b576106aae/library/core/src/slice/mod.rs#L6678-L6684
+/// Channel names according to RFC 2812, section 1.3. Channel names are case insensitive under the
+/// server's case mapping: compare them with `ChanNameRef::eq_with`, and use
+/// `ChanNameRef::normalized_with` for map keys. `Eq` and `Hash` compare the names exactly.
+/// `ChanName::display` method shows the channel name with the original casing.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ChanName(String);
 
-        // All characters in ASCII have the same encoding length so we can compare byte lenghts.
-        if self.0.as_bytes().len() != other.0.as_bytes().len() {
-            return false;
-        }
+/// Slice version of `ChanName`
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct ChanNameRef(str);
 
-        self.0
-            .chars()
-            .map(to_lower)
-            .zip(other.0.chars().map(to_lower))
-            .all(|(a, b)| a == b)
-    }
-}
+case_insensitive_str!(ChanName, ChanNameRef);
 
-impl Eq for ChanNameRef {}
+/// Nicks are case insensitive, similar to channel names. Like `ChanName`, `Eq` and `Hash` compare
+/// the nicks exactly, use `NickRef::eq_with` to compare under the server's case mapping.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Nick(String);
 
-impl PartialEq<ChanName> for ChanNameRef {
-    fn eq(&self, other: &ChanName) -> bool {
-        let other_borrowed: &ChanNameRef = other.borrow();
-        self.eq(other_borrowed)
-    }
-}
+/// Slice version of `Nick`
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct NickRef(str);
 
-impl Hash for ChanNameRef {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        // https://github.com/rust-lang/rust/blob/b4acb110333392ecdaf890fce080e4b576106aae/library/core/src/hash/mod.rs#L653-L656
-        self.0.len().hash(state);
-        for c in self.0.chars() {
-            to_lower(c).hash(state);
-        }
-    }
-}
+case_insensitive_str!(Nick, NickRef);
 
 /// Target of a message to be shown in a UI.
 #[derive(Debug)]
@@ -234,3 +288,45 @@ pub enum Event {
         source: MsgSource,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn case_mappings() {
+        use CaseMapping::*;
+
+        assert!(Ascii.equals("Nick", "nICK"));
+        assert!(!Ascii.equals("nick[a]", "nick{a}"));
+        assert!(!Ascii.equals("ğ", "Ğ"));
+
+        assert!(Rfc1459.equals("Nick[a]~", "nick{a}^"));
+        assert!(StrictRfc1459.equals("Nick[a]\\", "nick{a}|"));
+        assert!(!StrictRfc1459.equals("nick~", "nick^"));
+
+        assert!(Rfc7613.equals("ğÜ", "Ğü"));
+        assert!(!Rfc7613.equals("nick[", "nick{"));
+
+        assert_eq!(Rfc1459.normalize("#Chan[1]"), "#chan{1}");
+        assert_eq!(Rfc7613.normalize("ĞÜ"), "ğü");
+
+        for mapping in &[Ascii, Rfc1459, StrictRfc1459, Rfc7613] {
+            assert_eq!(CaseMapping::from_name(mapping.name()), Some(*mapping));
+        }
+        assert_eq!(CaseMapping::from_name("foo"), None);
+    }
+
+    #[test]
+    fn nick_eq() {
+        // No case mapping by default
+        assert_ne!(
+            Nick::new("Nick[]".to_owned()),
+            Nick::new("nick{}".to_owned())
+        );
+        assert!(NickRef::new("Nick[]").eq_with(NickRef::new("nick{}"), CaseMapping::Rfc1459));
+        assert!(NickRef::new("Nick~").eq_with(NickRef::new("nick^"), CaseMapping::Rfc1459));
+        assert!(!NickRef::new("Nick~").eq_with(NickRef::new("nick^"), CaseMapping::Ascii));
+        assert!(ChanNameRef::new("#Chan").eq_with(ChanNameRef::new("#chan"), CaseMapping::Ascii));
+    }
+}
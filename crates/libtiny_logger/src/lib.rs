@@ -8,7 +8,8 @@ use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use time::Tm;
 
-use libtiny_common::{ChanName, ChanNameRef, MsgTarget};
+use libtiny_common::{CaseMapping, ChanNameRef, MsgTarget};
+use libtiny_wire::formatting;
 
 #[macro_use]
 extern crate log;
@@ -45,6 +46,7 @@ macro_rules! delegate {
 impl Logger {
     delegate!(new_server_tab(serv: &str,));
     delegate!(close_server_tab(serv: &str,));
+    delegate!(set_case_mapping(serv: &str, mapping: CaseMapping,));
     delegate!(new_chan_tab(serv: &str, chan: &ChanNameRef,));
     delegate!(close_chan_tab(serv: &str, chan: &ChanNameRef,));
     delegate!(close_user_tab(serv: &str, nick: &str,));
@@ -95,7 +97,10 @@ impl Drop for LoggerInner {
 
 struct ServerLogs {
     fd: File,
-    chans: HashMap<ChanName, File>,
+    /// Case mapping of the server, used to normalize channel names
+    case_mapping: CaseMapping,
+    /// Maps normalized channel names to their fds
+    chans: HashMap<String, File>,
     users: HashMap<String, File>,
 }
 
@@ -155,6 +160,7 @@ fn close_server_tabs(server: ServerLogs, report_err: &dyn Fn(String)) {
         mut fd,
         chans,
         users,
+        ..
     } = server;
     report_io_err!(report_err, print_footer(&mut fd));
     for (_, mut fd) in chans.into_iter() {
@@ -199,6 +205,7 @@ impl LoggerInner {
                 serv.to_string(),
                 ServerLogs {
                     fd,
+                    case_mapping: CaseMapping::default(),
                     chans: HashMap::new(),
                     users: HashMap::new(),
                 },
@@ -217,17 +224,25 @@ impl LoggerInner {
         }
     }
 
+    fn set_case_mapping(&mut self, serv: &str, mapping: CaseMapping) {
+        match self.servers.get_mut(serv) {
+            None => {
+                info!("set_case_mapping: can't find server: {:?}", serv);
+            }
+            Some(server) => {
+                server.case_mapping = mapping;
+            }
+        }
+    }
+
     fn new_chan_tab(&mut self, serv: &str, chan: &ChanNameRef) {
         match self.servers.get_mut(serv) {
             None => {
                 info!("new_chan_tab: can't find server: {:?}", serv);
             }
             Some(server) => {
-                let chan_name_normalized = chan.normalized();
-                if server
-                    .chans
-                    .contains_key(ChanNameRef::new(&chan_name_normalized))
-                {
+                let chan_name_normalized = chan.normalized_with(server.case_mapping);
+                if server.chans.contains_key(&chan_name_normalized) {
                     return;
                 }
 
@@ -239,7 +254,7 @@ impl LoggerInner {
                 ));
                 if let Some(mut fd) = try_open_log_file(&path, &*self.report_err) {
                     report_io_err!(self.report_err, print_header(&mut fd));
-                    server.chans.insert(ChanName::new(chan_name_normalized), fd);
+                    server.chans.insert(chan_name_normalized, fd);
                 }
             }
         }
@@ -250,7 +265,10 @@ impl LoggerInner {
             None => {
                 info!("close_chan_tab: can't find server: {:?}", serv);
             }
-            Some(server) => match server.chans.remove(chan) {
+            Some(server) => match server
+                .chans
+                .remove(&chan.normalized_with(server.case_mapping))
+            {
                 None => {
                     info!(
                         "close_chan_tab: can't find chan {:?} in server {:?}",
@@ -292,6 +310,7 @@ impl LoggerInner {
     }
 
     fn add_msg(&mut self, msg: &str, ts: Tm, target: &MsgTarget) {
+        let msg = formatting::strip(msg);
         self.apply_to_target(target, |fd: &mut File, report_err: &dyn Fn(String)| {
             report_io_err!(report_err, writeln!(fd, "[{}] {}", strf(&ts), msg));
         });
@@ -306,6 +325,7 @@ impl LoggerInner {
         _highlight: bool,
         is_action: bool,
     ) {
+        let msg = formatting::strip(msg);
         self.apply_to_target(target, |fd: &mut File, report_err: &dyn Fn(String)| {
             let io_ret = if is_action {
                 writeln!(fd, "[{}] {} {}", strf(&ts), sender, msg)
@@ -317,22 +337,22 @@ impl LoggerInner {
     }
 
     fn add_nick(&mut self, nick: &str, ts: Option<Tm>, target: &MsgTarget) {
-        if let Some(_ts) = ts {
+        if let Some(ts) = ts {
             // This method is only called when a user joins a chan
             self.apply_to_target(target, |fd: &mut File, report_err: &dyn Fn(String)| {
                 report_io_err!(
                     report_err,
-                    writeln!(fd, "[{}] {} joined the channel.", now(), nick)
+                    writeln!(fd, "[{}] {} joined the channel.", strf(&ts), nick)
                 );
             });
         }
     }
 
     fn remove_nick(&mut self, nick: &str, ts: Option<Tm>, target: &MsgTarget) {
-        if let Some(_ts) = ts {
+        if let Some(ts) = ts {
             // TODO: Did the user leave a channel or the server? Currently we can't tell.
             self.apply_to_target(target, |fd: &mut File, report_err: &dyn Fn(String)| {
-                report_io_err!(report_err, writeln!(fd, "[{}] {} left.", now(), nick));
+                report_io_err!(report_err, writeln!(fd, "[{}] {} left.", strf(&ts), nick));
             });
         }
     }
@@ -353,6 +373,7 @@ impl LoggerInner {
     }
 
     fn set_topic(&mut self, topic: &str, ts: Tm, serv: &str, chan: &ChanNameRef) {
+        let topic = formatting::strip(topic);
         let target = MsgTarget::Chan { serv, chan };
         self.apply_to_target(&target, |fd: &mut File, report_err: &dyn Fn(String)| {
             report_io_err!(
@@ -376,12 +397,16 @@ impl LoggerInner {
                 None => {
                     info!("Can't find server: {:?}", serv);
                 }
-                Some(ServerLogs { ref mut chans, .. }) => match chans.get_mut(chan) {
+                Some(ServerLogs {
+                    ref mut chans,
+                    case_mapping,
+                    ..
+                }) => match chans.get_mut(&chan.normalized_with(*case_mapping)) {
                     None => {
                         // Create a file for the channel. FIXME Code copied from new_chan_tab:
                         // can't reuse it because of borrowchk issues.
                         let mut path = self.log_dir.clone();
-                        let chan_name_normalized = chan.normalized();
+                        let chan_name_normalized = chan.normalized_with(*case_mapping);
                         path.push(&format!(
                             "{}_{}.txt",
                             serv,
@@ -390,7 +415,7 @@ impl LoggerInner {
                         if let Some(mut fd) = try_open_log_file(&path, &*self.report_err) {
                             report_io_err!(self.report_err, print_header(&mut fd));
                             f(&mut fd, &*self.report_err);
-                            chans.insert(ChanName::new(chan_name_normalized), fd);
+                            chans.insert(chan_name_normalized, fd);
                         }
                     }
                     Some(fd) => {
@@ -1,4 +1,5 @@
 use std::cmp::{max, min};
+use std::collections::HashMap;
 use std::mem;
 
 use termbox_simple::Termbox;
@@ -635,7 +636,14 @@ impl InputArea {
             } => {
                 let mut buffer = mem::replace(original_buffer, InputLine::new());
                 let completions: Vec<String> = mem::replace(completions, vec![]);
-                let word = &completions[current_completion];
+                let completion = &completions[current_completion];
+
+                // Drop the membership prefix of the completion
+                let word = completion.trim_start_matches(|c| !utils::is_nick_char(c));
+                let prefix_len = completion.len() - word.len();
+                if self.cursor as usize > insertion_point {
+                    self.cursor = max(insertion_point, self.cursor as usize - prefix_len) as i32;
+                }
 
                 // FIXME: This is inefficient
                 for char in word.chars() {
@@ -717,7 +725,10 @@ impl InputArea {
 }
 
 impl InputArea {
-    pub(crate) fn autocomplete(&mut self, dict: &Trie) {
+    /// Complete the nick before the cursor. Completions replace the typed part of the nick and
+    /// are shown with the membership prefixes in `prefixes` (e.g. "@nick"), which are dropped when
+    /// a completion is accepted.
+    pub(crate) fn autocomplete(&mut self, dict: &Trie, prefixes: &HashMap<String, char>) {
         if self.in_autocomplete() {
             // scroll next if you hit the KeyAction::InputAutoComplete key again
             self.completion_prev_entry();
@@ -742,29 +753,42 @@ impl InputArea {
                 cursor_left -= 1;
             }
 
-            let word = {
+            let word: String = {
                 if cursor_left == cursor_right {
-                    &[]
+                    String::new()
                 } else {
                     cursor_left += 1;
-                    &line[(cursor_left as usize)..(cursor_right as usize)]
+                    line[(cursor_left as usize)..(cursor_right as usize)]
+                        .iter()
+                        .collect()
                 }
             };
 
-            dict.drop_pfx(&mut word.iter().cloned())
+            dict.drop_pfx(&mut word.chars())
+                .into_iter()
+                .map(|rest| {
+                    let nick = format!("{}{}", word, rest);
+                    match prefixes.get(&nick) {
+                        None => nick,
+                        Some(prefix) => format!("{}{}", prefix, nick),
+                    }
+                })
+                .collect::<Vec<_>>()
         };
 
         if !completions.is_empty() {
-            let completion_len = completions[0].len();
+            let word_starts = cursor_left as usize;
+            let mut original_buffer = self.shown_line().to_owned();
+            original_buffer.drain(word_starts..cursor_right as usize);
+            let cursor = word_starts + completions[0].len();
             self.mode = Mode::Autocomplete {
-                original_buffer: self.shown_line().to_owned(),
-                insertion_point: self.cursor as usize,
-                word_starts: cursor_left as usize,
+                original_buffer,
+                insertion_point: word_starts,
+                word_starts,
                 completions,
                 current_completion: 0,
             };
-            let cursor = self.cursor;
-            self.move_cursor(cursor + completion_len as i32);
+            self.move_cursor(cursor as i32);
         }
     }
 
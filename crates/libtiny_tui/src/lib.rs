@@ -24,7 +24,7 @@ mod widget;
 mod tests;
 
 use crate::tui::{CmdResult, TUIRet};
-use libtiny_common::{ChanNameRef, Event, MsgSource, MsgTarget, TabStyle};
+use libtiny_common::{CaseMapping, ChanNameRef, Event, MsgSource, MsgTarget, TabStyle};
 use term_input::Input;
 
 use std::cell::RefCell;
@@ -232,6 +232,14 @@ impl TUI {
     delegate!(add_client_err_msg(msg: &str, target: &MsgTarget,));
     delegate!(clear_nicks(serv_name: &str,));
     delegate!(set_nick(serv_name: &str, new_nick: &str,));
+    delegate!(set_nick_away(serv_name: &str, nick: &str, away: bool,));
+    delegate!(set_case_mapping(serv_name: &str, mapping: CaseMapping,));
+    delegate!(set_nick_prefix(
+        serv_name: &str,
+        chan: &ChanNameRef,
+        nick: &str,
+        prefix: Option<char>,
+    ));
     delegate!(add_privmsg(
         sender: &str,
         msg: &str,
@@ -240,6 +248,13 @@ impl TUI {
         highlight: bool,
         is_action: bool,
     ));
+    delegate!(add_history_privmsg(
+        sender: &str,
+        msg: &str,
+        ts: Tm,
+        target: &MsgTarget,
+        is_action: bool,
+    ));
     delegate!(add_nick(nick: &str, ts: Option<Tm>, target: &MsgTarget,));
     delegate!(remove_nick(nick: &str, ts: Option<Tm>, target: &MsgTarget,));
     delegate!(rename_nick(
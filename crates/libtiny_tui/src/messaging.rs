@@ -1,5 +1,6 @@
 use termbox_simple::Termbox;
 
+use std::collections::{HashMap, HashSet};
 use std::convert::From;
 
 use time::{self, Tm};
@@ -34,6 +35,12 @@ pub(crate) struct MessagingUI {
     // properly highlight mentions.
     nicks: Trie,
 
+    // Nicks of the users that are away. Nicks in messages from those users are faded.
+    away_nicks: HashSet<String>,
+
+    // Membership prefixes (e.g. '@' for ops) of the nicks in the channel, shown in completions.
+    nick_prefixes: HashMap<String, char>,
+
     last_activity_line: Option<ActivityLine>,
     last_activity_ts: Option<Timestamp>,
 }
@@ -41,13 +48,24 @@ pub(crate) struct MessagingUI {
 /// Length of ": " suffix of nicks in messages
 pub(crate) const MSG_NICK_SUFFIX_LEN: usize = 2;
 
-/// Like `time::Tm`, but we only care about hour and minute parts.
-#[derive(PartialEq, Eq, Clone, Copy)]
+/// Like `time::Tm`, but we only show hour and minute parts. Timestamps in the same minute are
+/// equal.
+#[derive(Clone, Copy)]
 pub(crate) struct Timestamp {
     hour: i32,
     min: i32,
+    /// Seconds since the epoch, to insert messages fetched from the server's history in order.
+    secs: i64,
+}
+
+impl PartialEq for Timestamp {
+    fn eq(&self, other: &Timestamp) -> bool {
+        self.hour == other.hour && self.min == other.min
+    }
 }
 
+impl Eq for Timestamp {}
+
 // 80 characters. TODO: We need to make sure we don't need more whitespace than that. We should
 // probably add an upper bound to max_nick_length config field?
 static WHITESPACE: &str =
@@ -75,6 +93,7 @@ impl From<Tm> for Timestamp {
         Timestamp {
             hour: tm.tm_hour,
             min: tm.tm_min,
+            secs: tm.to_timespec().sec,
         }
     }
 }
@@ -103,6 +122,8 @@ impl MessagingUI {
             height,
             show_status: status,
             nicks: Trie::new(),
+            away_nicks: HashSet::new(),
+            nick_prefixes: HashMap::new(),
             last_activity_line: None,
             last_activity_ts: None,
         }
@@ -167,7 +188,8 @@ impl MessagingUI {
             }
             KeyAction::InputAutoComplete => {
                 if self.exit_dialogue.is_none() {
-                    self.input_field.autocomplete(&self.nicks);
+                    self.input_field
+                        .autocomplete(&self.nicks, &self.nick_prefixes);
                 }
                 WidgetRet::KeyHandled
             }
@@ -245,6 +267,7 @@ fn get_input_field_max_height(window_height: i32) -> i32 {
 
 impl MessagingUI {
     fn add_timestamp(&mut self, ts: Timestamp) {
+        self.msg_area.set_current_line_time(ts.secs);
         if let Some(ts_) = self.last_activity_ts {
             if ts_ != ts {
                 ts.stamp(&mut self.msg_area);
@@ -304,9 +327,42 @@ impl MessagingUI {
 
         self.reset_activity_line();
         self.add_timestamp(ts);
+        self.add_privmsg_text(sender, msg, highlight, is_action);
+        self.msg_area.flush_line();
+    }
 
-        let nick_color = self.get_nick_color(sender);
-        let nick_col_style = SegStyle::NickColor(nick_color);
+    /// A privmsg fetched from the server's history. Inserted after the messages sent before it.
+    pub(crate) fn add_history_privmsg(
+        &mut self,
+        sender: &str,
+        msg: &str,
+        ts: Timestamp,
+        is_action: bool,
+    ) {
+        self.reset_activity_line();
+
+        // Like `add_timestamp`, but the timestamp is compared with the line that the message is
+        // inserted after
+        let prev_min = self
+            .msg_area
+            .prev_line_time(ts.secs)
+            .map(|secs| secs.div_euclid(60));
+        self.msg_area.set_current_line_time(ts.secs);
+        if prev_min != Some(ts.secs.div_euclid(60)) {
+            ts.stamp(&mut self.msg_area);
+        } else if matches!(self.msg_area.layout(), Layout::Aligned { .. }) {
+            Timestamp::blank(&mut self.msg_area);
+        }
+        self.add_privmsg_text(sender, msg, false, is_action);
+        self.msg_area.insert_line();
+    }
+
+    fn add_privmsg_text(&mut self, sender: &str, msg: &str, highlight: bool, is_action: bool) {
+        let nick_col_style = if self.away_nicks.contains(sender) {
+            SegStyle::Faded
+        } else {
+            SegStyle::NickColor(self.get_nick_color(sender))
+        };
 
         // actions are /me msgs so they don't show the nick in the nick column, but in the msg
         let layout = self.msg_area.layout();
@@ -344,7 +400,6 @@ impl MessagingUI {
 
         self.msg_area.add_text(msg, msg_style);
         self.msg_area.set_current_line_alignment();
-        self.msg_area.flush_line();
     }
 
     pub(crate) fn add_msg(&mut self, msg: &str, ts: Timestamp) {
@@ -383,6 +438,27 @@ impl MessagingUI {
 impl MessagingUI {
     pub(crate) fn clear_nicks(&mut self) {
         self.nicks.clear();
+        self.away_nicks.clear();
+        self.nick_prefixes.clear();
+    }
+
+    pub(crate) fn set_nick_prefix(&mut self, nick: &str, prefix: Option<char>) {
+        match prefix {
+            None => {
+                self.nick_prefixes.remove(nick);
+            }
+            Some(prefix) => {
+                self.nick_prefixes.insert(nick.to_owned(), prefix);
+            }
+        }
+    }
+
+    pub(crate) fn set_away(&mut self, nick: &str, away: bool) {
+        if away {
+            self.away_nicks.insert(nick.to_owned());
+        } else {
+            self.away_nicks.remove(nick);
+        }
     }
 
     pub(crate) fn join(&mut self, nick: &str, ts: Option<Timestamp>) {
@@ -401,6 +477,8 @@ impl MessagingUI {
 
     pub(crate) fn part(&mut self, nick: &str, ts: Option<Timestamp>) {
         self.nicks.remove(nick);
+        self.away_nicks.remove(nick);
+        self.nick_prefixes.remove(nick);
 
         if self.show_status {
             if let Some(ts) = ts {
@@ -431,6 +509,12 @@ impl MessagingUI {
     pub(crate) fn nick(&mut self, old_nick: &str, new_nick: &str, ts: Timestamp) {
         self.nicks.remove(old_nick);
         self.nicks.insert(new_nick);
+        if self.away_nicks.remove(old_nick) {
+            self.away_nicks.insert(new_nick.to_owned());
+        }
+        if let Some(prefix) = self.nick_prefixes.remove(old_nick) {
+            self.nick_prefixes.insert(new_nick.to_owned(), prefix);
+        }
 
         let line_idx = self.get_activity_line_idx(ts);
         self.msg_area.modify_line(line_idx, |line| {
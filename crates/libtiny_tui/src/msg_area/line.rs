@@ -2,8 +2,8 @@ use crate::line_split::LineType;
 use crate::{
     config::{Colors, Style},
     line_split::LineDataCache,
-    utils::translate_irc_control_chars,
 };
+use libtiny_wire::formatting::{self, Attrs, Color};
 use std::mem;
 use termbox_simple::{self, Termbox};
 
@@ -17,20 +17,21 @@ pub(crate) struct Line {
     current_seg: StyledString,
 
     line_data: LineDataCache,
+
+    /// Time of the message in seconds since the epoch, for lines with a timestamp.
+    time: Option<i64>,
 }
 
 #[derive(Debug)]
 struct StyledString {
     string: String,
     style: SegStyle,
+    /// IRC formatting, applied on top of `style`.
+    attrs: Attrs,
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub(crate) enum SegStyle {
-    /// A specific style. Useful when rendering IRC colors (which should look
-    /// the same across color schemes).
-    Fixed(Style),
-
     /// An index to nick colors. Note that the index should be larger than size
     /// of the color list, so make sure to use mod.
     NickColor(usize),
@@ -49,9 +50,37 @@ pub(crate) enum SegStyle {
 
 impl StyledString {
     pub(crate) fn style(&self, colors: &Colors) -> Style {
+        let style = self.base_style(colors);
+        if self.attrs.is_plain() {
+            return style;
+        }
+
+        let attrs = &self.attrs;
+        let mut fg = match attrs.fg {
+            None => style.fg,
+            Some(color) => (style.fg & 0xFF00) | u16::from(irc_color_to_termbox(color)),
+        };
+        let bg = match attrs.bg {
+            None => style.bg,
+            Some(color) => u16::from(irc_color_to_termbox(color)),
+        };
+        for &(set, attr) in &[
+            (attrs.bold, termbox_simple::TB_BOLD),
+            (attrs.italic, termbox_simple::TB_ITALIC),
+            (attrs.underline, termbox_simple::TB_UNDERLINE),
+            (attrs.strikethrough, termbox_simple::TB_STRIKETHROUGH),
+            (attrs.reverse, termbox_simple::TB_REVERSE),
+        ] {
+            if set {
+                fg |= attr;
+            }
+        }
+        Style { fg, bg }
+    }
+
+    fn base_style(&self, colors: &Colors) -> Style {
         use SegStyle::*;
         match self.style {
-            Fixed(style) => style,
             NickColor(idx) => Style {
                 fg: u16::from(colors.nick[idx % colors.nick.len()]),
                 bg: colors.user_msg.bg,
@@ -74,22 +103,29 @@ impl Default for StyledString {
         StyledString {
             string: String::new(),
             style: SegStyle::UserMsg,
+            attrs: Attrs::default(),
         }
     }
 }
 
-// TODO get rid of this
-const TERMBOX_COLOR_PREFIX: char = '\x00';
-
 impl Line {
     pub(crate) fn new() -> Line {
         Line {
             segments: vec![],
             current_seg: StyledString::default(),
             line_data: LineDataCache::msg_line(0, None),
+            time: None,
         }
     }
 
+    pub(crate) fn set_time(&mut self, time: i64) {
+        self.time = Some(time);
+    }
+
+    pub(crate) fn time(&self) -> Option<i64> {
+        self.time
+    }
+
     pub(crate) fn set_type(&mut self, line_type: LineType) {
         self.line_data.set_line_type(line_type)
     }
@@ -98,60 +134,43 @@ impl Line {
         self.line_data.line_type()
     }
 
-    fn set_message_style(&mut self, style: SegStyle) {
+    fn set_message_style(&mut self, style: SegStyle, attrs: Attrs) {
         // Just update the last segment if it's empty
         if self.current_seg.string.is_empty() {
             self.current_seg.style = style;
-        } else if self.current_seg.style != style {
+            self.current_seg.attrs = attrs;
+        } else if self.current_seg.style != style || self.current_seg.attrs != attrs {
             let seg = mem::replace(
                 &mut self.current_seg,
                 StyledString {
                     string: String::new(),
                     style,
+                    attrs,
                 },
             );
             self.segments.push(seg);
         }
     }
 
-    fn add_text_inner(&mut self, str: &str) {
-        fn push_color(ret: &mut String, irc_fg: u8, irc_bg: Option<u8>) {
-            ret.push(TERMBOX_COLOR_PREFIX);
-            ret.push(0 as char); // style
-            ret.push(irc_color_to_termbox(irc_fg) as char);
-            ret.push(
-                irc_bg
-                    .map(irc_color_to_termbox)
-                    .unwrap_or(termbox_simple::TB_DEFAULT as u8) as char,
-            );
-        }
-        let str = translate_irc_control_chars(str, push_color);
+    /// Add text with IRC formatting. Tabs are replaced with 8 spaces, other ASCII control
+    /// characters are removed.
+    pub(crate) fn add_text(&mut self, str: &str, style: SegStyle) {
         self.current_seg.string.reserve(str.len());
-
-        let mut iter = str.chars();
-        while let Some(char) = iter.next() {
-            if char == TERMBOX_COLOR_PREFIX {
-                let st = iter.next().unwrap() as u8;
-                let fg = iter.next().unwrap() as u8;
-                let bg = iter.next().unwrap() as u8;
-                let fg = (u16::from(st) << 8) | u16::from(fg);
-                let bg = u16::from(bg);
-                let style = Style { fg, bg };
-                self.set_message_style(SegStyle::Fixed(style));
-            } else if char > '\x08' {
-                self.current_seg.string.push(char);
+        for span in formatting::parse(str) {
+            self.set_message_style(style, span.attrs);
+            for char in span.text.chars() {
+                if char == '\t' {
+                    self.current_seg.string.push_str("        ");
+                } else {
+                    self.current_seg.string.push(char);
+                }
             }
         }
     }
 
-    pub(crate) fn add_text(&mut self, str: &str, style: SegStyle) {
-        self.set_message_style(style);
-        self.add_text_inner(str)
-    }
-
     pub(crate) fn add_char(&mut self, char: char, style: SegStyle) {
-        assert_ne!(char, TERMBOX_COLOR_PREFIX);
-        self.set_message_style(style);
+        assert!(!char.is_ascii_control());
+        self.set_message_style(style, Attrs::default());
         self.current_seg.string.push(char);
     }
 
@@ -233,7 +252,41 @@ impl Line {
 // IRC colors: http://en.wikichip.org/wiki/irc/colors
 // Termbox colors: http://www.calmar.ws/vim/256-xterm-24bit-rgb-color-chart.html
 //                 (alternatively just run `cargo run --example colors`)
-fn irc_color_to_termbox(irc_color: u8) -> u8 {
+fn irc_color_to_termbox(color: Color) -> u8 {
+    match color {
+        Color::Irc(color) => irc_code_to_termbox(color),
+        Color::Rgb(r, g, b) => rgb_to_termbox(r, g, b),
+    }
+}
+
+// Colors 16-98 are from https://modern.ircdocs.horse/formatting.html#colors-16-98
+#[rustfmt::skip]
+const IRC_EXTENDED_COLORS: [u8; 83] = [
+    52, 94, 100, 58, 22, 29, 23, 24, 17, 54, 53, 89,
+    88, 130, 142, 64, 28, 35, 30, 25, 18, 91, 90, 125,
+    124, 166, 184, 106, 34, 49, 37, 33, 19, 129, 127, 161,
+    196, 208, 226, 154, 46, 86, 51, 75, 21, 171, 201, 198,
+    203, 215, 227, 191, 83, 122, 87, 111, 63, 177, 207, 205,
+    217, 223, 229, 193, 157, 158, 159, 153, 147, 183, 219, 212,
+    16, 233, 235, 237, 239, 241, 244, 247, 250, 254, 231,
+];
+
+/// Closest color in the 6x6x6 color cube of the 256-color palette.
+fn rgb_to_termbox(r: u8, g: u8, b: u8) -> u8 {
+    fn to_cube(c: u8) -> u8 {
+        // Cube levels are 0, 95, 135, 175, 215, 255
+        if c < 48 {
+            0
+        } else if c < 115 {
+            1
+        } else {
+            (c - 35) / 40
+        }
+    }
+    16 + 36 * to_cube(r) + 6 * to_cube(g) + to_cube(b)
+}
+
+fn irc_code_to_termbox(irc_color: u8) -> u8 {
     match irc_color {
         0 => 15,  // white
         1 => 0,   // black
@@ -251,6 +304,7 @@ fn irc_color_to_termbox(irc_color: u8) -> u8 {
         13 => 13, // magenta
         14 => 8,  // gray
         15 => 7,  // light gray
+        16..=98 => IRC_EXTENDED_COLORS[usize::from(irc_color - 16)],
         _ => termbox_simple::TB_DEFAULT as u8,
     }
 }
@@ -352,8 +406,44 @@ mod tests {
          67
          8
         */
-        line.add_text_inner("12345678");
+        line.add_text("12345678", SegStyle::UserMsg);
 
         assert_eq!(line.rendered_height(3), 4);
     }
+
+    #[test]
+    fn formatting_test() {
+        let colors = Colors::default();
+        let mut line = Line::new();
+        line.add_text("a\x02b\x034,99c\x0F\td", SegStyle::UserMsg);
+        let segs: Vec<(&str, Style)> = line
+            .segments
+            .iter()
+            .chain(std::iter::once(&line.current_seg))
+            .map(|seg| (seg.string.as_str(), seg.style(&colors)))
+            .collect();
+        let user_msg = colors.user_msg;
+        let bold = user_msg.fg | termbox_simple::TB_BOLD;
+        assert_eq!(
+            segs,
+            vec![
+                ("a", user_msg),
+                (
+                    "b",
+                    Style {
+                        fg: bold,
+                        ..user_msg
+                    }
+                ),
+                (
+                    "c",
+                    Style {
+                        fg: (bold & 0xFF00) | 9,
+                        ..user_msg
+                    }
+                ),
+                ("        d", user_msg),
+            ]
+        );
+    }
 } // mod tests
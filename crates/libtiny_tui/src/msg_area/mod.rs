@@ -201,6 +201,57 @@ impl MsgArea {
         self.lines.len() - 1
     }
 
+    /// Like `flush_line`, but the line is inserted after the lines with the same or an earlier
+    /// time, instead of at the end. Used for messages fetched from the server's history, which
+    /// can be older than the messages we've already shown. Lines without a time are skipped.
+    pub(crate) fn insert_line(&mut self) {
+        let time = match self.line_buf.time() {
+            None => {
+                self.flush_line();
+                return;
+            }
+            Some(time) => time,
+        };
+        let idx = self.insert_idx(time);
+        let line_height = self.line_buf.rendered_height(self.width);
+        self.lines
+            .insert(idx, mem::replace(&mut self.line_buf, Line::new()));
+        let mut removed_line_height = 0;
+        if self.lines.len() > self.scrollback {
+            // Remove oldest line
+            if let Some(mut removed) = self.lines.pop_front() {
+                removed_line_height = removed.rendered_height(self.width);
+            }
+        }
+        if self.scroll != 0 {
+            self.scroll += line_height;
+        }
+        if let Some(ref mut total_height) = self.lines_height {
+            *total_height += line_height - removed_line_height;
+        }
+    }
+
+    /// Time of the line that a line with the given time is inserted after. See `insert_line`.
+    pub(crate) fn prev_line_time(&self, time: i64) -> Option<i64> {
+        match self.insert_idx(time) {
+            0 => None,
+            idx => self.lines[idx - 1].time(),
+        }
+    }
+
+    fn insert_idx(&self, time: i64) -> usize {
+        self.lines
+            .iter()
+            .rposition(|line| matches!(line.time(), Some(line_time) if line_time <= time))
+            .map(|idx| idx + 1)
+            .unwrap_or(0)
+    }
+
+    /// Set the time of the current line. See `insert_line`.
+    pub(crate) fn set_current_line_time(&mut self, time: i64) {
+        self.line_buf.set_time(time);
+    }
+
     pub(crate) fn modify_line<F>(&mut self, idx: usize, f: F)
     where
         F: Fn(&mut Line),
@@ -1,4 +1,5 @@
-use crate::{utils::remove_irc_control_chars, MsgTarget};
+use crate::MsgTarget;
+use libtiny_wire::formatting;
 
 #[cfg(feature = "desktop-notifications")]
 use notify_rust::Notification;
@@ -36,7 +37,7 @@ impl Notifier {
             return;
         }
 
-        let msg = remove_irc_control_chars(msg);
+        let msg = formatting::strip(msg);
 
         match *target {
             MsgTarget::Chan { chan, .. } => {
@@ -489,3 +489,75 @@ fn test_resize() {
         tui.draw();
     }
 }
+
+#[test]
+fn test_history_privmsg_order() {
+    let mut tui = TUI::new_test(20, 6);
+    let serv = "irc.server_1.org";
+    let chan = ChanNameRef::new("#chan");
+    tui.new_server_tab(serv, None);
+    tui.set_nick(serv, "osa1");
+    tui.new_chan_tab(serv, chan);
+    tui.next_tab();
+    tui.next_tab();
+
+    let target = MsgTarget::Chan { serv, chan };
+    let ts = |secs| time::at_utc(time::Timespec::new(secs, 0));
+    tui.add_privmsg("a", "1", ts(60), &target, false, false);
+    tui.add_privmsg("a", "4", ts(240), &target, false, false);
+    tui.add_history_privmsg("b", "3", ts(180), &target, false);
+    tui.add_history_privmsg("b", "2", ts(120), &target, false);
+    tui.draw();
+
+    #[rustfmt::skip]
+    let screen =
+        "|00:01 a: 1          |
+         |00:02 b: 2          |
+         |00:03 b: 3          |
+         |00:04 a: 4          |
+         |osa1:               |
+         |< #chan             |";
+
+    expect_screen(screen, &tui.get_front_buffer(), 20, 6, Location::caller());
+}
+
+#[test]
+fn test_completion_prefixes() {
+    let mut tui = TUI::new_test(20, 3);
+    let serv = "irc.server_1.org";
+    let chan = ChanNameRef::new("#chan");
+    tui.new_server_tab(serv, None);
+    tui.set_nick(serv, "osa1");
+    tui.new_chan_tab(serv, chan);
+    tui.next_tab();
+    tui.next_tab();
+
+    let target = MsgTarget::Chan { serv, chan };
+    tui.add_nick("abc", None, &target);
+    tui.set_nick_prefix(serv, chan, "abc", Some('@'));
+
+    // Completions are shown with the prefixes
+    enter_string(&mut tui, "hi a");
+    tui.handle_input_event(Event::Key(Key::Tab), &mut None);
+    tui.draw();
+
+    #[rustfmt::skip]
+    let screen =
+        "|                    |
+         |osa1: hi @abc       |
+         |< #chan             |";
+
+    expect_screen(screen, &tui.get_front_buffer(), 20, 3, Location::caller());
+
+    // Prefixes are dropped when the completion is accepted
+    enter_string(&mut tui, "!");
+    tui.draw();
+
+    #[rustfmt::skip]
+    let screen =
+        "|                    |
+         |osa1: hi abc!       |
+         |< #chan             |";
+
+    expect_screen(screen, &tui.get_front_buffer(), 20, 3, Location::caller());
+}
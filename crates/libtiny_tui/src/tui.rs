@@ -18,7 +18,7 @@ use crate::notifier::Notifier;
 use crate::tab::Tab;
 use crate::widget::WidgetRet;
 
-use libtiny_common::{ChanNameRef, MsgSource, MsgTarget, TabStyle};
+use libtiny_common::{CaseMapping, ChanNameRef, MsgSource, MsgTarget, TabStyle};
 use term_input::{Event, Key};
 pub use termbox_simple::{CellBuf, Termbox};
 
@@ -96,6 +96,10 @@ pub struct TUI {
 
     /// Config file path
     config_path: Option<PathBuf>,
+
+    /// Case mappings of the servers, used to find channel and private tabs. See
+    /// `set_case_mapping`.
+    case_mappings: HashMap<String, CaseMapping>,
 }
 
 pub(crate) enum CmdResult {
@@ -157,6 +161,7 @@ impl TUI {
             h_scroll: 0,
             key_map: KeyMap::default(),
             config_path,
+            case_mappings: HashMap::new(),
         };
 
         // Init "mentions" tab. This needs to happen right after creating the TUI to be able to
@@ -428,6 +433,7 @@ impl TUI {
 
     /// Closes a server tab and all associated channel tabs.
     pub(crate) fn close_server_tab(&mut self, serv: &str) {
+        self.case_mappings.remove(serv);
         if let Some(tab_idx) = self.find_serv_tab_idx(serv) {
             self.tabs.retain(|tab: &Tab| tab.src.serv_name() != serv);
             if self.active_idx == tab_idx {
@@ -1165,7 +1171,7 @@ impl TUI {
                         chan: ref chan_,
                     } = tab.src
                     {
-                        if serv == serv_ && chan == chan_ {
+                        if serv == serv_ && chan.eq_with(chan_, self.case_mapping(serv)) {
                             target_idxs.push(tab_idx);
                             break;
                         }
@@ -1180,7 +1186,7 @@ impl TUI {
                         nick: ref nick_,
                     } = tab.src
                     {
-                        if serv == serv_ && nick == nick_ {
+                        if serv == serv_ && self.case_mapping(serv).equals(nick, nick_) {
                             target_idxs.push(tab_idx);
                             break;
                         }
@@ -1284,6 +1290,23 @@ impl TUI {
         });
     }
 
+    /// A privmsg that we missed while disconnected, fetched from the server's history. Shown like
+    /// `add_privmsg`, but inserted in order of the message times, never highlighted and doesn't
+    /// trigger notifications.
+    pub(crate) fn add_history_privmsg(
+        &mut self,
+        sender: &str,
+        msg: &str,
+        ts: Tm,
+        target: &MsgTarget,
+        is_action: bool,
+    ) {
+        self.apply_to_target(target, true, &|tab: &mut Tab, _| {
+            tab.widget
+                .add_history_privmsg(sender, msg, Timestamp::from(ts), is_action);
+        });
+    }
+
     /// A message without any explicit sender info. Useful for e.g. in server
     /// and debug log tabs. Timestamped and logged.
     pub fn add_msg(&mut self, msg: &str, ts: Tm, target: &MsgTarget) {
@@ -1314,6 +1337,41 @@ impl TUI {
         });
     }
 
+    /// Set away status of a user in all tabs of a server. Messages from away users are shown with
+    /// faded nicks.
+    pub(crate) fn set_nick_away(&mut self, serv: &str, nick: &str, away: bool) {
+        let target = MsgTarget::AllServTabs { serv };
+        self.apply_to_target(&target, false, &|tab: &mut Tab, _| {
+            tab.widget.set_away(nick, away);
+        });
+    }
+
+    /// Set the membership prefix (e.g. '@' for ops) of a user in a channel, shown in nick
+    /// completions. `None` means the user doesn't have a prefix mode.
+    /// Set the case mapping of a server (see `ISupport::casemapping`). Channel names and nicks of
+    /// the server are compared with this mapping when finding tabs. Servers use `rfc1459` until
+    /// they advertise another mapping.
+    pub(crate) fn set_case_mapping(&mut self, serv: &str, mapping: CaseMapping) {
+        self.case_mappings.insert(serv.to_owned(), mapping);
+    }
+
+    fn case_mapping(&self, serv: &str) -> CaseMapping {
+        self.case_mappings.get(serv).copied().unwrap_or_default()
+    }
+
+    pub(crate) fn set_nick_prefix(
+        &mut self,
+        serv: &str,
+        chan: &ChanNameRef,
+        nick: &str,
+        prefix: Option<char>,
+    ) {
+        let target = MsgTarget::Chan { serv, chan };
+        self.apply_to_target(&target, false, &|tab: &mut Tab, _| {
+            tab.widget.set_nick_prefix(nick, prefix);
+        });
+    }
+
     pub(crate) fn add_nick(&mut self, nick: &str, ts: Option<Tm>, target: &MsgTarget) {
         self.apply_to_target(target, false, &|tab: &mut Tab, _| {
             tab.widget.join(nick, ts.map(Timestamp::from));
@@ -1381,7 +1439,7 @@ impl TUI {
     pub(crate) fn user_tab_exists(&self, serv_: &str, nick_: &str) -> bool {
         for tab in &self.tabs {
             if let MsgSource::User { ref serv, ref nick } = tab.src {
-                if serv_ == serv && nick_ == nick {
+                if serv_ == serv && self.case_mapping(serv).equals(nick_, nick) {
                     return true;
                 }
             }
@@ -1423,7 +1481,7 @@ impl TUI {
     fn find_chan_tab_idx(&self, serv_: &str, chan_: &ChanNameRef) -> Option<usize> {
         for (tab_idx, tab) in self.tabs.iter().enumerate() {
             if let MsgSource::Chan { ref serv, ref chan } = tab.src {
-                if serv_ == serv && chan_ == chan {
+                if serv_ == serv && chan_.eq_with(chan, self.case_mapping(serv)) {
                     return Some(tab_idx);
                 }
             }
@@ -1434,7 +1492,7 @@ impl TUI {
     fn find_user_tab_idx(&self, serv_: &str, nick_: &str) -> Option<usize> {
         for (tab_idx, tab) in self.tabs.iter().enumerate() {
             if let MsgSource::User { ref serv, ref nick } = tab.src {
-                if serv_ == serv && nick_ == nick {
+                if serv_ == serv && self.case_mapping(serv).equals(nick_, nick) {
                     return Some(tab_idx);
                 }
             }
@@ -0,0 +1,67 @@
+#[macro_use]
+extern crate bencher;
+
+use bencher::Bencher;
+use libtiny_wire::{parse_irc_msg, MsgRef};
+
+/// A few thousand lines similar to a bouncer playback.
+fn playback_lines() -> Vec<u8> {
+    let mut buf = Vec::with_capacity(1_000_000);
+    for i in 0..1000 {
+        buf.extend_from_slice(
+            format!(
+                "@time=2021-06-01T12:{:02}:00.000Z;msgid=abc{} :nick{}!~user@unaffiliated/nick PRIVMSG #chan :message number {} with some words in it\r\n",
+                i % 60, i, i % 20, i
+            )
+            .as_bytes(),
+        );
+        buf.extend_from_slice(
+            format!(":nick{}!~user@host.example.com JOIN #chan\r\n", i % 20).as_bytes(),
+        );
+        buf.extend_from_slice(
+            b":x.y.z 353 tiny = #chan :@op +voiced nick1 nick2 nick3 nick4 nick5 nick6 nick7\r\n",
+        );
+    }
+    buf
+}
+
+/// Lines of the playback, with the "\r\n"s.
+fn split_lines(buf: &[u8]) -> impl Iterator<Item = &[u8]> {
+    buf.split_inclusive(|b| *b == b'\n')
+}
+
+fn parse_owned(b: &mut Bencher) {
+    let lines = playback_lines();
+    b.bytes = lines.len() as u64;
+    b.iter(|| {
+        // Feed the parser one line at a time, as the client does with its read buffer. Parsing
+        // the whole playback from one buffer would measure draining the buffer after each line.
+        let mut buf = Vec::with_capacity(1024);
+        let mut n_msgs = 0;
+        for line in split_lines(&lines) {
+            buf.extend_from_slice(line);
+            while let Some(msg) = parse_irc_msg(&mut buf) {
+                msg.unwrap();
+                n_msgs += 1;
+            }
+        }
+        n_msgs
+    });
+}
+
+fn parse_borrowed(b: &mut Bencher) {
+    let lines = playback_lines();
+    b.bytes = lines.len() as u64;
+    b.iter(|| {
+        let mut n_msgs = 0;
+        for line in split_lines(&lines) {
+            let line = line.strip_suffix(b"\r\n").unwrap_or(line);
+            MsgRef::parse(line).unwrap();
+            n_msgs += 1;
+        }
+        n_msgs
+    });
+}
+
+benchmark_group!(benches, parse_owned, parse_borrowed);
+benchmark_main!(benches);
@@ -0,0 +1,175 @@
+//! Client-to-client protocol (CTCP) messages. See https://modern.ircdocs.horse/ctcp.html
+//!
+//! CTCP messages are PRIVMSGs (queries) and NOTICEs (replies) wrapped in `\x01` characters. The
+//! first word is the CTCP command and the rest is the arguments, which are in the `msg` field of
+//! `Cmd::PRIVMSG`.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+/// A client-to-client protocol message. See https://defs.ircdocs.horse/defs/ctcp.html
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum CTCP {
+    Version,
+    Action,
+    /// Arguments of a query are an opaque token that is sent back in the reply.
+    Ping,
+    /// Arguments of a reply are the local time of the client, in any format.
+    Time,
+    /// Arguments of a reply are the list of supported CTCP commands.
+    ClientInfo,
+    UserInfo,
+    Source,
+    /// A Direct Client-to-Client offer. Use `DCC::parse` to parse the arguments.
+    DCC,
+    Other(String),
+}
+
+impl CTCP {
+    /// Get the CTCP for a command name. Command names are case sensitive.
+    pub fn from_name(name: &str) -> CTCP {
+        match name {
+            "VERSION" => CTCP::Version,
+            "ACTION" => CTCP::Action,
+            "PING" => CTCP::Ping,
+            "TIME" => CTCP::Time,
+            "CLIENTINFO" => CTCP::ClientInfo,
+            "USERINFO" => CTCP::UserInfo,
+            "SOURCE" => CTCP::Source,
+            "DCC" => CTCP::DCC,
+            _ => CTCP::Other(name.to_owned()),
+        }
+    }
+
+    /// Command name of the CTCP, e.g. "PING".
+    pub fn name(&self) -> &str {
+        match self {
+            CTCP::Version => "VERSION",
+            CTCP::Action => "ACTION",
+            CTCP::Ping => "PING",
+            CTCP::Time => "TIME",
+            CTCP::ClientInfo => "CLIENTINFO",
+            CTCP::UserInfo => "USERINFO",
+            CTCP::Source => "SOURCE",
+            CTCP::DCC => "DCC",
+            CTCP::Other(other) => other,
+        }
+    }
+}
+
+/// Generate the `\x01` delimited part of a CTCP message.
+pub(crate) fn ctcp_msg(ctcp: &CTCP, args: &str) -> String {
+    if args.is_empty() {
+        format!("\x01{}\x01", ctcp.name())
+    } else {
+        format!("\x01{} {}\x01", ctcp.name(), args)
+    }
+}
+
+/// Arguments of a DCC offer, e.g. `SEND "file name.txt" 3232235777 5000 1024`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DCC {
+    /// Type of the offer, e.g. "SEND" or "CHAT".
+    pub kind: String,
+    /// File name for SEND, "chat" for CHAT. Quotes are removed.
+    pub arg: String,
+    pub addr: IpAddr,
+    /// 0 for reverse (passive) DCC offers.
+    pub port: u16,
+    /// File size for SEND.
+    pub size: Option<u64>,
+}
+
+impl DCC {
+    /// Parse the arguments of a DCC CTCP. IPv4 addresses can be in the traditional integer form
+    /// or in the dotted form.
+    pub fn parse(args: &str) -> Option<DCC> {
+        let args = args.trim_start();
+        let (kind, rest) = split_word(args)?;
+
+        let rest = rest.trim_start();
+        let (arg, rest) = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"')?;
+            (&quoted[..end], &quoted[end + 1..])
+        } else {
+            split_word(rest)?
+        };
+
+        let mut words = rest.split_whitespace();
+        let addr = words.next()?;
+        let addr = match addr.parse::<u32>() {
+            Ok(addr) => IpAddr::V4(Ipv4Addr::from(addr)),
+            Err(_) => addr.parse::<IpAddr>().ok()?,
+        };
+        let port = words.next()?.parse::<u16>().ok()?;
+        let size = words.next().and_then(|size| size.parse::<u64>().ok());
+
+        Some(DCC {
+            kind: kind.to_owned(),
+            arg: arg.to_owned(),
+            addr,
+            port,
+            size,
+        })
+    }
+}
+
+fn split_word(s: &str) -> Option<(&str, &str)> {
+    if s.is_empty() {
+        return None;
+    }
+    match s.find(' ') {
+        None => Some((s, "")),
+        Some(idx) => Some((&s[..idx], &s[idx + 1..])),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ctcp_names() {
+        for ctcp in &[
+            CTCP::Version,
+            CTCP::Action,
+            CTCP::Ping,
+            CTCP::Time,
+            CTCP::ClientInfo,
+            CTCP::UserInfo,
+            CTCP::Source,
+            CTCP::DCC,
+            CTCP::Other("FINGER".to_owned()),
+        ] {
+            assert_eq!(&CTCP::from_name(ctcp.name()), ctcp);
+        }
+        assert_eq!(CTCP::from_name("ping"), CTCP::Other("ping".to_owned()));
+    }
+
+    #[test]
+    fn dcc_parsing() {
+        assert_eq!(
+            DCC::parse("SEND \"file name.txt\" 3232235777 5000 1024"),
+            Some(DCC {
+                kind: "SEND".to_owned(),
+                arg: "file name.txt".to_owned(),
+                addr: "192.168.1.1".parse().unwrap(),
+                port: 5000,
+                size: Some(1024),
+            })
+        );
+        assert_eq!(
+            DCC::parse("CHAT chat ::1 5000"),
+            Some(DCC {
+                kind: "CHAT".to_owned(),
+                arg: "chat".to_owned(),
+                addr: "::1".parse().unwrap(),
+                port: 5000,
+                size: None,
+            })
+        );
+        assert_eq!(DCC::parse("SEND file.txt 3232235777"), None);
+        assert_eq!(DCC::parse("SEND \"file.txt 3232235777 5000"), None);
+        assert_eq!(DCC::parse("SEND file.txt localhost 5000"), None);
+        assert_eq!(DCC::parse(""), None);
+    }
+}
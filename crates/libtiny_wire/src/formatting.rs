@@ -0,0 +1,288 @@
+//! IRC text formatting. See https://modern.ircdocs.horse/formatting.html
+//!
+//! Formatting is done with control characters that toggle attributes (bold, italic, ...) or set
+//! colors until the end of the message or until they're toggled again or reset. `parse` splits a
+//! message into spans of text with the same attributes, removing the control characters.
+
+pub const BOLD: char = '\x02';
+pub const ITALIC: char = '\x1D';
+pub const UNDERLINE: char = '\x1F';
+pub const STRIKETHROUGH: char = '\x1E';
+pub const MONOSPACE: char = '\x11';
+pub const REVERSE: char = '\x16';
+pub const COLOR: char = '\x03';
+pub const HEX_COLOR: char = '\x04';
+pub const RESET: char = '\x0F';
+
+/// A foreground or background color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    /// One of the IRC colors 0-98. Color 99 means "default color" and is represented as no color
+    /// in `Attrs`.
+    Irc(u8),
+    /// A `\x04` hex color.
+    Rgb(u8, u8, u8),
+}
+
+/// Attributes of a span of text.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Attrs {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub strikethrough: bool,
+    pub monospace: bool,
+    /// Swap foreground and background colors.
+    pub reverse: bool,
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+}
+
+impl Attrs {
+    /// Whether the span should be rendered as plain text.
+    pub fn is_plain(&self) -> bool {
+        *self == Attrs::default()
+    }
+}
+
+/// A span of text with the same attributes. The text does not contain any ASCII control
+/// characters other than tabs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span<'a> {
+    pub text: &'a str,
+    pub attrs: Attrs,
+}
+
+/// Split a message into formatted spans. Formatting characters are interpreted, other ASCII
+/// control characters (except tabs) are removed. Empty spans are not generated.
+pub fn parse(msg: &str) -> Spans<'_> {
+    Spans {
+        rest: msg,
+        attrs: Attrs::default(),
+    }
+}
+
+/// Remove formatting and ASCII control characters (except tabs) from a message.
+pub fn strip(msg: &str) -> String {
+    let mut ret = String::with_capacity(msg.len());
+    for span in parse(msg) {
+        ret.push_str(span.text);
+    }
+    ret
+}
+
+/// Iterator returned by `parse`.
+#[derive(Debug, Clone)]
+pub struct Spans<'a> {
+    rest: &'a str,
+    attrs: Attrs,
+}
+
+impl<'a> Iterator for Spans<'a> {
+    type Item = Span<'a>;
+
+    fn next(&mut self) -> Option<Span<'a>> {
+        loop {
+            let mut chars = self.rest.chars();
+            let char = chars.next()?;
+            if !is_control_char(char) {
+                break;
+            }
+            self.rest = chars.as_str();
+            match char {
+                BOLD => self.attrs.bold = !self.attrs.bold,
+                ITALIC => self.attrs.italic = !self.attrs.italic,
+                UNDERLINE => self.attrs.underline = !self.attrs.underline,
+                STRIKETHROUGH => self.attrs.strikethrough = !self.attrs.strikethrough,
+                MONOSPACE => self.attrs.monospace = !self.attrs.monospace,
+                REVERSE => self.attrs.reverse = !self.attrs.reverse,
+                RESET => self.attrs = Attrs::default(),
+                COLOR => self.parse_colors(parse_irc_color),
+                HEX_COLOR => self.parse_colors(parse_hex_color),
+                _ => {}
+            }
+        }
+
+        let end = self.rest.find(is_control_char).unwrap_or(self.rest.len());
+        let (text, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Some(Span {
+            text,
+            attrs: self.attrs,
+        })
+    }
+}
+
+impl<'a> Spans<'a> {
+    /// Parse the `<fg>[,<bg>]` part of a color code. When the foreground color is missing the
+    /// colors are reset. The comma is not consumed when it's not followed by a background color.
+    fn parse_colors(&mut self, parse_color: ColorParser) {
+        match parse_color(self.rest) {
+            None => {
+                self.attrs.fg = None;
+                self.attrs.bg = None;
+            }
+            Some((fg, len)) => {
+                self.attrs.fg = fg;
+                self.rest = &self.rest[len..];
+                if let Some(bg_str) = self.rest.strip_prefix(',') {
+                    if let Some((bg, len)) = parse_color(bg_str) {
+                        self.attrs.bg = bg;
+                        self.rest = &bg_str[len..];
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Parses a color at the beginning of a string, returns the color (`None` for the default color)
+/// and the length of the color code.
+type ColorParser = fn(&str) -> Option<(Option<Color>, usize)>;
+
+fn is_control_char(c: char) -> bool {
+    c.is_ascii_control() && c != '\t'
+}
+
+/// Parse one or two digits. 99 is the default color.
+fn parse_irc_color(s: &str) -> Option<(Option<Color>, usize)> {
+    let bytes = s.as_bytes();
+    let len = bytes
+        .iter()
+        .take(2)
+        .take_while(|b| b.is_ascii_digit())
+        .count();
+    if len == 0 {
+        return None;
+    }
+    match s[..len].parse::<u8>().unwrap() {
+        99 => Some((None, len)),
+        color => Some((Some(Color::Irc(color)), len)),
+    }
+}
+
+/// Parse six hex digits.
+fn parse_hex_color(s: &str) -> Option<(Option<Color>, usize)> {
+    let hex = s.get(..6)?;
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let rgb = u32::from_str_radix(hex, 16).unwrap();
+    let color = Color::Rgb((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8);
+    Some((Some(color), 6))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(msg: &str) -> Vec<Span<'_>> {
+        parse(msg).collect()
+    }
+
+    #[test]
+    fn strip_formatting() {
+        assert_eq!(
+            strip("  Le Voyageur imprudent  "),
+            "  Le Voyageur imprudent  "
+        );
+        assert_eq!(strip("\x0301,02foo"), "foo");
+        assert_eq!(strip("\x0301,2foo"), "foo");
+        assert_eq!(strip("\x031,2foo"), "foo");
+        assert_eq!(strip("\x031,foo"), ",foo");
+        assert_eq!(strip("\x03,foo"), ",foo");
+        assert_eq!(strip("\x03123"), "3");
+        assert_eq!(strip("\x04FF0000,00ff00foo"), "foo");
+        assert_eq!(strip("\x04FF00foo"), "FF00foo");
+        assert_eq!(strip("\x02a\x1Db\x1Fc\x1Ed\x11e\x16f\x0Fg"), "abcdefg");
+        assert_eq!(strip("a\x01b\x07c\td"), "abc\td");
+    }
+
+    #[test]
+    fn attrs() {
+        let bold = Attrs {
+            bold: true,
+            ..Attrs::default()
+        };
+        assert_eq!(
+            spans("a\x02b\x1Dc\x02d\x0Fe"),
+            vec![
+                Span {
+                    text: "a",
+                    attrs: Attrs::default()
+                },
+                Span {
+                    text: "b",
+                    attrs: bold
+                },
+                Span {
+                    text: "c",
+                    attrs: Attrs {
+                        italic: true,
+                        ..bold
+                    }
+                },
+                Span {
+                    text: "d",
+                    attrs: Attrs {
+                        italic: true,
+                        ..Attrs::default()
+                    }
+                },
+                Span {
+                    text: "e",
+                    attrs: Attrs::default()
+                },
+            ]
+        );
+
+        // Empty spans are skipped
+        assert_eq!(spans("\x02\x02"), vec![]);
+        assert_eq!(
+            spans("\x1F\x01\x16x"),
+            vec![Span {
+                text: "x",
+                attrs: Attrs {
+                    underline: true,
+                    reverse: true,
+                    ..Attrs::default()
+                }
+            }]
+        );
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!(
+            spans("\x034,12a\x0399b\x03c\x04ff8000d"),
+            vec![
+                Span {
+                    text: "a",
+                    attrs: Attrs {
+                        fg: Some(Color::Irc(4)),
+                        bg: Some(Color::Irc(12)),
+                        ..Attrs::default()
+                    }
+                },
+                Span {
+                    text: "b",
+                    attrs: Attrs {
+                        bg: Some(Color::Irc(12)),
+                        ..Attrs::default()
+                    }
+                },
+                Span {
+                    text: "c",
+                    attrs: Attrs::default()
+                },
+                Span {
+                    text: "d",
+                    attrs: Attrs {
+                        fg: Some(Color::Rgb(0xFF, 0x80, 0)),
+                        ..Attrs::default()
+                    }
+                },
+            ]
+        );
+    }
+}
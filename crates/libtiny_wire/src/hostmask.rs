@@ -0,0 +1,215 @@
+//! `nick!user@host` masks and IRC glob matching.
+
+use crate::Pfx;
+use libtiny_common::CaseMapping;
+
+use std::fmt;
+
+/// A `nick!user@host` mask. Parts that are missing in the parsed string are `None`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Hostmask {
+    pub nick: String,
+    pub user: Option<String>,
+    pub host: Option<String>,
+}
+
+impl Hostmask {
+    /// Parse a `nick!user@host` string. `user` and `host` parts are optional, so "nick",
+    /// "nick!user", and "nick@host" are all valid. Masks can contain the wildcards `*` and `?`.
+    pub fn parse(s: &str) -> Hostmask {
+        let (rest, host) = match s.find('@') {
+            None => (s, None),
+            Some(idx) => (&s[..idx], Some(s[idx + 1..].to_owned())),
+        };
+        let (nick, user) = match rest.find('!') {
+            None => (rest, None),
+            Some(idx) => (&rest[..idx], Some(rest[idx + 1..].to_owned())),
+        };
+        Hostmask {
+            nick: nick.to_owned(),
+            user,
+            host,
+        }
+    }
+
+    /// Check if the mask matches a user. Missing parts of the mask match anything, so mask "nick"
+    /// is the same as "nick!*@*". Missing parts of the user are matched as empty strings.
+    pub fn matches(&self, user: &Hostmask, mapping: CaseMapping) -> bool {
+        fn part_matches(pat: &Option<String>, part: &Option<String>, mapping: CaseMapping) -> bool {
+            match pat {
+                None => true,
+                Some(pat) => wildcard_match(pat, part.as_deref().unwrap_or(""), mapping),
+            }
+        }
+
+        wildcard_match(&self.nick, &user.nick, mapping)
+            && part_matches(&self.user, &user.user, mapping)
+            && part_matches(&self.host, &user.host, mapping)
+    }
+}
+
+impl fmt::Display for Hostmask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.nick)?;
+        if let Some(user) = &self.user {
+            write!(f, "!{}", user)?;
+        }
+        if let Some(host) = &self.host {
+            write!(f, "@{}", host)?;
+        }
+        Ok(())
+    }
+}
+
+impl Pfx {
+    /// Get the hostmask of a user prefix. Ambiguous prefixes are interpreted as nicks.
+    pub fn hostmask(&self) -> Option<Hostmask> {
+        match self {
+            Pfx::User { nick, user } => {
+                let (user, host) = match user.find('@') {
+                    None => (user.clone(), None),
+                    Some(idx) => (user[..idx].to_owned(), Some(user[idx + 1..].to_owned())),
+                };
+                Some(Hostmask {
+                    nick: nick.clone(),
+                    user: Some(user),
+                    host,
+                })
+            }
+            Pfx::Ambiguous(nick) => Some(Hostmask {
+                nick: nick.clone(),
+                user: None,
+                host: None,
+            }),
+            Pfx::Server(_) => None,
+        }
+    }
+}
+
+/// Match a string against a glob pattern with the wildcards `*` (any number of characters) and `?`
+/// (a single character). Characters are compared under the case mapping.
+pub fn wildcard_match(pattern: &str, s: &str, mapping: CaseMapping) -> bool {
+    let pattern: Vec<char> = mapping.normalize(pattern).chars().collect();
+    let s: Vec<char> = mapping.normalize(s).chars().collect();
+
+    let mut p_idx = 0;
+    let mut s_idx = 0;
+    // Position of the last '*' in the pattern, and the position in `s` that it's currently
+    // matched up to. Used to backtrack when the rest of the pattern fails to match.
+    let mut star: Option<(usize, usize)> = None;
+
+    while s_idx < s.len() {
+        match pattern.get(p_idx) {
+            Some('*') => {
+                star = Some((p_idx, s_idx));
+                p_idx += 1;
+            }
+            Some(&c) if c == '?' || c == s[s_idx] => {
+                p_idx += 1;
+                s_idx += 1;
+            }
+            _ => match star {
+                None => return false,
+                Some((star_p_idx, star_s_idx)) => {
+                    p_idx = star_p_idx + 1;
+                    s_idx = star_s_idx + 1;
+                    star = Some((star_p_idx, s_idx));
+                }
+            },
+        }
+    }
+
+    pattern[p_idx..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hostmask() {
+        assert_eq!(
+            Hostmask::parse("nick!~user@host.com"),
+            Hostmask {
+                nick: "nick".to_owned(),
+                user: Some("~user".to_owned()),
+                host: Some("host.com".to_owned()),
+            }
+        );
+        assert_eq!(
+            Hostmask::parse("*@*.example.com"),
+            Hostmask {
+                nick: "*".to_owned(),
+                user: None,
+                host: Some("*.example.com".to_owned()),
+            }
+        );
+        assert_eq!(
+            Hostmask::parse("nick"),
+            Hostmask {
+                nick: "nick".to_owned(),
+                user: None,
+                host: None,
+            }
+        );
+        for s in &[
+            "nick!~user@host.com",
+            "*@*.example.com",
+            "nick",
+            "nick!user",
+        ] {
+            assert_eq!(&Hostmask::parse(s).to_string(), s);
+        }
+    }
+
+    #[test]
+    fn pfx_hostmask() {
+        let pfx = Pfx::User {
+            nick: "nick".to_owned(),
+            user: "~user@host.com".to_owned(),
+        };
+        assert_eq!(pfx.hostmask(), Some(Hostmask::parse("nick!~user@host.com")));
+        assert_eq!(
+            Pfx::Ambiguous("nick".to_owned()).hostmask(),
+            Some(Hostmask::parse("nick"))
+        );
+        assert_eq!(Pfx::Server("irc.example.com".to_owned()).hostmask(), None);
+    }
+
+    #[test]
+    fn glob_matching() {
+        let m = CaseMapping::Rfc1459;
+        assert!(wildcard_match("", "", m));
+        assert!(wildcard_match("*", "", m));
+        assert!(!wildcard_match("?", "", m));
+        assert!(wildcard_match("a*b?d", "aXXbcd", m));
+        assert!(wildcard_match("a*b*", "abab", m));
+        assert!(wildcard_match("*.example.com", "foo.bar.EXAMPLE.com", m));
+        assert!(!wildcard_match("*.example.com", "example.com", m));
+        assert!(wildcard_match("nick[away]", "NICK{AWAY}", m));
+        assert!(!wildcard_match(
+            "nick[away]",
+            "NICK{AWAY}",
+            CaseMapping::Ascii
+        ));
+        assert!(wildcard_match("*a*a*a", "aaaaaaaaaa", m));
+        assert!(!wildcard_match("*a*a*b", "aaaaaaaaaa", m));
+    }
+
+    #[test]
+    fn mask_matching() {
+        let m = CaseMapping::Rfc1459;
+        let user = Hostmask::parse("Nick!~user@host.example.com");
+        assert!(Hostmask::parse("nick").matches(&user, m));
+        assert!(Hostmask::parse("*!*@*.example.com").matches(&user, m));
+        assert!(Hostmask::parse("*@HOST.example.com").matches(&user, m));
+        assert!(Hostmask::parse("n?ck!~*").matches(&user, m));
+        assert!(!Hostmask::parse("*!user@*").matches(&user, m));
+        assert!(!Hostmask::parse("other").matches(&user, m));
+
+        // Missing parts of the user only match '*'
+        let nick_only = Hostmask::parse("nick");
+        assert!(Hostmask::parse("nick!*@*").matches(&nick_only, m));
+        assert!(!Hostmask::parse("*@host").matches(&nick_only, m));
+    }
+}
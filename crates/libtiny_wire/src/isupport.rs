@@ -0,0 +1,512 @@
+//! RPL_ISUPPORT (005) parsing. See https://modern.ircdocs.horse/#rplisupport-parameters
+//!
+//! Servers send 005 replies during registration to advertise what they support. A server may send
+//! multiple 005 replies, each one updates the state.
+
+use libtiny_common::CaseMapping;
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+/// Accepted `LINELEN` values. Lines can't be shorter than 512 bytes, and we don't want to buffer
+/// arbitrarily long lines. Values out of the range are ignored.
+const LINELEN_RANGE: RangeInclusive<usize> = 512..=16384;
+
+/// Accepted `USERLEN` values. Values out of the range are ignored.
+const USERLEN_RANGE: RangeInclusive<usize> = 1..=64;
+
+/// Accepted `HOSTLEN` values. Host names are at most 255 bytes. Values out of the range are
+/// ignored.
+const HOSTLEN_RANGE: RangeInclusive<usize> = 1..=255;
+
+/// Server capabilities advertised with RPL_ISUPPORT. Fields that the server does not advertise
+/// have the defaults specified in the RFCs or in the modern IRC docs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ISupport {
+    /// Channel membership prefixes, as (mode, prefix) pairs, in order of decreasing rank. E.g.
+    /// `[('o', '@'), ('v', '+')]`.
+    pub prefix: Vec<(char, char)>,
+
+    /// Channel name prefixes.
+    pub chantypes: String,
+
+    /// Channel modes, by type. See `ChanModes`.
+    pub chanmodes: ChanModes,
+
+    /// Case mapping used by the server for nicks and channel names. Unknown mappings are ignored.
+    pub casemapping: CaseMapping,
+
+    /// Max. nick length.
+    pub nicklen: Option<usize>,
+
+    /// Max. topic length.
+    pub topiclen: Option<usize>,
+
+    /// Max. number of channel modes with a parameter in a single MODE command. `None` means
+    /// unlimited.
+    pub modes: Option<usize>,
+
+    /// Prefixes that can be used before a channel name in a PRIVMSG or NOTICE to send the
+    /// message to members of the channel with the given prefix mode.
+    pub statusmsg: String,
+
+    /// Network name.
+    pub network: Option<String>,
+
+    /// Max. number of targets allowed for commands. `None` values mean unlimited.
+    pub targmax: HashMap<String, Option<usize>>,
+
+    /// Max. number of entries in list modes, as (modes, limit) pairs. E.g. `MAXLIST=bqeI:100`
+    /// is `[("bqeI", 100)]`.
+    pub maxlist: Vec<(String, usize)>,
+
+    /// Max. length of a message, in bytes, including the trailing `\r\n`. Not a part of the
+    /// modern IRC docs but advertised by some servers. Between 512 and 16384.
+    pub linelen: usize,
+
+    /// Max. length of the user part of a `user@host` (`USERLEN`). At most 64.
+    pub userlen: Option<usize>,
+
+    /// Max. length of the host part of a `user@host` (`HOSTLEN`). At most 255.
+    pub hostlen: Option<usize>,
+
+    /// Max. number of messages that can be requested with one `CHATHISTORY` command
+    /// (`draft/chathistory`). `Some(0)` means unlimited.
+    pub chathistory: Option<usize>,
+
+    /// Tokens not listed above. Tokens without values are mapped to `None`.
+    pub other: HashMap<String, Option<String>>,
+}
+
+/// Channel modes advertised with `CHANMODES=A,B,C,D`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChanModes {
+    /// Type A: modes that add or remove an entry to a list (e.g. bans). Always take a parameter.
+    pub a: String,
+    /// Type B: modes that always take a parameter (e.g. channel key).
+    pub b: String,
+    /// Type C: modes that take a parameter only when set (e.g. user limit).
+    pub c: String,
+    /// Type D: modes that never take a parameter.
+    pub d: String,
+}
+
+impl Default for ChanModes {
+    fn default() -> Self {
+        ChanModes {
+            a: "beI".to_owned(),
+            b: "k".to_owned(),
+            c: "l".to_owned(),
+            d: "imnpst".to_owned(),
+        }
+    }
+}
+
+impl Default for ISupport {
+    fn default() -> Self {
+        ISupport {
+            prefix: vec![('o', '@'), ('v', '+')],
+            chantypes: "#&".to_owned(),
+            chanmodes: ChanModes::default(),
+            casemapping: CaseMapping::default(),
+            nicklen: None,
+            topiclen: None,
+            modes: Some(3),
+            statusmsg: String::new(),
+            network: None,
+            targmax: HashMap::new(),
+            maxlist: vec![],
+            linelen: 512,
+            userlen: None,
+            hostlen: None,
+            chathistory: None,
+            other: HashMap::new(),
+        }
+    }
+}
+
+impl ISupport {
+    /// Update the state with parameters of a 005 reply. First parameter (our nick) and the last
+    /// parameter ("are supported by this server") are skipped.
+    pub fn update(&mut self, params: &[String]) {
+        if params.len() < 2 {
+            return;
+        }
+        for token in &params[1..params.len() - 1] {
+            self.update_token(token);
+        }
+    }
+
+    fn update_token(&mut self, token: &str) {
+        if let Some(token) = token.strip_prefix('-') {
+            // Server no longer supports the token, reset to the default
+            self.reset_token(token);
+            return;
+        }
+
+        let (key, value) = match token.find('=') {
+            None => (token, None),
+            Some(idx) => (&token[..idx], Some(unescape_value(&token[idx + 1..]))),
+        };
+
+        match key {
+            "PREFIX" => match value.as_deref().and_then(parse_prefix) {
+                Some(prefix) => self.prefix = prefix,
+                None => self.prefix = vec![],
+            },
+            "CHANTYPES" => self.chantypes = value.unwrap_or_default(),
+            "CHANMODES" => {
+                if let Some(value) = value {
+                    let mut types = value.split(',').map(str::to_owned);
+                    self.chanmodes = ChanModes {
+                        a: types.next().unwrap_or_default(),
+                        b: types.next().unwrap_or_default(),
+                        c: types.next().unwrap_or_default(),
+                        d: types.next().unwrap_or_default(),
+                    };
+                }
+            }
+            "CASEMAPPING" => {
+                if let Some(casemapping) = value.as_deref().and_then(CaseMapping::from_name) {
+                    self.casemapping = casemapping;
+                }
+            }
+            "NICKLEN" => self.nicklen = value.and_then(|v| v.parse().ok()),
+            "TOPICLEN" => self.topiclen = value.and_then(|v| v.parse().ok()),
+            "MODES" => self.modes = value.and_then(|v| v.parse().ok()),
+            "STATUSMSG" => self.statusmsg = value.unwrap_or_default(),
+            "NETWORK" => self.network = value,
+            "TARGMAX" => {
+                self.targmax.clear();
+                if let Some(value) = value {
+                    for target in value.split(',') {
+                        if let Some(idx) = target.find(':') {
+                            self.targmax
+                                .insert(target[..idx].to_owned(), target[idx + 1..].parse().ok());
+                        }
+                    }
+                }
+            }
+            "MAXLIST" => {
+                self.maxlist.clear();
+                if let Some(value) = value {
+                    for list in value.split(',') {
+                        if let Some(idx) = list.find(':') {
+                            if let Ok(limit) = list[idx + 1..].parse() {
+                                self.maxlist.push((list[..idx].to_owned(), limit));
+                            }
+                        }
+                    }
+                }
+            }
+            "LINELEN" => {
+                self.linelen =
+                    parse_len(value, LINELEN_RANGE).unwrap_or_else(|| ISupport::default().linelen)
+            }
+            "USERLEN" => self.userlen = parse_len(value, USERLEN_RANGE),
+            "HOSTLEN" => self.hostlen = parse_len(value, HOSTLEN_RANGE),
+            "CHATHISTORY" => self.chathistory = value.and_then(|v| v.parse().ok()),
+            _ => {
+                self.other.insert(key.to_owned(), value);
+            }
+        }
+    }
+
+    fn reset_token(&mut self, key: &str) {
+        let default = ISupport::default();
+        match key {
+            "PREFIX" => self.prefix = default.prefix,
+            "CHANTYPES" => self.chantypes = default.chantypes,
+            "CHANMODES" => self.chanmodes = default.chanmodes,
+            "CASEMAPPING" => self.casemapping = default.casemapping,
+            "NICKLEN" => self.nicklen = default.nicklen,
+            "TOPICLEN" => self.topiclen = default.topiclen,
+            "MODES" => self.modes = default.modes,
+            "STATUSMSG" => self.statusmsg = default.statusmsg,
+            "NETWORK" => self.network = default.network,
+            "TARGMAX" => self.targmax = default.targmax,
+            "MAXLIST" => self.maxlist = default.maxlist,
+            "LINELEN" => self.linelen = default.linelen,
+            "USERLEN" => self.userlen = default.userlen,
+            "HOSTLEN" => self.hostlen = default.hostlen,
+            "CHATHISTORY" => self.chathistory = default.chathistory,
+            _ => {
+                self.other.remove(key);
+            }
+        }
+    }
+
+    /// Is the given name a channel name, according to `CHANTYPES`?
+    pub fn is_chan(&self, name: &str) -> bool {
+        match name.chars().next() {
+            None => false,
+            Some(c) => self.chantypes.contains(c),
+        }
+    }
+
+    /// Get the membership prefix mode for a prefix character. E.g. `'o'` for `'@'`.
+    pub fn prefix_mode(&self, prefix: char) -> Option<char> {
+        self.prefix
+            .iter()
+            .find(|(_, p)| *p == prefix)
+            .map(|(mode, _)| *mode)
+    }
+
+    /// Is the mode a membership prefix mode? E.g. `'o'` with the default `PREFIX`.
+    pub fn is_prefix_mode(&self, mode: char) -> bool {
+        self.prefix.iter().any(|(mode_, _)| *mode_ == mode)
+    }
+
+    /// Split membership prefixes of a nick in a RPL_NAMREPLY. Returns the prefixes and the nick.
+    /// With `multi-prefix` a nick can have more than one prefix.
+    pub fn split_nick_prefix<'a>(&self, nick: &'a str) -> (&'a str, &'a str) {
+        let nick_start = nick
+            .char_indices()
+            .find(|(_, c)| self.prefix_mode(*c).is_none())
+            .map(|(idx, _)| idx)
+            .unwrap_or_else(|| nick.len());
+        (&nick[..nick_start], &nick[nick_start..])
+    }
+
+    /// Drop membership prefixes of a nick in a RPL_NAMREPLY.
+    pub fn strip_nick_prefix<'a>(&self, nick: &'a str) -> &'a str {
+        self.split_nick_prefix(nick).1
+    }
+
+    /// Split a STATUSMSG prefix of a PRIVMSG or NOTICE target. E.g. with `STATUSMSG=@+`,
+    /// `@#chan` is split as `("@", "#chan")`. Returns `None` if the rest is not a channel.
+    pub fn split_statusmsg<'a>(&self, target: &'a str) -> Option<(&'a str, &'a str)> {
+        let chan_start = target
+            .char_indices()
+            .find(|(_, c)| !self.statusmsg.contains(*c))
+            .map(|(idx, _)| idx)?;
+        let chan = &target[chan_start..];
+        if self.is_chan(chan) {
+            Some((&target[..chan_start], chan))
+        } else {
+            None
+        }
+    }
+
+    /// Whether a channel mode takes an argument when set (`set` is `true`) or unset, according to
+    /// `CHANMODES` and `PREFIX`.
+    pub fn chan_mode_takes_arg(&self, mode: char, set: bool) -> bool {
+        if self.prefix.iter().any(|(m, _)| *m == mode) {
+            return true;
+        }
+        let ChanModes { a, b, c, .. } = &self.chanmodes;
+        a.contains(mode) || b.contains(mode) || (set && c.contains(mode))
+    }
+
+    /// Max. number of targets for the given command. `None` means unlimited.
+    pub fn max_targets(&self, cmd: &str) -> Option<usize> {
+        self.targmax.get(cmd).copied().flatten()
+    }
+}
+
+/// Parse `(modes)prefixes`, e.g. `(ov)@+`.
+/// Parse a length token, ignoring values out of `range`.
+fn parse_len(value: Option<String>, range: RangeInclusive<usize>) -> Option<usize> {
+    value
+        .and_then(|v| v.parse().ok())
+        .filter(|len| range.contains(len))
+}
+
+fn parse_prefix(value: &str) -> Option<Vec<(char, char)>> {
+    if value.is_empty() {
+        return Some(vec![]);
+    }
+    let value = value.strip_prefix('(')?;
+    let close_idx = value.find(')')?;
+    let modes = &value[..close_idx];
+    let prefixes = &value[close_idx + 1..];
+    if modes.chars().count() != prefixes.chars().count() {
+        return None;
+    }
+    Some(modes.chars().zip(prefixes.chars()).collect())
+}
+
+/// Values may contain `\xHH` escapes, see "Parameters" section of the RPL_ISUPPORT docs.
+fn unescape_value(value: &str) -> String {
+    let mut ret = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(idx) = rest.find("\\x") {
+        ret.push_str(&rest[..idx]);
+        match rest
+            .get(idx + 2..idx + 4)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            Some(byte) => {
+                ret.push(byte as char);
+                rest = &rest[idx + 4..];
+            }
+            None => {
+                ret.push_str("\\x");
+                rest = &rest[idx + 2..];
+            }
+        }
+    }
+    ret.push_str(rest);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(tokens: &[&str]) -> Vec<String> {
+        let mut params = vec!["tiny".to_owned()];
+        params.extend(tokens.iter().map(|s| (*s).to_owned()));
+        params.push("are supported by this server".to_owned());
+        params
+    }
+
+    #[test]
+    fn freenode_005() {
+        let mut isupport = ISupport::default();
+        isupport.update(&params(&[
+            "CHANTYPES=#",
+            "EXCEPTS",
+            "INVEX",
+            "CHANMODES=eIbq,k,flj,CFLMPQScgimnprstz",
+            "CHANLIMIT=#:120",
+            "PREFIX=(ov)@+",
+            "MAXLIST=bqeI:100",
+            "MODES=4",
+            "NETWORK=freenode",
+            "STATUSMSG=@+",
+            "CALLERID=g",
+            "CASEMAPPING=rfc1459",
+        ]));
+        isupport.update(&params(&[
+            "CHARSET=ascii",
+            "NICKLEN=16",
+            "CHANNELLEN=50",
+            "TOPICLEN=390",
+            "DEAF=D",
+            "FNC",
+            "TARGMAX=NAMES:1,LIST:1,KICK:1,WHOIS:1,PRIVMSG:4,NOTICE:4,ACCEPT:,MONITOR:",
+            "EXTBAN=$,ajrxz",
+            "CLIENTVER=3.0",
+            "WHOX",
+            "KNOCK",
+            "ETRACE",
+        ]));
+
+        assert_eq!(isupport.chantypes, "#");
+        assert_eq!(
+            isupport.chanmodes,
+            ChanModes {
+                a: "eIbq".to_owned(),
+                b: "k".to_owned(),
+                c: "flj".to_owned(),
+                d: "CFLMPQScgimnprstz".to_owned(),
+            }
+        );
+        assert_eq!(isupport.prefix, vec![('o', '@'), ('v', '+')]);
+        assert_eq!(isupport.maxlist, vec![("bqeI".to_owned(), 100)]);
+        assert_eq!(isupport.modes, Some(4));
+        assert_eq!(isupport.network, Some("freenode".to_owned()));
+        assert_eq!(isupport.statusmsg, "@+");
+        assert_eq!(isupport.casemapping, CaseMapping::Rfc1459);
+        assert_eq!(isupport.nicklen, Some(16));
+        assert_eq!(isupport.topiclen, Some(390));
+        assert_eq!(isupport.max_targets("PRIVMSG"), Some(4));
+        assert_eq!(isupport.max_targets("ACCEPT"), None);
+        assert_eq!(isupport.max_targets("JOIN"), None);
+        assert_eq!(isupport.other.get("EXCEPTS"), Some(&None));
+        assert_eq!(
+            isupport.other.get("CHANLIMIT"),
+            Some(&Some("#:120".to_owned()))
+        );
+        assert!(isupport.other.contains_key("WHOX"));
+    }
+
+    #[test]
+    fn nick_prefixes() {
+        let mut isupport = ISupport::default();
+        isupport.update(&params(&["PREFIX=(qaohv)~&@%+"]));
+        assert_eq!(isupport.strip_nick_prefix("@+nick"), "nick");
+        assert_eq!(isupport.strip_nick_prefix("~nick"), "nick");
+        assert_eq!(isupport.strip_nick_prefix("nick"), "nick");
+        assert_eq!(isupport.split_nick_prefix("@%nick"), ("@%", "nick"));
+        assert_eq!(isupport.prefix_mode('%'), Some('h'));
+        assert_eq!(isupport.prefix_mode('!'), None);
+        assert!(isupport.chan_mode_takes_arg('h', false));
+
+        // Default prefixes don't include '%'
+        assert_eq!(ISupport::default().strip_nick_prefix("%nick"), "%nick");
+    }
+
+    #[test]
+    fn chan_detection() {
+        let mut isupport = ISupport::default();
+        assert!(isupport.is_chan("#chan"));
+        assert!(isupport.is_chan("&chan"));
+        assert!(!isupport.is_chan("nick"));
+        assert!(!isupport.is_chan(""));
+
+        isupport.update(&params(&["CHANTYPES=#!", "STATUSMSG=@+"]));
+        assert!(isupport.is_chan("!chan"));
+        assert!(!isupport.is_chan("&chan"));
+        assert_eq!(isupport.split_statusmsg("@#chan"), Some(("@", "#chan")));
+        assert_eq!(isupport.split_statusmsg("#chan"), Some(("", "#chan")));
+        assert_eq!(isupport.split_statusmsg("@nick"), None);
+
+        // Empty CHANTYPES means no channels
+        isupport.update(&params(&["CHANTYPES="]));
+        assert!(!isupport.is_chan("#chan"));
+    }
+
+    #[test]
+    fn chan_mode_args() {
+        let isupport = ISupport::default();
+        assert!(isupport.chan_mode_takes_arg('b', true));
+        assert!(isupport.chan_mode_takes_arg('b', false));
+        assert!(isupport.chan_mode_takes_arg('k', false));
+        assert!(isupport.chan_mode_takes_arg('l', true));
+        assert!(!isupport.chan_mode_takes_arg('l', false));
+        assert!(!isupport.chan_mode_takes_arg('n', true));
+    }
+
+    #[test]
+    fn negation_and_escapes() {
+        let mut isupport = ISupport::default();
+        isupport.update(&params(&[
+            "NETWORK=Example\\x20Net",
+            "MODES",
+            "FOO=bar",
+            "CASEMAPPING=ascii",
+        ]));
+        assert_eq!(isupport.casemapping, CaseMapping::Ascii);
+        assert_eq!(isupport.network, Some("Example Net".to_owned()));
+        assert_eq!(isupport.modes, None);
+        assert_eq!(isupport.other.get("FOO"), Some(&Some("bar".to_owned())));
+
+        isupport.update(&params(&["-NETWORK", "-MODES", "-FOO"]));
+        assert_eq!(isupport.network, None);
+        assert_eq!(isupport.modes, Some(3));
+        assert!(!isupport.other.contains_key("FOO"));
+
+        assert_eq!(unescape_value("a\\x3Db\\x"), "a=b\\x");
+    }
+
+    #[test]
+    fn out_of_range_lens() {
+        let mut isupport = ISupport::default();
+        isupport.update(&params(&["LINELEN=1024", "USERLEN=12", "HOSTLEN=64"]));
+        assert_eq!(isupport.linelen, 1024);
+        assert_eq!(isupport.userlen, Some(12));
+        assert_eq!(isupport.hostlen, Some(64));
+
+        isupport.update(&params(&["LINELEN=100", "USERLEN=1000", "HOSTLEN=600"]));
+        assert_eq!(isupport.linelen, 512);
+        assert_eq!(isupport.userlen, None);
+        assert_eq!(isupport.hostlen, None);
+
+        isupport.update(&params(&["LINELEN=99999999", "USERLEN=0", "HOSTLEN=-1"]));
+        assert_eq!(isupport.linelen, 512);
+        assert_eq!(isupport.userlen, None);
+        assert_eq!(isupport.hostlen, None);
+    }
+}
@@ -5,6 +5,23 @@
 //! This library is for implementing clients rather than servers or services, and does not support
 //! the IRC message format in full generality.
 
+mod ctcp;
+pub mod formatting;
+mod hostmask;
+mod isupport;
+mod msg_ref;
+pub mod numeric;
+mod serialize;
+
+pub use ctcp::{CTCP, DCC};
+pub use encoding_rs::{EncoderResult, Encoding};
+pub use hostmask::{wildcard_match, Hostmask};
+pub use isupport::{ChanModes, ISupport};
+pub use msg_ref::{CTCPRef, CmdRef, CommaList, MsgRef, MsgTargetRef, Params, PfxRef, TagsRef};
+pub use numeric::Numeric;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::str;
 
 use libtiny_common::{ChanName, ChanNameRef};
@@ -45,10 +62,97 @@ where
     format!("JOIN {}\r\n", chans.join(","))
 }
 
+/// Generate a JOIN message for channels with optional keys. Keys are matched with channels by
+/// position, so channels with keys are moved to the front of the list.
+pub fn join_with_keys<'a, I>(chans: I) -> String
+where
+    I: Iterator<Item = (&'a ChanNameRef, Option<&'a str>)> + 'a,
+{
+    let (with_keys, without_keys): (Vec<_>, Vec<_>) = chans.partition(|(_, key)| key.is_some());
+    if with_keys.is_empty() {
+        return join(without_keys.into_iter().map(|(chan, _)| chan));
+    }
+    let keys = with_keys
+        .iter()
+        .filter_map(|(_, key)| *key)
+        .collect::<Vec<_>>();
+    let chans = with_keys
+        .iter()
+        .chain(without_keys.iter())
+        .map(|(chan, _)| chan.display())
+        .collect::<Vec<_>>();
+    format!("JOIN {} {}\r\n", chans.join(","), keys.join(","))
+}
+
 pub fn part(chan: &ChanNameRef) -> String {
     format!("PART {}\r\n", chan.display())
 }
 
+pub fn kick(chan: &ChanNameRef, nick: &str, reason: Option<&str>) -> String {
+    match reason {
+        None => format!("KICK {} {}\r\n", chan.display(), nick),
+        Some(reason) => format!("KICK {} {} :{}\r\n", chan.display(), nick, reason),
+    }
+}
+
+/// Generate a MODE message. When `changes` is empty this queries the current modes of the target.
+pub fn mode(target: &str, changes: &[ModeChange]) -> String {
+    if changes.is_empty() {
+        format!("MODE {}\r\n", target)
+    } else {
+        format!("MODE {} {}\r\n", target, mode_str(changes))
+    }
+}
+
+/// Render mode changes as a mode string followed by the arguments, e.g. `+ov-k a b *`.
+pub fn mode_str(changes: &[ModeChange]) -> String {
+    let mut mode_str = String::new();
+    let mut args: Vec<&str> = vec![];
+    let mut last_sign: Option<bool> = None;
+    for change in changes {
+        if last_sign != Some(change.set) {
+            mode_str.push(if change.set { '+' } else { '-' });
+            last_sign = Some(change.set);
+        }
+        mode_str.push(change.mode);
+        if let Some(ref arg) = change.arg {
+            args.push(arg);
+        }
+    }
+
+    for arg in args {
+        mode_str.push(' ');
+        mode_str.push_str(arg);
+    }
+
+    mode_str
+}
+
+pub fn invite(nick: &str, chan: &ChanNameRef) -> String {
+    format!("INVITE {} {}\r\n", nick, chan.display())
+}
+
+pub fn whois(nick: &str) -> String {
+    format!("WHOIS {}\r\n", nick)
+}
+
+pub fn who(mask: &str) -> String {
+    format!("WHO {}\r\n", mask)
+}
+
+/// `filter` is a comma-separated list of channels, or a server-specific filter (see `ELIST` in
+/// RPL_ISUPPORT).
+pub fn list(filter: Option<&str>) -> String {
+    match filter {
+        None => "LIST\r\n".to_string(),
+        Some(filter) => format!("LIST {}\r\n", filter),
+    }
+}
+
+pub fn names(chan: &ChanNameRef) -> String {
+    format!("NAMES {}\r\n", chan.display())
+}
+
 pub fn privmsg(msgtarget: &str, msg: &str) -> String {
     // IRC messages need to be shorter than 512 bytes (see RFC 1459 or 2812). This should be dealt
     // with at call sites as we can't show how we split messages into multiple messages in the UI
@@ -62,6 +166,16 @@ pub fn action(msgtarget: &str, msg: &str) -> String {
     format!("PRIVMSG {} :\x01ACTION {}\x01\r\n", msgtarget, msg)
 }
 
+/// Send a CTCP query. `args` is added after the CTCP command when not empty.
+pub fn ctcp_query(msgtarget: &str, ctcp: &CTCP, args: &str) -> String {
+    format!("PRIVMSG {} :{}\r\n", msgtarget, ctcp::ctcp_msg(ctcp, args))
+}
+
+/// Send a CTCP reply. `args` is added after the CTCP command when not empty.
+pub fn ctcp_reply(msgtarget: &str, ctcp: &CTCP, args: &str) -> String {
+    format!("NOTICE {} :{}\r\n", msgtarget, ctcp::ctcp_msg(ctcp, args))
+}
+
 pub fn away(msg: Option<&str>) -> String {
     match msg {
         None => "AWAY\r\n".to_string(),
@@ -69,8 +183,9 @@ pub fn away(msg: Option<&str>) -> String {
     }
 }
 
+/// `CAP LS` with version 302, to get capability values and `CAP NEW`/`CAP DEL` notifications.
 pub fn cap_ls() -> String {
-    "CAP LS\r\n".to_string()
+    "CAP LS 302\r\n".to_string()
 }
 
 pub fn cap_req(cap_identifiers: &[&str]) -> String {
@@ -85,6 +200,42 @@ pub fn authenticate(msg: &str) -> String {
     format!("AUTHENTICATE {}\r\n", msg)
 }
 
+/// `CHATHISTORY AFTER`: Request at most `limit` messages sent to `target` after `timestamp`, which
+/// should be in the format of `time` tags (ISO 8601, e.g. "2021-01-01T00:00:00.000Z").
+pub fn chathistory_after(target: &str, timestamp: &str, limit: usize) -> String {
+    format!(
+        "CHATHISTORY AFTER {} timestamp={} {}\r\n",
+        target, timestamp, limit
+    )
+}
+
+/// Add message tags to a message generated by one of the functions above. Tag values are escaped
+/// according to the IRCv3 message tags spec. Empty values are rendered as just the key.
+///
+/// Note that servers only relay client-only tags, which are tags with a `+` prefix (e.g.
+/// `+draft/reply`). Other tags are only meaningful when the server supports them.
+pub fn tagged(tags: &[(&str, &str)], msg: String) -> String {
+    if tags.is_empty() {
+        return msg;
+    }
+
+    let mut ret = String::with_capacity(msg.len() + 64);
+    ret.push('@');
+    for (tag_idx, (key, value)) in tags.iter().enumerate() {
+        if tag_idx != 0 {
+            ret.push(';');
+        }
+        ret.push_str(key);
+        if !value.is_empty() {
+            ret.push('=');
+            escape_tag_value(value, &mut ret);
+        }
+    }
+    ret.push(' ');
+    ret.push_str(&msg);
+    ret
+}
+
 /// Sender of a message ("prefix" in the RFC). Instead of returning a `String` we parse prefix part
 /// of the message according to the RFC because users of this library sometimes need to distinguish
 /// a server from a user. For example, in tiny if a PRIVMSG to us is coming from a server then we
@@ -113,7 +264,7 @@ pub enum Pfx {
     User {
         /// Nick of the sender
         nick: String,
-        /// `user@host` part
+        /// `user@host` part. Use `Pfx::hostmask` to split it into parts.
         user: String,
     },
 
@@ -144,32 +295,6 @@ impl Pfx {
 }
 */
 
-// RFC 2812 section 2.3.1
-fn parse_pfx(pfx: &str) -> Pfx {
-    match pfx.find(&['!', '@'][..]) {
-        Some(idx) => Pfx::User {
-            nick: (&pfx[0..idx]).to_owned(),
-            user: (&pfx[idx + 1..]).to_owned(),
-        },
-        None => {
-            // Chars that nicks can have but servernames cannot
-            match pfx.find(&['[', ']', '\\', '`', '_', '^', '{', '|', '}'][..]) {
-                Some(_) => Pfx::User {
-                    nick: pfx.to_owned(),
-                    user: "".to_owned(),
-                },
-                None => {
-                    // Nicks can't have '.'
-                    match pfx.find('.') {
-                        Some(_) => Pfx::Server(pfx.to_owned()),
-                        None => Pfx::Ambiguous(pfx.to_owned()),
-                    }
-                }
-            }
-        }
-    }
-}
-
 /// Target of a message
 ///
 /// Masks are not parsed, as rules for masks are not clear in RFC 2818 (for example, `#x.y` can be
@@ -183,9 +308,17 @@ pub enum MsgTarget {
     User(String),
 }
 
+/// IRCv3 message tags. Values are unescaped. A tag without a value (e.g. `@foo`) is mapped to an
+/// empty string, as the spec says empty and missing values are equivalent.
+///
+/// See https://ircv3.net/specs/extensions/message-tags
+pub type Tags = HashMap<String, String>;
+
 /// An IRC message
 #[derive(Debug, PartialEq, Eq)]
 pub struct Msg {
+    /// IRCv3 message tags. Empty when the server doesn't send any tags.
+    pub tags: Tags,
     /// Sender of a message. According to RFC 2812 it's optional:
     ///
     /// > If the prefix is missing from the message, it is assumed to have originated from the
@@ -194,46 +327,35 @@ pub struct Msg {
     pub cmd: Cmd,
 }
 
-/// A client-to-client protocol message. See https://defs.ircdocs.horse/defs/ctcp.html
-#[derive(Debug, PartialEq, Eq)]
-pub enum CTCP {
-    Version,
-    Action,
-    Other(String),
-}
-
-impl CTCP {
-    fn parse(s: &str) -> CTCP {
-        match s {
-            "VERSION" => CTCP::Version,
-            "ACTION" => CTCP::Action,
-            _ => CTCP::Other(s.to_owned()),
-        }
-    }
-}
-
 /// An IRC command or reply
 #[derive(Debug, PartialEq, Eq)]
 pub enum Cmd {
     /// A PRIVMSG or NOTICE. Check `is_notice` field.
     PRIVMSG {
-        // TODO: In theory this should be a list of targets, but in practice I've never
-        // encountered that case.
-        target: MsgTarget,
+        /// Targets of the message. Usually there's only one target, but the message can be sent
+        /// to a comma-separated list of targets.
+        targets: Vec<MsgTarget>,
         msg: String,
         is_notice: bool,
         ctcp: Option<CTCP>,
     },
 
     JOIN {
-        // TODO: Same as above, this should be a list ...
-        chan: ChanName, // TODO: key field might be useful when joining restricted channels. In
-                        // practice I've never needed it.
+        /// Channels joined. Servers may send a list of channels, e.g. on bouncer attach.
+        chans: Vec<ChanName>,
+        /// Channel keys. `keys[i]` is the key of `chans[i]`, empty when the channel doesn't have
+        /// a key. There may be less keys than channels; the rest of the channels don't have keys.
+        keys: Vec<String>,
+        /// With `extended-join`: services account of the user, `None` when the user is not logged
+        /// in.
+        account: Option<String>,
+        /// With `extended-join`: realname of the user. Always set in extended JOINs, so this can
+        /// be used to tell whether `account` is sent.
+        realname: Option<String>,
     },
 
     PART {
-        // TODO: List of channels
-        chan: ChanName,
+        chans: Vec<ChanName>,
         msg: Option<String>,
     },
 
@@ -270,16 +392,91 @@ pub enum Cmd {
         topic: String,
     },
 
+    /// A user was kicked from a channel. The user who kicked is the message prefix.
+    KICK {
+        chan: ChanName,
+        /// The user who was kicked
+        nick: String,
+        /// Kick reason
+        msg: Option<String>,
+    },
+
+    /// A channel or user mode change. The user (or server) that changed the modes is the message
+    /// prefix.
+    MODE {
+        target: MsgTarget,
+        /// Mode changes, parsed assuming default channel modes (see
+        /// `default_chan_mode_takes_arg`)
+        changes: Vec<ModeChange>,
+        /// Mode string as sent by the server, e.g. `+o-v`. Use with `args` to parse the changes
+        /// again with the server's `CHANMODES` and `PREFIX`.
+        modes: String,
+        /// Mode arguments as sent by the server
+        args: Vec<String>,
+    },
+
+    /// We (or, with `invite-notify`, someone else) were invited to a channel. The user who sent
+    /// the invite is the message prefix.
+    INVITE {
+        /// The user who was invited
+        nick: String,
+        chan: ChanName,
+    },
+
+    WALLOPS {
+        msg: String,
+    },
+
+    /// With `away-notify`: a user in one of our channels is away (`msg` is the away message), or
+    /// back (`msg` is `None`). The user is the message prefix.
+    AWAY {
+        msg: Option<String>,
+    },
+
+    /// With `account-notify`: a user in one of our channels logged in to a services account, or
+    /// logged out (`account` is `None`). The user is the message prefix.
+    ACCOUNT {
+        account: Option<String>,
+    },
+
+    /// With `chghost`: username or hostname of a user in one of our channels changed. The user is
+    /// the message prefix, with the old username and hostname.
+    CHGHOST {
+        user: String,
+        host: String,
+    },
+
+    /// With `setname`: realname of a user in one of our channels (or ours) changed. The user is
+    /// the message prefix.
+    SETNAME {
+        realname: String,
+    },
+
     CAP {
         client: String,
         subcommand: String,
+        /// Capabilities, with values (e.g. "sasl=PLAIN,EXTERNAL") in `LS` and `NEW` replies
         params: Vec<String>,
+        /// `LS` and `LIST` replies can span multiple messages; this is set in all but the last
+        /// message of a reply.
+        more: bool,
     },
 
     AUTHENTICATE {
         param: String,
     },
 
+    /// Start (`start` is `true`) or end of a batch. Messages in the batch have a `batch` tag with
+    /// the reference. See https://ircv3.net/specs/extensions/batch
+    BATCH {
+        /// Reference of the batch, without the `+` or `-` prefix
+        reference: String,
+        start: bool,
+        /// Type of the batch (e.g. "chathistory") followed by its parameters. Empty at the end of a
+        /// batch.
+        params: Vec<String>,
+    },
+
     /// An IRC message other than the ones listed above.
     Other {
         cmd: String,
@@ -294,19 +491,78 @@ pub enum Cmd {
     },
 }
 
-/// An intermediate type used during parsing.
-enum MsgType<'a> {
-    Cmd(&'a str),
-    Num(u16),
+/// A single mode change in a MODE message. For example, `MODE #chan +o-v a b` has two changes:
+/// `+o a` and `-v b`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ModeChange {
+    /// `true` for `+`, `false` for `-`.
+    pub set: bool,
+    pub mode: char,
+    pub arg: Option<String>,
+}
+
+/// Parse a mode string (e.g. `+o-v`) and its arguments into a list of mode changes.
+/// `takes_arg(mode, set)` should return whether the mode takes an argument when set (`set` is
+/// `true`) or unset.
+///
+/// Modes without a leading `+` or `-` are considered to be set. Missing arguments are `None`,
+/// extra arguments are ignored.
+pub fn parse_mode_changes<'a, I, F>(mode_str: &str, args: I, takes_arg: F) -> Vec<ModeChange>
+where
+    I: IntoIterator<Item = &'a str>,
+    F: Fn(char, bool) -> bool,
+{
+    let mut args = args.into_iter();
+    let mut set = true;
+    let mut changes = vec![];
+    for c in mode_str.chars() {
+        match c {
+            '+' => set = true,
+            '-' => set = false,
+            _ => {
+                let arg = if takes_arg(c, set) {
+                    args.next().map(str::to_owned)
+                } else {
+                    None
+                };
+                changes.push(ModeChange { set, mode: c, arg });
+            }
+        }
+    }
+    changes
+}
+
+/// Whether a channel mode takes an argument, when we don't know what the server supports. Covers
+/// list modes (`beIq`), membership prefix modes (`qaohv`), the key (`k`) and the limit (`l`, only
+/// when set).
+pub fn default_chan_mode_takes_arg(mode: char, set: bool) -> bool {
+    match mode {
+        'b' | 'e' | 'I' | 'q' | 'a' | 'o' | 'h' | 'v' | 'k' => true,
+        'l' => set,
+        _ => false,
+    }
 }
 
 static CRLF: [u8; 2] = [b'\r', b'\n'];
 
 /// Try to read an IRC message off a buffer. Drops the message when parsing is successful.
 /// Otherwise the buffer is left unchanged.
+///
+/// Invalid UTF-8 sequences in the message are replaced with U+FFFD REPLACEMENT CHARACTER. Use
+/// `parse_irc_msg_with_fallback` to decode such messages with another encoding.
 pub fn parse_irc_msg(buf: &mut Vec<u8>) -> Option<Result<Msg, String>> {
-    // Find "\r\n" separator. We can't do this *after* generating the lossy UTF-8, as that may have
-    // different size than the original buffer after inserting "REPLACEMENT CHARACTER"s.
+    parse_irc_msg_with_fallback(buf, None)
+}
+
+/// Like `parse_irc_msg`, but messages that are not valid UTF-8 are decoded with the `fallback`
+/// encoding (e.g. windows-1252 on networks with latin-1 users). Lossy UTF-8 is used when
+/// `fallback` is `None`.
+pub fn parse_irc_msg_with_fallback(
+    buf: &mut Vec<u8>,
+    fallback: Option<&'static Encoding>,
+) -> Option<Result<Msg, String>> {
+    // Find "\r\n" separator. We can't do this *after* decoding, as that may have different size
+    // than the original buffer after inserting "REPLACEMENT CHARACTER"s.
     let crlf_idx = {
         match buf.windows(2).position(|sub| sub == CRLF) {
             None => return None,
@@ -314,209 +570,97 @@ pub fn parse_irc_msg(buf: &mut Vec<u8>) -> Option<Result<Msg, String>> {
         }
     };
 
-    let msg_owned: String = String::from_utf8_lossy(&buf[0..crlf_idx]).to_string();
-    let msg: &str = &msg_owned;
-
-    let ret = parse_one_message(msg);
+    let ret = parse_irc_line(&buf[0..crlf_idx], fallback);
     buf.drain(0..crlf_idx + 2);
 
     Some(ret)
 }
 
-// NB. 'msg' does not contain '\r\n' suffix.
-fn parse_one_message(mut msg: &str) -> Result<Msg, String> {
-    let pfx: Option<Pfx> = {
-        if let Some(':') = msg.chars().next() {
-            // parse prefix
-            let ws_idx = msg.find(' ').ok_or(format!(
-                "Can't find prefix terminator (' ') in msg: {:?}",
-                msg
-            ))?;
-            let pfx = &msg[1..ws_idx]; // consume ':'
-            msg = &msg[ws_idx + 1..]; // consume ' '
-            Some(parse_pfx(pfx))
-        } else {
-            None
-        }
-    };
-
-    let msg_ty: MsgType = {
-        let ws_idx = msg.find(' ').ok_or(format!(
-            "Can't find message type terminator (' ') in msg: {:?}",
-            msg
-        ))?;
-        let cmd = &msg[..ws_idx];
-        msg = &msg[ws_idx + 1..]; // Consume ' '
-        match cmd.parse::<u16>() {
-            Ok(num) => MsgType::Num(num),
-            Err(_) => MsgType::Cmd(cmd),
+/// Parse a single message without the "\r\n" suffix. Lines that are not valid UTF-8 are decoded
+/// with the `fallback` encoding, or with lossy UTF-8 when `fallback` is `None`.
+pub fn parse_irc_line(line: &[u8], fallback: Option<&'static Encoding>) -> Result<Msg, String> {
+    // Only allocates when the message is not valid UTF-8
+    let msg = match str::from_utf8(line) {
+        Ok(msg) => Cow::Borrowed(msg),
+        Err(_) => {
+            fallback
+                .unwrap_or(encoding_rs::UTF_8)
+                .decode_without_bom_handling(line)
+                .0
         }
     };
+    MsgRef::parse_str(&msg).map(|msg| msg.to_owned())
+}
 
-    let params = parse_params(msg);
-    let cmd = match msg_ty {
-        MsgType::Cmd("PRIVMSG") | MsgType::Cmd("NOTICE") if params.len() == 2 => {
-            let is_notice = matches!(msg_ty, MsgType::Cmd("NOTICE"));
-            let target = params[0];
-            let mut msg = params[1];
-            let target = if target.starts_with('#') {
-                MsgTarget::Chan(ChanName::new(target.to_owned()))
-            } else {
-                MsgTarget::User(target.to_owned())
-            };
-
-            let mut ctcp: Option<CTCP> = None;
-            if !msg.is_empty() && msg.as_bytes()[0] == 0x01 {
-                // Drop 0x01
-                msg = &msg[1..];
-                // Parse message type
-                for (byte_idx, byte) in msg.as_bytes().iter().enumerate() {
-                    if *byte == 0x01 {
-                        let ctcp_type = &msg[0..byte_idx];
-                        ctcp = Some(CTCP::parse(ctcp_type));
-                        msg = &msg[byte_idx + 1..];
-                        break;
-                    } else if *byte == b' ' {
-                        let ctcp_type = &msg[0..byte_idx];
-                        ctcp = Some(CTCP::parse(ctcp_type));
-                        msg = &msg[byte_idx + 1..];
-                        if !msg.is_empty() && msg.as_bytes()[msg.len() - 1] == 0x01 {
-                            msg = &msg[..msg.len() - 1];
-                        }
-                        break;
-                    }
-                }
-            }
-
-            Cmd::PRIVMSG {
-                target,
-                msg: msg.to_owned(),
-                is_notice,
-                ctcp,
-            }
+// https://ircv3.net/specs/extensions/message-tags#format
+//
+//     <tags>          ::= <tag> [';' <tag>]*
+//     <tag>           ::= <key> ['=' <escaped_value>]
+//
+// When a key is repeated the last value is used.
+fn parse_tags(tags_str: &str) -> Tags {
+    let mut tags = HashMap::new();
+    for tag in tags_str.split(';') {
+        if tag.is_empty() {
+            continue;
         }
-        MsgType::Cmd("JOIN") if params.len() == 1 => {
-            let chan = params[0];
-            Cmd::JOIN {
-                chan: ChanName::new(chan.to_owned()),
+        match tag.find('=') {
+            None => {
+                tags.insert(tag.to_owned(), String::new());
             }
-        }
-        MsgType::Cmd("PART") if params.len() == 1 || params.len() == 2 => {
-            let mb_msg = if params.len() == 2 {
-                Some(params[1].to_owned())
-            } else {
-                None
-            };
-            Cmd::PART {
-                chan: ChanName::new(params[0].to_owned()),
-                msg: mb_msg,
+            Some(eq_idx) => {
+                tags.insert(
+                    tag[..eq_idx].to_owned(),
+                    unescape_tag_value(&tag[eq_idx + 1..]),
+                );
             }
         }
-        MsgType::Cmd("QUIT") if params.is_empty() || params.len() == 1 => {
-            let mb_msg = params.get(1).map(|s| (*s).to_owned());
+    }
+    tags
+}
 
-            Cmd::QUIT {
-                msg: mb_msg,
-                chans: Vec::new(),
+/// Unescape a tag value. See "Escaping values" in the message tags spec. Invalid escapes drop
+/// the backslash, and a trailing backslash is removed.
+fn unescape_tag_value(value: &str) -> String {
+    let mut ret = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some(':') => ret.push(';'),
+                Some('s') => ret.push(' '),
+                Some('\\') => ret.push('\\'),
+                Some('r') => ret.push('\r'),
+                Some('n') => ret.push('\n'),
+                Some(c) => ret.push(c),
+                None => {}
             }
+        } else {
+            ret.push(c);
         }
-        MsgType::Cmd("NICK") if params.len() == 1 => {
-            let nick = params[0];
-            Cmd::NICK {
-                nick: nick.to_owned(),
-                chans: Vec::new(),
-            }
-        }
-        MsgType::Cmd("PING") if params.len() == 1 => Cmd::PING {
-            server: params[0].to_owned(),
-        },
-        MsgType::Cmd("PONG") if !params.is_empty() => Cmd::PONG {
-            server: params[0].to_owned(),
-        },
-        MsgType::Cmd("ERROR") if params.len() == 1 => Cmd::ERROR {
-            msg: params[0].to_owned(),
-        },
-        MsgType::Cmd("TOPIC") if params.len() == 2 => Cmd::TOPIC {
-            chan: ChanName::new(params[0].to_owned()),
-            topic: params[1].to_owned(),
-        },
-        MsgType::Cmd("CAP") if params.len() == 3 => Cmd::CAP {
-            client: params[0].to_owned(),
-            subcommand: params[1].to_owned(),
-            params: params[2].split(' ').map(|s| s.to_owned()).collect(),
-        },
-        MsgType::Cmd("AUTHENTICATE") if params.len() == 1 => Cmd::AUTHENTICATE {
-            param: params[0].to_owned(),
-        },
-        MsgType::Num(n) => Cmd::Reply {
-            num: n,
-            params: params.into_iter().map(|s| s.to_owned()).collect(),
-        },
-        MsgType::Cmd(cmd) => Cmd::Other {
-            cmd: cmd.to_owned(),
-            params: params.into_iter().map(|s| s.to_owned()).collect(),
-        },
-    };
-
-    Ok(Msg { pfx, cmd })
-}
-
-fn parse_params(chrs: &str) -> Vec<&str> {
-    // Spec:
-    //
-    //     params     =  *14( SPACE middle ) [ SPACE ":" trailing ]
-    //                =/ 14( SPACE middle ) [ SPACE [ ":" ] trailing ]
-    //
-    //     nospcrlfcl =  %x01-09 / %x0B-0C / %x0E-1F / %x21-39 / %x3B-FF
-    //                     ; any octet except NUL, CR, LF, " " and ":"
-    //     middle     =  nospcrlfcl *( ":" / nospcrlfcl )
-    //     trailing   =  *( ":" / " " / nospcrlfcl )
-    //
-    // The RFC doesn't explain the syntax with `14` here as if it's something standard. I'm
-    // guessing it's number of repetitions, and `*14` means "14 or less" repetitions.
-
-    let mut params = Vec::new();
-    let mut char_indices = chrs.char_indices();
-
-    while let Some((idx, c)) = char_indices.next() {
-        if c == ':' {
-            params.push(&chrs[idx + 1..]); // Skip ':'
-            break;
-        }
-
-        if params.len() == 14 {
-            params.push(&chrs[idx..]);
-            break;
-        }
-
-        if c == ' ' {
-            continue;
-        }
+    }
+    ret
+}
 
-        loop {
-            match char_indices.next() {
-                Some((idx_, c)) => {
-                    if c == ' ' {
-                        params.push(&chrs[idx..idx_]);
-                        break;
-                    }
-                }
-                None => {
-                    params.push(&chrs[idx..]);
-                    break;
-                }
-            }
+fn escape_tag_value(value: &str, ret: &mut String) {
+    for c in value.chars() {
+        match c {
+            ';' => ret.push_str("\\:"),
+            ' ' => ret.push_str("\\s"),
+            '\\' => ret.push_str("\\\\"),
+            '\r' => ret.push_str("\\r"),
+            '\n' => ret.push_str("\\n"),
+            _ => ret.push(c),
         }
     }
-
-    params
 }
 
 /// Nicks may have prefixes, indicating it is a operator, founder, or something else.
 ///
 /// Channel Membership Prefixes: http://modern.ircdocs.horse/#channel-membership-prefixes
 ///
-/// Returns the nick without prefix.
+/// Returns the nick without prefix. This uses a fixed set of prefixes; use
+/// `ISupport::strip_nick_prefix` to use the prefixes advertised by the server.
 pub fn drop_nick_prefix(nick: &str) -> &str {
     static PREFIXES: [char; 5] = ['~', '&', '@', '%', '+'];
 
@@ -534,29 +678,29 @@ mod tests {
 
     #[test]
     fn test_parse_params() {
-        assert_eq!(parse_params("p1 p2 p3"), vec!["p1", "p2", "p3"]);
+        assert_eq!(Params::parse("p1 p2 p3"), vec!["p1", "p2", "p3"]);
         let empty: Vec<&str> = vec![];
-        assert_eq!(parse_params(""), empty);
-        assert_eq!(parse_params(":foo bar baz "), vec!["foo bar baz "]);
+        assert_eq!(Params::parse(""), empty);
+        assert_eq!(Params::parse(":foo bar baz "), vec!["foo bar baz "]);
         assert_eq!(
-            parse_params(":foo : bar : baz :"),
+            Params::parse(":foo : bar : baz :"),
             vec!["foo : bar : baz :"]
         );
-        assert_eq!(parse_params(":"), vec![""]);
-        assert_eq!(parse_params("x:"), vec!["x:"]);
-        assert_eq!(parse_params("x:y"), vec!["x:y"]);
-        assert_eq!(parse_params("x:y:z"), vec!["x:y:z"]);
-        assert_eq!(parse_params(":::::"), vec!["::::"]);
+        assert_eq!(Params::parse(":"), vec![""]);
+        assert_eq!(Params::parse("x:"), vec!["x:"]);
+        assert_eq!(Params::parse("x:y"), vec!["x:y"]);
+        assert_eq!(Params::parse("x:y:z"), vec!["x:y:z"]);
+        assert_eq!(Params::parse(":::::"), vec!["::::"]);
 
-        let params = parse_params("1 2 3 4 5 6 7 8 9 10 11 12 13 14 blah blah blah");
+        let params = Params::parse("1 2 3 4 5 6 7 8 9 10 11 12 13 14 blah blah blah");
         assert_eq!(params.len(), 15);
         assert_eq!(params[params.len() - 1], "blah blah blah");
 
-        assert_eq!(parse_params("   "), empty); // Not valid according to the RFC, I think
-        assert_eq!(parse_params(":  "), vec!["  "]);
-        assert_eq!(parse_params(": : :"), vec![" : :"]);
-        assert_eq!(parse_params("x y : : :"), vec!["x", "y", " : :"]);
-        assert_eq!(parse_params("aaa://aaa"), vec!["aaa://aaa"]);
+        assert_eq!(Params::parse("   "), empty); // Not valid according to the RFC, I think
+        assert_eq!(Params::parse(":  "), vec!["  "]);
+        assert_eq!(Params::parse(": : :"), vec![" : :"]);
+        assert_eq!(Params::parse("x y : : :"), vec!["x", "y", " : :"]);
+        assert_eq!(Params::parse("aaa://aaa"), vec!["aaa://aaa"]);
     }
 
     #[test]
@@ -570,12 +714,13 @@ mod tests {
         assert_eq!(
             parse_irc_msg(&mut buf).unwrap().unwrap(),
             Msg {
+                tags: HashMap::new(),
                 pfx: Some(Pfx::User {
                     nick: "nick".to_owned(),
                     user: "~nick@unaffiliated/nick".to_owned(),
                 }),
                 cmd: Cmd::PRIVMSG {
-                    target: MsgTarget::User("tiny".to_owned()),
+                    targets: vec![MsgTarget::User("tiny".to_owned())],
                     msg: "a b c".to_owned(),
                     is_notice: false,
                     ctcp: None,
@@ -596,9 +741,10 @@ mod tests {
         assert_eq!(
             parse_irc_msg(&mut buf).unwrap().unwrap(),
             Msg {
+                tags: HashMap::new(),
                 pfx: Some(Pfx::Server("barjavel.freenode.net".to_owned())),
                 cmd: Cmd::PRIVMSG {
-                    target: MsgTarget::User("*".to_owned()),
+                    targets: vec![MsgTarget::User("*".to_owned())],
                     msg: "*** Looking up your hostname...".to_owned(),
                     is_notice: true,
                     ctcp: None,
@@ -654,12 +800,13 @@ mod tests {
         assert_eq!(
             parse_irc_msg(&mut buf).unwrap().unwrap(),
             Msg {
+                tags: HashMap::new(),
                 pfx: Some(Pfx::User {
                     nick: "tiny".to_owned(),
                     user: "~tiny@123.123.123.123".to_owned(),
                 }),
                 cmd: Cmd::PART {
-                    chan: ChanName::new("#haskell".to_owned()),
+                    chans: vec![ChanName::new("#haskell".to_owned())],
                     msg: None,
                 },
             }
@@ -673,18 +820,64 @@ mod tests {
         assert_eq!(
             parse_irc_msg(&mut buf).unwrap().unwrap(),
             Msg {
+                tags: HashMap::new(),
                 pfx: Some(Pfx::User {
                     nick: "tiny".to_owned(),
                     user: "~tiny@192.168.0.1".to_owned(),
                 }),
                 cmd: Cmd::JOIN {
-                    chan: ChanName::new("#haskell".to_owned()),
+                    chans: vec![ChanName::new("#haskell".to_owned())],
+                    keys: vec![],
+                    account: None,
+                    realname: None,
                 },
             }
         );
         assert_eq!(buf.len(), 0);
     }
 
+    #[test]
+    fn test_join_list_parsing() {
+        let mut buf = vec![];
+        write!(
+            &mut buf,
+            ":tiny!~tiny@192.168.0.1 JOIN #a,#b,#c key1,,key3\r\n"
+        )
+        .unwrap();
+        assert_eq!(
+            parse_irc_msg(&mut buf).unwrap().unwrap().cmd,
+            Cmd::JOIN {
+                chans: vec![
+                    ChanName::new("#a".to_owned()),
+                    ChanName::new("#b".to_owned()),
+                    ChanName::new("#c".to_owned())
+                ],
+                keys: vec!["key1".to_owned(), "".to_owned(), "key3".to_owned()],
+                account: None,
+                realname: None,
+            }
+        );
+
+        write!(&mut buf, ":tiny!~tiny@192.168.0.1 PART #a,#b :bye\r\n").unwrap();
+        assert_eq!(
+            parse_irc_msg(&mut buf).unwrap().unwrap().cmd,
+            Cmd::PART {
+                chans: vec![
+                    ChanName::new("#a".to_owned()),
+                    ChanName::new("#b".to_owned())
+                ],
+                msg: Some("bye".to_owned()),
+            }
+        );
+
+        let chans = [
+            (ChanNameRef::new("#a"), None),
+            (ChanNameRef::new("#b"), Some("key")),
+        ];
+        assert_eq!(join_with_keys(chans.iter().copied()), "JOIN #b,#a key\r\n");
+        assert_eq!(join_with_keys(chans.iter().take(1).copied()), "JOIN #a\r\n");
+    }
+
     // Example from https://tools.ietf.org/id/draft-oakley-irc-ctcp-01.html
     #[test]
     fn test_ctcp_action_parsing_1() {
@@ -697,12 +890,13 @@ mod tests {
         assert_eq!(
             parse_irc_msg(&mut buf).unwrap().unwrap(),
             Msg {
+                tags: HashMap::new(),
                 pfx: Some(Pfx::User {
                     nick: "dan".to_owned(),
                     user: "u@localhost".to_owned(),
                 }),
                 cmd: Cmd::PRIVMSG {
-                    target: MsgTarget::Chan(ChanName::new("#ircv3".to_owned())),
+                    targets: vec![MsgTarget::Chan(ChanName::new("#ircv3".to_owned()))],
                     msg: "writes some specs!".to_owned(),
                     is_notice: false,
                     ctcp: Some(CTCP::Action),
@@ -728,7 +922,7 @@ mod tests {
         assert_eq!(
             parse_irc_msg(&mut buf).unwrap().unwrap().cmd,
             Cmd::PRIVMSG {
-                target: MsgTarget::User("target".to_owned()),
+                targets: vec![MsgTarget::User("target".to_owned())],
                 msg: "msg contents".to_owned(),
                 is_notice: false,
                 ctcp: Some(CTCP::Action),
@@ -741,7 +935,7 @@ mod tests {
         assert_eq!(
             parse_irc_msg(&mut buf).unwrap().unwrap().cmd,
             Cmd::PRIVMSG {
-                target: MsgTarget::User("target".to_owned()),
+                targets: vec![MsgTarget::User("target".to_owned())],
                 msg: "".to_owned(),
                 is_notice: false,
                 ctcp: Some(CTCP::Action),
@@ -756,7 +950,7 @@ mod tests {
         assert_eq!(
             parse_irc_msg(&mut buf).unwrap().unwrap().cmd,
             Cmd::PRIVMSG {
-                target: MsgTarget::User("target".to_owned()),
+                targets: vec![MsgTarget::User("target".to_owned())],
                 msg: "’’’’’’’".to_owned(),
                 is_notice: false,
                 ctcp: None,
@@ -772,7 +966,7 @@ mod tests {
         assert_eq!(
             parse_irc_msg(&mut buf).unwrap().unwrap().cmd,
             Cmd::PRIVMSG {
-                target: MsgTarget::User("target".to_owned()),
+                targets: vec![MsgTarget::User("target".to_owned())],
                 msg: "".to_owned(),
                 is_notice: false,
                 ctcp: Some(CTCP::Version),
@@ -784,7 +978,7 @@ mod tests {
         assert_eq!(
             parse_irc_msg(&mut buf).unwrap().unwrap().cmd,
             Cmd::PRIVMSG {
-                target: MsgTarget::User("target".to_owned()),
+                targets: vec![MsgTarget::User("target".to_owned())],
                 msg: "".to_owned(),
                 is_notice: false,
                 ctcp: Some(CTCP::Version),
@@ -792,6 +986,33 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_ctcp_ping_parsing() {
+        let mut buf = vec![];
+        write!(
+            &mut buf,
+            ":a!b@c NOTICE target :\x01PING 1234567890\x01\r\n"
+        )
+        .unwrap();
+        assert_eq!(
+            parse_irc_msg(&mut buf).unwrap().unwrap().cmd,
+            Cmd::PRIVMSG {
+                targets: vec![MsgTarget::User("target".to_owned())],
+                msg: "1234567890".to_owned(),
+                is_notice: true,
+                ctcp: Some(CTCP::Ping),
+            }
+        );
+        assert_eq!(
+            ctcp_reply("a", &CTCP::Ping, "1234567890"),
+            "NOTICE a :\x01PING 1234567890\x01\r\n"
+        );
+        assert_eq!(
+            ctcp_query("a", &CTCP::ClientInfo, ""),
+            "PRIVMSG a :\x01CLIENTINFO\x01\r\n"
+        );
+    }
+
     #[test]
     fn other_ctcp_parsing() {
         let mut buf = vec![];
@@ -799,7 +1020,7 @@ mod tests {
         assert_eq!(
             parse_irc_msg(&mut buf).unwrap().unwrap().cmd,
             Cmd::PRIVMSG {
-                target: MsgTarget::User("target".to_owned()),
+                targets: vec![MsgTarget::User("target".to_owned())],
                 msg: "blah ".to_owned(),
                 is_notice: false,
                 ctcp: Some(CTCP::Other("blah".to_owned())),
@@ -811,7 +1032,7 @@ mod tests {
         assert_eq!(
             parse_irc_msg(&mut buf).unwrap().unwrap().cmd,
             Cmd::PRIVMSG {
-                target: MsgTarget::User("target".to_owned()),
+                targets: vec![MsgTarget::User("target".to_owned())],
                 msg: "blah ".to_owned(),
                 is_notice: false,
                 ctcp: Some(CTCP::Other("blah".to_owned())),
@@ -819,6 +1040,33 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_encoding_fallback() {
+        let latin1 = Encoding::for_label(b"latin1").unwrap();
+
+        let mut buf = b":a!b@c PRIVMSG #chan :caf\xe9\r\n".to_vec();
+        let msg = parse_irc_msg_with_fallback(&mut buf, Some(latin1));
+        match msg.unwrap().unwrap().cmd {
+            Cmd::PRIVMSG { msg, .. } => assert_eq!(msg, "café"),
+            other => panic!("Unexpected cmd: {:?}", other),
+        }
+
+        // Valid UTF-8 is not decoded with the fallback encoding
+        let mut buf = ":a!b@c PRIVMSG #chan :café\r\n".as_bytes().to_vec();
+        let msg = parse_irc_msg_with_fallback(&mut buf, Some(latin1));
+        match msg.unwrap().unwrap().cmd {
+            Cmd::PRIVMSG { msg, .. } => assert_eq!(msg, "café"),
+            other => panic!("Unexpected cmd: {:?}", other),
+        }
+
+        // Lossy UTF-8 without a fallback
+        let mut buf = b":a!b@c PRIVMSG #chan :caf\xe9\r\n".to_vec();
+        match parse_irc_msg(&mut buf).unwrap().unwrap().cmd {
+            Cmd::PRIVMSG { msg, .. } => assert_eq!(msg, "caf\u{FFFD}"),
+            other => panic!("Unexpected cmd: {:?}", other),
+        }
+    }
+
     #[test]
     fn test_error_parsing() {
         let mut buf = vec![];
@@ -830,6 +1078,7 @@ mod tests {
         assert_eq!(
             parse_irc_msg(&mut buf).unwrap().unwrap(),
             Msg {
+                tags: HashMap::new(),
                 pfx: None,
                 cmd: Cmd::ERROR {
                     msg: "Closing Link: 212.252.143.51 (Excess Flood)".to_owned(),
@@ -838,32 +1087,367 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_kick_parsing() {
+        let mut buf = vec![];
+        write!(&mut buf, ":op!o@h KICK #chan victim :bye bye\r\n").unwrap();
+        assert_eq!(
+            parse_irc_msg(&mut buf).unwrap().unwrap().cmd,
+            Cmd::KICK {
+                chan: ChanName::new("#chan".to_owned()),
+                nick: "victim".to_owned(),
+                msg: Some("bye bye".to_owned()),
+            }
+        );
+
+        let mut buf = vec![];
+        write!(&mut buf, ":op!o@h KICK #chan victim\r\n").unwrap();
+        assert_eq!(
+            parse_irc_msg(&mut buf).unwrap().unwrap().cmd,
+            Cmd::KICK {
+                chan: ChanName::new("#chan".to_owned()),
+                nick: "victim".to_owned(),
+                msg: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_cap_parsing() {
+        let mut buf = vec![];
+        write!(
+            &mut buf,
+            ":irc.server CAP * LS * :multi-prefix sasl=PLAIN,EXTERNAL\r\n"
+        )
+        .unwrap();
+        assert_eq!(
+            parse_irc_msg(&mut buf).unwrap().unwrap().cmd,
+            Cmd::CAP {
+                client: "*".to_owned(),
+                subcommand: "LS".to_owned(),
+                params: vec!["multi-prefix".to_owned(), "sasl=PLAIN,EXTERNAL".to_owned()],
+                more: true,
+            }
+        );
+
+        let mut buf = vec![];
+        write!(&mut buf, ":irc.server CAP tiny ACK :sasl\r\n").unwrap();
+        assert_eq!(
+            parse_irc_msg(&mut buf).unwrap().unwrap().cmd,
+            Cmd::CAP {
+                client: "tiny".to_owned(),
+                subcommand: "ACK".to_owned(),
+                params: vec!["sasl".to_owned()],
+                more: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_presence_parsing() {
+        let mut buf = vec![];
+        write!(&mut buf, ":a!u@h JOIN #chan acc :Real Name\r\n").unwrap();
+        write!(&mut buf, ":a!u@h JOIN #chan * :Real Name\r\n").unwrap();
+        write!(&mut buf, ":a!u@h AWAY :gone fishing\r\n").unwrap();
+        write!(&mut buf, ":a!u@h AWAY\r\n").unwrap();
+        write!(&mut buf, ":a!u@h ACCOUNT acc\r\n").unwrap();
+        write!(&mut buf, ":a!u@h ACCOUNT *\r\n").unwrap();
+        write!(&mut buf, ":a!u@h CHGHOST user new.host\r\n").unwrap();
+        write!(&mut buf, ":a!u@h SETNAME :New Name\r\n").unwrap();
+
+        let mut cmds = vec![];
+        while let Some(msg) = parse_irc_msg(&mut buf) {
+            cmds.push(msg.unwrap().cmd);
+        }
+        assert_eq!(
+            cmds,
+            vec![
+                Cmd::JOIN {
+                    chans: vec![ChanName::new("#chan".to_owned())],
+                    keys: vec![],
+                    account: Some("acc".to_owned()),
+                    realname: Some("Real Name".to_owned()),
+                },
+                Cmd::JOIN {
+                    chans: vec![ChanName::new("#chan".to_owned())],
+                    keys: vec![],
+                    account: None,
+                    realname: Some("Real Name".to_owned()),
+                },
+                Cmd::AWAY {
+                    msg: Some("gone fishing".to_owned())
+                },
+                Cmd::AWAY { msg: None },
+                Cmd::ACCOUNT {
+                    account: Some("acc".to_owned())
+                },
+                Cmd::ACCOUNT { account: None },
+                Cmd::CHGHOST {
+                    user: "user".to_owned(),
+                    host: "new.host".to_owned()
+                },
+                Cmd::SETNAME {
+                    realname: "New Name".to_owned()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_batch_parsing() {
+        let mut buf = vec![];
+        write!(&mut buf, ":irc.server BATCH +abc chathistory #chan\r\n").unwrap();
+        write!(&mut buf, "@batch=abc :a!u@h PRIVMSG #chan :hi\r\n").unwrap();
+        write!(&mut buf, ":irc.server BATCH -abc\r\n").unwrap();
+
+        let msg = parse_irc_msg(&mut buf).unwrap().unwrap();
+        assert_eq!(
+            msg.cmd,
+            Cmd::BATCH {
+                reference: "abc".to_owned(),
+                start: true,
+                params: vec!["chathistory".to_owned(), "#chan".to_owned()],
+            }
+        );
+        let msg = parse_irc_msg(&mut buf).unwrap().unwrap();
+        assert_eq!(msg.tags.get("batch").map(String::as_str), Some("abc"));
+        let msg = parse_irc_msg(&mut buf).unwrap().unwrap();
+        assert_eq!(
+            msg.cmd,
+            Cmd::BATCH {
+                reference: "abc".to_owned(),
+                start: false,
+                params: vec![],
+            }
+        );
+        assert!(parse_irc_msg(&mut buf).is_none());
+    }
+
+    #[test]
+    fn test_mode_parsing() {
+        let mut buf = vec![];
+        write!(&mut buf, ":op!o@h MODE #chan +ol-v+tk a 10 b key\r\n").unwrap();
+        assert_eq!(
+            parse_irc_msg(&mut buf).unwrap().unwrap().cmd,
+            Cmd::MODE {
+                target: MsgTarget::Chan(ChanName::new("#chan".to_owned())),
+                changes: vec![
+                    ModeChange {
+                        set: true,
+                        mode: 'o',
+                        arg: Some("a".to_owned()),
+                    },
+                    ModeChange {
+                        set: true,
+                        mode: 'l',
+                        arg: Some("10".to_owned()),
+                    },
+                    ModeChange {
+                        set: false,
+                        mode: 'v',
+                        arg: Some("b".to_owned()),
+                    },
+                    ModeChange {
+                        set: true,
+                        mode: 't',
+                        arg: None,
+                    },
+                    ModeChange {
+                        set: true,
+                        mode: 'k',
+                        arg: Some("key".to_owned()),
+                    },
+                ],
+                modes: "+ol-v+tk".to_owned(),
+                args: vec![
+                    "a".to_owned(),
+                    "10".to_owned(),
+                    "b".to_owned(),
+                    "key".to_owned()
+                ],
+            }
+        );
+
+        let mut buf = vec![];
+        write!(&mut buf, ":tiny MODE tiny :+iw\r\n").unwrap();
+        assert_eq!(
+            parse_irc_msg(&mut buf).unwrap().unwrap().cmd,
+            Cmd::MODE {
+                target: MsgTarget::User("tiny".to_owned()),
+                changes: vec![
+                    ModeChange {
+                        set: true,
+                        mode: 'i',
+                        arg: None,
+                    },
+                    ModeChange {
+                        set: true,
+                        mode: 'w',
+                        arg: None,
+                    },
+                ],
+                modes: "+iw".to_owned(),
+                args: vec![],
+            }
+        );
+
+        // `-l` doesn't take an argument
+        assert_eq!(
+            parse_mode_changes("-l+b", vec!["*!*@*"], default_chan_mode_takes_arg),
+            vec![
+                ModeChange {
+                    set: false,
+                    mode: 'l',
+                    arg: None,
+                },
+                ModeChange {
+                    set: true,
+                    mode: 'b',
+                    arg: Some("*!*@*".to_owned()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invite_wallops_parsing() {
+        let mut buf = vec![];
+        write!(&mut buf, ":a!b@c INVITE tiny :#chan\r\n").unwrap();
+        assert_eq!(
+            parse_irc_msg(&mut buf).unwrap().unwrap().cmd,
+            Cmd::INVITE {
+                nick: "tiny".to_owned(),
+                chan: ChanName::new("#chan".to_owned()),
+            }
+        );
+
+        let mut buf = vec![];
+        write!(&mut buf, ":irc.x.y WALLOPS :server restarting\r\n").unwrap();
+        assert_eq!(
+            parse_irc_msg(&mut buf).unwrap().unwrap().cmd,
+            Cmd::WALLOPS {
+                msg: "server restarting".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_kick_mode_invite_generation() {
+        let chan = ChanNameRef::new("#c");
+        assert_eq!(kick(chan, "n", None), "KICK #c n\r\n");
+        assert_eq!(kick(chan, "n", Some("bye")), "KICK #c n :bye\r\n");
+        assert_eq!(invite("n", chan), "INVITE n #c\r\n");
+        assert_eq!(mode("#c", &[]), "MODE #c\r\n");
+        let changes =
+            parse_mode_changes("+o+v-k", vec!["a", "b", "*"], default_chan_mode_takes_arg);
+        assert_eq!(mode("#c", &changes), "MODE #c +ov-k a b *\r\n");
+        let changes = parse_mode_changes("+i", vec![], |_, _| false);
+        assert_eq!(mode("tiny", &changes), "MODE tiny +i\r\n");
+    }
+
+    #[test]
+    fn test_tag_parsing() {
+        let mut buf = vec![];
+        write!(
+            &mut buf,
+            "@aaa=bbb;ccc;example.com/ddd=eee :nick!ident@host.com PRIVMSG me :Hello\r\n"
+        )
+        .unwrap();
+        let msg = parse_irc_msg(&mut buf).unwrap().unwrap();
+        let mut tags = HashMap::new();
+        tags.insert("aaa".to_owned(), "bbb".to_owned());
+        tags.insert("ccc".to_owned(), "".to_owned());
+        tags.insert("example.com/ddd".to_owned(), "eee".to_owned());
+        assert_eq!(msg.tags, tags);
+        assert_eq!(
+            msg.pfx,
+            Some(Pfx::User {
+                nick: "nick".to_owned(),
+                user: "ident@host.com".to_owned(),
+            })
+        );
+        assert_eq!(buf.len(), 0);
+
+        // Tags without a prefix
+        let mut buf = vec![];
+        write!(&mut buf, "@time=2021-06-01T12:00:00.000Z PING :x\r\n").unwrap();
+        let msg = parse_irc_msg(&mut buf).unwrap().unwrap();
+        assert_eq!(msg.tags.get("time").unwrap(), "2021-06-01T12:00:00.000Z");
+        assert_eq!(msg.pfx, None);
+        assert_eq!(
+            msg.cmd,
+            Cmd::PING {
+                server: "x".to_owned()
+            }
+        );
+
+        // Last value wins, empty value is the same as no value
+        let mut buf = vec![];
+        write!(&mut buf, "@a=1;b=;a=2 PING :x\r\n").unwrap();
+        let msg = parse_irc_msg(&mut buf).unwrap().unwrap();
+        assert_eq!(msg.tags.get("a").unwrap(), "2");
+        assert_eq!(msg.tags.get("b").unwrap(), "");
+    }
+
+    #[test]
+    fn test_tag_escaping() {
+        assert_eq!(unescape_tag_value("a\\sb\\:c\\\\d\\r\\n"), "a b;c\\d\r\n");
+        // Invalid escapes drop the backslash
+        assert_eq!(unescape_tag_value("\\b"), "b");
+        // Trailing backslash is dropped
+        assert_eq!(unescape_tag_value("ab\\"), "ab");
+        assert_eq!(unescape_tag_value(""), "");
+
+        let mut escaped = String::new();
+        escape_tag_value("a b;c\\d\r\n", &mut escaped);
+        assert_eq!(escaped, "a\\sb\\:c\\\\d\\r\\n");
+    }
+
+    #[test]
+    fn test_tagged() {
+        assert_eq!(
+            tagged(&[("+draft/reply", "abc"), ("+x", "")], privmsg("#c", "hi")),
+            "@+draft/reply=abc;+x PRIVMSG #c :hi\r\n"
+        );
+        assert_eq!(
+            tagged(&[("+typing", "a b")], "TAGMSG #c\r\n".to_owned()),
+            "@+typing=a\\sb TAGMSG #c\r\n"
+        );
+        assert_eq!(tagged(&[], privmsg("#c", "hi")), privmsg("#c", "hi"));
+    }
+
     #[test]
     fn test_parse_pfx() {
         use Pfx::*;
-        assert_eq!(parse_pfx("xyz"), Ambiguous("xyz".to_string()));
-        assert_eq!(parse_pfx("xy-z"), Ambiguous("xy-z".to_string()),);
-        assert_eq!(parse_pfx("xy.z"), Server("xy.z".to_string()));
         assert_eq!(
-            parse_pfx("xyz[m]"),
+            PfxRef::parse("xyz").to_owned(),
+            Ambiguous("xyz".to_string())
+        );
+        assert_eq!(
+            PfxRef::parse("xy-z").to_owned(),
+            Ambiguous("xy-z".to_string()),
+        );
+        assert_eq!(PfxRef::parse("xy.z").to_owned(), Server("xy.z".to_string()));
+        assert_eq!(
+            PfxRef::parse("xyz[m]").to_owned(),
             User {
                 nick: "xyz[m]".to_string(),
                 user: "".to_string()
             }
         );
         assert_eq!(
-            parse_pfx("fe-00106.xyz.net"),
+            PfxRef::parse("fe-00106.xyz.net").to_owned(),
             Server("fe-00106.xyz.net".to_string())
         );
         assert_eq!(
-            parse_pfx("osa1!osa1@x.y.im"),
+            PfxRef::parse("osa1!osa1@x.y.im").to_owned(),
             User {
                 nick: "osa1".to_string(),
                 user: "osa1@x.y.im".to_string(),
             }
         );
         assert_eq!(
-            parse_pfx("IRC!IRC@fe-00106.xyz.net"),
+            PfxRef::parse("IRC!IRC@fe-00106.xyz.net").to_owned(),
             User {
                 nick: "IRC".to_string(),
                 user: "IRC@fe-00106.xyz.net".to_string()
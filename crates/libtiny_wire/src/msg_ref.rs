@@ -0,0 +1,890 @@
+//! Borrowed versions of the message types. `MsgRef::parse` parses a message without allocating;
+//! strings in the message are slices of the input. Use `MsgRef::to_owned` to get a `Msg`.
+
+use crate::{
+    default_chan_mode_takes_arg, parse_mode_changes, parse_tags, unescape_tag_value, Cmd, Msg,
+    MsgTarget, Pfx, Tags, CTCP,
+};
+use libtiny_common::ChanNameRef;
+
+use std::borrow::Cow;
+use std::ops::Deref;
+use std::str;
+
+/// Borrowed version of `Msg`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsgRef<'a> {
+    pub tags: TagsRef<'a>,
+    pub pfx: Option<PfxRef<'a>>,
+    pub cmd: CmdRef<'a>,
+}
+
+/// Borrowed version of `Tags`. Tags are parsed lazily, see `TagsRef::iter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TagsRef<'a>(&'a str);
+
+/// Borrowed version of `Pfx`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PfxRef<'a> {
+    Server(&'a str),
+    User { nick: &'a str, user: &'a str },
+    Ambiguous(&'a str),
+}
+
+/// Borrowed version of `MsgTarget`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsgTargetRef<'a> {
+    Chan(&'a ChanNameRef),
+    User(&'a str),
+}
+
+/// Borrowed version of `CTCP`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CTCPRef<'a> {
+    Version,
+    Action,
+    Ping,
+    Time,
+    ClientInfo,
+    UserInfo,
+    Source,
+    DCC,
+    Other(&'a str),
+}
+
+/// A comma-separated list in a parameter, e.g. channels in a JOIN. Empty elements are skipped,
+/// except in `keys`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CommaList<'a>(&'a str);
+
+/// Max. number of parameters in a message. See `parse_params`.
+const MAX_PARAMS: usize = 15;
+
+/// Parameters of a message. Derefs to `[&str]`.
+#[derive(Clone, Copy)]
+pub struct Params<'a> {
+    params: [&'a str; MAX_PARAMS],
+    len: usize,
+}
+
+/// Borrowed version of `Cmd`. See `Cmd` for the documentation of the variants.
+///
+/// Fields that `libtiny_client` fills in (`chans` of `QUIT` and `NICK`) are not here. Mode changes
+/// and `CAP` parameters are parsed by `to_owned`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmdRef<'a> {
+    PRIVMSG {
+        /// See `CommaList::msg_targets`
+        targets: CommaList<'a>,
+        msg: &'a str,
+        is_notice: bool,
+        ctcp: Option<CTCPRef<'a>>,
+    },
+
+    JOIN {
+        /// See `CommaList::chans`
+        chans: CommaList<'a>,
+        /// See `CommaList::keys`
+        keys: CommaList<'a>,
+        /// `extended-join` account, "*" when the user is not logged in
+        account: Option<&'a str>,
+        realname: Option<&'a str>,
+    },
+
+    PART {
+        /// See `CommaList::chans`
+        chans: CommaList<'a>,
+        msg: Option<&'a str>,
+    },
+
+    QUIT {
+        msg: Option<&'a str>,
+    },
+
+    NICK {
+        nick: &'a str,
+    },
+
+    PING {
+        server: &'a str,
+    },
+
+    PONG {
+        server: &'a str,
+    },
+
+    ERROR {
+        msg: &'a str,
+    },
+
+    TOPIC {
+        chan: &'a ChanNameRef,
+        topic: &'a str,
+    },
+
+    KICK {
+        chan: &'a ChanNameRef,
+        nick: &'a str,
+        msg: Option<&'a str>,
+    },
+
+    MODE {
+        target: MsgTargetRef<'a>,
+        /// Mode string, e.g. `+o-v`
+        modes: &'a str,
+        /// Mode arguments
+        args: Params<'a>,
+    },
+
+    INVITE {
+        nick: &'a str,
+        chan: &'a ChanNameRef,
+    },
+
+    WALLOPS {
+        msg: &'a str,
+    },
+
+    AWAY {
+        msg: Option<&'a str>,
+    },
+
+    /// "*" when the user logged out
+    ACCOUNT {
+        account: &'a str,
+    },
+
+    CHGHOST {
+        user: &'a str,
+        host: &'a str,
+    },
+
+    SETNAME {
+        realname: &'a str,
+    },
+
+    CAP {
+        client: &'a str,
+        subcommand: &'a str,
+        /// Space-separated list of parameters
+        params: &'a str,
+        /// Set in all but the last message of multi-line `LS` and `LIST` replies.
+        more: bool,
+    },
+
+    AUTHENTICATE {
+        param: &'a str,
+    },
+
+    BATCH {
+        reference: &'a str,
+        start: bool,
+        params: Params<'a>,
+    },
+
+    Other {
+        cmd: &'a str,
+        params: Params<'a>,
+    },
+
+    Reply {
+        num: u16,
+        params: Params<'a>,
+    },
+}
+
+impl<'a> MsgRef<'a> {
+    /// Parse a single line. The line may or may not have the `\r\n` suffix. Fails when the line is
+    /// not valid UTF-8; use `parse_irc_msg` to parse with lossy UTF-8 decoding.
+    pub fn parse(line: &'a [u8]) -> Result<MsgRef<'a>, String> {
+        let line = line.strip_suffix(b"\r\n").unwrap_or(line);
+        let line = str::from_utf8(line).map_err(|err| format!("Invalid UTF-8 in msg: {}", err))?;
+        MsgRef::parse_str(line)
+    }
+
+    /// Same as `parse`, but for a `&str`. The line should not have the `\r\n` suffix.
+    pub fn parse_str(mut msg: &'a str) -> Result<MsgRef<'a>, String> {
+        let tags = {
+            if let Some('@') = msg.chars().next() {
+                let ws_idx = msg.find(' ').ok_or(format!(
+                    "Can't find tags terminator (' ') in msg: {:?}",
+                    msg
+                ))?;
+                let tags = TagsRef(&msg[1..ws_idx]); // consume '@'
+                msg = msg[ws_idx + 1..].trim_start_matches(' '); // consume ' '
+                tags
+            } else {
+                TagsRef::default()
+            }
+        };
+
+        let pfx = {
+            if let Some(':') = msg.chars().next() {
+                // parse prefix
+                let ws_idx = msg.find(' ').ok_or(format!(
+                    "Can't find prefix terminator (' ') in msg: {:?}",
+                    msg
+                ))?;
+                let pfx = &msg[1..ws_idx]; // consume ':'
+                msg = &msg[ws_idx + 1..]; // consume ' '
+                Some(PfxRef::parse(pfx))
+            } else {
+                None
+            }
+        };
+
+        let msg_ty: MsgType = {
+            // Command is terminated by ' ', or the end of the message when there are no parameters
+            let cmd = match msg.find(' ') {
+                Some(ws_idx) => {
+                    let cmd = &msg[..ws_idx];
+                    msg = &msg[ws_idx + 1..]; // Consume ' '
+                    cmd
+                }
+                None => {
+                    let cmd = msg;
+                    msg = "";
+                    cmd
+                }
+            };
+            if cmd.is_empty() {
+                return Err(format!("Can't find message type in msg: {:?}", msg));
+            }
+            match cmd.parse::<u16>() {
+                Ok(num) => MsgType::Num(num),
+                Err(_) => MsgType::Cmd(cmd),
+            }
+        };
+
+        let params = Params::parse(msg);
+        let cmd = match msg_ty {
+            MsgType::Cmd("PRIVMSG") | MsgType::Cmd("NOTICE") if params.len() == 2 => {
+                let is_notice = matches!(msg_ty, MsgType::Cmd("NOTICE"));
+                let targets = CommaList(params[0]);
+                let mut msg = params[1];
+
+                let mut ctcp: Option<CTCPRef> = None;
+                if !msg.is_empty() && msg.as_bytes()[0] == 0x01 {
+                    // Drop 0x01
+                    msg = &msg[1..];
+                    // Parse message type
+                    for (byte_idx, byte) in msg.as_bytes().iter().enumerate() {
+                        if *byte == 0x01 {
+                            let ctcp_type = &msg[0..byte_idx];
+                            ctcp = Some(CTCPRef::parse(ctcp_type));
+                            msg = &msg[byte_idx + 1..];
+                            break;
+                        } else if *byte == b' ' {
+                            let ctcp_type = &msg[0..byte_idx];
+                            ctcp = Some(CTCPRef::parse(ctcp_type));
+                            msg = &msg[byte_idx + 1..];
+                            if !msg.is_empty() && msg.as_bytes()[msg.len() - 1] == 0x01 {
+                                msg = &msg[..msg.len() - 1];
+                            }
+                            break;
+                        }
+                    }
+                }
+
+                CmdRef::PRIVMSG {
+                    targets,
+                    msg,
+                    is_notice,
+                    ctcp,
+                }
+            }
+            MsgType::Cmd("JOIN") if params.len() == 1 || params.len() == 2 => CmdRef::JOIN {
+                chans: CommaList(params[0]),
+                keys: CommaList(params.get(1).copied().unwrap_or("")),
+                account: None,
+                realname: None,
+            },
+            // extended-join: `JOIN <chan> <account> :<realname>`
+            MsgType::Cmd("JOIN") if params.len() == 3 => CmdRef::JOIN {
+                chans: CommaList(params[0]),
+                keys: CommaList(""),
+                account: Some(params[1]),
+                realname: Some(params[2]),
+            },
+            MsgType::Cmd("PART") if params.len() == 1 || params.len() == 2 => CmdRef::PART {
+                chans: CommaList(params[0]),
+                msg: params.get(1).copied(),
+            },
+            MsgType::Cmd("QUIT") if params.is_empty() || params.len() == 1 => CmdRef::QUIT {
+                msg: params.first().copied(),
+            },
+            MsgType::Cmd("NICK") if params.len() == 1 => CmdRef::NICK { nick: params[0] },
+            MsgType::Cmd("PING") if params.len() == 1 => CmdRef::PING { server: params[0] },
+            MsgType::Cmd("PONG") if !params.is_empty() => CmdRef::PONG { server: params[0] },
+            MsgType::Cmd("ERROR") if params.len() == 1 => CmdRef::ERROR { msg: params[0] },
+            MsgType::Cmd("TOPIC") if params.len() == 2 => CmdRef::TOPIC {
+                chan: ChanNameRef::new(params[0]),
+                topic: params[1],
+            },
+            MsgType::Cmd("KICK") if params.len() == 2 || params.len() == 3 => CmdRef::KICK {
+                chan: ChanNameRef::new(params[0]),
+                nick: params[1],
+                msg: params.get(2).copied(),
+            },
+            MsgType::Cmd("MODE") if !params.is_empty() => CmdRef::MODE {
+                target: MsgTargetRef::parse(params[0]),
+                modes: params.get(1).copied().unwrap_or(""),
+                args: params.skip(2),
+            },
+            MsgType::Cmd("INVITE") if params.len() == 2 => CmdRef::INVITE {
+                nick: params[0],
+                chan: ChanNameRef::new(params[1]),
+            },
+            MsgType::Cmd("WALLOPS") if params.len() == 1 => CmdRef::WALLOPS { msg: params[0] },
+            MsgType::Cmd("AWAY") if params.len() <= 1 => CmdRef::AWAY {
+                msg: params.first().copied(),
+            },
+            MsgType::Cmd("ACCOUNT") if params.len() == 1 => CmdRef::ACCOUNT { account: params[0] },
+            MsgType::Cmd("CHGHOST") if params.len() == 2 => CmdRef::CHGHOST {
+                user: params[0],
+                host: params[1],
+            },
+            MsgType::Cmd("SETNAME") if params.len() == 1 => CmdRef::SETNAME {
+                realname: params[0],
+            },
+            MsgType::Cmd("CAP") if params.len() == 3 => CmdRef::CAP {
+                client: params[0],
+                subcommand: params[1],
+                params: params[2],
+                more: false,
+            },
+            MsgType::Cmd("CAP") if params.len() == 4 && params[2] == "*" => CmdRef::CAP {
+                client: params[0],
+                subcommand: params[1],
+                params: params[3],
+                more: true,
+            },
+            MsgType::Cmd("AUTHENTICATE") if params.len() == 1 => {
+                CmdRef::AUTHENTICATE { param: params[0] }
+            }
+            MsgType::Cmd("BATCH")
+                if !params.is_empty()
+                    && params[0].len() > 1
+                    && (params[0].starts_with('+') || params[0].starts_with('-')) =>
+            {
+                CmdRef::BATCH {
+                    reference: &params[0][1..],
+                    start: params[0].starts_with('+'),
+                    params: params.skip(1),
+                }
+            }
+            MsgType::Num(num) => CmdRef::Reply { num, params },
+            MsgType::Cmd(cmd) => CmdRef::Other { cmd, params },
+        };
+
+        Ok(MsgRef { tags, pfx, cmd })
+    }
+
+    pub fn to_owned(&self) -> Msg {
+        Msg {
+            tags: self.tags.to_owned(),
+            pfx: self.pfx.map(|pfx| pfx.to_owned()),
+            cmd: self.cmd.to_owned(),
+        }
+    }
+}
+
+/// An intermediate type used during parsing.
+enum MsgType<'a> {
+    Cmd(&'a str),
+    Num(u16),
+}
+
+impl<'a> TagsRef<'a> {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Iterate tags. Values are unescaped; only values with escapes are allocated. A key may
+    /// appear more than once, see `get`.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, Cow<'a, str>)> {
+        self.0
+            .split(';')
+            .filter(|tag| !tag.is_empty())
+            .map(|tag| match tag.find('=') {
+                None => (tag, Cow::Borrowed("")),
+                Some(eq_idx) => {
+                    let value = &tag[eq_idx + 1..];
+                    let value = if value.contains('\\') {
+                        Cow::Owned(unescape_tag_value(value))
+                    } else {
+                        Cow::Borrowed(value)
+                    };
+                    (&tag[..eq_idx], value)
+                }
+            })
+    }
+
+    /// Get value of a tag. When a key is repeated the last value is returned.
+    pub fn get(&self, key: &str) -> Option<Cow<'a, str>> {
+        self.iter()
+            .filter(|(key_, _)| *key_ == key)
+            .last()
+            .map(|(_, value)| value)
+    }
+
+    pub fn to_owned(&self) -> Tags {
+        parse_tags(self.0)
+    }
+}
+
+impl<'a> CommaList<'a> {
+    pub fn iter(&self) -> impl Iterator<Item = &'a str> {
+        self.0.split(',').filter(|s| !s.is_empty())
+    }
+
+    /// Iterate the list as message targets.
+    pub fn msg_targets(&self) -> impl Iterator<Item = MsgTargetRef<'a>> {
+        self.iter().map(MsgTargetRef::parse)
+    }
+
+    /// Iterate the list as channel names.
+    pub fn chans(&self) -> impl Iterator<Item = &'a ChanNameRef> {
+        self.iter().map(ChanNameRef::new)
+    }
+
+    /// Iterate the list as channel keys. Empty keys are not skipped as keys are matched with
+    /// channels by position, e.g. `JOIN #a,#b,#c k1,,k3`.
+    pub fn keys(&self) -> impl Iterator<Item = &'a str> {
+        let list = if self.0.is_empty() {
+            None
+        } else {
+            Some(self.0)
+        };
+        list.into_iter().flat_map(|list| list.split(','))
+    }
+}
+
+impl<'a> PfxRef<'a> {
+    // RFC 2812 section 2.3.1
+    pub(crate) fn parse(pfx: &'a str) -> PfxRef<'a> {
+        match pfx.find(&['!', '@'][..]) {
+            Some(idx) => PfxRef::User {
+                nick: &pfx[0..idx],
+                user: &pfx[idx + 1..],
+            },
+            None => {
+                // Chars that nicks can have but servernames cannot
+                match pfx.find(&['[', ']', '\\', '`', '_', '^', '{', '|', '}'][..]) {
+                    Some(_) => PfxRef::User {
+                        nick: pfx,
+                        user: "",
+                    },
+                    None => {
+                        // Nicks can't have '.'
+                        match pfx.find('.') {
+                            Some(_) => PfxRef::Server(pfx),
+                            None => PfxRef::Ambiguous(pfx),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn to_owned(&self) -> Pfx {
+        match *self {
+            PfxRef::Server(server) => Pfx::Server(server.to_owned()),
+            PfxRef::User { nick, user } => Pfx::User {
+                nick: nick.to_owned(),
+                user: user.to_owned(),
+            },
+            PfxRef::Ambiguous(pfx) => Pfx::Ambiguous(pfx.to_owned()),
+        }
+    }
+}
+
+impl<'a> MsgTargetRef<'a> {
+    /// See `MsgTarget` for the rules.
+    fn parse(target: &'a str) -> MsgTargetRef<'a> {
+        if target.starts_with('#') {
+            MsgTargetRef::Chan(ChanNameRef::new(target))
+        } else {
+            MsgTargetRef::User(target)
+        }
+    }
+
+    pub fn to_owned(&self) -> MsgTarget {
+        match *self {
+            MsgTargetRef::Chan(chan) => MsgTarget::Chan(chan.to_owned()),
+            MsgTargetRef::User(user) => MsgTarget::User(user.to_owned()),
+        }
+    }
+}
+
+impl<'a> CTCPRef<'a> {
+    fn parse(s: &'a str) -> CTCPRef<'a> {
+        match s {
+            "VERSION" => CTCPRef::Version,
+            "ACTION" => CTCPRef::Action,
+            "PING" => CTCPRef::Ping,
+            "TIME" => CTCPRef::Time,
+            "CLIENTINFO" => CTCPRef::ClientInfo,
+            "USERINFO" => CTCPRef::UserInfo,
+            "SOURCE" => CTCPRef::Source,
+            "DCC" => CTCPRef::DCC,
+            _ => CTCPRef::Other(s),
+        }
+    }
+
+    pub fn to_owned(&self) -> CTCP {
+        match *self {
+            CTCPRef::Version => CTCP::Version,
+            CTCPRef::Action => CTCP::Action,
+            CTCPRef::Ping => CTCP::Ping,
+            CTCPRef::Time => CTCP::Time,
+            CTCPRef::ClientInfo => CTCP::ClientInfo,
+            CTCPRef::UserInfo => CTCP::UserInfo,
+            CTCPRef::Source => CTCP::Source,
+            CTCPRef::DCC => CTCP::DCC,
+            CTCPRef::Other(other) => CTCP::Other(other.to_owned()),
+        }
+    }
+}
+
+impl<'a> CmdRef<'a> {
+    pub fn to_owned(&self) -> Cmd {
+        match *self {
+            CmdRef::PRIVMSG {
+                targets,
+                msg,
+                is_notice,
+                ctcp,
+            } => Cmd::PRIVMSG {
+                targets: targets.msg_targets().map(|t| t.to_owned()).collect(),
+                msg: msg.to_owned(),
+                is_notice,
+                ctcp: ctcp.map(|ctcp| ctcp.to_owned()),
+            },
+            CmdRef::JOIN {
+                chans,
+                keys,
+                account,
+                realname,
+            } => Cmd::JOIN {
+                chans: chans.chans().map(ChanNameRef::to_owned).collect(),
+                keys: keys.keys().map(str::to_owned).collect(),
+                account: account.filter(|account| *account != "*").map(str::to_owned),
+                realname: realname.map(str::to_owned),
+            },
+            CmdRef::PART { chans, msg } => Cmd::PART {
+                chans: chans.chans().map(ChanNameRef::to_owned).collect(),
+                msg: msg.map(str::to_owned),
+            },
+            CmdRef::QUIT { msg } => Cmd::QUIT {
+                msg: msg.map(str::to_owned),
+                chans: Vec::new(),
+            },
+            CmdRef::NICK { nick } => Cmd::NICK {
+                nick: nick.to_owned(),
+                chans: Vec::new(),
+            },
+            CmdRef::PING { server } => Cmd::PING {
+                server: server.to_owned(),
+            },
+            CmdRef::PONG { server } => Cmd::PONG {
+                server: server.to_owned(),
+            },
+            CmdRef::ERROR { msg } => Cmd::ERROR {
+                msg: msg.to_owned(),
+            },
+            CmdRef::TOPIC { chan, topic } => Cmd::TOPIC {
+                chan: chan.to_owned(),
+                topic: topic.to_owned(),
+            },
+            CmdRef::KICK { chan, nick, msg } => Cmd::KICK {
+                chan: chan.to_owned(),
+                nick: nick.to_owned(),
+                msg: msg.map(str::to_owned),
+            },
+            CmdRef::MODE {
+                target,
+                modes,
+                args,
+            } => {
+                let changes = match target {
+                    MsgTargetRef::Chan(_) => {
+                        parse_mode_changes(modes, args.iter().copied(), default_chan_mode_takes_arg)
+                    }
+                    MsgTargetRef::User(_) => {
+                        parse_mode_changes(modes, args.iter().copied(), |_, _| false)
+                    }
+                };
+                Cmd::MODE {
+                    target: target.to_owned(),
+                    changes,
+                    modes: modes.to_owned(),
+                    args: args.iter().map(|arg| (*arg).to_owned()).collect(),
+                }
+            }
+            CmdRef::INVITE { nick, chan } => Cmd::INVITE {
+                nick: nick.to_owned(),
+                chan: chan.to_owned(),
+            },
+            CmdRef::WALLOPS { msg } => Cmd::WALLOPS {
+                msg: msg.to_owned(),
+            },
+            CmdRef::AWAY { msg } => Cmd::AWAY {
+                msg: msg.map(str::to_owned),
+            },
+            CmdRef::ACCOUNT { account } => Cmd::ACCOUNT {
+                account: if account == "*" {
+                    None
+                } else {
+                    Some(account.to_owned())
+                },
+            },
+            CmdRef::CHGHOST { user, host } => Cmd::CHGHOST {
+                user: user.to_owned(),
+                host: host.to_owned(),
+            },
+            CmdRef::SETNAME { realname } => Cmd::SETNAME {
+                realname: realname.to_owned(),
+            },
+            CmdRef::CAP {
+                client,
+                subcommand,
+                params,
+                more,
+            } => Cmd::CAP {
+                client: client.to_owned(),
+                subcommand: subcommand.to_owned(),
+                params: params.split(' ').map(str::to_owned).collect(),
+                more,
+            },
+            CmdRef::AUTHENTICATE { param } => Cmd::AUTHENTICATE {
+                param: param.to_owned(),
+            },
+            CmdRef::BATCH {
+                reference,
+                start,
+                params,
+            } => Cmd::BATCH {
+                reference: reference.to_owned(),
+                start,
+                params: params.iter().map(|s| (*s).to_owned()).collect(),
+            },
+            CmdRef::Other { cmd, params } => Cmd::Other {
+                cmd: cmd.to_owned(),
+                params: params.iter().map(|s| (*s).to_owned()).collect(),
+            },
+            CmdRef::Reply { num, params } => Cmd::Reply {
+                num,
+                params: params.iter().map(|s| (*s).to_owned()).collect(),
+            },
+        }
+    }
+}
+
+impl<'a> Params<'a> {
+    pub(crate) fn parse(chrs: &'a str) -> Params<'a> {
+        // Spec:
+        //
+        //     params     =  *14( SPACE middle ) [ SPACE ":" trailing ]
+        //                =/ 14( SPACE middle ) [ SPACE [ ":" ] trailing ]
+        //
+        //     nospcrlfcl =  %x01-09 / %x0B-0C / %x0E-1F / %x21-39 / %x3B-FF
+        //                     ; any octet except NUL, CR, LF, " " and ":"
+        //     middle     =  nospcrlfcl *( ":" / nospcrlfcl )
+        //     trailing   =  *( ":" / " " / nospcrlfcl )
+        //
+        // The RFC doesn't explain the syntax with `14` here as if it's something standard. I'm
+        // guessing it's number of repetitions, and `*14` means "14 or less" repetitions.
+
+        let mut params = Params {
+            params: [""; MAX_PARAMS],
+            len: 0,
+        };
+        let mut char_indices = chrs.char_indices();
+
+        while let Some((idx, c)) = char_indices.next() {
+            if c == ':' {
+                params.push(&chrs[idx + 1..]); // Skip ':'
+                break;
+            }
+
+            if params.len() == MAX_PARAMS - 1 {
+                params.push(&chrs[idx..]);
+                break;
+            }
+
+            if c == ' ' {
+                continue;
+            }
+
+            loop {
+                match char_indices.next() {
+                    Some((idx_, c)) => {
+                        if c == ' ' {
+                            params.push(&chrs[idx..idx_]);
+                            break;
+                        }
+                    }
+                    None => {
+                        params.push(&chrs[idx..]);
+                        break;
+                    }
+                }
+            }
+        }
+
+        params
+    }
+
+    fn push(&mut self, param: &'a str) {
+        self.params[self.len] = param;
+        self.len += 1;
+    }
+
+    /// Drop the first `n` parameters.
+    fn skip(&self, n: usize) -> Params<'a> {
+        let mut params = Params {
+            params: [""; MAX_PARAMS],
+            len: 0,
+        };
+        for param in self.iter().skip(n) {
+            params.push(param);
+        }
+        params
+    }
+}
+
+impl<'a> Deref for Params<'a> {
+    type Target = [&'a str];
+
+    fn deref(&self) -> &Self::Target {
+        &self.params[..self.len]
+    }
+}
+
+impl<'a> std::fmt::Debug for Params<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<'a> PartialEq for Params<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<'a> Eq for Params<'a> {}
+
+impl<'a, 'b> PartialEq<Vec<&'b str>> for Params<'a> {
+    fn eq(&self, other: &Vec<&'b str>) -> bool {
+        **self == **other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn borrowed_parsing() {
+        let line = b"@time=2021-01-01T00:00:00.000Z;msgid=a\\sb :nick!user@host PRIVMSG #chan :\x01ACTION waves\x01\r\n";
+        let msg = MsgRef::parse(line).unwrap();
+        assert_eq!(
+            msg.pfx,
+            Some(PfxRef::User {
+                nick: "nick",
+                user: "user@host"
+            })
+        );
+        assert_eq!(
+            msg.cmd,
+            CmdRef::PRIVMSG {
+                targets: CommaList("#chan"),
+                msg: "waves",
+                is_notice: false,
+                ctcp: Some(CTCPRef::Action),
+            }
+        );
+        assert_eq!(msg.tags.get("msgid").as_deref(), Some("a b"));
+        assert!(matches!(
+            msg.tags.get("time"),
+            Some(Cow::Borrowed("2021-01-01T00:00:00.000Z"))
+        ));
+        assert_eq!(msg.tags.get("foo"), None);
+
+        let owned = msg.to_owned();
+        assert_eq!(owned.tags.get("msgid").map(String::as_str), Some("a b"));
+        assert_eq!(
+            owned.cmd,
+            Cmd::PRIVMSG {
+                targets: vec![MsgTarget::Chan(ChanNameRef::new("#chan").to_owned())],
+                msg: "waves".to_owned(),
+                is_notice: false,
+                ctcp: Some(CTCP::Action),
+            }
+        );
+    }
+
+    #[test]
+    fn borrowed_mode_and_reply() {
+        let msg = MsgRef::parse(b":op!u@h MODE #chan +ov a b").unwrap();
+        match msg.cmd {
+            CmdRef::MODE {
+                target,
+                modes,
+                args,
+            } => {
+                assert_eq!(target, MsgTargetRef::Chan(ChanNameRef::new("#chan")));
+                assert_eq!(modes, "+ov");
+                assert_eq!(args, vec!["a", "b"]);
+            }
+            other => panic!("Unexpected cmd: {:?}", other),
+        }
+
+        let msg = MsgRef::parse(b":x.y.z 005 tiny NICKLEN=16 :are supported").unwrap();
+        assert_eq!(msg.pfx, Some(PfxRef::Server("x.y.z")));
+        match msg.cmd {
+            CmdRef::Reply { num, params } => {
+                assert_eq!(num, 5);
+                assert_eq!(params, vec!["tiny", "NICKLEN=16", "are supported"]);
+            }
+            other => panic!("Unexpected cmd: {:?}", other),
+        }
+
+        assert!(MsgRef::parse(b"PRIVMSG #chan :\xff\r\n").is_err());
+    }
+
+    #[test]
+    fn borrowed_lists() {
+        let msg = MsgRef::parse(b":tiny!u@h JOIN #a,#b,#c k1,k2").unwrap();
+        match msg.cmd {
+            CmdRef::JOIN { chans, keys, .. } => {
+                assert_eq!(
+                    chans.chans().collect::<Vec<_>>(),
+                    vec![
+                        ChanNameRef::new("#a"),
+                        ChanNameRef::new("#b"),
+                        ChanNameRef::new("#c")
+                    ]
+                );
+                assert_eq!(keys.iter().collect::<Vec<_>>(), vec!["k1", "k2"]);
+            }
+            other => panic!("Unexpected cmd: {:?}", other),
+        }
+
+        let msg = MsgRef::parse(b"PRIVMSG #a,nick :hi").unwrap();
+        match msg.cmd {
+            CmdRef::PRIVMSG { targets, .. } => {
+                assert_eq!(
+                    targets.msg_targets().collect::<Vec<_>>(),
+                    vec![
+                        MsgTargetRef::Chan(ChanNameRef::new("#a")),
+                        MsgTargetRef::User("nick")
+                    ]
+                );
+            }
+            other => panic!("Unexpected cmd: {:?}", other),
+        }
+    }
+}
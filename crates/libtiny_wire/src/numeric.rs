@@ -0,0 +1,893 @@
+//! Numeric replies. See https://modern.ircdocs.horse/#numerics and
+//! https://defs.ircdocs.horse/defs/numerics.html
+//!
+//! `Cmd::Reply` has the number and parameters of a numeric reply. `Numeric::parse` decodes the
+//! parameters of common numerics, so that handlers don't need to index into the parameters. The
+//! first parameter of all numerics is the client's nick (or `*` during registration), which is
+//! not included in `Numeric`.
+
+#![allow(clippy::zero_prefixed_literal)]
+
+use crate::Cmd;
+use libtiny_common::ChanNameRef;
+
+pub const RPL_WELCOME: u16 = 001;
+pub const RPL_YOURHOST: u16 = 002;
+pub const RPL_CREATED: u16 = 003;
+pub const RPL_MYINFO: u16 = 004;
+pub const RPL_ISUPPORT: u16 = 005;
+pub const RPL_STATSCONN: u16 = 250;
+pub const RPL_LUSERCLIENT: u16 = 251;
+pub const RPL_LUSEROP: u16 = 252;
+pub const RPL_LUSERUNKNOWN: u16 = 253;
+pub const RPL_LUSERCHANNELS: u16 = 254;
+pub const RPL_LUSERME: u16 = 255;
+pub const RPL_TRYAGAIN: u16 = 263;
+pub const RPL_LOCALUSERS: u16 = 265;
+pub const RPL_GLOBALUSERS: u16 = 266;
+/// Bahamut, sent in WHOIS replies of users connected with TLS
+pub const RPL_USINGSSL: u16 = 275;
+pub const RPL_WHOISCERTFP: u16 = 276;
+pub const RPL_AWAY: u16 = 301;
+pub const RPL_UNAWAY: u16 = 305;
+pub const RPL_NOWAWAY: u16 = 306;
+pub const RPL_WHOISREGNICK: u16 = 307;
+pub const RPL_WHOISHELPOP: u16 = 310;
+pub const RPL_WHOISUSER: u16 = 311;
+pub const RPL_WHOISSERVER: u16 = 312;
+pub const RPL_WHOISOPERATOR: u16 = 313;
+pub const RPL_WHOWASUSER: u16 = 314;
+pub const RPL_ENDOFWHO: u16 = 315;
+pub const RPL_WHOISIDLE: u16 = 317;
+pub const RPL_ENDOFWHOIS: u16 = 318;
+pub const RPL_WHOISCHANNELS: u16 = 319;
+pub const RPL_WHOISSPECIAL: u16 = 320;
+pub const RPL_LISTSTART: u16 = 321;
+pub const RPL_LIST: u16 = 322;
+pub const RPL_LISTEND: u16 = 323;
+pub const RPL_CHANNELMODEIS: u16 = 324;
+pub const RPL_CREATIONTIME: u16 = 329;
+pub const RPL_WHOISACCOUNT: u16 = 330;
+pub const RPL_NOTOPIC: u16 = 331;
+pub const RPL_TOPIC: u16 = 332;
+pub const RPL_TOPICWHOTIME: u16 = 333;
+/// UnrealIRCd and InspIRCd, sent in WHOIS replies of bots
+pub const RPL_WHOISBOT: u16 = 335;
+pub const RPL_WHOISACTUALLY: u16 = 338;
+pub const RPL_INVITING: u16 = 341;
+pub const RPL_INVITELIST: u16 = 346;
+pub const RPL_ENDOFINVITELIST: u16 = 347;
+pub const RPL_EXCEPTLIST: u16 = 348;
+pub const RPL_ENDOFEXCEPTLIST: u16 = 349;
+pub const RPL_WHOREPLY: u16 = 352;
+pub const RPL_NAMREPLY: u16 = 353;
+pub const RPL_ENDOFNAMES: u16 = 366;
+pub const RPL_BANLIST: u16 = 367;
+pub const RPL_ENDOFBANLIST: u16 = 368;
+pub const RPL_ENDOFWHOWAS: u16 = 369;
+pub const RPL_MOTD: u16 = 372;
+pub const RPL_MOTDSTART: u16 = 375;
+pub const RPL_ENDOFMOTD: u16 = 376;
+pub const RPL_WHOISHOST: u16 = 378;
+pub const RPL_WHOISMODES: u16 = 379;
+pub const RPL_YOUREOPER: u16 = 381;
+pub const RPL_HOSTHIDDEN: u16 = 396;
+pub const RPL_WHOISSECURE: u16 = 671;
+
+pub const ERR_NOSUCHNICK: u16 = 401;
+pub const ERR_NOSUCHSERVER: u16 = 402;
+pub const ERR_NOSUCHCHANNEL: u16 = 403;
+pub const ERR_CANNOTSENDTOCHAN: u16 = 404;
+pub const ERR_TOOMANYCHANNELS: u16 = 405;
+pub const ERR_WASNOSUCHNICK: u16 = 406;
+pub const ERR_TOOMANYMATCHES: u16 = 416;
+pub const ERR_UNKNOWNCOMMAND: u16 = 421;
+pub const ERR_NOMOTD: u16 = 422;
+pub const ERR_NONICKNAMEGIVEN: u16 = 431;
+pub const ERR_ERRONEUSNICKNAME: u16 = 432;
+pub const ERR_NICKNAMEINUSE: u16 = 433;
+pub const ERR_USERNOTINCHANNEL: u16 = 441;
+pub const ERR_NOTONCHANNEL: u16 = 442;
+pub const ERR_USERONCHANNEL: u16 = 443;
+pub const ERR_NOTREGISTERED: u16 = 451;
+pub const ERR_NEEDMOREPARAMS: u16 = 461;
+pub const ERR_ALREADYREGISTERED: u16 = 462;
+pub const ERR_PASSWDMISMATCH: u16 = 464;
+pub const ERR_YOUREBANNEDCREEP: u16 = 465;
+pub const ERR_KEYSET: u16 = 467;
+pub const ERR_CHANNELISFULL: u16 = 471;
+pub const ERR_UNKNOWNMODE: u16 = 472;
+pub const ERR_INVITEONLYCHAN: u16 = 473;
+pub const ERR_BANNEDFROMCHAN: u16 = 474;
+pub const ERR_BADCHANNELKEY: u16 = 475;
+/// Not in the RFCs, but sent by freenode/Libera and others when joining a channel requires an
+/// identified nick.
+pub const ERR_NEEDREGGEDNICK: u16 = 477;
+pub const ERR_NOPRIVILEGES: u16 = 481;
+pub const ERR_CHANOPRIVSNEEDED: u16 = 482;
+pub const ERR_UMODEUNKNOWNFLAG: u16 = 501;
+pub const ERR_USERSDONTMATCH: u16 = 502;
+
+pub const RPL_LOGGEDIN: u16 = 900;
+pub const RPL_LOGGEDOUT: u16 = 901;
+pub const ERR_NICKLOCKED: u16 = 902;
+pub const RPL_SASLSUCCESS: u16 = 903;
+pub const ERR_SASLFAIL: u16 = 904;
+pub const ERR_SASLTOOLONG: u16 = 905;
+pub const ERR_SASLABORTED: u16 = 906;
+pub const ERR_SASLALREADY: u16 = 907;
+pub const RPL_SASLMECHS: u16 = 908;
+
+/// A decoded numeric reply. Numerics that are not in this list, or that don't have the expected
+/// parameters, are decoded as `Other`.
+///
+/// `msg` fields are the human-readable trailing parameters, e.g. "End of /NAMES list".
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Numeric<'a> {
+    RplWelcome {
+        msg: &'a str,
+    },
+    RplYourHost {
+        msg: &'a str,
+    },
+    RplCreated {
+        msg: &'a str,
+    },
+    RplMyInfo {
+        servername: &'a str,
+        version: &'a str,
+    },
+    /// Parameters are the ISUPPORT tokens, see `ISupport`.
+    RplISupport {
+        tokens: &'a [String],
+    },
+    RplStatsConn {
+        msg: &'a str,
+    },
+    RplLuserClient {
+        msg: &'a str,
+    },
+    RplLuserOp {
+        ops: usize,
+        msg: &'a str,
+    },
+    RplLuserUnknown {
+        connections: usize,
+        msg: &'a str,
+    },
+    RplLuserChannels {
+        chans: usize,
+        msg: &'a str,
+    },
+    RplLuserMe {
+        msg: &'a str,
+    },
+    RplLocalUsers {
+        msg: &'a str,
+    },
+    RplGlobalUsers {
+        msg: &'a str,
+    },
+
+    RplAway {
+        nick: &'a str,
+        msg: &'a str,
+    },
+    RplUnaway {
+        msg: &'a str,
+    },
+    RplNowAway {
+        msg: &'a str,
+    },
+
+    RplWhoisUser {
+        nick: &'a str,
+        user: &'a str,
+        host: &'a str,
+        realname: &'a str,
+    },
+    RplWhoisServer {
+        nick: &'a str,
+        server: &'a str,
+        info: &'a str,
+    },
+    RplWhoisOperator {
+        nick: &'a str,
+        msg: &'a str,
+    },
+    RplWhoWasUser {
+        nick: &'a str,
+        user: &'a str,
+        host: &'a str,
+        realname: &'a str,
+    },
+    RplEndOfWho {
+        mask: &'a str,
+    },
+    /// `idle` is in seconds, `signon` is a Unix timestamp.
+    RplWhoisIdle {
+        nick: &'a str,
+        idle: u64,
+        signon: Option<u64>,
+    },
+    RplEndOfWhois {
+        nick: &'a str,
+    },
+    /// `chans` is a space-separated list of channels, with membership prefixes.
+    RplWhoisChannels {
+        nick: &'a str,
+        chans: &'a str,
+    },
+    RplWhoisAccount {
+        nick: &'a str,
+        account: &'a str,
+    },
+    RplEndOfWhoWas {
+        nick: &'a str,
+    },
+
+    RplListStart,
+    RplList {
+        chan: &'a ChanNameRef,
+        visible: usize,
+        topic: &'a str,
+    },
+    RplListEnd,
+
+    /// `modes` is the mode string followed by mode arguments.
+    RplChannelModeIs {
+        chan: &'a ChanNameRef,
+        modes: &'a [String],
+    },
+    /// `time` is a Unix timestamp.
+    RplCreationTime {
+        chan: &'a ChanNameRef,
+        time: u64,
+    },
+    RplNoTopic {
+        chan: &'a ChanNameRef,
+    },
+    RplTopic {
+        chan: &'a ChanNameRef,
+        topic: &'a str,
+    },
+    /// `setter` is a nick or a `nick!user@host` mask, `time` is a Unix timestamp.
+    RplTopicWhoTime {
+        chan: &'a ChanNameRef,
+        setter: &'a str,
+        time: u64,
+    },
+    RplInviting {
+        nick: &'a str,
+        chan: &'a ChanNameRef,
+    },
+
+    /// `chan` is `*` when the user is not in a visible channel. `flags` is `H` (here) or `G`
+    /// (gone) followed by optional `*` (IRC operator) and membership prefixes.
+    RplWhoReply {
+        chan: &'a str,
+        user: &'a str,
+        host: &'a str,
+        server: &'a str,
+        nick: &'a str,
+        flags: &'a str,
+        hopcount: u32,
+        realname: &'a str,
+    },
+    /// `symbol` is `=` (public), `@` (secret) or `*` (private). `nicks` is a space-separated list
+    /// of nicks, with membership prefixes.
+    RplNamReply {
+        symbol: &'a str,
+        chan: &'a ChanNameRef,
+        nicks: &'a str,
+    },
+    RplEndOfNames {
+        chan: &'a ChanNameRef,
+    },
+    /// `setter` and `time` (a Unix timestamp) are not in the RFCs but most servers send them.
+    RplBanList {
+        chan: &'a ChanNameRef,
+        mask: &'a str,
+        setter: Option<&'a str>,
+        time: Option<u64>,
+    },
+    RplEndOfBanList {
+        chan: &'a ChanNameRef,
+    },
+    /// Ban exceptions (mode `e`), same as `RplBanList`
+    RplExceptList {
+        chan: &'a ChanNameRef,
+        mask: &'a str,
+        setter: Option<&'a str>,
+        time: Option<u64>,
+    },
+    RplEndOfExceptList {
+        chan: &'a ChanNameRef,
+    },
+    /// Invite exceptions (mode `I`), same as `RplBanList`
+    RplInviteList {
+        chan: &'a ChanNameRef,
+        mask: &'a str,
+        setter: Option<&'a str>,
+        time: Option<u64>,
+    },
+    RplEndOfInviteList {
+        chan: &'a ChanNameRef,
+    },
+
+    RplMotd {
+        line: &'a str,
+    },
+    RplMotdStart {
+        msg: &'a str,
+    },
+    RplEndOfMotd {
+        msg: &'a str,
+    },
+    RplYoureOper {
+        msg: &'a str,
+    },
+    RplHostHidden {
+        host: &'a str,
+    },
+
+    ErrNoSuchNick {
+        nick: &'a str,
+        msg: &'a str,
+    },
+    ErrNoSuchServer {
+        server: &'a str,
+        msg: &'a str,
+    },
+    ErrNoSuchChannel {
+        chan: &'a ChanNameRef,
+        msg: &'a str,
+    },
+    ErrCannotSendToChan {
+        chan: &'a ChanNameRef,
+        msg: &'a str,
+    },
+    ErrTooManyChannels {
+        chan: &'a ChanNameRef,
+        msg: &'a str,
+    },
+    ErrWasNoSuchNick {
+        nick: &'a str,
+        msg: &'a str,
+    },
+    ErrUnknownCommand {
+        cmd: &'a str,
+        msg: &'a str,
+    },
+    ErrNoMotd {
+        msg: &'a str,
+    },
+    ErrNoNicknameGiven {
+        msg: &'a str,
+    },
+    ErrErroneusNickname {
+        nick: &'a str,
+        msg: &'a str,
+    },
+    ErrNicknameInUse {
+        nick: &'a str,
+        msg: &'a str,
+    },
+    ErrUserNotInChannel {
+        nick: &'a str,
+        chan: &'a ChanNameRef,
+        msg: &'a str,
+    },
+    ErrNotOnChannel {
+        chan: &'a ChanNameRef,
+        msg: &'a str,
+    },
+    ErrUserOnChannel {
+        nick: &'a str,
+        chan: &'a ChanNameRef,
+        msg: &'a str,
+    },
+    ErrNotRegistered {
+        msg: &'a str,
+    },
+    ErrNeedMoreParams {
+        cmd: &'a str,
+        msg: &'a str,
+    },
+    ErrAlreadyRegistered {
+        msg: &'a str,
+    },
+    ErrPasswdMismatch {
+        msg: &'a str,
+    },
+    ErrYoureBannedCreep {
+        msg: &'a str,
+    },
+    ErrKeySet {
+        chan: &'a ChanNameRef,
+        msg: &'a str,
+    },
+    ErrChannelIsFull {
+        chan: &'a ChanNameRef,
+        msg: &'a str,
+    },
+    ErrUnknownMode {
+        mode: &'a str,
+        msg: &'a str,
+    },
+    ErrInviteOnlyChan {
+        chan: &'a ChanNameRef,
+        msg: &'a str,
+    },
+    ErrBannedFromChan {
+        chan: &'a ChanNameRef,
+        msg: &'a str,
+    },
+    ErrBadChannelKey {
+        chan: &'a ChanNameRef,
+        msg: &'a str,
+    },
+    ErrNeedReggedNick {
+        chan: &'a ChanNameRef,
+        msg: &'a str,
+    },
+    ErrNoPrivileges {
+        msg: &'a str,
+    },
+    ErrChanOPrivsNeeded {
+        chan: &'a ChanNameRef,
+        msg: &'a str,
+    },
+    ErrUModeUnknownFlag {
+        msg: &'a str,
+    },
+    ErrUsersDontMatch {
+        msg: &'a str,
+    },
+
+    RplLoggedIn {
+        mask: &'a str,
+        account: &'a str,
+        msg: &'a str,
+    },
+    RplLoggedOut {
+        mask: &'a str,
+        msg: &'a str,
+    },
+    ErrNickLocked {
+        msg: &'a str,
+    },
+    RplSaslSuccess {
+        msg: &'a str,
+    },
+    ErrSaslFail {
+        msg: &'a str,
+    },
+    ErrSaslTooLong {
+        msg: &'a str,
+    },
+    ErrSaslAborted {
+        msg: &'a str,
+    },
+    ErrSaslAlready {
+        msg: &'a str,
+    },
+    /// `mechs` is a comma-separated list of SASL mechanisms.
+    RplSaslMechs {
+        mechs: &'a str,
+        msg: &'a str,
+    },
+
+    /// A numeric not in the list above, or with unexpected parameters. `params` includes the
+    /// client's nick.
+    Other {
+        num: u16,
+        params: &'a [String],
+    },
+}
+
+impl<'a> Numeric<'a> {
+    /// Decode a numeric reply. `params` are the parameters of `Cmd::Reply`, starting with the
+    /// client's nick.
+    pub fn parse(num: u16, params: &'a [String]) -> Numeric<'a> {
+        parse_numeric(num, params).unwrap_or(Numeric::Other { num, params })
+    }
+}
+
+impl Cmd {
+    /// Decode a numeric reply. Returns `None` if the command is not a `Cmd::Reply`.
+    pub fn numeric(&self) -> Option<Numeric<'_>> {
+        match self {
+            Cmd::Reply { num, params } => Some(Numeric::parse(*num, params)),
+            _ => None,
+        }
+    }
+}
+
+/// Is the numeric an error reply?
+pub fn is_error(num: u16) -> bool {
+    (400..600).contains(&num)
+        || matches!(
+            num,
+            ERR_NICKLOCKED | ERR_SASLFAIL | ERR_SASLTOOLONG | ERR_SASLABORTED | ERR_SASLALREADY
+        )
+}
+
+fn parse_numeric(num: u16, params: &[String]) -> Option<Numeric<'_>> {
+    use Numeric::*;
+
+    // Parameters after the client's nick
+    let args = params.get(1..).unwrap_or(&[]);
+    let chan = ChanNameRef::new;
+
+    Some(match (num, args) {
+        (RPL_WELCOME, [.., msg]) => RplWelcome { msg },
+        (RPL_YOURHOST, [.., msg]) => RplYourHost { msg },
+        (RPL_CREATED, [.., msg]) => RplCreated { msg },
+        (RPL_MYINFO, [servername, version, ..]) => RplMyInfo {
+            servername,
+            version,
+        },
+        (RPL_ISUPPORT, [_, ..]) => RplISupport {
+            tokens: &params[1..params.len() - 1],
+        },
+        (RPL_STATSCONN, [.., msg]) => RplStatsConn { msg },
+        (RPL_LUSERCLIENT, [.., msg]) => RplLuserClient { msg },
+        (RPL_LUSEROP, [ops, msg]) => RplLuserOp {
+            ops: ops.parse().ok()?,
+            msg,
+        },
+        (RPL_LUSERUNKNOWN, [connections, msg]) => RplLuserUnknown {
+            connections: connections.parse().ok()?,
+            msg,
+        },
+        (RPL_LUSERCHANNELS, [chans, msg]) => RplLuserChannels {
+            chans: chans.parse().ok()?,
+            msg,
+        },
+        (RPL_LUSERME, [.., msg]) => RplLuserMe { msg },
+        (RPL_LOCALUSERS, [.., msg]) => RplLocalUsers { msg },
+        (RPL_GLOBALUSERS, [.., msg]) => RplGlobalUsers { msg },
+
+        (RPL_AWAY, [nick, msg, ..]) => RplAway { nick, msg },
+        (RPL_UNAWAY, [msg, ..]) => RplUnaway { msg },
+        (RPL_NOWAWAY, [msg, ..]) => RplNowAway { msg },
+
+        (RPL_WHOISUSER, [nick, user, host, _, realname]) => RplWhoisUser {
+            nick,
+            user,
+            host,
+            realname,
+        },
+        (RPL_WHOISSERVER, [nick, server, info]) => RplWhoisServer { nick, server, info },
+        (RPL_WHOISOPERATOR, [nick, msg]) => RplWhoisOperator { nick, msg },
+        (RPL_WHOWASUSER, [nick, user, host, _, realname]) => RplWhoWasUser {
+            nick,
+            user,
+            host,
+            realname,
+        },
+        (RPL_ENDOFWHO, [mask, ..]) => RplEndOfWho { mask },
+        (RPL_WHOISIDLE, [nick, idle, rest @ ..]) => RplWhoisIdle {
+            nick,
+            idle: idle.parse().ok()?,
+            signon: match rest {
+                [signon, _] => Some(signon.parse().ok()?),
+                _ => None,
+            },
+        },
+        (RPL_ENDOFWHOIS, [nick, ..]) => RplEndOfWhois { nick },
+        (RPL_WHOISCHANNELS, [nick, chans]) => RplWhoisChannels { nick, chans },
+        (RPL_WHOISACCOUNT, [nick, account, _]) => RplWhoisAccount { nick, account },
+        (RPL_ENDOFWHOWAS, [nick, ..]) => RplEndOfWhoWas { nick },
+
+        (RPL_LISTSTART, _) => RplListStart,
+        (RPL_LIST, [name, visible, topic]) => RplList {
+            chan: chan(name),
+            visible: visible.parse().ok()?,
+            topic,
+        },
+        (RPL_LISTEND, _) => RplListEnd,
+
+        (RPL_CHANNELMODEIS, [name, _, ..]) => RplChannelModeIs {
+            chan: chan(name),
+            modes: &params[2..],
+        },
+        (RPL_CREATIONTIME, [name, time]) => RplCreationTime {
+            chan: chan(name),
+            time: time.parse().ok()?,
+        },
+        (RPL_NOTOPIC, [name, ..]) => RplNoTopic { chan: chan(name) },
+        // RFC 2812 says this will have 2 arguments, but servers send 3 (extra one being our nick)
+        (RPL_TOPIC, _) => match params {
+            [.., name, topic] if params.len() == 2 || params.len() == 3 => RplTopic {
+                chan: chan(name),
+                topic,
+            },
+            _ => return None,
+        },
+        (RPL_TOPICWHOTIME, [name, setter, time]) => RplTopicWhoTime {
+            chan: chan(name),
+            setter,
+            time: time.parse().ok()?,
+        },
+        (RPL_INVITING, [nick, name]) => RplInviting {
+            nick,
+            chan: chan(name),
+        },
+
+        (RPL_WHOREPLY, [chan, user, host, server, nick, flags, hopcount_realname]) => {
+            let (hopcount, realname) = match hopcount_realname.find(' ') {
+                None => (hopcount_realname.as_str(), ""),
+                Some(idx) => (&hopcount_realname[..idx], &hopcount_realname[idx + 1..]),
+            };
+            RplWhoReply {
+                chan,
+                user,
+                host,
+                server,
+                nick,
+                flags,
+                hopcount: hopcount.parse().ok()?,
+                realname,
+            }
+        }
+        (RPL_NAMREPLY, [symbol, name, nicks, ..]) => RplNamReply {
+            symbol,
+            chan: chan(name),
+            nicks,
+        },
+        (RPL_ENDOFNAMES, [name, ..]) => RplEndOfNames { chan: chan(name) },
+        (RPL_BANLIST | RPL_EXCEPTLIST | RPL_INVITELIST, [name, mask, rest @ ..]) => {
+            let chan = chan(name);
+            let setter = rest.first().map(String::as_str);
+            let time = match rest.get(1) {
+                Some(time) => Some(time.parse().ok()?),
+                None => None,
+            };
+            match num {
+                RPL_BANLIST => RplBanList {
+                    chan,
+                    mask,
+                    setter,
+                    time,
+                },
+                RPL_EXCEPTLIST => RplExceptList {
+                    chan,
+                    mask,
+                    setter,
+                    time,
+                },
+                _ => RplInviteList {
+                    chan,
+                    mask,
+                    setter,
+                    time,
+                },
+            }
+        }
+        (RPL_ENDOFBANLIST, [name, ..]) => RplEndOfBanList { chan: chan(name) },
+        (RPL_ENDOFEXCEPTLIST, [name, ..]) => RplEndOfExceptList { chan: chan(name) },
+        (RPL_ENDOFINVITELIST, [name, ..]) => RplEndOfInviteList { chan: chan(name) },
+
+        (RPL_MOTD, [line]) => RplMotd { line },
+        (RPL_MOTDSTART, [msg]) => RplMotdStart { msg },
+        (RPL_ENDOFMOTD, [msg]) => RplEndOfMotd { msg },
+        (RPL_YOUREOPER, [msg]) => RplYoureOper { msg },
+        (RPL_HOSTHIDDEN, [host, ..]) => RplHostHidden { host },
+
+        (ERR_NOSUCHNICK, [nick, msg]) => ErrNoSuchNick { nick, msg },
+        (ERR_NOSUCHSERVER, [server, msg]) => ErrNoSuchServer { server, msg },
+        (ERR_NOSUCHCHANNEL, [name, msg]) => ErrNoSuchChannel {
+            chan: chan(name),
+            msg,
+        },
+        (ERR_CANNOTSENDTOCHAN, [name, msg]) => ErrCannotSendToChan {
+            chan: chan(name),
+            msg,
+        },
+        (ERR_TOOMANYCHANNELS, [name, msg]) => ErrTooManyChannels {
+            chan: chan(name),
+            msg,
+        },
+        (ERR_WASNOSUCHNICK, [nick, msg]) => ErrWasNoSuchNick { nick, msg },
+        (ERR_UNKNOWNCOMMAND, [cmd, msg]) => ErrUnknownCommand { cmd, msg },
+        (ERR_NOMOTD, [msg]) => ErrNoMotd { msg },
+        (ERR_NONICKNAMEGIVEN, [msg]) => ErrNoNicknameGiven { msg },
+        (ERR_ERRONEUSNICKNAME, [nick, msg]) => ErrErroneusNickname { nick, msg },
+        (ERR_NICKNAMEINUSE, [nick, msg]) => ErrNicknameInUse { nick, msg },
+        (ERR_USERNOTINCHANNEL, [nick, name, msg]) => ErrUserNotInChannel {
+            nick,
+            chan: chan(name),
+            msg,
+        },
+        (ERR_NOTONCHANNEL, [name, msg]) => ErrNotOnChannel {
+            chan: chan(name),
+            msg,
+        },
+        (ERR_USERONCHANNEL, [nick, name, msg]) => ErrUserOnChannel {
+            nick,
+            chan: chan(name),
+            msg,
+        },
+        (ERR_NOTREGISTERED, [msg]) => ErrNotRegistered { msg },
+        (ERR_NEEDMOREPARAMS, [cmd, msg]) => ErrNeedMoreParams { cmd, msg },
+        (ERR_ALREADYREGISTERED, [msg]) => ErrAlreadyRegistered { msg },
+        (ERR_PASSWDMISMATCH, [msg]) => ErrPasswdMismatch { msg },
+        (ERR_YOUREBANNEDCREEP, [msg]) => ErrYoureBannedCreep { msg },
+        (ERR_KEYSET, [name, msg]) => ErrKeySet {
+            chan: chan(name),
+            msg,
+        },
+        (ERR_CHANNELISFULL, [name, msg]) => ErrChannelIsFull {
+            chan: chan(name),
+            msg,
+        },
+        (ERR_UNKNOWNMODE, [mode, msg]) => ErrUnknownMode { mode, msg },
+        (ERR_INVITEONLYCHAN, [name, msg]) => ErrInviteOnlyChan {
+            chan: chan(name),
+            msg,
+        },
+        (ERR_BANNEDFROMCHAN, [name, msg]) => ErrBannedFromChan {
+            chan: chan(name),
+            msg,
+        },
+        (ERR_BADCHANNELKEY, [name, msg]) => ErrBadChannelKey {
+            chan: chan(name),
+            msg,
+        },
+        (ERR_NEEDREGGEDNICK, [name, msg]) => ErrNeedReggedNick {
+            chan: chan(name),
+            msg,
+        },
+        (ERR_NOPRIVILEGES, [msg]) => ErrNoPrivileges { msg },
+        (ERR_CHANOPRIVSNEEDED, [name, msg]) => ErrChanOPrivsNeeded {
+            chan: chan(name),
+            msg,
+        },
+        (ERR_UMODEUNKNOWNFLAG, [msg]) => ErrUModeUnknownFlag { msg },
+        (ERR_USERSDONTMATCH, [msg]) => ErrUsersDontMatch { msg },
+
+        (RPL_LOGGEDIN, [mask, account, msg]) => RplLoggedIn { mask, account, msg },
+        (RPL_LOGGEDOUT, [mask, msg]) => RplLoggedOut { mask, msg },
+        (ERR_NICKLOCKED, [msg]) => ErrNickLocked { msg },
+        (RPL_SASLSUCCESS, [msg]) => RplSaslSuccess { msg },
+        (ERR_SASLFAIL, [msg]) => ErrSaslFail { msg },
+        (ERR_SASLTOOLONG, [msg]) => ErrSaslTooLong { msg },
+        (ERR_SASLABORTED, [msg]) => ErrSaslAborted { msg },
+        (ERR_SASLALREADY, [msg]) => ErrSaslAlready { msg },
+        (RPL_SASLMECHS, [mechs, msg]) => RplSaslMechs { mechs, msg },
+
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(params: &[&str]) -> Vec<String> {
+        params.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn numeric_parsing() {
+        let ps = params(&["tiny", "#chan", "a topic"]);
+        assert_eq!(
+            Numeric::parse(RPL_TOPIC, &ps),
+            Numeric::RplTopic {
+                chan: ChanNameRef::new("#chan"),
+                topic: "a topic"
+            }
+        );
+
+        let ps = params(&["tiny", "#chan", "nick!u@h", "1600000000"]);
+        assert_eq!(
+            Numeric::parse(RPL_TOPICWHOTIME, &ps),
+            Numeric::RplTopicWhoTime {
+                chan: ChanNameRef::new("#chan"),
+                setter: "nick!u@h",
+                time: 1600000000
+            }
+        );
+
+        let ps = params(&["tiny", "#chan", "*!*@spam", "op", "1600000000"]);
+        assert_eq!(
+            Numeric::parse(RPL_BANLIST, &ps),
+            Numeric::RplBanList {
+                chan: ChanNameRef::new("#chan"),
+                mask: "*!*@spam",
+                setter: Some("op"),
+                time: Some(1600000000),
+            }
+        );
+
+        let ps = params(&["tiny", "#chan", "*!*@friend"]);
+        assert_eq!(
+            Numeric::parse(RPL_EXCEPTLIST, &ps),
+            Numeric::RplExceptList {
+                chan: ChanNameRef::new("#chan"),
+                mask: "*!*@friend",
+                setter: None,
+                time: None,
+            }
+        );
+
+        let ps = params(&["tiny", "nick", "~u", "host", "*", "Real Name"]);
+        assert_eq!(
+            Numeric::parse(RPL_WHOISUSER, &ps),
+            Numeric::RplWhoisUser {
+                nick: "nick",
+                user: "~u",
+                host: "host",
+                realname: "Real Name"
+            }
+        );
+
+        let ps = params(&["tiny", "#c", "~u", "h", "s", "nick", "H@", "0 Real Name"]);
+        assert_eq!(
+            Numeric::parse(RPL_WHOREPLY, &ps),
+            Numeric::RplWhoReply {
+                chan: "#c",
+                user: "~u",
+                host: "h",
+                server: "s",
+                nick: "nick",
+                flags: "H@",
+                hopcount: 0,
+                realname: "Real Name"
+            }
+        );
+
+        let ps = params(&["tiny", "CHANTYPES=#", "EXCEPTS", "are supported"]);
+        assert_eq!(
+            Numeric::parse(RPL_ISUPPORT, &ps),
+            Numeric::RplISupport { tokens: &ps[1..3] }
+        );
+
+        let ps = params(&["*", "nick", "Nickname is already in use"]);
+        assert_eq!(
+            Numeric::parse(ERR_NICKNAMEINUSE, &ps),
+            Numeric::ErrNicknameInUse {
+                nick: "nick",
+                msg: "Nickname is already in use"
+            }
+        );
+    }
+
+    #[test]
+    fn unexpected_params() {
+        // Not a number
+        let ps = params(&["tiny", "#chan", "nick", "yesterday"]);
+        assert_eq!(
+            Numeric::parse(RPL_TOPICWHOTIME, &ps),
+            Numeric::Other {
+                num: RPL_TOPICWHOTIME,
+                params: &ps
+            }
+        );
+
+        // Missing parameters
+        let ps = params(&["tiny", "nick"]);
+        assert_eq!(
+            Numeric::parse(ERR_NOSUCHNICK, &ps),
+            Numeric::Other {
+                num: ERR_NOSUCHNICK,
+                params: &ps
+            }
+        );
+
+        // Unknown numeric
+        let ps = params(&["tiny", "blah"]);
+        assert_eq!(
+            Numeric::parse(999, &ps),
+            Numeric::Other {
+                num: 999,
+                params: &ps
+            }
+        );
+        assert!(is_error(ERR_NOSUCHNICK));
+        assert!(is_error(ERR_SASLFAIL));
+        assert!(!is_error(RPL_SASLSUCCESS));
+    }
+}
@@ -0,0 +1,528 @@
+//! Rendering messages in IRC wire format. `Display` implementations render a message without the
+//! `\r\n` suffix, `Msg::serialize` adds the suffix.
+//!
+//! Parsing a serialized message gives back the original message (see the property tests below),
+//! as long as the message is something the parser can generate. For example, a middle parameter
+//! (e.g. a channel name) can't contain spaces, and `chans` fields of `QUIT` and `NICK`, which
+//! are filled in by `libtiny_client`, are not a part of the wire format.
+
+use crate::ctcp::ctcp_msg;
+use crate::{escape_tag_value, Cmd, Msg, MsgTarget, Pfx, CTCP};
+
+use libtiny_common::ChanName;
+
+use std::fmt;
+
+impl Msg {
+    /// Render the message in wire format, with the `\r\n` suffix.
+    pub fn serialize(&self) -> String {
+        format!("{}\r\n", self)
+    }
+}
+
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.tags.is_empty() {
+            // Sort the tags to render the same message the same way every time
+            let mut tags: Vec<(&String, &String)> = self.tags.iter().collect();
+            tags.sort();
+
+            let mut tags_str = String::new();
+            for (tag_idx, (key, value)) in tags.into_iter().enumerate() {
+                if tag_idx != 0 {
+                    tags_str.push(';');
+                }
+                tags_str.push_str(key);
+                if !value.is_empty() {
+                    tags_str.push('=');
+                    escape_tag_value(value, &mut tags_str);
+                }
+            }
+            write!(f, "@{} ", tags_str)?;
+        }
+
+        if let Some(pfx) = &self.pfx {
+            write!(f, ":{} ", pfx)?;
+        }
+
+        write!(f, "{}", self.cmd)
+    }
+}
+
+impl fmt::Display for Pfx {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pfx::Server(name) | Pfx::Ambiguous(name) => f.write_str(name),
+            Pfx::User { nick, user } => {
+                if user.is_empty() {
+                    f.write_str(nick)
+                } else {
+                    write!(f, "{}!{}", nick, user)
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for MsgTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MsgTarget::Chan(chan) => f.write_str(chan.display()),
+            MsgTarget::User(nick) => f.write_str(nick),
+        }
+    }
+}
+
+impl fmt::Display for CTCP {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl fmt::Display for Cmd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cmd::PRIVMSG {
+                targets,
+                msg,
+                is_notice,
+                ctcp,
+            } => {
+                let cmd = if *is_notice { "NOTICE" } else { "PRIVMSG" };
+                let target = targets
+                    .iter()
+                    .map(MsgTarget::to_string)
+                    .collect::<Vec<_>>()
+                    .join(",");
+                match ctcp {
+                    None => write_cmd(f, cmd, &[&target], Some(msg)),
+                    Some(ctcp) => write_cmd(f, cmd, &[&target], Some(&ctcp_msg(ctcp, msg))),
+                }
+            }
+
+            Cmd::JOIN {
+                chans,
+                keys,
+                account,
+                realname,
+            } => {
+                let chans = join_chans(chans);
+                if let Some(realname) = realname {
+                    let account = account.as_deref().unwrap_or("*");
+                    write_cmd(f, "JOIN", &[&chans, account], Some(realname))
+                } else if keys.is_empty() {
+                    write_cmd(f, "JOIN", &[&chans], None)
+                } else {
+                    write_cmd(f, "JOIN", &[&chans, &keys.join(",")], None)
+                }
+            }
+
+            Cmd::PART { chans, msg } => write_cmd(f, "PART", &[&join_chans(chans)], msg.as_deref()),
+
+            Cmd::QUIT { msg, chans: _ } => write_cmd(f, "QUIT", &[], msg.as_deref()),
+
+            Cmd::NICK { nick, chans: _ } => write_cmd(f, "NICK", &[nick], None),
+
+            Cmd::PING { server } => write_cmd(f, "PING", &[server], None),
+
+            Cmd::PONG { server } => write_cmd(f, "PONG", &[server], None),
+
+            Cmd::ERROR { msg } => write_cmd(f, "ERROR", &[], Some(msg)),
+
+            Cmd::TOPIC { chan, topic } => write_cmd(f, "TOPIC", &[chan.display()], Some(topic)),
+
+            Cmd::KICK { chan, nick, msg } => {
+                write_cmd(f, "KICK", &[chan.display(), nick], msg.as_deref())
+            }
+
+            Cmd::MODE {
+                target,
+                modes,
+                args,
+                ..
+            } => {
+                let target = target.to_string();
+                let mut params: Vec<&str> = vec![&target];
+                if !modes.is_empty() {
+                    params.push(modes);
+                    params.extend(args.iter().map(String::as_str));
+                }
+                write_cmd(f, "MODE", &params, None)
+            }
+
+            Cmd::INVITE { nick, chan } => write_cmd(f, "INVITE", &[nick, chan.display()], None),
+
+            Cmd::WALLOPS { msg } => write_cmd(f, "WALLOPS", &[], Some(msg)),
+
+            Cmd::AWAY { msg } => write_cmd(f, "AWAY", &[], msg.as_deref()),
+
+            Cmd::ACCOUNT { account } => {
+                write_cmd(f, "ACCOUNT", &[account.as_deref().unwrap_or("*")], None)
+            }
+
+            Cmd::CHGHOST { user, host } => write_cmd(f, "CHGHOST", &[user, host], None),
+
+            Cmd::SETNAME { realname } => write_cmd(f, "SETNAME", &[], Some(realname)),
+
+            Cmd::CAP {
+                client,
+                subcommand,
+                params,
+                more,
+            } => {
+                let mut args = vec![client.as_str(), subcommand.as_str()];
+                if *more {
+                    args.push("*");
+                }
+                write_cmd(f, "CAP", &args, Some(&params.join(" ")))
+            }
+
+            Cmd::AUTHENTICATE { param } => write_cmd(f, "AUTHENTICATE", &[param], None),
+
+            Cmd::BATCH {
+                reference,
+                start,
+                params,
+            } => {
+                let reference = format!("{}{}", if *start { '+' } else { '-' }, reference);
+                let mut args = vec![reference.as_str()];
+                args.extend(params.iter().map(String::as_str));
+                write_cmd(f, "BATCH", &args, None)
+            }
+
+            Cmd::Other { cmd, params } => {
+                let params: Vec<&str> = params.iter().map(String::as_str).collect();
+                write_cmd(f, cmd, &params, None)
+            }
+
+            Cmd::Reply { num, params } => {
+                let params: Vec<&str> = params.iter().map(String::as_str).collect();
+                write_cmd(f, &format!("{:03}", num), &params, None)
+            }
+        }
+    }
+}
+
+fn join_chans(chans: &[ChanName]) -> String {
+    chans
+        .iter()
+        .map(ChanName::display)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Write a command and its parameters. `trailing` is always rendered as a trailing parameter
+/// (with a ':' prefix). When `trailing` is `None`, the last parameter in `params` is rendered as a
+/// trailing parameter if it needs to be (when it's empty, has spaces, or starts with ':').
+fn write_cmd(
+    f: &mut fmt::Formatter<'_>,
+    cmd: &str,
+    params: &[&str],
+    trailing: Option<&str>,
+) -> fmt::Result {
+    f.write_str(cmd)?;
+
+    for (param_idx, param) in params.iter().enumerate() {
+        let is_last = trailing.is_none() && param_idx == params.len() - 1;
+        if is_last && (param.is_empty() || param.contains(' ') || param.starts_with(':')) {
+            write!(f, " :{}", param)?;
+        } else {
+            write!(f, " {}", param)?;
+        }
+    }
+
+    if let Some(trailing) = trailing {
+        write!(f, " :{}", trailing)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{default_chan_mode_takes_arg, mode_str, parse_irc_msg, ModeChange};
+    use proptest::collection::{hash_map, vec};
+    use proptest::option;
+    use proptest::prelude::*;
+
+    #[test]
+    fn serialize_examples() {
+        let msg = Msg {
+            tags: vec![("msgid".to_owned(), "a;b c".to_owned())]
+                .into_iter()
+                .collect(),
+            pfx: Some(Pfx::User {
+                nick: "nick".to_owned(),
+                user: "~u@host".to_owned(),
+            }),
+            cmd: Cmd::PRIVMSG {
+                targets: vec![MsgTarget::Chan(ChanName::new("#chan".to_owned()))],
+                msg: "waves".to_owned(),
+                is_notice: false,
+                ctcp: Some(CTCP::Action),
+            },
+        };
+        assert_eq!(
+            msg.serialize(),
+            "@msgid=a\\:b\\sc :nick!~u@host PRIVMSG #chan :\x01ACTION waves\x01\r\n"
+        );
+
+        let msg = Msg {
+            tags: Default::default(),
+            pfx: Some(Pfx::Server("x.y.z".to_owned())),
+            cmd: Cmd::Reply {
+                num: 1,
+                params: vec!["tiny".to_owned(), "Welcome!".to_owned()],
+            },
+        };
+        assert_eq!(msg.serialize(), ":x.y.z 001 tiny Welcome!\r\n");
+
+        let msg = Msg {
+            tags: Default::default(),
+            pfx: None,
+            cmd: Cmd::Other {
+                cmd: "FOO".to_owned(),
+                params: vec!["a".to_owned(), ":b".to_owned()],
+            },
+        };
+        assert_eq!(msg.serialize(), "FOO a ::b\r\n");
+
+        let msg = Msg {
+            tags: Default::default(),
+            pfx: None,
+            cmd: Cmd::QUIT {
+                msg: None,
+                chans: vec![],
+            },
+        };
+        assert_eq!(msg.serialize(), "QUIT\r\n");
+    }
+
+    fn round_trip(msg: &Msg) -> Msg {
+        let mut buf = msg.serialize().into_bytes();
+        let parsed = parse_irc_msg(&mut buf).unwrap().unwrap();
+        assert!(buf.is_empty());
+        parsed
+    }
+
+    //
+    // Generators for messages that the parser can generate
+    //
+
+    /// Text of a trailing parameter
+    fn trailing() -> impl Strategy<Value = String> {
+        "[^\r\n\x00\x01]{0,40}"
+    }
+
+    /// A middle parameter: not empty, no spaces, doesn't start with ':'
+    fn middle() -> impl Strategy<Value = String> {
+        "[a-zA-Z0-9#&!@.*=+/-][a-zA-Z0-9#&!@.*=+/:-]{0,15}"
+    }
+
+    fn nick() -> impl Strategy<Value = String> {
+        "[a-zA-Z][a-zA-Z0-9_`^{}|\\[\\]-]{0,15}"
+    }
+
+    fn chan() -> impl Strategy<Value = ChanName> {
+        "#[a-zA-Z0-9_.-]{1,20}".prop_map(ChanName::new)
+    }
+
+    fn msg_target() -> impl Strategy<Value = MsgTarget> {
+        prop_oneof![
+            chan().prop_map(MsgTarget::Chan),
+            nick().prop_map(MsgTarget::User)
+        ]
+    }
+
+    fn pfx() -> impl Strategy<Value = Pfx> {
+        prop_oneof![
+            "[a-z]{1,8}(\\.[a-z]{1,8}){1,2}".prop_map(Pfx::Server),
+            "[a-zA-Z][a-zA-Z0-9-]{0,10}".prop_map(Pfx::Ambiguous),
+            (nick(), "~?[a-z]{1,8}@[a-z0-9./-]{1,20}")
+                .prop_map(|(nick, user)| Pfx::User { nick, user }),
+            // Nicks with characters that servernames can't have are parsed as nicks
+            "[a-z]{1,5}[_`^{}|\\[\\]][a-z]{0,5}".prop_map(|nick| Pfx::User {
+                nick,
+                user: String::new()
+            }),
+        ]
+    }
+
+    fn ctcp() -> impl Strategy<Value = CTCP> {
+        prop_oneof![
+            Just(CTCP::Version),
+            Just(CTCP::Action),
+            Just(CTCP::Ping),
+            Just(CTCP::Time),
+            Just(CTCP::ClientInfo),
+            Just(CTCP::UserInfo),
+            Just(CTCP::Source),
+            Just(CTCP::DCC),
+            "[A-Z]{1,10}".prop_map(|s| CTCP::from_name(&s)),
+        ]
+    }
+
+    fn mode_changes() -> impl Strategy<Value = Vec<ModeChange>> {
+        vec(
+            (any::<bool>(), "[beIqaohvklimnst]", middle()).prop_map(|(set, mode, arg)| {
+                let mode = mode.chars().next().unwrap();
+                ModeChange {
+                    set,
+                    mode,
+                    arg: if default_chan_mode_takes_arg(mode, set) {
+                        Some(arg)
+                    } else {
+                        None
+                    },
+                }
+            }),
+            0..5,
+        )
+    }
+
+    fn user_mode_changes() -> impl Strategy<Value = Vec<ModeChange>> {
+        vec(
+            (any::<bool>(), "[iwoxZ]").prop_map(|(set, mode)| ModeChange {
+                set,
+                mode: mode.chars().next().unwrap(),
+                arg: None,
+            }),
+            0..5,
+        )
+    }
+
+    /// A MODE command with the mode string and arguments of `changes`, as the parser returns
+    fn mode_cmd(target: MsgTarget, changes: Vec<ModeChange>) -> Cmd {
+        let mode_str = mode_str(&changes);
+        let mut words = mode_str.split(' ').filter(|word| !word.is_empty());
+        let modes = words.next().unwrap_or("").to_owned();
+        let args = words.map(str::to_owned).collect();
+        Cmd::MODE {
+            target,
+            changes,
+            modes,
+            args,
+        }
+    }
+
+    /// Parameters of a numeric reply or an unknown command
+    fn params() -> impl Strategy<Value = Vec<String>> {
+        (vec(middle(), 0..14), option::of(trailing())).prop_map(|(mut params, trailing)| {
+            params.extend(trailing);
+            params
+        })
+    }
+
+    fn cmd() -> impl Strategy<Value = Cmd> {
+        prop_oneof![
+            (
+                vec(msg_target(), 1..4),
+                trailing(),
+                any::<bool>(),
+                option::of(ctcp())
+            )
+                .prop_map(|(targets, msg, is_notice, ctcp)| Cmd::PRIVMSG {
+                    targets,
+                    msg,
+                    is_notice,
+                    ctcp
+                }),
+            vec(chan(), 1..4)
+                .prop_flat_map(|chans| {
+                    let n_chans = chans.len();
+                    (Just(chans), vec("[a-zA-Z0-9]{0,10}", 0..=n_chans))
+                })
+                .prop_map(|(chans, mut keys)| {
+                    // An empty keys parameter is the same as no keys
+                    while keys.last().map(String::is_empty) == Some(true) {
+                        keys.pop();
+                    }
+                    Cmd::JOIN {
+                        chans,
+                        keys,
+                        account: None,
+                        realname: None,
+                    }
+                }),
+            // extended-join
+            (chan(), option::of(nick()), trailing()).prop_map(|(chan, account, realname)| {
+                Cmd::JOIN {
+                    chans: vec![chan],
+                    keys: vec![],
+                    account,
+                    realname: Some(realname),
+                }
+            }),
+            (vec(chan(), 1..4), option::of(trailing()))
+                .prop_map(|(chans, msg)| Cmd::PART { chans, msg }),
+            option::of(trailing()).prop_map(|msg| Cmd::QUIT { msg, chans: vec![] }),
+            nick().prop_map(|nick| Cmd::NICK {
+                nick,
+                chans: vec![]
+            }),
+            trailing().prop_map(|server| Cmd::PING { server }),
+            trailing().prop_map(|server| Cmd::PONG { server }),
+            trailing().prop_map(|msg| Cmd::ERROR { msg }),
+            (chan(), trailing()).prop_map(|(chan, topic)| Cmd::TOPIC { chan, topic }),
+            (chan(), nick(), option::of(trailing())).prop_map(|(chan, nick, msg)| Cmd::KICK {
+                chan,
+                nick,
+                msg
+            }),
+            (chan(), mode_changes())
+                .prop_map(|(chan, changes)| mode_cmd(MsgTarget::Chan(chan), changes)),
+            (nick(), user_mode_changes())
+                .prop_map(|(nick, changes)| mode_cmd(MsgTarget::User(nick), changes)),
+            (nick(), chan()).prop_map(|(nick, chan)| Cmd::INVITE { nick, chan }),
+            trailing().prop_map(|msg| Cmd::WALLOPS { msg }),
+            option::of(trailing()).prop_map(|msg| Cmd::AWAY { msg }),
+            option::of(nick()).prop_map(|account| Cmd::ACCOUNT { account }),
+            (middle(), middle()).prop_map(|(user, host)| Cmd::CHGHOST { user, host }),
+            trailing().prop_map(|realname| Cmd::SETNAME { realname }),
+            (
+                prop_oneof![Just("*".to_owned()), nick()],
+                "[A-Z]{2,4}",
+                vec("[a-z][a-z0-9=./-]{0,10}", 1..5),
+                any::<bool>()
+            )
+                .prop_map(|(client, subcommand, params, more)| Cmd::CAP {
+                    client,
+                    subcommand,
+                    params,
+                    more
+                }),
+            "[a-zA-Z0-9+/=]{1,20}".prop_map(|param| Cmd::AUTHENTICATE { param }),
+            ("[a-zA-Z0-9]{1,10}", vec(middle(), 1..3)).prop_map(|(reference, params)| {
+                Cmd::BATCH {
+                    reference,
+                    start: true,
+                    params,
+                }
+            }),
+            "[a-zA-Z0-9]{1,10}".prop_map(|reference| Cmd::BATCH {
+                reference,
+                start: false,
+                params: vec![]
+            }),
+            ("X[A-Z]{2,10}", params()).prop_map(|(cmd, params)| Cmd::Other { cmd, params }),
+            (0u16..1000, params()).prop_map(|(num, params)| Cmd::Reply { num, params }),
+        ]
+    }
+
+    fn msg() -> impl Strategy<Value = Msg> {
+        (
+            hash_map("\\+?[a-z][a-z0-9./-]{0,10}", "[^\r\n\x00]{0,20}", 0..3),
+            option::of(pfx()),
+            cmd(),
+        )
+            .prop_map(|(tags, pfx, cmd)| Msg { tags, pfx, cmd })
+    }
+
+    proptest! {
+        #[test]
+        fn serialize_round_trip(msg in msg()) {
+            prop_assert_eq!(round_trip(&msg), msg);
+        }
+    }
+}
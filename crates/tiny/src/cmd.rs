@@ -1,8 +1,9 @@
 use crate::config;
 use crate::ui::UI;
 use crate::utils;
-use libtiny_client::{Client, ServerInfo};
-use libtiny_common::{ChanName, ChanNameRef, MsgSource, MsgTarget};
+use libtiny_client::{Client, ServerInfo, WhoisInfo};
+use libtiny_common::{ChanName, MsgSource, MsgTarget};
+use libtiny_wire as wire;
 
 use std::borrow::Borrow;
 use std::path::Path;
@@ -97,15 +98,17 @@ fn find_client<'a>(clients: &'a mut Vec<Client>, serv_name: &str) -> Option<&'a
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 
-static CMDS: [&Cmd; 9] = [
+static CMDS: [&Cmd; 11] = [
     &AWAY_CMD,
     &CLOSE_CMD,
     &CONNECT_CMD,
+    &CTCP_CMD,
     &JOIN_CMD,
     &ME_CMD,
     &MSG_CMD,
     &NAMES_CMD,
     &NICK_CMD,
+    &WHOIS_CMD,
     &HELP_CMD,
 ];
 
@@ -156,10 +159,14 @@ fn close(args: CmdArgs) {
         MsgSource::Chan { serv, chan } => {
             ui.close_chan_tab(&serv, chan.borrow());
             let client_idx = find_client_idx(&clients, &serv).unwrap();
+            clients[client_idx].cancel_queued(chan.display());
             clients[client_idx].part(&chan);
         }
         MsgSource::User { serv, nick } => {
             ui.close_user_tab(&serv, &nick);
+            if let Some(client) = find_client(clients, &serv) {
+                client.cancel_queued(&nick);
+            }
         }
     }
 }
@@ -262,10 +269,17 @@ fn connect_(
         auto_join: defaults
             .join
             .iter()
-            .map(|c| ChanNameRef::new(c).to_owned())
+            .filter_map(|c| libtiny_client::AutoJoin::parse(c))
             .collect(),
         nickserv_ident: None,
+        tls_client_cert: None,
         sasl_auth: None,
+        encoding: None,
+        send_encoding: None,
+        caps: libtiny_client::default_caps(),
+        send_burst: libtiny_client::DEFAULT_SEND_BURST,
+        send_interval_ms: libtiny_client::DEFAULT_SEND_INTERVAL_MS,
+        proxy: defaults.proxy.as_ref().map(config::Proxy::to_client_proxy),
     });
 
     // Spawn UI task
@@ -278,6 +292,66 @@ fn connect_(
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 
+static CTCP_CMD: Cmd = Cmd {
+    name: "ctcp",
+    cmd_fn: ctcp,
+    description: "Sends a CTCP query",
+    usage: "`/ctcp <nick> <command> [<args>]`",
+};
+
+/// Split `/ctcp` arguments into target, CTCP command and CTCP arguments.
+fn split_ctcp_args(args: &str) -> Option<(&str, &str, &str)> {
+    let mut words = args.trim().splitn(3, char::is_whitespace);
+    let target = words.next().filter(|s| !s.is_empty())?;
+    let cmd = words.next().filter(|s| !s.is_empty())?;
+    let args = words.next().unwrap_or("").trim();
+    Some((target, cmd, args))
+}
+
+fn ctcp(args: CmdArgs) {
+    let CmdArgs {
+        args,
+        ui,
+        clients,
+        src,
+        ..
+    } = args;
+
+    let (target, cmd, args) = match split_ctcp_args(args) {
+        None => {
+            return ui.add_client_err_msg(
+                &format!("Usage: {}", CTCP_CMD.usage),
+                &MsgTarget::CurrentTab,
+            );
+        }
+        Some(args) => args,
+    };
+
+    let ctcp = wire::CTCP::from_name(&cmd.to_uppercase());
+    // PING queries without arguments are sent with the current time, to show round-trip time when
+    // we get the reply
+    let ping_arg;
+    let args = if ctcp == wire::CTCP::Ping && args.is_empty() {
+        ping_arg = utils::now_millis().to_string();
+        &ping_arg
+    } else {
+        args
+    };
+
+    match find_client(clients, src.serv_name()) {
+        Some(client) => client.ctcp(target, &ctcp, args),
+        None => ui.add_client_err_msg(
+            &format!(
+                "Can't send CTCP: Not connected to server {}",
+                src.serv_name()
+            ),
+            &MsgTarget::CurrentTab,
+        ),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
 static JOIN_CMD: Cmd = Cmd {
     name: "join",
     cmd_fn: join,
@@ -460,17 +534,38 @@ fn names(args: CmdArgs) {
     };
 
     if let MsgSource::Chan { ref serv, ref chan } = src {
-        let nicks_vec = client.get_chan_nicks(chan);
+        let members = match client.get_channel(chan) {
+            None => vec![],
+            Some(info) => info.members,
+        };
         let target = MsgTarget::Chan { serv, chan };
         if words.is_empty() {
+            let nicks_vec: Vec<String> = members
+                .iter()
+                .map(|member| match member.prefixes.chars().next() {
+                    None => member.nick.clone(),
+                    Some(prefix) => format!("{}{}", prefix, member.nick),
+                })
+                .collect();
             ui.add_client_msg(
                 &format!("{} users: {}", nicks_vec.len(), nicks_vec.join(", ")),
                 &target,
             );
         } else {
             let nick = words[0];
-            if nicks_vec.iter().any(|v| v == nick) {
-                ui.add_client_msg(&format!("{} is online", nick), &target);
+            if members.iter().any(|member| member.nick == nick) {
+                let mut msg = format!("{} is online", nick);
+                if let Some(user) = client.get_user(nick) {
+                    if let Some(account) = user.account {
+                        msg.push_str(&format!(", logged in as {}", account));
+                    }
+                    match user.away {
+                        None => {}
+                        Some(away) if away.is_empty() => msg.push_str(", away"),
+                        Some(away) => msg.push_str(&format!(", away: {}", away)),
+                    }
+                }
+                ui.add_client_msg(&msg, &target);
             } else {
                 ui.add_client_msg(&format!("{} is not in the channel", nick), &target);
             }
@@ -511,6 +606,112 @@ fn nick(args: CmdArgs) {
     }
 }
 
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+static WHOIS_CMD: Cmd = Cmd {
+    name: "whois",
+    cmd_fn: whois,
+    description: "Shows information about a user",
+    usage: "`/whois <nick>`",
+};
+
+fn whois(args: CmdArgs) {
+    let CmdArgs {
+        args,
+        ui,
+        clients,
+        src,
+        ..
+    } = args;
+
+    let nick = match args.split_whitespace().next() {
+        None => {
+            return ui.add_client_err_msg(
+                &format!("Usage: {}", WHOIS_CMD.usage),
+                &MsgTarget::CurrentTab,
+            );
+        }
+        Some(nick) => nick.to_owned(),
+    };
+
+    let mut client = match find_client(clients, src.serv_name()) {
+        None => {
+            return ui.add_client_err_msg(
+                &format!(
+                    "Can't send WHOIS: Not connected to server {}",
+                    src.serv_name()
+                ),
+                &MsgTarget::CurrentTab,
+            );
+        }
+        Some(client) => client.clone(),
+    };
+
+    // Show the reply in the tab that the command was sent in
+    let ui = ui.clone();
+    tokio::task::spawn_local(async move {
+        let target = src.to_target();
+        match client.whois(&nick).await {
+            Ok(info) => {
+                for line in whois_lines(&info) {
+                    ui.add_client_msg(&line, &target);
+                }
+            }
+            Err(err) => {
+                ui.add_client_err_msg(&format!("WHOIS {}: {}", nick, err), &target);
+            }
+        }
+        ui.draw();
+    });
+}
+
+/// Lines to show for a WHOIS reply.
+fn whois_lines(info: &WhoisInfo) -> Vec<String> {
+    let nick = &info.nick;
+    let mut lines = vec![];
+    if let (Some(user), Some(host)) = (&info.user, &info.host) {
+        lines.push(format!(
+            "{} is {}@{} ({})",
+            nick,
+            user,
+            host,
+            info.realname.as_deref().unwrap_or("")
+        ));
+    }
+    if !info.chans.is_empty() {
+        lines.push(format!("{} is on {}", nick, info.chans.join(" ")));
+    }
+    if let Some(server) = &info.server {
+        lines.push(format!(
+            "{} is connected to {} ({})",
+            nick,
+            server,
+            info.server_info.as_deref().unwrap_or("")
+        ));
+    }
+    if let Some(account) = &info.account {
+        lines.push(format!("{} is logged in as {}", nick, account));
+    }
+    if let Some(away) = &info.away {
+        lines.push(format!("{} is away: {}", nick, away));
+    }
+    if info.operator {
+        lines.push(format!("{} is an IRC operator", nick));
+    }
+    if info.secure {
+        lines.push(format!("{} is using a secure connection", nick));
+    }
+    if let Some(idle) = info.idle {
+        lines.push(format!("{} has been idle for {} seconds", nick, idle));
+    }
+    for other in &info.other {
+        lines.push(format!("{} {}", nick, other));
+    }
+    lines
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
 static HELP_CMD: Cmd = Cmd {
     name: "help",
     cmd_fn: help,
@@ -567,3 +768,39 @@ fn test_msg_args() {
     assert_eq!(split_msg_args("foo ,bar"), Some(("foo", ",bar")));
     assert_eq!(split_msg_args("#blah blah"), None);
 }
+
+#[test]
+fn test_ctcp_args() {
+    assert_eq!(split_ctcp_args("nick ping"), Some(("nick", "ping", "")));
+    assert_eq!(
+        split_ctcp_args(" nick PING 123 456 "),
+        Some(("nick", "PING", "123 456"))
+    );
+    assert_eq!(split_ctcp_args("nick"), None);
+    assert_eq!(split_ctcp_args(""), None);
+}
+
+#[test]
+fn test_whois_lines() {
+    let info = WhoisInfo {
+        nick: "nick".to_owned(),
+        user: Some("~u".to_owned()),
+        host: Some("host".to_owned()),
+        realname: Some("Real Name".to_owned()),
+        account: Some("acc".to_owned()),
+        chans: vec!["@#a".to_owned(), "#b".to_owned()],
+        idle: Some(10),
+        other: vec!["is connecting from *@1.2.3.4".to_owned()],
+        ..WhoisInfo::default()
+    };
+    assert_eq!(
+        whois_lines(&info),
+        vec![
+            "nick is ~u@host (Real Name)",
+            "nick is on @#a #b",
+            "nick is logged in as acc",
+            "nick has been idle for 10 seconds",
+            "nick is connecting from *@1.2.3.4",
+        ]
+    );
+}
@@ -1,3 +1,6 @@
+use libtiny_client::TlsClientCert;
+use libtiny_wire::Encoding;
+use serde::de::Error;
 use serde::{Deserialize, Deserializer};
 use std::fs;
 use std::fs::File;
@@ -6,10 +9,98 @@ use std::path::{Path, PathBuf};
 
 #[derive(Clone, Deserialize, Debug, PartialEq, Eq)]
 pub(crate) struct SASLAuth {
+    #[serde(default)]
+    pub(crate) mechanism: SASLMechanism,
+    /// Not used with EXTERNAL
+    #[serde(default)]
     pub(crate) username: String,
+    /// Not used with EXTERNAL
+    #[serde(default)]
     pub(crate) password: String,
 }
 
+#[derive(Clone, Copy, Default, Deserialize, Debug, PartialEq, Eq)]
+pub(crate) enum SASLMechanism {
+    #[default]
+    #[serde(rename = "PLAIN")]
+    Plain,
+    /// Authenticate with the TLS client certificate (`tls_client_cert`)
+    #[serde(rename = "EXTERNAL")]
+    External,
+    /// Falls back to SCRAM-SHA-1 when the server doesn't support SCRAM-SHA-256
+    #[serde(rename = "SCRAM-SHA-256")]
+    ScramSha256,
+    #[serde(rename = "SCRAM-SHA-1")]
+    ScramSha1,
+}
+
+impl SASLMechanism {
+    fn name(self) -> &'static str {
+        match self {
+            SASLMechanism::Plain => "PLAIN",
+            SASLMechanism::External => "EXTERNAL",
+            SASLMechanism::ScramSha256 => "SCRAM-SHA-256",
+            SASLMechanism::ScramSha1 => "SCRAM-SHA-1",
+        }
+    }
+}
+
+/// A proxy to connect to a server through
+#[derive(Clone, Deserialize, Debug, PartialEq, Eq)]
+pub(crate) struct Proxy {
+    #[serde(rename = "type")]
+    pub(crate) proxy_type: ProxyType,
+    /// Address of the proxy
+    pub(crate) addr: String,
+    /// Port of the proxy
+    pub(crate) port: u16,
+    #[serde(default)]
+    pub(crate) username: Option<String>,
+    #[serde(default)]
+    pub(crate) password: Option<String>,
+    /// SOCKS5 only: let the proxy resolve the server's host name
+    #[serde(default = "default_remote_dns")]
+    pub(crate) remote_dns: bool,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Eq)]
+pub(crate) enum ProxyType {
+    #[serde(rename = "socks5")]
+    Socks5,
+    /// HTTP proxy with the CONNECT method
+    #[serde(rename = "http")]
+    Http,
+}
+
+fn default_remote_dns() -> bool {
+    true
+}
+
+impl Proxy {
+    pub(crate) fn to_client_proxy(&self) -> libtiny_client::Proxy {
+        let auth = self
+            .username
+            .as_ref()
+            .map(|username| libtiny_client::ProxyAuth {
+                username: username.clone(),
+                password: self.password.clone().unwrap_or_default(),
+            });
+        match self.proxy_type {
+            ProxyType::Socks5 => libtiny_client::Proxy::Socks5 {
+                addr: self.addr.clone(),
+                port: self.port,
+                auth,
+                remote_dns: self.remote_dns,
+            },
+            ProxyType::Http => libtiny_client::Proxy::HttpConnect {
+                addr: self.addr.clone(),
+                port: self.port,
+                auth,
+            },
+        }
+    }
+}
+
 #[derive(Clone, Deserialize)]
 pub(crate) struct Server {
     /// Address of the server
@@ -26,6 +117,15 @@ pub(crate) struct Server {
     #[serde(default)]
     pub(crate) tls: bool,
 
+    /// TLS client certificate (PEM), for CertFP or SASL EXTERNAL
+    #[serde(default)]
+    pub(crate) tls_client_cert: Option<PathBuf>,
+
+    /// Private key of the TLS client certificate (PEM). When not specified the key is read from
+    /// `tls_client_cert`.
+    #[serde(default)]
+    pub(crate) tls_client_key: Option<PathBuf>,
+
     /// Server password (optional)
     #[serde(default)]
     pub(crate) pass: Option<String>,
@@ -49,6 +149,32 @@ pub(crate) struct Server {
     /// Authenication method
     #[serde(rename = "sasl")]
     pub(crate) sasl_auth: Option<SASLAuth>,
+
+    /// Encoding for incoming messages that are not valid UTF-8.
+    #[serde(default, deserialize_with = "deser_encoding")]
+    pub(crate) encoding: Option<&'static Encoding>,
+
+    /// Encoding for outgoing messages. UTF-8 by default.
+    #[serde(default, deserialize_with = "deser_encoding")]
+    pub(crate) send_encoding: Option<&'static Encoding>,
+
+    /// IRCv3 capabilities to request. `libtiny_client::DEFAULT_CAPS` when not specified.
+    #[serde(default)]
+    pub(crate) capabilities: Option<Vec<String>>,
+
+    /// Max. number of messages to send at once, before flood control kicks in.
+    /// `libtiny_client::DEFAULT_SEND_BURST` when not specified.
+    #[serde(default)]
+    pub(crate) send_burst: Option<usize>,
+
+    /// Interval between messages when flood control is in effect, in milliseconds.
+    /// `libtiny_client::DEFAULT_SEND_INTERVAL_MS` when not specified.
+    #[serde(default)]
+    pub(crate) send_interval_ms: Option<u64>,
+
+    /// Proxy to connect to the server through (optional)
+    #[serde(default)]
+    pub(crate) proxy: Option<Proxy>,
 }
 
 /// Similar to `Server`, but used when connecting via the `/connect` command.
@@ -62,6 +188,9 @@ pub(crate) struct Defaults {
     pub(crate) join: Vec<String>,
     #[serde(default)]
     pub(crate) tls: bool,
+    /// Proxy to connect to the servers through (optional)
+    #[serde(default)]
+    pub(crate) proxy: Option<Proxy>,
 }
 
 #[derive(Deserialize)]
@@ -87,6 +216,24 @@ where
     Ok(strs.into_iter().map(|s| s.trim().to_owned()).collect())
 }
 
+/// Parse an encoding label, e.g. "latin1" or "windows-1252". See
+/// https://encoding.spec.whatwg.org/#names-and-labels for supported labels. Only encodings that
+/// are compatible with ASCII can be used on IRC.
+fn deser_encoding<'de, D>(d: D) -> Result<Option<&'static Encoding>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let label = String::deserialize(d)?;
+    match Encoding::for_label(label.trim().as_bytes()) {
+        None => Err(D::Error::custom(format!("unknown encoding: {}", label))),
+        Some(encoding) if !encoding.is_ascii_compatible() => Err(D::Error::custom(format!(
+            "encoding {} is not compatible with ASCII",
+            encoding.name()
+        ))),
+        Some(encoding) => Ok(Some(encoding)),
+    }
+}
+
 impl Config {
     /// Returns error descriptions
     pub(crate) fn validate(&self) -> Vec<String> {
@@ -110,6 +257,15 @@ impl Config {
             }
         }
 
+        if let Some(Proxy {
+            username: None,
+            password: Some(_),
+            ..
+        }) = &self.defaults.proxy
+        {
+            errors.push("Proxy 'password' is set without 'username' for 'defaults'".to_owned());
+        }
+
         for server in &self.servers {
             if server.nicks.is_empty() {
                 errors.push(format!(
@@ -133,12 +289,84 @@ impl Config {
                     server.addr
                 ));
             }
+
+            if server.tls_client_cert.is_some() && !server.tls {
+                errors.push(format!(
+                    "'tls_client_cert' is set but 'tls' is not enabled for '{}'",
+                    server.addr
+                ));
+            }
+
+            if server.tls_client_key.is_some() && server.tls_client_cert.is_none() {
+                errors.push(format!(
+                    "'tls_client_key' is set without 'tls_client_cert' for '{}'",
+                    server.addr
+                ));
+            }
+
+            if let Some(Proxy {
+                username: None,
+                password: Some(_),
+                ..
+            }) = &server.proxy
+            {
+                errors.push(format!(
+                    "Proxy 'password' is set without 'username' for '{}'",
+                    server.addr
+                ));
+            }
+
+            match &server.sasl_auth {
+                Some(SASLAuth {
+                    mechanism,
+                    username,
+                    password,
+                }) if *mechanism != SASLMechanism::External
+                    && (username.is_empty() || password.is_empty()) =>
+                {
+                    errors.push(format!(
+                        "SASL {} authentication needs 'username' and 'password' fields, \
+                         please update 'sasl' field of '{}'",
+                        mechanism.name(),
+                        server.addr
+                    ));
+                }
+                Some(SASLAuth {
+                    mechanism: SASLMechanism::External,
+                    ..
+                }) if server.tls_client_cert.is_none() => {
+                    errors.push(format!(
+                        "SASL EXTERNAL authentication needs a TLS client certificate, \
+                         please update 'tls_client_cert' field of '{}'",
+                        server.addr
+                    ));
+                }
+                _ => {}
+            }
         }
 
         errors
     }
 }
 
+impl Server {
+    /// Read the TLS client certificate and key files.
+    pub(crate) fn read_tls_client_cert(&self) -> Result<Option<TlsClientCert>, String> {
+        let cert_path = match &self.tls_client_cert {
+            None => return Ok(None),
+            Some(cert_path) => cert_path,
+        };
+        let key_path = self.tls_client_key.as_ref().unwrap_or(cert_path);
+        let read = |path: &Path| {
+            fs::read(path).map_err(|err| format!("Can't read {}: {}", path.display(), err))
+        };
+        Ok(Some(TlsClientCert {
+            cert: read(cert_path)?,
+            key: read(key_path)?,
+        }))
+    }
+}
+
 /// Returns tiny config file path. File may or may not exist.
 ///
 /// Places to look: (in priority order)
@@ -229,6 +457,162 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parse_encoding() {
+        let server_yaml = |encoding: &str| {
+            format!(
+                "addr: x.y.z\nport: 6667\nrealname: tiny\nnicks: [tiny]\nencoding: {}",
+                encoding
+            )
+        };
+
+        let server: Server = serde_yaml::from_str(&server_yaml("latin1")).unwrap();
+        assert_eq!(server.encoding.map(Encoding::name), Some("windows-1252"));
+        assert_eq!(server.send_encoding, None);
+
+        assert!(serde_yaml::from_str::<Server>(&server_yaml("blah")).is_err());
+        assert!(serde_yaml::from_str::<Server>(&server_yaml("utf-16le")).is_err());
+    }
+
+    #[test]
+    fn parse_proxy() {
+        let server_yaml = |proxy: &str| {
+            format!(
+                "addr: x.y.z\nport: 6697\nrealname: tiny\nnicks: [tiny]\nproxy:\n{}",
+                proxy
+            )
+        };
+
+        let config = |server: Server| Config {
+            servers: vec![server],
+            defaults: Defaults {
+                nicks: vec!["tiny".to_owned()],
+                realname: "tiny".to_owned(),
+                join: vec![],
+                tls: false,
+                proxy: None,
+            },
+            log_dir: None,
+        };
+
+        let server: Server = serde_yaml::from_str(&server_yaml(
+            "  type: socks5\n  addr: 127.0.0.1\n  port: 9050",
+        ))
+        .unwrap();
+        assert_eq!(
+            server.proxy,
+            Some(Proxy {
+                proxy_type: ProxyType::Socks5,
+                addr: "127.0.0.1".to_owned(),
+                port: 9050,
+                username: None,
+                password: None,
+                remote_dns: true,
+            })
+        );
+
+        let server: Server = serde_yaml::from_str(&server_yaml(
+            "  type: http\n  addr: proxy\n  port: 8080\n  password: p",
+        ))
+        .unwrap();
+        assert_eq!(
+            server.proxy.as_ref().map(|proxy| proxy.proxy_type),
+            Some(ProxyType::Http)
+        );
+        assert_eq!(
+            config(server).validate(),
+            vec!["Proxy 'password' is set without 'username' for 'x.y.z'".to_owned()]
+        );
+
+        // `/connect` uses the proxy in defaults
+        let defaults: Defaults = serde_yaml::from_str(
+            "nicks: [tiny]\nrealname: tiny\nproxy:\n  type: socks5\n  addr: 127.0.0.1\n  port: 9050",
+        )
+        .unwrap();
+        assert_eq!(defaults.proxy.map(|proxy| proxy.port), Some(9050));
+
+        assert!(serde_yaml::from_str::<Server>(&server_yaml(
+            "  type: socks4\n  addr: 127.0.0.1\n  port: 9050"
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn parse_sasl() {
+        let server_yaml = |extra: &str| {
+            format!(
+                "addr: x.y.z\nport: 6697\ntls: true\nrealname: tiny\nnicks: [tiny]\n{}",
+                extra
+            )
+        };
+        let config = |server: Server| Config {
+            servers: vec![server],
+            defaults: Defaults {
+                nicks: vec!["tiny".to_owned()],
+                realname: "tiny".to_owned(),
+                join: vec![],
+                tls: false,
+                proxy: None,
+            },
+            log_dir: None,
+        };
+
+        let server: Server =
+            serde_yaml::from_str(&server_yaml("sasl:\n  username: u\n  password: p")).unwrap();
+        assert_eq!(
+            server.sasl_auth,
+            Some(SASLAuth {
+                mechanism: SASLMechanism::Plain,
+                username: "u".to_owned(),
+                password: "p".to_owned(),
+            })
+        );
+        assert!(config(server).validate().is_empty());
+
+        let server: Server =
+            serde_yaml::from_str(&server_yaml("sasl:\n  mechanism: EXTERNAL")).unwrap();
+        assert_eq!(
+            server.sasl_auth.as_ref().map(|auth| auth.mechanism),
+            Some(SASLMechanism::External)
+        );
+        assert_eq!(
+            config(server).validate(),
+            vec![
+                "SASL EXTERNAL authentication needs a TLS client certificate, please update \
+                 'tls_client_cert' field of 'x.y.z'"
+                    .to_owned()
+            ]
+        );
+
+        let server: Server = serde_yaml::from_str(&server_yaml(
+            "tls_client_cert: /a/b.pem\nsasl:\n  mechanism: EXTERNAL",
+        ))
+        .unwrap();
+        assert_eq!(server.tls_client_cert, Some(PathBuf::from("/a/b.pem")));
+        assert!(config(server).validate().is_empty());
+
+        let server: Server = serde_yaml::from_str(&server_yaml(
+            "sasl:\n  mechanism: SCRAM-SHA-256\n  username: u\n  password: p",
+        ))
+        .unwrap();
+        assert_eq!(
+            server.sasl_auth.as_ref().map(|auth| auth.mechanism),
+            Some(SASLMechanism::ScramSha256)
+        );
+        assert!(config(server).validate().is_empty());
+
+        let server: Server =
+            serde_yaml::from_str(&server_yaml("sasl:\n  mechanism: PLAIN\n  username: u")).unwrap();
+        assert_eq!(
+            config(server).validate(),
+            vec![
+                "SASL PLAIN authentication needs 'username' and 'password' fields, please \
+                 update 'sasl' field of 'x.y.z'"
+                    .to_owned()
+            ]
+        );
+    }
+
     #[test]
     fn validation() {
         // We trim the string fields when deserializing, so `validate` doesn't consider non-empty
@@ -239,18 +623,27 @@ mod tests {
                 alias: None,
                 port: 123,
                 tls: false,
+                tls_client_cert: None,
+                tls_client_key: None,
                 pass: None,
                 realname: "".to_owned(),
                 nicks: vec!["".to_owned()],
                 join: vec![],
                 nickserv_ident: None,
                 sasl_auth: None,
+                encoding: None,
+                send_encoding: None,
+                capabilities: None,
+                send_burst: None,
+                send_interval_ms: None,
+                proxy: None,
             }],
             defaults: Defaults {
                 nicks: vec!["".to_owned()],
                 realname: "".to_owned(),
                 join: vec![],
                 tls: false,
+                proxy: None,
             },
             log_dir: None,
         };
@@ -3,9 +3,11 @@
 
 //! IRC event handling
 
-use crate::ui::UI;
-use libtiny_common::{ChanNameRef, MsgTarget, TabStyle};
+use crate::ui::{is_service, UI};
+use crate::utils;
+use libtiny_common::{CaseMapping, ChanNameRef, MsgTarget, TabStyle};
 use libtiny_wire as wire;
+use libtiny_wire::{numeric, Numeric};
 
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
@@ -17,6 +19,25 @@ pub(crate) trait Client {
     fn get_nick(&self) -> String;
 
     fn is_nick_accepted(&self) -> bool;
+
+    fn get_case_mapping(&self) -> CaseMapping;
+
+    /// Whether `nick` is our current nick, compared with the server's case mapping.
+    fn is_own_nick(&self, nick: &str) -> bool {
+        self.get_case_mapping().equals(nick, &self.get_nick())
+    }
+
+    fn is_chan(&self, name: &str) -> bool;
+
+    fn is_prefix_mode(&self, mode: char) -> bool;
+
+    fn split_nick_prefix<'a>(&self, nick: &'a str) -> (&'a str, &'a str);
+
+    fn get_channel(&self, chan: &ChanNameRef) -> Option<libtiny_client::ChannelInfo>;
+
+    fn is_cap_wanted(&self, cap: &str) -> bool;
+
+    fn is_cap_available(&self, cap: &str) -> bool;
 }
 
 impl Client for libtiny_client::Client {
@@ -31,6 +52,34 @@ impl Client for libtiny_client::Client {
     fn is_nick_accepted(&self) -> bool {
         self.is_nick_accepted()
     }
+
+    fn get_case_mapping(&self) -> CaseMapping {
+        self.get_case_mapping()
+    }
+
+    fn is_chan(&self, name: &str) -> bool {
+        self.is_chan(name)
+    }
+
+    fn is_prefix_mode(&self, mode: char) -> bool {
+        self.is_prefix_mode(mode)
+    }
+
+    fn split_nick_prefix<'a>(&self, nick: &'a str) -> (&'a str, &'a str) {
+        self.split_nick_prefix(nick)
+    }
+
+    fn get_channel(&self, chan: &ChanNameRef) -> Option<libtiny_client::ChannelInfo> {
+        self.get_channel(chan)
+    }
+
+    fn is_cap_wanted(&self, cap: &str) -> bool {
+        self.is_cap_wanted(cap)
+    }
+
+    fn is_cap_available(&self, cap: &str) -> bool {
+        self.is_cap_available(cap)
+    }
 }
 
 pub(crate) async fn task(
@@ -123,8 +172,8 @@ fn handle_conn_ev(ui: &UI, client: &dyn Client, ev: libtiny_client::Event) {
         NickChange { new_nick } => {
             ui.set_nick(client.get_serv_name(), &new_nick);
         }
-        Msg(msg) => {
-            handle_irc_msg(ui, client, msg);
+        Msg { msg, timestamp } => {
+            handle_irc_msg(ui, client, msg, timestamp);
         }
         WireError(err) => {
             ui.add_err_msg(
@@ -142,19 +191,163 @@ fn handle_conn_ev(ui: &UI, client: &dyn Client, ev: libtiny_client::Event) {
                 chan: &chan,
             },
         ),
+        MsgNotEchoed { target, msg } => {
+            let serv = client.get_serv_name();
+            let msg_target = if client.is_chan(&target) {
+                MsgTarget::Chan {
+                    serv,
+                    chan: ChanNameRef::new(&target),
+                }
+            } else if is_service(&target) {
+                MsgTarget::Server { serv }
+            } else {
+                MsgTarget::User {
+                    serv,
+                    nick: &target,
+                }
+            };
+            ui.add_err_msg(
+                &format!("Message not confirmed by the server: {}", msg),
+                time::now(),
+                &msg_target,
+            );
+        }
+        History { target, msgs } => {
+            handle_history(ui, client, target, msgs);
+        }
     }
 }
 
-fn handle_irc_msg(ui: &UI, client: &dyn Client, msg: wire::Msg) {
+/// Show messages that we missed while disconnected (see `libtiny_client::Event::History`) in the
+/// channel or private conversation tab, without highlights or notifications.
+fn handle_history(
+    ui: &UI,
+    client: &dyn Client,
+    target: wire::MsgTarget,
+    msgs: Vec<(wire::Msg, time::Tm)>,
+) {
+    use wire::Pfx::*;
+
+    let serv = client.get_serv_name();
+    let msg_target = match &target {
+        wire::MsgTarget::Chan(chan) => MsgTarget::Chan { serv, chan },
+        wire::MsgTarget::User(nick) if is_service(nick) => MsgTarget::Server { serv },
+        wire::MsgTarget::User(nick) => MsgTarget::User { serv, nick },
+    };
+
+    let mut new_msgs = false;
+    for (msg, ts) in msgs {
+        let sender = match &msg.pfx {
+            Some(Server(sender)) | Some(User { nick: sender, .. }) | Some(Ambiguous(sender)) => {
+                sender
+            }
+            None => continue,
+        };
+        // Only messages and actions. History can also have other messages, e.g. JOINs and PARTs
+        // with `draft/event-playback`.
+        if let wire::Cmd::PRIVMSG {
+            msg: text, ctcp, ..
+        } = &msg.cmd
+        {
+            if matches!(ctcp, None | Some(wire::CTCP::Action)) {
+                ui.add_history_privmsg(sender, text, ts, &msg_target, ctcp.is_some());
+                new_msgs = true;
+            }
+        }
+    }
+
+    if new_msgs {
+        ui.set_tab_style(TabStyle::NewMsg, &msg_target);
+    }
+}
+
+/// Message to show for a CTCP query. Replies to the queries that we answer are sent by the client.
+fn ctcp_query_msg(sender: &str, ctcp: &wire::CTCP, args: &str) -> String {
+    match ctcp {
+        wire::CTCP::Version => format!("Received version request from {}", sender),
+        wire::CTCP::DCC => match wire::DCC::parse(args) {
+            Some(dcc) => {
+                let size = match dcc.size {
+                    Some(size) => format!(" ({} bytes)", size),
+                    None => String::new(),
+                };
+                format!(
+                    "{} offers DCC {} {}{} from {}:{} (DCC is not supported)",
+                    sender, dcc.kind, dcc.arg, size, dcc.addr, dcc.port
+                )
+            }
+            None => format!("Received invalid DCC request from {}: {}", sender, args),
+        },
+        _ => format!("Received CTCP {} request from {}", ctcp.name(), sender),
+    }
+}
+
+/// Message to show for a CTCP reply. PING replies to our queries (see `/ctcp`) have the time we
+/// sent the query, we show the round-trip time for those.
+fn ctcp_reply_msg(sender: &str, ctcp: &wire::CTCP, args: &str) -> String {
+    if let wire::CTCP::Ping = ctcp {
+        if let Ok(sent) = args.parse::<u128>() {
+            if let Some(rtt) = utils::now_millis().checked_sub(sent) {
+                return format!(
+                    "CTCP PING reply from {}: {}.{:03} seconds",
+                    sender,
+                    rtt / 1000,
+                    rtt % 1000
+                );
+            }
+        }
+    }
+    if args.is_empty() {
+        format!("CTCP {} reply from {}", ctcp.name(), sender)
+    } else {
+        format!("CTCP {} reply from {}: {}", ctcp.name(), sender, args)
+    }
+}
+
+/// A tab to show a PRIVMSG or NOTICE in.
+struct PrivmsgTab<'a> {
+    target: MsgTarget<'a>,
+    /// Sender to show in the tab
+    sender: &'a str,
+    /// Whether to highlight the message
+    highlight: bool,
+    style: Option<TabStyle>,
+}
+
+/// Whether two message targets are the same tab.
+fn same_tab(t1: &MsgTarget, t2: &MsgTarget, mapping: CaseMapping) -> bool {
+    match (t1, t2) {
+        (MsgTarget::Server { serv: s1 }, MsgTarget::Server { serv: s2 }) => s1 == s2,
+        (MsgTarget::Chan { serv: s1, chan: c1 }, MsgTarget::Chan { serv: s2, chan: c2 }) => {
+            s1 == s2 && c1.eq_with(c2, mapping)
+        }
+        (MsgTarget::User { serv: s1, nick: n1 }, MsgTarget::User { serv: s2, nick: n2 }) => {
+            s1 == s2 && mapping.equals(n1, n2)
+        }
+        _ => false,
+    }
+}
+
+/// Sender of a message to be shown in the UI: the nick or server name in the prefix, or the server
+/// we're connected to when the message doesn't have a prefix.
+fn pfx_sender<'a>(pfx: &'a Option<wire::Pfx>, serv: &'a str) -> &'a str {
+    match pfx {
+        Some(wire::Pfx::User { nick, .. }) | Some(wire::Pfx::Ambiguous(nick)) => nick,
+        Some(wire::Pfx::Server(serv)) => serv,
+        None => serv,
+    }
+}
+
+/// `ts` is the time of the message, see `libtiny_client::Event::Msg`.
+fn handle_irc_msg(ui: &UI, client: &dyn Client, msg: wire::Msg, ts: time::Tm) {
     use wire::Cmd::*;
     use wire::Pfx::*;
 
-    let wire::Msg { pfx, cmd } = msg;
-    let ts = time::now();
+    let wire::Msg { pfx, cmd, .. } = msg;
     let serv = client.get_serv_name();
     match cmd {
         PRIVMSG {
-            target,
+            targets,
             msg,
             is_notice,
             ctcp,
@@ -173,194 +366,218 @@ fn handle_irc_msg(ui: &UI, client: &dyn Client, msg: wire::Msg) {
                 User { ref nick, .. } | Ambiguous(ref nick) => nick,
             };
 
-            if ctcp == Some(wire::CTCP::Version) {
-                let msg_target = if ui.user_tab_exists(serv, sender) {
-                    MsgTarget::User { serv, nick: sender }
-                } else {
-                    MsgTarget::Server { serv }
-                };
-                ui.add_client_msg(
-                    &format!("Received version request from {}", sender),
-                    &msg_target,
-                );
-                return;
+            match ctcp {
+                None | Some(wire::CTCP::Action) => {}
+                // Our own CTCP queries and replies, echoed back by the server (`echo-message`)
+                Some(_) if client.is_own_nick(sender) => {
+                    return;
+                }
+                Some(ref ctcp) => {
+                    let msg_target = if ui.user_tab_exists(serv, sender) {
+                        MsgTarget::User { serv, nick: sender }
+                    } else {
+                        MsgTarget::Server { serv }
+                    };
+                    let ui_msg = if is_notice {
+                        ctcp_reply_msg(sender, ctcp, &msg)
+                    } else {
+                        ctcp_query_msg(sender, ctcp, &msg)
+                    };
+                    ui.add_client_msg(&ui_msg, &msg_target);
+                    return;
+                }
             }
 
             let is_action = ctcp == Some(wire::CTCP::Action);
 
-            match target {
-                wire::MsgTarget::Chan(chan) => {
-                    let ui_msg_target = MsgTarget::Chan { serv, chan: &chan };
-                    // highlight the message if it mentions us
-                    if msg.find(&client.get_nick()).is_some() {
-                        ui.add_privmsg(sender, &msg, ts, &ui_msg_target, true, is_action);
-                        ui.set_tab_style(TabStyle::Highlight, &ui_msg_target);
-                        let mentions_target = MsgTarget::Server { serv: "mentions" };
-                        ui.add_msg(
-                            &format!("{} in {}:{}: {}", sender, serv, chan.display(), msg),
-                            ts,
-                            &mentions_target,
-                        );
-                        ui.set_tab_style(TabStyle::Highlight, &mentions_target);
-                    } else {
-                        ui.add_privmsg(sender, &msg, ts, &ui_msg_target, false, is_action);
-                        ui.set_tab_style(TabStyle::NewMsg, &ui_msg_target);
-                    }
-                }
-                wire::MsgTarget::User(target) => {
-                    // If the sender is a server we show the message in the server tab. Otherwise
-                    // we show it in a private tab.
-                    //
-                    // Some bouncers send PRIVMSGs from users with ambiguous prefix without a
-                    // `user@host` part so we treat ambiguity as nick. See #247.
-                    match pfx {
-                        Server(_) => {
-                            let msg_target = MsgTarget::Server { serv };
-                            ui.add_privmsg(serv, &msg, ts, &msg_target, false, is_action);
-                            if target == client.get_nick() {
-                                ui.set_tab_style(TabStyle::Highlight, &msg_target);
-                            } else {
-                                ui.set_tab_style(TabStyle::NewMsg, &msg_target);
+            // Find the tabs to show the message in. Multiple targets can be shown in the same tab
+            // (e.g. `PRIVMSG #chan,#CHAN`), the message is added to each tab once.
+            let own_nick = client.get_nick();
+            let mapping = client.get_case_mapping();
+            let mentions_us = mapping
+                .normalize(&msg)
+                .contains(&mapping.normalize(&own_nick));
+            let mut tabs: Vec<PrivmsgTab> = Vec::with_capacity(targets.len());
+            for target in &targets {
+                let tab = match target {
+                    wire::MsgTarget::Chan(chan) => {
+                        let ui_msg_target = MsgTarget::Chan { serv, chan };
+                        if client.is_own_nick(sender) {
+                            // Our own message, echoed back by the server (`echo-message`) or
+                            // relayed by a bouncer (#271). Not highlighted.
+                            PrivmsgTab {
+                                target: ui_msg_target,
+                                sender,
+                                highlight: false,
+                                style: None,
+                            }
+                        } else if mentions_us {
+                            // highlight the message if it mentions us
+                            PrivmsgTab {
+                                target: ui_msg_target,
+                                sender,
+                                highlight: true,
+                                style: Some(TabStyle::Highlight),
+                            }
+                        } else {
+                            PrivmsgTab {
+                                target: ui_msg_target,
+                                sender,
+                                highlight: false,
+                                style: Some(TabStyle::NewMsg),
                             }
                         }
-                        User { ref nick, .. } | Ambiguous(ref nick) => {
-                            if target == client.get_nick() {
-                                // Message is sent to us. Show NOTICE messages in server tabs if we
-                                // don't have a tab for the sender already (see #21).
-                                let msg_target = if is_notice && !ui.user_tab_exists(serv, nick) {
-                                    MsgTarget::Server { serv }
+                    }
+                    wire::MsgTarget::User(target) => {
+                        // If the sender is a server we show the message in the server tab. Otherwise
+                        // we show it in a private tab.
+                        //
+                        // Some bouncers send PRIVMSGs from users with ambiguous prefix without a
+                        // `user@host` part so we treat ambiguity as nick. See #247.
+                        match pfx {
+                            Server(_) => PrivmsgTab {
+                                target: MsgTarget::Server { serv },
+                                sender: serv,
+                                highlight: false,
+                                style: Some(if client.is_own_nick(target) {
+                                    TabStyle::Highlight
                                 } else {
-                                    MsgTarget::User { serv, nick }
-                                };
-                                ui.add_privmsg(nick, &msg, ts, &msg_target, false, is_action);
-                                ui.set_tab_style(TabStyle::Highlight, &msg_target);
-                            } else {
-                                // PRIVMSG not sent to us. This case can happen in a few cases:
-                                //
-                                // - When using a bouncer, see #271. When multiple clients connect
-                                //   to the same bouncer and one of them sends a PRIVMSG, the
-                                //   message is relayed to the other clients. Example:
-                                //
-                                //       <our_nick> PRIVMSG <target> :...
-                                //
-                                //   In this case (when the sender is us) we show the message in
-                                //   the target's tab and our nick as the sender.
-                                //
-                                // - When the message target is a "host mask" (e.g. message was
-                                //   sent to all users matching a mask), see #278. Example:
-                                //
-                                //       <some prefix> PRIVMSG $$* :...
-                                //
-                                //    In this case (when the sender is not us) we show the message
-                                //    in the target's tab as the prefix as the sender.
-
-                                match pfx {
-                                    Server(ref sender) => {
-                                        // A message sent by a server to someone other than us?
-                                        // The target is probably a host mask. Show the message in
-                                        // the server tab.
-                                        let msg_target = MsgTarget::Server { serv };
-                                        ui.add_privmsg(
-                                            sender,
-                                            &msg,
-                                            ts,
-                                            &msg_target,
-                                            false,
-                                            is_action,
-                                        );
-                                        ui.set_tab_style(TabStyle::Highlight, &msg_target);
+                                    TabStyle::NewMsg
+                                }),
+                            },
+                            User { ref nick, .. } | Ambiguous(ref nick) => {
+                                if client.is_own_nick(target) {
+                                    // Message is sent to us. Show NOTICE messages in server tabs if we
+                                    // don't have a tab for the sender already (see #21).
+                                    let msg_target = if is_notice && !ui.user_tab_exists(serv, nick)
+                                    {
+                                        MsgTarget::Server { serv }
+                                    } else {
+                                        MsgTarget::User { serv, nick }
+                                    };
+                                    PrivmsgTab {
+                                        target: msg_target,
+                                        sender: nick,
+                                        highlight: false,
+                                        style: Some(TabStyle::Highlight),
                                     }
-                                    User { ref nick, .. } | Ambiguous(ref nick) => {
-                                        if nick == &client.get_nick() {
-                                            // Case (1). Don't highlight the tab as `Highlight`:
-                                            // the message was sent by us so the tab probably
-                                            // doesn't need that much attention. Highlight as
-                                            // `NewMsg` instead.
-                                            let msg_target = MsgTarget::User {
-                                                serv,
-                                                nick: &target,
-                                            };
-                                            ui.add_privmsg(
-                                                &client.get_nick(),
-                                                &msg,
-                                                ts,
-                                                &msg_target,
-                                                false,
-                                                is_action,
-                                            );
-                                            // Don't highlight the tab as `Highlight`: the message was sent by us so
-                                            // the tab probably doesn't need that much attention. Highlight as `NewMsg`
-                                            // instead.
-                                            ui.set_tab_style(TabStyle::NewMsg, &msg_target);
-                                        } else {
-                                            // Case (2)
-                                            let msg_target = MsgTarget::User { serv, nick };
-                                            ui.add_privmsg(
-                                                nick,
-                                                &msg,
-                                                ts,
-                                                &msg_target,
-                                                false,
-                                                is_action,
-                                            );
-                                            ui.set_tab_style(TabStyle::Highlight, &msg_target);
-                                        }
+                                } else if client.is_own_nick(nick) {
+                                    // PRIVMSG not sent to us. This case can happen in a few cases:
+                                    //
+                                    // - When the server echoes our messages back (`echo-message`
+                                    //   capability), or when using a bouncer, see #271. When
+                                    //   multiple clients connect to the same bouncer and one of
+                                    //   them sends a PRIVMSG, the message is relayed to the other
+                                    //   clients. Example:
+                                    //
+                                    //       <our_nick> PRIVMSG <target> :...
+                                    //
+                                    //   In this case (when the sender is us) we show the message in
+                                    //   the target's tab and our nick as the sender.
+                                    //
+                                    // - When the message target is a "host mask" (e.g. message was
+                                    //   sent to all users matching a mask), see #278. Example:
+                                    //
+                                    //       <some prefix> PRIVMSG $$* :...
+                                    //
+                                    //    In this case (when the sender is not us) we show the message
+                                    //    in the target's tab as the prefix as the sender.
+                                    //
+                                    // Case (1). Don't highlight the tab as `Highlight`: the message
+                                    // was sent by us so the tab probably doesn't need that much
+                                    // attention. Highlight as `NewMsg` instead.
+                                    let msg_target = if is_service(target) {
+                                        MsgTarget::Server { serv }
+                                    } else {
+                                        MsgTarget::User { serv, nick: target }
+                                    };
+                                    PrivmsgTab {
+                                        target: msg_target,
+                                        sender: &own_nick,
+                                        highlight: false,
+                                        style: Some(TabStyle::NewMsg),
                                     }
-                                };
+                                } else {
+                                    // Case (2)
+                                    PrivmsgTab {
+                                        target: MsgTarget::User { serv, nick },
+                                        sender: nick,
+                                        highlight: false,
+                                        style: Some(TabStyle::Highlight),
+                                    }
+                                }
                             }
                         }
                     }
+                };
+                if !tabs
+                    .iter()
+                    .any(|tab_| same_tab(&tab_.target, &tab.target, mapping))
+                {
+                    tabs.push(tab);
+                }
+            }
+
+            for tab in &tabs {
+                ui.add_privmsg(tab.sender, &msg, ts, &tab.target, tab.highlight, is_action);
+                if let Some(style) = tab.style {
+                    ui.set_tab_style(style, &tab.target);
+                }
+                if let (true, MsgTarget::Chan { chan, .. }) = (tab.highlight, &tab.target) {
+                    let mentions_target = MsgTarget::Server { serv: "mentions" };
+                    ui.add_msg(
+                        &format!("{} in {}:{}: {}", tab.sender, serv, chan.display(), msg),
+                        ts,
+                        &mentions_target,
+                    );
+                    ui.set_tab_style(TabStyle::Highlight, &mentions_target);
                 }
             }
         }
 
-        JOIN { chan } => {
+        JOIN { chans, .. } => {
             let nick = match pfx {
                 Some(User { nick, .. }) | Some(Ambiguous(nick)) => nick,
                 Some(Server(_)) | None => {
-                    debug!(
-                        "JOIN with weird prefix: pfx={:?}, cmd={:?}",
-                        pfx,
-                        JOIN { chan }
-                    );
+                    debug!("JOIN with weird prefix: pfx={:?}, chans={:?}", pfx, chans);
                     return;
                 }
             };
 
-            if nick == client.get_nick() {
-                ui.new_chan_tab(serv, &chan);
-            } else {
-                let nick = wire::drop_nick_prefix(&nick);
-                let ts = Some(time::now());
-                ui.add_nick(nick, ts, &MsgTarget::Chan { serv, chan: &chan });
-                // Also update the private message tab if it exists
-                // Nothing will be shown if the user already known to be online by the tab
-                if ui.user_tab_exists(serv, nick) {
-                    ui.add_nick(nick, ts, &MsgTarget::User { serv, nick });
+            for chan in &chans {
+                if client.is_own_nick(&nick) {
+                    ui.new_chan_tab(serv, chan);
+                } else {
+                    let (_, nick) = client.split_nick_prefix(&nick);
+                    let ts = Some(ts);
+                    ui.add_nick(nick, ts, &MsgTarget::Chan { serv, chan });
+                    // Also update the private message tab if it exists
+                    // Nothing will be shown if the user already known to be online by the tab
+                    if ui.user_tab_exists(serv, nick) {
+                        ui.add_nick(nick, ts, &MsgTarget::User { serv, nick });
+                    }
+                    ui.set_tab_style(TabStyle::JoinOrPart, &MsgTarget::Chan { serv, chan })
                 }
-                ui.set_tab_style(TabStyle::JoinOrPart, &MsgTarget::Chan { serv, chan: &chan })
             }
         }
 
-        PART { chan, msg } => {
+        PART { chans, msg } => {
             let nick = match pfx {
                 Some(User { nick, .. }) | Some(Ambiguous(nick)) => nick,
                 Some(Server(_)) | None => {
                     debug!(
                         "PART with weird prefix: pfx={:?}, cmd={:?}",
                         pfx,
-                        PART { chan, msg }
+                        PART { chans, msg }
                     );
                     return;
                 }
             };
-            if nick != client.get_nick() {
-                ui.remove_nick(
-                    &nick,
-                    Some(time::now()),
-                    &MsgTarget::Chan { serv, chan: &chan },
-                );
-                ui.set_tab_style(TabStyle::JoinOrPart, &MsgTarget::Chan { serv, chan: &chan })
+            if !client.is_own_nick(&nick) {
+                for chan in &chans {
+                    ui.remove_nick(&nick, Some(ts), &MsgTarget::Chan { serv, chan });
+                    ui.set_tab_style(TabStyle::JoinOrPart, &MsgTarget::Chan { serv, chan })
+                }
             }
         }
 
@@ -378,10 +595,10 @@ fn handle_irc_msg(ui: &UI, client: &dyn Client, msg: wire::Msg) {
             };
 
             for chan in &chans {
-                ui.remove_nick(nick, Some(time::now()), &MsgTarget::Chan { serv, chan });
+                ui.remove_nick(nick, Some(ts), &MsgTarget::Chan { serv, chan });
             }
             if ui.user_tab_exists(serv, nick) {
-                ui.remove_nick(nick, Some(time::now()), &MsgTarget::User { serv, nick });
+                ui.remove_nick(nick, Some(ts), &MsgTarget::User { serv, nick });
             }
         }
 
@@ -399,18 +616,13 @@ fn handle_irc_msg(ui: &UI, client: &dyn Client, msg: wire::Msg) {
             };
 
             for chan in &chans {
-                ui.rename_nick(
-                    &old_nick,
-                    &nick,
-                    time::now(),
-                    &MsgTarget::Chan { serv, chan },
-                );
+                ui.rename_nick(&old_nick, &nick, ts, &MsgTarget::Chan { serv, chan });
             }
             if ui.user_tab_exists(serv, &old_nick) {
                 ui.rename_nick(
                     &old_nick,
                     &nick,
-                    time::now(),
+                    ts,
                     &MsgTarget::User {
                         serv,
                         nick: &old_nick,
@@ -419,13 +631,139 @@ fn handle_irc_msg(ui: &UI, client: &dyn Client, msg: wire::Msg) {
             }
         }
 
-        Reply { num: 433, .. } => {
-            // ERR_NICKNAMEINUSE
+        KICK {
+            chan,
+            nick: victim,
+            msg,
+        } => {
+            let kicker = pfx_sender(&pfx, serv);
+            let reason = match msg {
+                Some(ref msg) if !msg.is_empty() => format!(": {}", msg),
+                _ => "".to_owned(),
+            };
+            let chan_target = MsgTarget::Chan { serv, chan: &chan };
+
+            if client.is_own_nick(&victim) {
+                ui.add_err_msg(
+                    &format!("You were kicked by {}{}", kicker, reason),
+                    ts,
+                    &chan_target,
+                );
+                ui.set_tab_style(TabStyle::Highlight, &chan_target);
+            } else {
+                ui.remove_nick(&victim, None, &chan_target);
+                ui.add_msg(
+                    &format!("{} was kicked by {}{}", victim, kicker, reason),
+                    ts,
+                    &chan_target,
+                );
+                ui.set_tab_style(TabStyle::JoinOrPart, &chan_target);
+            }
+        }
+
+        MODE {
+            target, changes, ..
+        } => {
+            if changes.is_empty() {
+                return;
+            }
+            let setter = pfx_sender(&pfx, serv);
+            let modes = wire::mode_str(&changes);
+            match target {
+                wire::MsgTarget::Chan(chan) => {
+                    // Update membership prefixes of the users whose prefix modes changed
+                    if let Some(info) = client.get_channel(&chan) {
+                        let mapping = client.get_case_mapping();
+                        for change in &changes {
+                            let nick = match &change.arg {
+                                Some(nick) if client.is_prefix_mode(change.mode) => nick,
+                                _ => continue,
+                            };
+                            if let Some(member) = info
+                                .members
+                                .iter()
+                                .find(|member| mapping.equals(&member.nick, nick))
+                            {
+                                let prefix = member.prefixes.chars().next();
+                                ui.set_nick_prefix(serv, &chan, &member.nick, prefix);
+                            }
+                        }
+                    }
+                    ui.add_msg(
+                        &format!("{} sets mode {}", setter, modes),
+                        ts,
+                        &MsgTarget::Chan { serv, chan: &chan },
+                    );
+                }
+                wire::MsgTarget::User(nick) => {
+                    ui.add_msg(
+                        &format!("{} sets mode {} on {}", setter, modes, nick),
+                        ts,
+                        &MsgTarget::Server { serv },
+                    );
+                }
+            }
+        }
+
+        INVITE { nick, chan } => {
+            let inviter = pfx_sender(&pfx, serv);
+            if client.is_own_nick(&nick) {
+                let msg_target = MsgTarget::Server { serv };
+                ui.add_msg(
+                    &format!("{} invited you to {}", inviter, chan.display()),
+                    ts,
+                    &msg_target,
+                );
+                ui.set_tab_style(TabStyle::Highlight, &msg_target);
+            } else {
+                // invite-notify: someone else was invited to a channel we're in
+                ui.add_msg(
+                    &format!("{} invited {} to {}", inviter, nick, chan.display()),
+                    ts,
+                    &MsgTarget::Chan { serv, chan: &chan },
+                );
+            }
+        }
+
+        AWAY { msg } => {
+            if let Some(User { nick, .. }) | Some(Ambiguous(nick)) = pfx {
+                ui.set_nick_away(serv, &nick, msg.is_some());
+            }
+        }
+
+        ACCOUNT { account } => {
+            // Only shown in the private tab of the user, to avoid noise in channels
+            if let Some(User { nick, .. }) | Some(Ambiguous(nick)) = pfx {
+                if ui.user_tab_exists(serv, &nick) {
+                    let msg = match account {
+                        Some(account) => format!("{} is now logged in as {}", nick, account),
+                        None => format!("{} logged out", nick),
+                    };
+                    ui.add_msg(&msg, ts, &MsgTarget::User { serv, nick: &nick });
+                }
+            }
+        }
+
+        CHGHOST { .. } | SETNAME { .. } => {
+            // Tracked by the client, see `Client::get_user`
+        }
+
+        WALLOPS { msg } => {
+            let sender = pfx_sender(&pfx, serv);
+            let msg_target = MsgTarget::Server { serv };
+            ui.add_privmsg(sender, &msg, ts, &msg_target, false, false);
+            ui.set_tab_style(TabStyle::NewMsg, &msg_target);
+        }
+
+        Reply {
+            num: numeric::ERR_NICKNAMEINUSE,
+            ..
+        } => {
             if client.is_nick_accepted() {
                 // Nick change request from user failed. Just show an error message.
                 ui.add_err_msg(
                     "Nickname is already in use",
-                    time::now(),
+                    ts,
                     &MsgTarget::AllServTabs { serv },
                 );
             }
@@ -436,149 +774,142 @@ fn handle_irc_msg(ui: &UI, client: &dyn Client, msg: wire::Msg) {
         }
 
         ERROR { msg } => {
-            ui.add_err_msg(&msg, time::now(), &MsgTarget::AllServTabs { serv });
+            ui.add_err_msg(&msg, ts, &MsgTarget::AllServTabs { serv });
         }
 
         TOPIC { chan, topic } => {
-            ui.set_topic(&topic, time::now(), serv, &chan);
+            ui.set_topic(&topic, ts, serv, &chan);
         }
 
         CAP {
             client: _,
             subcommand,
             params,
+            more,
         } => match subcommand.as_ref() {
             "NAK" => {
                 if params.iter().any(|cap| cap.as_str() == "sasl") {
                     let msg_target = MsgTarget::Server { serv };
                     ui.add_err_msg(
                         "Server rejected using SASL authenication capability",
-                        time::now(),
+                        ts,
                         &msg_target,
                     );
                 }
             }
-            "LS" => {
-                if !params.iter().any(|cap| cap.as_str() == "sasl") {
+            "LS" if !more => {
+                if client.is_cap_wanted("sasl") && !client.is_cap_available("sasl") {
                     let msg_target = MsgTarget::Server { serv };
                     ui.add_err_msg(
                         "Server does not support SASL authenication",
-                        time::now(),
+                        ts,
                         &msg_target,
                     );
                 }
             }
-            "ACK" => {}
+            "LS" | "ACK" | "NEW" | "DEL" => {}
             cmd => {
                 debug!("Ignoring CAP subcommand {}: params={:?}", cmd, params);
             }
         },
 
-        AUTHENTICATE { .. } => {
+        AUTHENTICATE { .. } | BATCH { .. } => {
             // Ignore
         }
 
-        Reply { num: n, params } => {
-            let n_params = params.len();
-            if (
-                n <= 003 // RPL_WELCOME, RPL_YOURHOST, RPL_CREATED
-                    || n == 251 // RPL_LUSERCLIENT
-                    || n == 255 // RPL_LUSERME
-                    || n == 372 // RPL_MOTD
-                    || n == 375 // RPL_MOTDSTART
-                    || n == 376
-                // RPL_ENDOFMOTD
-            ) && n_params == 2
-            {
-                let msg = &params[1];
-                ui.add_msg(msg, time::now(), &MsgTarget::Server { serv });
-            } else if n == 4 // RPL_MYINFO
-                    || n == 5 // RPL_BOUNCE
-                    || (n >= 252 && n <= 254)
-            // RPL_LUSEROP, RPL_LUSERUNKNOWN, RPL_LUSERCHANNELS
-            {
-                let msg = params.into_iter().collect::<Vec<String>>().join(" ");
-                ui.add_msg(&msg, time::now(), &MsgTarget::Server { serv });
-            } else if (n == 265 || n == 266 || n == 250) && n_params > 0 {
-                let msg = &params[n_params - 1];
-                ui.add_msg(msg, time::now(), &MsgTarget::Server { serv });
-            }
-            // RPL_TOPIC
-            else if n == 332 && (n_params == 3 || n_params == 2) {
-                // RFC 2812 says this will have 2 arguments, but freenode sends 3 arguments (extra
-                // one being our nick).
-                let chan = &params[n_params - 2];
-                let topic = &params[n_params - 1];
-                ui.set_topic(topic, time::now(), serv, ChanNameRef::new(chan));
-            }
-            // RPL_NAMREPLY: List of users in a channel
-            else if n == 353 && n_params > 3 {
-                let chan = &params[2];
-                let chan_target = MsgTarget::Chan {
-                    serv,
-                    chan: ChanNameRef::new(chan),
-                };
+        Reply { num, params } => match Numeric::parse(num, &params) {
+            Numeric::RplWelcome { msg }
+            | Numeric::RplYourHost { msg }
+            | Numeric::RplCreated { msg }
+            | Numeric::RplStatsConn { msg }
+            | Numeric::RplLuserClient { msg }
+            | Numeric::RplLuserMe { msg }
+            | Numeric::RplLocalUsers { msg }
+            | Numeric::RplGlobalUsers { msg }
+            | Numeric::RplMotd { line: msg }
+            | Numeric::RplMotdStart { msg }
+            | Numeric::RplEndOfMotd { msg } => {
+                ui.add_msg(msg, ts, &MsgTarget::Server { serv });
+            }
 
-                for nick in params[3].split_whitespace() {
-                    ui.add_nick(wire::drop_nick_prefix(nick), None, &chan_target);
-                }
+            Numeric::RplISupport { .. } => {
+                // The client updates the case mapping before we get the message
+                ui.set_case_mapping(serv, client.get_case_mapping());
+                let msg = params.join(" ");
+                ui.add_msg(&msg, ts, &MsgTarget::Server { serv });
+            }
+
+            Numeric::RplMyInfo { .. }
+            | Numeric::RplLuserOp { .. }
+            | Numeric::RplLuserUnknown { .. }
+            | Numeric::RplLuserChannels { .. } => {
+                let msg = params.join(" ");
+                ui.add_msg(&msg, ts, &MsgTarget::Server { serv });
             }
-            // RPL_ENDOFNAMES: End of NAMES list
-            else if n == 366 {
+
+            Numeric::RplTopic { chan, topic } => {
+                ui.set_topic(topic, ts, serv, chan);
+            }
+
+            // Reply to a `/mode #chan` query. Replies to the query that libtiny_client sends when
+            // we join a channel are not sent to us.
+            Numeric::RplChannelModeIs { chan, modes } => {
+                ui.add_client_msg(
+                    &format!("Channel modes: {}", modes.join(" ")),
+                    &MsgTarget::Chan { serv, chan },
+                );
             }
-            // RPL_UNAWAY or RPL_NOWAWAY
-            else if (n == 305 || n == 306) && n_params > 1 {
-                let msg = &params[1];
+
+            Numeric::RplCreationTime { .. } => {}
+
+            // List of users in a channel. With `userhost-in-names` nicks are `nick!user@host`.
+            Numeric::RplNamReply { chan, nicks, .. } => {
+                let chan_target = MsgTarget::Chan { serv, chan };
+                for nick in nicks.split_whitespace() {
+                    let (prefixes, nick) = client.split_nick_prefix(nick);
+                    let nick = nick.split('!').next().unwrap_or(nick);
+                    ui.add_nick(nick, None, &chan_target);
+                    ui.set_nick_prefix(serv, chan, nick, prefixes.chars().next());
+                }
+            }
+
+            Numeric::RplEndOfNames { .. } => {}
+
+            Numeric::RplUnaway { msg } | Numeric::RplNowAway { msg } => {
                 ui.add_client_msg(msg, &MsgTarget::AllServTabs { serv });
             }
-            // ERR_NOSUCHNICK
-            else if n == 401 && n_params > 2 {
-                let nick = &params[1];
-                let msg = &params[2];
+
+            Numeric::ErrNoSuchNick { nick, msg } => {
                 ui.add_client_msg(msg, &MsgTarget::User { serv, nick });
-            // RPL_AWAY
-            } else if n == 301 && n_params > 2 {
-                let nick = &params[1];
-                let msg = &params[2];
+            }
+
+            Numeric::RplAway { nick, msg } => {
+                ui.set_nick_away(serv, nick, true);
                 ui.add_client_msg(
                     &format!("{} is away: {}", nick, msg),
                     &MsgTarget::User { serv, nick },
                 );
-            } else {
-                match pfx {
-                    Some(Server(msg_serv)) | Some(Ambiguous(msg_serv)) => {
-                        let msg_target = MsgTarget::Server { serv };
-                        ui.add_privmsg(
-                            &msg_serv,
-                            &params.join(" "),
-                            time::now(),
-                            &msg_target,
-                            false,
-                            false,
-                        );
-                        ui.set_tab_style(TabStyle::NewMsg, &msg_target);
-                    }
-                    Some(User { .. }) | None => {
-                        debug!(
-                            "Ignoring numeric reply {}: pfx={:?}, params={:?}",
-                            n, pfx, params
-                        );
-                    }
-                }
             }
-        }
+
+            _ => match pfx {
+                Some(Server(msg_serv)) | Some(Ambiguous(msg_serv)) => {
+                    let msg_target = MsgTarget::Server { serv };
+                    ui.add_privmsg(&msg_serv, &params.join(" "), ts, &msg_target, false, false);
+                    ui.set_tab_style(TabStyle::NewMsg, &msg_target);
+                }
+                Some(User { .. }) | None => {
+                    debug!(
+                        "Ignoring numeric reply {}: pfx={:?}, params={:?}",
+                        num, pfx, params
+                    );
+                }
+            },
+        },
 
         Other { cmd, params } => match pfx {
             Some(Server(msg_serv)) => {
                 let msg_target = MsgTarget::Server { serv };
-                ui.add_privmsg(
-                    &msg_serv,
-                    &params.join(" "),
-                    time::now(),
-                    &msg_target,
-                    false,
-                    false,
-                );
+                ui.add_privmsg(&msg_serv, &params.join(" "), ts, &msg_target, false, false);
                 ui.set_tab_style(TabStyle::NewMsg, &msg_target);
             }
             Some(User { .. }) | Some(Ambiguous(_)) | None => {
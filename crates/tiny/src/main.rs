@@ -12,7 +12,7 @@ mod utils;
 mod tests;
 
 use libtiny_client::{Client, ServerInfo};
-use libtiny_common::{ChanNameRef, MsgTarget};
+use libtiny_common::MsgTarget;
 use libtiny_logger::{Logger, LoggerInitError};
 use libtiny_tui::TUI;
 use ui::UI;
@@ -140,25 +140,62 @@ fn run(
         let mut clients: Vec<Client> = Vec::with_capacity(servers.len());
 
         for server in servers.iter().cloned() {
-            tui.new_server_tab(&server.addr, server.alias);
+            tui.new_server_tab(&server.addr, server.alias.clone());
+
+            // Don't connect without the certificate: the server may accept the connection with a
+            // different identity, or without authentication
+            let tls_client_cert = match server.read_tls_client_cert() {
+                Ok(cert) => cert,
+                Err(err) => {
+                    tui.add_client_err_msg(
+                        &format!("{}. Not connecting to the server.", err),
+                        &MsgTarget::Server { serv: &server.addr },
+                    );
+                    continue;
+                }
+            };
 
             let server_info = ServerInfo {
                 addr: server.addr,
                 port: server.port,
                 tls: server.tls,
+                tls_client_cert,
                 pass: server.pass,
                 realname: server.realname,
                 nicks: server.nicks,
                 auto_join: server
                     .join
                     .iter()
-                    .map(|c| ChanNameRef::new(c).to_owned())
+                    .filter_map(|c| libtiny_client::AutoJoin::parse(c))
                     .collect(),
                 nickserv_ident: server.nickserv_ident,
-                sasl_auth: server.sasl_auth.map(|auth| libtiny_client::SASLAuth {
-                    username: auth.username,
-                    password: auth.password,
+                sasl_auth: server.sasl_auth.map(|auth| match auth.mechanism {
+                    config::SASLMechanism::Plain => libtiny_client::SASLAuth::Plain {
+                        username: auth.username,
+                        password: auth.password,
+                    },
+                    config::SASLMechanism::External => libtiny_client::SASLAuth::External,
+                    config::SASLMechanism::ScramSha256 => libtiny_client::SASLAuth::ScramSha256 {
+                        username: auth.username,
+                        password: auth.password,
+                    },
+                    config::SASLMechanism::ScramSha1 => libtiny_client::SASLAuth::ScramSha1 {
+                        username: auth.username,
+                        password: auth.password,
+                    },
                 }),
+                encoding: server.encoding,
+                send_encoding: server.send_encoding,
+                caps: server
+                    .capabilities
+                    .unwrap_or_else(libtiny_client::default_caps),
+                send_burst: server
+                    .send_burst
+                    .unwrap_or(libtiny_client::DEFAULT_SEND_BURST),
+                send_interval_ms: server
+                    .send_interval_ms
+                    .unwrap_or(libtiny_client::DEFAULT_SEND_INTERVAL_MS),
+                proxy: server.proxy.as_ref().map(config::Proxy::to_client_proxy),
             };
 
             let (client, rcv_conn_ev) = Client::new(server_info);
@@ -1,6 +1,6 @@
 use crate::conn;
 use crate::ui::UI;
-use libtiny_common::ChanName;
+use libtiny_common::{CaseMapping, ChanName, ChanNameRef};
 use libtiny_tui::test_utils::expect_screen;
 use libtiny_tui::TUI;
 use libtiny_wire::{Cmd, Msg, MsgTarget, Pfx};
@@ -33,6 +33,42 @@ impl conn::Client for TestClient {
     fn is_nick_accepted(&self) -> bool {
         true
     }
+
+    fn get_case_mapping(&self) -> CaseMapping {
+        CaseMapping::default()
+    }
+
+    fn is_chan(&self, name: &str) -> bool {
+        libtiny_wire::ISupport::default().is_chan(name)
+    }
+
+    fn is_prefix_mode(&self, mode: char) -> bool {
+        libtiny_wire::ISupport::default().is_prefix_mode(mode)
+    }
+
+    fn split_nick_prefix<'a>(&self, nick: &'a str) -> (&'a str, &'a str) {
+        libtiny_wire::ISupport::default().split_nick_prefix(nick)
+    }
+
+    fn get_channel(&self, _chan: &ChanNameRef) -> Option<client::ChannelInfo> {
+        None
+    }
+
+    fn is_cap_wanted(&self, _cap: &str) -> bool {
+        false
+    }
+
+    fn is_cap_available(&self, _cap: &str) -> bool {
+        false
+    }
+}
+
+/// A message event, timestamped with the current time.
+fn msg_ev(msg: Msg) -> client::Event {
+    client::Event::Msg {
+        msg,
+        timestamp: time::now(),
+    }
 }
 
 static SERV_NAME: &str = "x.y.z";
@@ -113,44 +149,47 @@ fn test_privmsg_from_user_without_user_or_host_part_issue_247() {
 
             // Join a channel to test msg sent to channel
             let join = Msg {
+                tags: Default::default(),
                 pfx: Some(Pfx::User {
                     nick: "osa1".to_owned(),
                     user: "a@b".to_owned(),
                 }),
                 cmd: Cmd::JOIN {
-                    chan: ChanName::new("#chan".to_owned()),
+                    chans: vec![ChanName::new("#chan".to_owned())],
+                    keys: vec![],
+                    account: None,
+                    realname: None,
                 },
             };
-            snd_conn_ev.send(client::Event::Msg(join)).await.unwrap();
+            snd_conn_ev.send(msg_ev(join)).await.unwrap();
             yield_(5).await;
 
             // Send a PRIVMSG to the channel
             let chan_msg = Msg {
+                tags: Default::default(),
                 pfx: Some(Pfx::Ambiguous("blah".to_owned())),
                 cmd: Cmd::PRIVMSG {
-                    target: MsgTarget::Chan(ChanName::new("#chan".to_owned())),
+                    targets: vec![MsgTarget::Chan(ChanName::new("#chan".to_owned()))],
                     msg: "msg to chan".to_owned(),
                     is_notice: false,
                     ctcp: None,
                 },
             };
-            snd_conn_ev
-                .send(client::Event::Msg(chan_msg))
-                .await
-                .unwrap();
+            snd_conn_ev.send(msg_ev(chan_msg)).await.unwrap();
             yield_(5).await;
 
             // Send a PRIVMSG to current nick
             let msg = Msg {
+                tags: Default::default(),
                 pfx: Some(Pfx::Ambiguous("blah".to_owned())),
                 cmd: Cmd::PRIVMSG {
-                    target: MsgTarget::User("osa1".to_owned()),
+                    targets: vec![MsgTarget::User("osa1".to_owned())],
                     msg: "msg to user".to_owned(),
                     is_notice: false,
                     ctcp: None,
                 },
             };
-            snd_conn_ev.send(client::Event::Msg(msg)).await.unwrap();
+            snd_conn_ev.send(msg_ev(msg)).await.unwrap();
             yield_(5).await;
 
             // Check channel tab
@@ -221,19 +260,20 @@ fn test_bouncer_relay_issue_271() {
                 .unwrap();
 
             let msg = Msg {
+                tags: Default::default(),
                 pfx: Some(Pfx::User {
                     nick: "osa1-soju".to_owned(),
                     user: "osa1-soju@127.0.0.1".to_owned(),
                 }),
                 cmd: Cmd::PRIVMSG {
-                    target: MsgTarget::User("osa1/oftc".to_owned()),
+                    targets: vec![MsgTarget::User("osa1/oftc".to_owned())],
                     msg: "blah blah".to_owned(),
                     is_notice: false,
                     ctcp: None,
                 },
             };
 
-            snd_conn_ev.send(client::Event::Msg(msg)).await.unwrap();
+            snd_conn_ev.send(msg_ev(msg)).await.unwrap();
 
             yield_(5).await;
             tui.draw();
@@ -284,13 +324,14 @@ fn test_privmsg_targetmask_issue_278() {
                 .unwrap();
 
             snd_conn_ev
-                .send(client::Event::Msg(Msg {
+                .send(msg_ev(Msg {
+                    tags: Default::default(),
                     pfx: Some(Pfx::User {
                         nick: "e".to_owned(),
                         user: "e@a/b/c.d".to_owned(),
                     }),
                     cmd: Cmd::PRIVMSG {
-                        target: MsgTarget::User("$$*".to_owned()),
+                        targets: vec![MsgTarget::User("$$*".to_owned())],
                         msg: "blah blah blah".to_owned(),
                         is_notice: true,
                         ctcp: None,
@@ -328,6 +369,426 @@ fn test_privmsg_targetmask_issue_278() {
     )
 }
 
+#[test]
+fn test_kick() {
+    run_test(
+        "osa1".to_owned(),
+        |TestSetup {
+             tui,
+             snd_input_ev,
+             snd_conn_ev,
+         }| async move {
+            snd_conn_ev.send(client::Event::Connected).await.unwrap();
+            snd_conn_ev
+                .send(client::Event::NickChange {
+                    new_nick: "osa1".to_owned(),
+                })
+                .await
+                .unwrap();
+
+            let join = Msg {
+                tags: Default::default(),
+                pfx: Some(Pfx::User {
+                    nick: "osa1".to_owned(),
+                    user: "a@b".to_owned(),
+                }),
+                cmd: Cmd::JOIN {
+                    chans: vec![ChanName::new("#chan".to_owned())],
+                    keys: vec![],
+                    account: None,
+                    realname: None,
+                },
+            };
+            snd_conn_ev.send(msg_ev(join)).await.unwrap();
+
+            let kick = Msg {
+                tags: Default::default(),
+                pfx: Some(Pfx::User {
+                    nick: "op".to_owned(),
+                    user: "a@b".to_owned(),
+                }),
+                cmd: Cmd::KICK {
+                    chan: ChanName::new("#chan".to_owned()),
+                    // Nicks are case insensitive
+                    nick: "OSA1".to_owned(),
+                    msg: Some("bye".to_owned()),
+                },
+            };
+            snd_conn_ev.send(msg_ev(kick)).await.unwrap();
+            yield_(5).await;
+
+            next_tab(&snd_input_ev).await; // server tab
+            next_tab(&snd_input_ev).await; // channel tab
+            yield_(5).await;
+            tui.draw();
+
+            #[rustfmt::skip]
+            let screen =
+            "|                                        |
+             |                                        |
+             |00:00 You were kicked by op: bye        |
+             |osa1:                                   |
+             |mentions x.y.z #chan                    |";
+
+            let mut front_buffer = tui.get_front_buffer();
+            normalize_timestamps(&mut front_buffer, DEFAULT_TUI_WIDTH, DEFAULT_TUI_HEIGHT);
+            expect_screen(
+                screen,
+                &front_buffer,
+                DEFAULT_TUI_WIDTH,
+                DEFAULT_TUI_HEIGHT,
+                Location::caller(),
+            );
+        },
+    )
+}
+
+#[test]
+fn test_privmsg_multiple_targets() {
+    run_test(
+        "osa1".to_owned(),
+        |TestSetup {
+             tui,
+             snd_input_ev,
+             snd_conn_ev,
+         }| async move {
+            snd_conn_ev.send(client::Event::Connected).await.unwrap();
+            snd_conn_ev
+                .send(client::Event::NickChange {
+                    new_nick: "osa1".to_owned(),
+                })
+                .await
+                .unwrap();
+
+            let join = Msg {
+                tags: Default::default(),
+                pfx: Some(Pfx::User {
+                    nick: "osa1".to_owned(),
+                    user: "a@b".to_owned(),
+                }),
+                cmd: Cmd::JOIN {
+                    chans: vec![ChanName::new("#chan".to_owned())],
+                    keys: vec![],
+                    account: None,
+                    realname: None,
+                },
+            };
+            snd_conn_ev.send(msg_ev(join)).await.unwrap();
+
+            // Both targets are the same tab, the message is shown once
+            let privmsg = Msg {
+                tags: Default::default(),
+                pfx: Some(Pfx::User {
+                    nick: "op".to_owned(),
+                    user: "a@b".to_owned(),
+                }),
+                cmd: Cmd::PRIVMSG {
+                    targets: vec![
+                        MsgTarget::Chan(ChanName::new("#chan".to_owned())),
+                        MsgTarget::Chan(ChanName::new("#CHAN".to_owned())),
+                    ],
+                    msg: "hi".to_owned(),
+                    is_notice: false,
+                    ctcp: None,
+                },
+            };
+            snd_conn_ev.send(msg_ev(privmsg)).await.unwrap();
+            yield_(5).await;
+
+            next_tab(&snd_input_ev).await; // server tab
+            next_tab(&snd_input_ev).await; // channel tab
+            yield_(5).await;
+            tui.draw();
+
+            #[rustfmt::skip]
+            let screen =
+            "|                                        |
+             |                                        |
+             |00:00 op: hi                            |
+             |osa1:                                   |
+             |mentions x.y.z #chan                    |";
+
+            let mut front_buffer = tui.get_front_buffer();
+            normalize_timestamps(&mut front_buffer, DEFAULT_TUI_WIDTH, DEFAULT_TUI_HEIGHT);
+            expect_screen(
+                screen,
+                &front_buffer,
+                DEFAULT_TUI_WIDTH,
+                DEFAULT_TUI_HEIGHT,
+                Location::caller(),
+            );
+        },
+    )
+}
+
+#[test]
+fn test_server_time() {
+    run_test(
+        "osa1".to_owned(),
+        |TestSetup {
+             tui,
+             snd_input_ev,
+             snd_conn_ev,
+         }| async move {
+            snd_conn_ev.send(client::Event::Connected).await.unwrap();
+            snd_conn_ev
+                .send(client::Event::NickChange {
+                    new_nick: "osa1".to_owned(),
+                })
+                .await
+                .unwrap();
+
+            let join = Msg {
+                tags: Default::default(),
+                pfx: Some(Pfx::User {
+                    nick: "osa1".to_owned(),
+                    user: "a@b".to_owned(),
+                }),
+                cmd: Cmd::JOIN {
+                    chans: vec![ChanName::new("#chan".to_owned())],
+                    keys: vec![],
+                    account: None,
+                    realname: None,
+                },
+            };
+            snd_conn_ev.send(msg_ev(join)).await.unwrap();
+
+            // Messages are shown with the timestamp of the event, e.g. the server-time of a
+            // message replayed by a bouncer
+            let msg = Msg {
+                tags: Default::default(),
+                pfx: Some(Pfx::Ambiguous("blah".to_owned())),
+                cmd: Cmd::PRIVMSG {
+                    targets: vec![MsgTarget::Chan(ChanName::new("#chan".to_owned()))],
+                    msg: "old msg".to_owned(),
+                    is_notice: false,
+                    ctcp: None,
+                },
+            };
+            let timestamp = time::Tm {
+                tm_hour: 12,
+                tm_min: 34,
+                ..time::empty_tm()
+            };
+            snd_conn_ev
+                .send(client::Event::Msg { msg, timestamp })
+                .await
+                .unwrap();
+            yield_(5).await;
+
+            next_tab(&snd_input_ev).await; // server tab
+            next_tab(&snd_input_ev).await; // channel tab
+            yield_(5).await;
+            tui.draw();
+
+            #[rustfmt::skip]
+            let screen =
+            "|                                        |
+             |                                        |
+             |12:34 blah: old msg                     |
+             |osa1:                                   |
+             |mentions x.y.z #chan                    |";
+
+            expect_screen(
+                screen,
+                &tui.get_front_buffer(),
+                DEFAULT_TUI_WIDTH,
+                DEFAULT_TUI_HEIGHT,
+                Location::caller(),
+            );
+        },
+    )
+}
+
+#[test]
+fn test_echo_message() {
+    run_test(
+        "osa1".to_owned(),
+        |TestSetup {
+             tui,
+             snd_input_ev,
+             snd_conn_ev,
+         }| async move {
+            snd_conn_ev.send(client::Event::Connected).await.unwrap();
+            snd_conn_ev
+                .send(client::Event::NickChange {
+                    new_nick: "osa1".to_owned(),
+                })
+                .await
+                .unwrap();
+
+            let join = Msg {
+                tags: Default::default(),
+                pfx: Some(Pfx::User {
+                    nick: "osa1".to_owned(),
+                    user: "a@b".to_owned(),
+                }),
+                cmd: Cmd::JOIN {
+                    chans: vec![ChanName::new("#chan".to_owned())],
+                    keys: vec![],
+                    account: None,
+                    realname: None,
+                },
+            };
+            snd_conn_ev.send(msg_ev(join)).await.unwrap();
+
+            // Our message echoed back by the server is shown, without highlighting
+            let echo = Msg {
+                tags: Default::default(),
+                pfx: Some(Pfx::User {
+                    nick: "osa1".to_owned(),
+                    user: "a@b".to_owned(),
+                }),
+                cmd: Cmd::PRIVMSG {
+                    targets: vec![MsgTarget::Chan(ChanName::new("#chan".to_owned()))],
+                    msg: "hi osa1".to_owned(),
+                    is_notice: false,
+                    ctcp: None,
+                },
+            };
+            snd_conn_ev.send(msg_ev(echo)).await.unwrap();
+            snd_conn_ev
+                .send(client::Event::MsgNotEchoed {
+                    target: "#chan".to_owned(),
+                    msg: "bye".to_owned(),
+                })
+                .await
+                .unwrap();
+            yield_(5).await;
+
+            next_tab(&snd_input_ev).await; // server tab
+            next_tab(&snd_input_ev).await; // channel tab
+            yield_(5).await;
+            tui.draw();
+
+            #[rustfmt::skip]
+            let screen =
+            "|                                        |
+             |00:00 osa1: hi osa1                     |
+             |Message not confirmed by the server: bye|
+             |osa1:                                   |
+             |mentions x.y.z #chan                    |";
+
+            let mut front_buffer = tui.get_front_buffer();
+            normalize_timestamps(&mut front_buffer, DEFAULT_TUI_WIDTH, DEFAULT_TUI_HEIGHT);
+            expect_screen(
+                screen,
+                &front_buffer,
+                DEFAULT_TUI_WIDTH,
+                DEFAULT_TUI_HEIGHT,
+                Location::caller(),
+            );
+        },
+    )
+}
+
+#[test]
+fn test_history() {
+    run_test(
+        "osa1".to_owned(),
+        |TestSetup {
+             tui,
+             snd_input_ev,
+             snd_conn_ev,
+         }| async move {
+            snd_conn_ev.send(client::Event::Connected).await.unwrap();
+            snd_conn_ev
+                .send(client::Event::NickChange {
+                    new_nick: "osa1".to_owned(),
+                })
+                .await
+                .unwrap();
+
+            let join = Msg {
+                tags: Default::default(),
+                pfx: Some(Pfx::User {
+                    nick: "osa1".to_owned(),
+                    user: "a@b".to_owned(),
+                }),
+                cmd: Cmd::JOIN {
+                    chans: vec![ChanName::new("#chan".to_owned())],
+                    keys: vec![],
+                    account: None,
+                    realname: None,
+                },
+            };
+            snd_conn_ev.send(msg_ev(join)).await.unwrap();
+
+            // Messages that mention us are not highlighted and not added to the mentions tab
+            let privmsg = |msg: &str| {
+                (
+                    Msg {
+                        tags: Default::default(),
+                        pfx: Some(Pfx::User {
+                            nick: "bob".to_owned(),
+                            user: "a@b".to_owned(),
+                        }),
+                        cmd: Cmd::PRIVMSG {
+                            targets: vec![MsgTarget::Chan(ChanName::new("#chan".to_owned()))],
+                            msg: msg.to_owned(),
+                            is_notice: false,
+                            ctcp: None,
+                        },
+                    },
+                    time::now(),
+                )
+            };
+            snd_conn_ev
+                .send(client::Event::History {
+                    target: MsgTarget::Chan(ChanName::new("#chan".to_owned())),
+                    msgs: vec![privmsg("hi osa1"), privmsg("bye")],
+                })
+                .await
+                .unwrap();
+            yield_(5).await;
+
+            next_tab(&snd_input_ev).await; // server tab
+            next_tab(&snd_input_ev).await; // channel tab
+            yield_(5).await;
+            tui.draw();
+
+            #[rustfmt::skip]
+            let screen =
+            "|                                        |
+             |00:00 bob: hi osa1                      |
+             |bob: bye                                |
+             |osa1:                                   |
+             |mentions x.y.z #chan                    |";
+
+            let mut front_buffer = tui.get_front_buffer();
+            normalize_timestamps(&mut front_buffer, DEFAULT_TUI_WIDTH, DEFAULT_TUI_HEIGHT);
+            expect_screen(
+                screen,
+                &front_buffer,
+                DEFAULT_TUI_WIDTH,
+                DEFAULT_TUI_HEIGHT,
+                Location::caller(),
+            );
+
+            next_tab(&snd_input_ev).await; // mentions tab
+            yield_(5).await;
+            tui.draw();
+
+            #[rustfmt::skip]
+            let screen =
+            "|                                        |
+             |                                        |
+             |Any mentions to you will be listed here.|
+             |                                        |
+             |mentions x.y.z #chan                    |";
+
+            let front_buffer = tui.get_front_buffer();
+            expect_screen(
+                screen,
+                &front_buffer,
+                DEFAULT_TUI_WIDTH,
+                DEFAULT_TUI_HEIGHT,
+                Location::caller(),
+            );
+        },
+    )
+}
+
 async fn next_tab(snd_input_ev: &mpsc::Sender<input::Event>) {
     snd_input_ev
         .send(term_input::Event::Key(term_input::Key::Ctrl('n')))
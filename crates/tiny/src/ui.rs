@@ -3,7 +3,7 @@
 use crate::cmd::{parse_cmd, CmdArgs, ParseCmdResult};
 use crate::config;
 use libtiny_client::Client;
-use libtiny_common::{ChanNameRef, MsgSource, MsgTarget, TabStyle};
+use libtiny_common::{CaseMapping, ChanNameRef, MsgSource, MsgTarget, TabStyle};
 use libtiny_logger::Logger;
 use libtiny_tui::TUI;
 
@@ -52,6 +52,7 @@ impl UI {
     }
 
     delegate!(close_server_tab(serv: &str,));
+    delegate!(set_case_mapping(serv: &str, mapping: CaseMapping,));
     delegate!(new_chan_tab(serv: &str, chan: &ChanNameRef,));
     delegate!(close_chan_tab(serv: &str, chan: &ChanNameRef,));
     delegate!(close_user_tab(serv: &str, nick: &str,));
@@ -65,6 +66,22 @@ impl UI {
         highlight: bool,
         is_action: bool,
     ));
+
+    pub(crate) fn add_history_privmsg(
+        &self,
+        sender: &str,
+        msg: &str,
+        ts: Tm,
+        target: &MsgTarget,
+        is_action: bool,
+    ) {
+        self.ui
+            .add_history_privmsg(sender, msg, ts, target, is_action);
+        if let Some(logger) = &self.logger {
+            logger.add_privmsg(sender, msg, ts, target, false, is_action);
+        }
+    }
+
     delegate!(add_nick(nick: &str, ts: Option<Tm>, target: &MsgTarget,));
     delegate!(remove_nick(nick: &str, ts: Option<Tm>, target: &MsgTarget,));
     delegate!(rename_nick(
@@ -85,6 +102,13 @@ impl UI {
     delegate_ui!(add_client_err_msg(msg: &str, target: &MsgTarget,));
     delegate_ui!(clear_nicks(serv: &str,));
     delegate_ui!(set_nick(serv: &str, nick: &str,));
+    delegate_ui!(set_nick_away(serv: &str, nick: &str, away: bool,));
+    delegate_ui!(set_nick_prefix(
+        serv: &str,
+        chan: &ChanNameRef,
+        nick: &str,
+        prefix: Option<char>,
+    ));
     delegate_ui!(set_tab_style(style: TabStyle, target: &MsgTarget,));
     delegate_ui!(user_tab_exists(serv_name: &str, nick: &str,) -> bool);
 
@@ -171,6 +195,11 @@ fn handle_cmd(
 }
 
 // TODO: move this somewhere else
+/// Messages to NickServ and ChanServ are shown in the server tab.
+pub(crate) fn is_service(nick: &str) -> bool {
+    nick.eq_ignore_ascii_case("nickserv") || nick.eq_ignore_ascii_case("chanserv")
+}
+
 pub(crate) fn send_msg(
     ui: &UI,
     clients: &mut Vec<Client>,
@@ -220,9 +249,7 @@ pub(crate) fn send_msg(
             }
 
             MsgSource::User { ref serv, ref nick } => {
-                let msg_target = if nick.eq_ignore_ascii_case("nickserv")
-                    || nick.eq_ignore_ascii_case("chanserv")
-                {
+                let msg_target = if is_service(nick) {
                     MsgTarget::Server { serv }
                 } else {
                     MsgTarget::User { serv, nick }
@@ -239,8 +266,12 @@ pub(crate) fn send_msg(
         } else {
             0
         };
+    // With `echo-message` messages are shown when the server sends them back
+    let local_echo = !client.is_cap_enabled("echo-message");
     for msg in client.split_privmsg(extra_len, &msg) {
         client.privmsg(msg_target, msg, is_action);
-        ui.add_privmsg(&client.get_nick(), msg, ts, &ui_target, false, is_action);
+        if local_echo {
+            ui.add_privmsg(&client.get_nick(), msg, ts, &ui_target, false, is_action);
+        }
     }
 }
@@ -25,6 +25,17 @@ pub(crate) fn split_whitespace_indices(str: &str) -> SplitWhitespaceIndices {
 
 ////////////////////////////////////////////////////////////////////////////////
 
+/// Milliseconds since the Unix epoch. Used as the argument of CTCP PING queries, to calculate
+/// round-trip times when we get the replies.
+pub(crate) fn now_millis() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
 // RFC 2812:
 //
 // nickname   =  ( letter / special ) *8( letter / digit / special / "-" )
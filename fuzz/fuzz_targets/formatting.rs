@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use libtiny_wire::formatting;
+
+fuzz_target!(|msg: &str| {
+    for span in formatting::parse(msg) {
+        assert!(!span.text.is_empty());
+        assert!(!span.text.chars().any(|c| c.is_ascii_control() && c != '\t'));
+    }
+});
@@ -0,0 +1,24 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use libtiny_common::MsgTarget;
+use libtiny_tui::tui::TUI;
+
+// First two bytes are the screen size, rest is the message.
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    let w = u16::from(data[0] % 64) + 1;
+    let h = u16::from(data[1] % 16) + 1;
+    let msg = String::from_utf8_lossy(&data[2..]);
+
+    let serv = "irc.server.org";
+    let target = MsgTarget::Server { serv };
+    let mut tui = TUI::new_test(w, h);
+    tui.new_server_tab(serv, None);
+    tui.add_msg(&msg, time::now(), &target);
+    tui.draw();
+    // Resizing recalculates line heights
+    tui.set_size(w / 2 + 1, h);
+    tui.draw();
+});
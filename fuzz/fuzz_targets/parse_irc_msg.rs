@@ -0,0 +1,19 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use libtiny_wire::{formatting, parse_irc_msg, Cmd, DCC};
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = data.to_vec();
+    while let Some(msg) = parse_irc_msg(&mut buf) {
+        if let Ok(msg) = msg {
+            // Exercise the parsers of message parts too
+            let _ = msg.cmd.numeric();
+            let _ = msg.pfx.as_ref().map(|pfx| pfx.hostmask());
+            if let Cmd::PRIVMSG { msg, .. } = &msg.cmd {
+                let _ = DCC::parse(msg);
+                let _ = formatting::strip(msg);
+            }
+            let _ = msg.to_string();
+        }
+    }
+});